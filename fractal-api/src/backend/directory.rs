@@ -1,6 +1,7 @@
 extern crate serde_json;
 
 use self::serde_json::Value as JsonValue;
+use self::serde_json::Map as JsonMap;
 
 use globals;
 
@@ -10,9 +11,13 @@ use backend::types::BKResponse;
 use backend::types::Backend;
 
 use util::json_q;
+use util::get_room_messages_page;
+use util::get_room_preview_from_json;
 
 use types::Room;
 use types::Protocol;
+use types::DirectoryQuery;
+use types::DirectoryPage;
 
 pub fn protocols(bk: &Backend) -> Result<(), Error> {
     let baseu = bk.get_base_url()?;
@@ -32,15 +37,16 @@ pub fn protocols(bk: &Backend) -> Result<(), Error> {
                 desc: String::from(s.split('/').last().unwrap_or("")),
             });
 
-            let prs = r.as_object().unwrap();
-            for k in prs.keys() {
-                let ins = prs[k]["instances"].as_array().unwrap();
-                for i in ins {
-                    let p = Protocol{
-                        id: String::from(i["instance_id"].as_str().unwrap()),
-                        desc: String::from(i["desc"].as_str().unwrap()),
-                    };
-                    protocols.push(p);
+            // the instances without id can't be searched
+            let empty = JsonMap::new();
+            for (_, p) in r.as_object().unwrap_or(&empty) {
+                for i in p["instances"].as_array().unwrap_or(&vec![]) {
+                    if let Some(id) = i["instance_id"].as_str() {
+                        protocols.push(Protocol {
+                            id: String::from(id),
+                            desc: String::from(i["desc"].as_str().unwrap_or(id)),
+                        });
+                    }
                 }
             }
 
@@ -52,40 +58,37 @@ pub fn protocols(bk: &Backend) -> Result<(), Error> {
     Ok(())
 }
 
-pub fn room_search(bk: &Backend,
-                   query: Option<String>,
-                   third_party: Option<String>,
-                   more: bool)
-                   -> Result<(), Error> {
-
-    let url = bk.url("publicRooms", vec![])?;
+/// Gets the page of rooms of the directory after the @since token, or the first one
+pub fn room_search(bk: &Backend, query: DirectoryQuery, since: Option<String>) -> Result<(), Error> {
+    let mut params = vec![];
+    if let Some(ref server) = query.server {
+        params.push(("server", server.clone()));
+    }
+    let url = bk.url("publicRooms", params)?;
 
-    let mut attrs = json!({"limit": 20});
+    let mut attrs = json!({"limit": query.limit});
 
-    if let Some(q) = query {
+    if let Some(ref q) = query.term {
         attrs["filter"] = json!({
             "generic_search_term": q
         });
     }
 
-    if let Some(tp) = third_party {
+    if query.all_networks {
+        attrs["include_all_networks"] = json!(true);
+    } else if let Some(ref tp) = query.third_party {
         attrs["third_party_instance_id"] = json!(tp);
     }
 
-    if more {
-        let since = bk.data.lock().unwrap().rooms_since.clone();
+    if let Some(ref since) = since {
         attrs["since"] = json!(since);
     }
 
     let tx = bk.tx.clone();
-    let data = bk.data.clone();
     post!(&url, &attrs,
         move |r: JsonValue| {
-            let next_branch = r["next_batch"].as_str().unwrap_or("");
-            data.lock().unwrap().rooms_since = String::from(next_branch);
-
             let mut rooms: Vec<Room> = vec![];
-            for room in r["chunk"].as_array().unwrap() {
+            for room in r["chunk"].as_array().unwrap_or(&vec![]) {
                 let alias = String::from(room["canonical_alias"].as_str().unwrap_or(""));
                 let id = String::from(room["room_id"].as_str().unwrap_or(""));
                 let name = String::from(room["name"].as_str().unwrap_or(""));
@@ -99,10 +102,57 @@ pub fn room_search(bk: &Backend,
                 rooms.push(r);
             }
 
-            tx.send(BKResponse::DirectorySearch(rooms)).unwrap();
+            // some servers send the same token again in the last page
+            let next_batch = match r["next_batch"].as_str() {
+                Some(nb) if !nb.is_empty() && since.as_ref().map(|s| s != nb).unwrap_or(true) => {
+                    Some(String::from(nb))
+                }
+                _ => None,
+            };
+
+            let page = DirectoryPage {
+                query: query,
+                rooms: rooms,
+                total: r["total_room_count_estimate"].as_u64(),
+                next_batch: next_batch,
+            };
+            tx.send(BKResponse::DirectorySearch(page)).unwrap();
         },
         |err| { tx.send(BKResponse::DirectoryError(err)).unwrap(); }
     );
 
     Ok(())
 }
+
+/// Gets the state and the last messages of a world readable room that we haven't joined,
+/// to see it before joining. We peek with initialSync, and some servers only let us read
+/// the messages.
+pub fn room_preview(bk: &Backend, roomid: String) -> Result<(), Error> {
+    let url = bk.url(&format!("rooms/{}/initialSync", roomid),
+                     vec![("limit", format!("{}", globals::PAGE_LIMIT))])?;
+    let baseu = bk.get_base_url()?;
+    let tk = bk.data.lock().unwrap().access_token.clone();
+
+    let tx = bk.tx.clone();
+    thread::spawn(move || {
+        let room = match json_q("get", &url, &json!(null), globals::TIMEOUT) {
+            Ok(r) => Ok(get_room_preview_from_json(&roomid, &r)),
+            Err(_) => {
+                get_room_messages_page(&baseu, tk, roomid.clone(), globals::PAGE_LIMIT, None)
+                    .map(|(ms, prev_batch)| {
+                        let mut room = Room::new(roomid.clone(), None);
+                        room.messages = ms;
+                        room.prev_batch = prev_batch;
+                        room
+                    })
+            }
+        };
+
+        match room {
+            Ok(r) => tx.send(BKResponse::RoomPreview(r)).unwrap(),
+            Err(err) => tx.send(BKResponse::RoomPreviewError(err)).unwrap(),
+        };
+    });
+
+    Ok(())
+}
@@ -1,43 +1,257 @@
+use std::fs;
 use std::thread;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
 use error::Error;
+use globals;
 use backend::types::BKResponse;
 use backend::types::Backend;
+use backend::types::BackendData;
 
 use util::dw_media;
+use util::get_media_file;
+use util;
+use types::UrlPreview;
+use mediacache;
+use mediacache::Bucket;
+
+/// Milliseconds between download progress notifications
+const PROGRESS_INTERVAL: u64 = 500;
+
+// Sends the thumbnail to the requests waiting for it when the download job ends. It's done
+// when it's dropped, so they're answered with an empty path if the job panics.
+struct ThumbWaiters {
+    data: Arc<Mutex<BackendData>>,
+    key: String,
+    fname: String,
+}
+
+impl Drop for ThumbWaiters {
+    fn drop(&mut self) {
+        let waiting = self.data.lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .thumbs_pending.remove(&self.key)
+            .unwrap_or_default();
+        for tx in waiting {
+            // the widget could be destroyed already
+            let _ = tx.send(self.fname.clone());
+        }
+    }
+}
 
 pub fn get_thumb_async(bk: &Backend, media: String, tx: Sender<String>) -> Result<(), Error> {
+    let size = util::avatar_pixels(globals::AVATAR_SIZE);
+    get_media_thumb_async(bk, media, size, size, tx)
+}
+
+/// Sends to @tx the path of the @w x @h thumbnail of the mxc @media, or an empty string if
+/// it can't be downloaded. Thumbnails are downloaded in the thread pool, and only once if
+/// it's requested again before it's done.
+pub fn get_media_thumb_async(bk: &Backend, media: String, w: i32, h: i32, tx: Sender<String>) -> Result<(), Error> {
+    let key = match mediacache::mxc_key(&media, Bucket::Thumb, w, h) {
+        Some(k) => k,
+        None => {
+            tx.send(String::new()).unwrap();
+            return Ok(());
+        }
+    };
+
+    if let Some(fname) = mediacache::lookup(&key) {
+        tx.send(fname).unwrap();
+        return Ok(());
+    }
+
     let baseu = bk.get_base_url()?;
+    {
+        let mut data = bk.data.lock().unwrap();
+        if let Some(waiting) = data.thumbs_pending.get_mut(&key) {
+            waiting.push(tx);
+            return Ok(());
+        }
+        data.thumbs_pending.insert(key.clone(), vec![tx]);
+    }
+
+    let data = bk.data.clone();
+    bk.pool.run(move || {
+        let mut waiters = ThumbWaiters { data: data, key: key, fname: String::new() };
+        waiters.fname = thumb!(&baseu, &media, w, h).unwrap_or_default();
+    });
+
+    Ok(())
+}
 
+/// Sends to @tx the path of the full mxc @media, downloading it to the cache if it isn't
+/// there, or an empty string if it can't be downloaded.
+pub fn get_media_async(bk: &Backend, media: String, tx: Sender<String>) -> Result<(), Error> {
+    let baseu = bk.get_base_url()?;
     thread::spawn(move || {
-        match thumb!(&baseu, &media) {
-            Ok(fname) => {
-                tx.send(fname).unwrap();
-            }
-            Err(_) => {
-                tx.send(String::from("")).unwrap();
+        let cancel = AtomicBool::new(false);
+        let fname = get_media_file(&baseu, &media, &cancel, |_, _| {}).unwrap_or_default();
+        // the widget could be destroyed already
+        let _ = tx.send(fname);
+    });
+
+    Ok(())
+}
+
+/// Sends to @tx the preview of the link @url for the message time @ts, or None if the server
+/// can't preview it. The last previews are kept in memory for the session.
+pub fn get_url_preview(bk: &Backend, url: String, ts: i64, tx: Sender<Option<UrlPreview>>) -> Result<(), Error> {
+    let ts = ts - ts % globals::PREVIEW_TS_ROUND;
+    let key = format!("{} {}", ts, url);
+
+    {
+        let mut data = bk.data.lock().unwrap();
+        if let Some(preview) = data.url_previews.get(&key) {
+            tx.send(preview.clone()).unwrap();
+            return Ok(());
+        }
+        if let Some(waiting) = data.previews_pending.get_mut(&key) {
+            waiting.push(tx);
+            return Ok(());
+        }
+        data.previews_pending.insert(key.clone(), vec![tx]);
+    }
+
+    let baseu = bk.get_base_url()?;
+    let tk = bk.data.lock().unwrap().access_token.clone();
+    let data = bk.data.clone();
+    bk.pool.run(move || {
+        let preview = match util::get_url_preview(&baseu, &tk, &url, ts) {
+            Ok(ref p) if p.is_empty() => None,
+            Ok(p) => Some(p),
+            Err(_) => None,
+        };
+
+        let waiting = {
+            let mut d = data.lock().unwrap();
+            d.url_previews.insert(key.clone(), preview.clone());
+            d.url_previews_order.push_back(key.clone());
+            while d.url_previews_order.len() > globals::URL_PREVIEWS_KEPT {
+                if let Some(old) = d.url_previews_order.pop_front() {
+                    d.url_previews.remove(&old);
+                }
             }
+            d.previews_pending.remove(&key).unwrap_or_default()
         };
+        for tx in waiting {
+            // the message could be destroyed already
+            let _ = tx.send(preview.clone());
+        }
     });
 
     Ok(())
 }
 
 pub fn get_media(bk: &Backend, media: String) -> Result<(), Error> {
-    let baseu = bk.get_base_url()?;
+    download(bk, media, None)
+}
+
+pub fn save_media(bk: &Backend, media: String, dest: String) -> Result<(), Error> {
+    download(bk, media, Some(dest))
+}
 
+pub fn cancel_media(bk: &Backend, media: String) {
+    let mut data = bk.data.lock().unwrap();
+    data.downloads_waiting.remove(&media);
+    // the entry stays until the thread exits, so a new download doesn't write the same
+    // file while the cancelled one is still running
+    if let Some(cancel) = data.downloads.get(&media) {
+        cancel.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Sets the size limit of the media cache, in bytes. The files over the limit are removed
+/// in a thread.
+pub fn set_cache_size(bk: &Backend, size: u64) {
     let tx = bk.tx.clone();
     thread::spawn(move || {
-        match media!(&baseu, &media) {
-            Ok(fname) => {
-                tx.send(BKResponse::Media(fname)).unwrap();
+        if let Err(err) = mediacache::set_max_size(size) {
+            tx.send(BKResponse::MediaError(err)).unwrap();
+        }
+    });
+}
+
+pub fn clear_cache(bk: &Backend) {
+    let tx = bk.tx.clone();
+    thread::spawn(move || {
+        match mediacache::clear() {
+            Ok(_) => tx.send(BKResponse::MediaCacheCleared).unwrap(),
+            Err(err) => tx.send(BKResponse::MediaError(err)).unwrap(),
+        };
+    });
+}
+
+/// Downloads the full media to the cache, sending the progress, and copies it to @dest if
+/// it's set. Only one download for each media runs at the same time, the requests made
+/// while it's running are served when it's done.
+fn download(bk: &Backend, media: String, dest: Option<String>) -> Result<(), Error> {
+    let baseu = bk.get_base_url()?;
+
+    let cancel = {
+        let mut data = bk.data.lock().unwrap();
+        data.downloads_waiting.entry(media.clone()).or_insert(vec![]).push(dest);
+        if data.downloads.contains_key(&media) {
+            return Ok(());
+        }
+        let cancel = Arc::new(AtomicBool::new(false));
+        data.downloads.insert(media.clone(), cancel.clone());
+        cancel
+    };
+
+    let tx = bk.tx.clone();
+    let data = bk.data.clone();
+    thread::spawn(move || loop {
+        let r = {
+            let mut last = Instant::now();
+            let progress = |bytes: u64, total: u64| {
+                // the UI reads the responses slowly, so we don't flood it
+                if last.elapsed() >= Duration::from_millis(PROGRESS_INTERVAL) {
+                    last = Instant::now();
+                    tx.send(BKResponse::MediaProgress(media.clone(), bytes, total)).unwrap();
+                }
+            };
+
+            get_media_file(&baseu, &media, &cancel, progress)
+        };
+
+        let waiting = {
+            let mut d = data.lock().unwrap();
+            let waiting = d.downloads_waiting.remove(&media).unwrap_or_default();
+            if let Err(Error::Cancelled) = r {
+                // it was requested again after the cancel, so we resume it
+                if !waiting.is_empty() {
+                    cancel.store(false, Ordering::SeqCst);
+                    d.downloads_waiting.insert(media.clone(), waiting);
+                    continue;
+                }
             }
+            d.downloads.remove(&media);
+            waiting
+        };
+
+        let fname = match r {
+            Ok(fname) => fname,
             Err(err) => {
-                tx.send(BKResponse::MediaError(err)).unwrap();
+                tx.send(BKResponse::MediaFailed(media, err)).unwrap();
+                break;
             }
         };
+
+        if waiting.contains(&None) {
+            tx.send(BKResponse::Media(media.clone(), fname.clone())).unwrap();
+        }
+        for dest in waiting.into_iter().filter_map(|d| d) {
+            let resp = match fs::copy(&fname, &dest) {
+                Ok(_) => BKResponse::MediaSaved(media.clone(), dest),
+                Err(err) => BKResponse::MediaFailed(media.clone(), Error::from(err)),
+            };
+            tx.send(resp).unwrap();
+        }
+        break;
     });
 
     Ok(())
 }
-
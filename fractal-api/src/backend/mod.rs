@@ -1,6 +1,9 @@
 extern crate url;
 
-use std::sync::{Arc, Mutex, Condvar};
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
 use std::thread;
 use self::url::Url;
 use std::sync::mpsc::{Sender, Receiver};
@@ -8,6 +11,7 @@ use std::sync::mpsc::channel;
 use std::sync::mpsc::RecvError;
 
 use error::Error;
+use globals;
 
 use util::build_url;
 use cache::CacheMap;
@@ -19,6 +23,10 @@ mod room;
 mod sync;
 mod media;
 mod directory;
+mod outbox;
+mod pushrules;
+mod search;
+mod pool;
 
 pub use self::types::BKResponse;
 pub use self::types::BKCommand;
@@ -27,6 +35,10 @@ pub use self::types::Backend;
 pub use self::types::BackendData;
 
 pub use self::types::RoomType;
+pub use self::types::Attachment;
+pub use self::types::ConnectionState;
+
+use self::pool::ThreadPool;
 
 
 impl Backend {
@@ -36,16 +48,29 @@ impl Backend {
             access_token: String::from(""),
             server_url: String::from("https://matrix.org"),
             since: String::from(""),
-            msgid: 1,
-            rooms_since: String::from(""),
+            sync_running: false,
+            sync_gen: 0,
+            outbox: vec![],
+            outbox_failed: HashSet::new(),
+            outbox_sending: HashSet::new(),
+            downloads: HashMap::new(),
+            downloads_waiting: HashMap::new(),
+            uploads: HashMap::new(),
+            uploads_failed: HashMap::new(),
+            upload_limit: None,
+            thumbs_pending: HashMap::new(),
+            url_previews: HashMap::new(),
+            url_previews_order: VecDeque::new(),
+            previews_pending: HashMap::new(),
             join_to_room: String::from(""),
+            joined_rooms: HashSet::new(),
         };
         Backend {
             tx: tx,
             internal_tx: None,
             data: Arc::new(Mutex::new(data)),
             user_info_cache: CacheMap::new().timeout(60*60),
-            limit_threads: Arc::new((Mutex::new(0u8), Condvar::new())),
+            pool: ThreadPool::new(globals::POOL_SIZE),
         }
     }
 
@@ -89,10 +114,14 @@ impl Backend {
                 let r = register::login(self, user, passwd, server);
                 bkerror!(r, tx, BKResponse::LoginError);
             }
-            Ok(BKCommand::Logout) => {
-                let r = register::logout(self);
+            Ok(BKCommand::Logout(wipe)) => {
+                let r = register::logout(self, wipe);
                 bkerror!(r, tx, BKResponse::LogoutError);
             }
+            Ok(BKCommand::DeactivateAccount(passwd, erase)) => {
+                let r = register::deactivate(self, passwd, erase);
+                bkerror!(r, tx, BKResponse::AccountDeactivationError);
+            }
             Ok(BKCommand::Register(user, passwd, server)) => {
                 let r = register::register(self, user, passwd, server);
                 bkerror!(r, tx, BKResponse::LoginError);
@@ -124,13 +153,17 @@ impl Backend {
             // Sync module
 
             Ok(BKCommand::Sync) => {
-                let r = sync::sync(self);
+                let r = sync::start(self, false);
                 bkerror!(r, tx, BKResponse::SyncError);
             }
             Ok(BKCommand::SyncForced) => {
                 let r = sync::force_sync(self);
                 bkerror!(r, tx, BKResponse::SyncError);
             }
+            Ok(BKCommand::StopSync) => {
+                let r = sync::stop(self);
+                bkerror!(r, tx, BKResponse::SyncError);
+            }
 
             // Room module
 
@@ -138,12 +171,32 @@ impl Backend {
                 let r = room::get_room_messages(self, room);
                 bkerror!(r, tx, BKResponse::RoomMessagesError);
             }
+            Ok(BKCommand::BackPaginate(room, token)) => {
+                let r = room::back_paginate(self, room, token);
+                bkerror!(r, tx, BKResponse::RoomMessagesError);
+            }
             Ok(BKCommand::GetMessageContext(message)) => {
                 let r = room::get_message_context(self, message);
                 bkerror!(r, tx, BKResponse::RoomMessagesError);
             }
+            Ok(BKCommand::ChunkPaginate(room, token, dir)) => {
+                let r = room::chunk_paginate(self, room, token, dir);
+                bkerror!(r, tx, BKResponse::RoomMessagesError);
+            }
             Ok(BKCommand::SendMsg(msg)) => {
-                let r = room::send_msg(self, msg);
+                let r = outbox::push(self, msg);
+                bkerror!(r, tx, BKResponse::SendMsgError);
+            }
+            Ok(BKCommand::RetrySendMsg(txnid)) => {
+                let r = outbox::retry(self, txnid);
+                bkerror!(r, tx, BKResponse::SendMsgError);
+            }
+            Ok(BKCommand::CancelSendMsg(txnid)) => {
+                let r = outbox::cancel(self, txnid);
+                bkerror!(r, tx, BKResponse::SendMsgError);
+            }
+            Ok(BKCommand::LoadOutbox) => {
+                let r = outbox::load(self);
                 bkerror!(r, tx, BKResponse::SendMsgError);
             }
             Ok(BKCommand::SetRoom(room)) => {
@@ -178,20 +231,54 @@ impl Backend {
                 let r = room::set_room_avatar(self, roomid, fname);
                 bkerror!(r, tx, BKResponse::SetRoomAvatarError);
             }
+            Ok(BKCommand::AddRoomTag(roomid, tag, order)) => {
+                let r = room::add_room_tag(self, roomid, tag, order);
+                bkerror!(r, tx, BKResponse::RoomTagError);
+            }
+            Ok(BKCommand::RemoveRoomTag(roomid, tag)) => {
+                let r = room::remove_room_tag(self, roomid, tag);
+                bkerror!(r, tx, BKResponse::RoomTagError);
+            }
+            Ok(BKCommand::GetPushRules) => {
+                let r = pushrules::get_push_rules(self);
+                bkerror!(r, tx, BKResponse::PushRulesError);
+            }
+            Ok(BKCommand::SetPushRule(kind, rule)) => {
+                let r = pushrules::set_push_rule(self, kind, rule);
+                bkerror!(r, tx, BKResponse::PushRulesError);
+            }
+            Ok(BKCommand::DeletePushRule(kind, ruleid)) => {
+                let r = pushrules::delete_push_rule(self, kind, ruleid);
+                bkerror!(r, tx, BKResponse::PushRulesError);
+            }
+            Ok(BKCommand::SetPushRuleEnabled(kind, ruleid, enabled)) => {
+                let r = pushrules::set_push_rule_enabled(self, kind, ruleid, enabled);
+                bkerror!(r, tx, BKResponse::PushRulesError);
+            }
+            Ok(BKCommand::SetRoomNotify(roomid, level)) => {
+                let r = pushrules::set_room_notify(self, roomid, level);
+                bkerror!(r, tx, BKResponse::PushRulesError);
+            }
             Ok(BKCommand::AttachFile(roomid, fname)) => {
                 let r = room::attach_file(self, roomid, fname);
                 bkerror!(r, tx, BKResponse::AttachFileError);
             }
-            Ok(BKCommand::AttachImage(roomid, image)) => {
-                let r = room::attach_image(self, roomid, image);
+            Ok(BKCommand::AttachFiles(roomid, files)) => {
+                room::attach_files(self, roomid, files);
+            }
+            Ok(BKCommand::CancelUpload(txnid)) => {
+                room::cancel_upload(self, txnid);
+            }
+            Ok(BKCommand::RetryUpload(txnid)) => {
+                let r = room::retry_upload(self, txnid);
                 bkerror!(r, tx, BKResponse::AttachFileError);
             }
             Ok(BKCommand::NewRoom(name, privacy)) => {
                 let r = room::new_room(self, name, privacy);
                 bkerror!(r, tx, BKResponse::NewRoomError);
             }
-            Ok(BKCommand::Search(roomid, term)) => {
-                let r = room::search(self, roomid, term);
+            Ok(BKCommand::Search(query, next_batch)) => {
+                let r = search::search(self, query, next_batch);
                 bkerror!(r, tx, BKResponse::SearchError);
             }
 
@@ -201,10 +288,35 @@ impl Backend {
                 let r = media::get_thumb_async(self, media, ctx);
                 bkerror!(r, tx, BKResponse::CommandError);
             }
+            Ok(BKCommand::GetMediaThumbAsync(media, w, h, ctx)) => {
+                let r = media::get_media_thumb_async(self, media, w, h, ctx);
+                bkerror!(r, tx, BKResponse::CommandError);
+            }
+            Ok(BKCommand::GetMediaAsync(media, ctx)) => {
+                let r = media::get_media_async(self, media, ctx);
+                bkerror!(r, tx, BKResponse::CommandError);
+            }
+            Ok(BKCommand::GetUrlPreview(url, ts, ctx)) => {
+                let r = media::get_url_preview(self, url, ts, ctx);
+                bkerror!(r, tx, BKResponse::CommandError);
+            }
             Ok(BKCommand::GetMedia(media)) => {
                 let r = media::get_media(self, media);
                 bkerror!(r, tx, BKResponse::CommandError);
             }
+            Ok(BKCommand::SaveMedia(media, dest)) => {
+                let r = media::save_media(self, media, dest);
+                bkerror!(r, tx, BKResponse::CommandError);
+            }
+            Ok(BKCommand::CancelMedia(media)) => {
+                media::cancel_media(self, media);
+            }
+            Ok(BKCommand::SetMediaCacheSize(size)) => {
+                media::set_cache_size(self, size);
+            }
+            Ok(BKCommand::ClearMediaCache) => {
+                media::clear_cache(self);
+            }
 
             // Directory module
 
@@ -212,20 +324,14 @@ impl Backend {
                 let r = directory::protocols(self);
                 bkerror!(r, tx, BKResponse::DirectoryError);
             }
-            Ok(BKCommand::DirectorySearch(dq, dtp, more)) => {
-                let q = match dq {
-                    ref a if a.is_empty() => None,
-                    b => Some(b),
-                };
-
-                let tp = match dtp {
-                    ref a if a.is_empty() => None,
-                    b => Some(b),
-                };
-
-                let r = directory::room_search(self, q, tp, more);
+            Ok(BKCommand::DirectorySearch(query, since)) => {
+                let r = directory::room_search(self, query, since);
                 bkerror!(r, tx, BKResponse::DirectoryError);
             }
+            Ok(BKCommand::RoomPreview(room)) => {
+                let r = directory::room_preview(self, room);
+                bkerror!(r, tx, BKResponse::RoomPreviewError);
+            }
 
             // Internal commands
             Ok(BKCommand::ShutDown) => {
@@ -0,0 +1,195 @@
+extern crate serde_json;
+
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+use std::thread;
+
+use globals;
+use error::Error;
+
+use util::json_q;
+use util::cache_path;
+use util::new_txn_id;
+
+use backend::types::Backend;
+use backend::types::BKResponse;
+
+use types::Message;
+
+
+// The outbox keeps the messages that aren't sent yet. Messages are sent one by one for each
+// room to keep the order, and if one fails the next ones in the same room wait until it's
+// retried or cancelled. The outbox is stored in the cache so it survives restarts.
+
+fn store(outbox: &Vec<Message>) -> Result<(), Error> {
+    let fname = cache_path("outbox.json")?;
+    let serialized = serde_json::to_string(outbox)?;
+    File::create(fname)?.write_all(&serialized.into_bytes())?;
+
+    Ok(())
+}
+
+fn read() -> Result<Vec<Message>, Error> {
+    let fname = cache_path("outbox.json")?;
+    if !Path::new(&fname).is_file() {
+        return Ok(vec![]);
+    }
+
+    let mut file = File::open(fname)?;
+    let mut serialized = String::new();
+    file.read_to_string(&mut serialized)?;
+
+    Ok(serde_json::from_str(&serialized)?)
+}
+
+/// Loads the stored outbox for the current user, notifies the pending messages and starts
+/// sending them.
+pub fn load(bk: &Backend) -> Result<(), Error> {
+    let stored = read()?;
+
+    let (pending, rooms) = {
+        let mut data = bk.data.lock().unwrap();
+        let uid = data.user_id.clone();
+
+        for m in stored.into_iter().filter(|m| m.sender == uid) {
+            if !data.outbox.iter().any(|x| x.txnid == m.txnid) {
+                data.outbox.push(m);
+            }
+        }
+
+        let mut rooms: Vec<String> = vec![];
+        for m in data.outbox.iter() {
+            if !rooms.contains(&m.room) {
+                rooms.push(m.room.clone());
+            }
+        }
+
+        (data.outbox.clone(), rooms)
+    };
+
+    bk.tx.send(BKResponse::Outbox(pending)).unwrap();
+    for r in rooms {
+        process(bk, r);
+    }
+
+    Ok(())
+}
+
+pub fn push(bk: &Backend, msg: Message) -> Result<(), Error> {
+    let mut m = msg;
+    if m.txnid.is_none() {
+        m.txnid = Some(new_txn_id());
+    }
+
+    let roomid = m.room.clone();
+    {
+        let mut data = bk.data.lock().unwrap();
+        data.outbox.push(m);
+        store(&data.outbox)?;
+    }
+
+    process(bk, roomid);
+
+    Ok(())
+}
+
+pub fn retry(bk: &Backend, txnid: String) -> Result<(), Error> {
+    let roomid = {
+        let mut data = bk.data.lock().unwrap();
+        data.outbox_failed.remove(&txnid);
+        data.outbox.iter()
+            .find(|m| m.txnid.as_ref() == Some(&txnid))
+            .map(|m| m.room.clone())
+    };
+
+    if let Some(r) = roomid {
+        process(bk, r);
+    }
+
+    Ok(())
+}
+
+pub fn cancel(bk: &Backend, txnid: String) -> Result<(), Error> {
+    let roomid = {
+        let mut data = bk.data.lock().unwrap();
+        let roomid = data.outbox.iter()
+            .find(|m| m.txnid.as_ref() == Some(&txnid))
+            .map(|m| m.room.clone());
+
+        data.outbox_failed.remove(&txnid);
+        data.outbox.retain(|m| m.txnid.as_ref() != Some(&txnid));
+        store(&data.outbox)?;
+
+        roomid
+    };
+
+    if let Some(r) = roomid {
+        process(bk, r);
+    }
+
+    Ok(())
+}
+
+/// Sends the first message in the outbox for this room, if there's nothing being sent and
+/// the message hasn't failed.
+fn process(bk: &Backend, roomid: String) {
+    let msg = {
+        let mut data = bk.data.lock().unwrap();
+        if data.outbox_sending.contains(&roomid) {
+            return;
+        }
+
+        let next = match data.outbox.iter().find(|m| m.room == roomid) {
+            Some(m) => m.clone(),
+            None => return,
+        };
+
+        if data.outbox_failed.contains(&next.txnid.clone().unwrap_or_default()) {
+            return;
+        }
+
+        data.outbox_sending.insert(roomid.clone());
+        next
+    };
+
+    let txnid = msg.txnid.clone().unwrap_or_default();
+    let mut attrs = json!({
+        "body": msg.body.clone(),
+        "url": msg.url.clone(),
+        "msgtype": msg.mtype.clone()
+    });
+    if let Some(ref info) = msg.info {
+        attrs["info"] = serde_json::to_value(info).unwrap_or_default();
+    }
+
+    let tx = bk.tx.clone();
+    let bk = bk.clone();
+    thread::spawn(move || {
+        let r = bk.url(&format!("rooms/{}/send/m.room.message/{}", roomid, txnid), vec![])
+            .and_then(|url| json_q("put", &url, &attrs, globals::TIMEOUT));
+
+        {
+            let mut data = bk.data.lock().unwrap();
+            data.outbox_sending.remove(&roomid);
+            match r {
+                Ok(_) => data.outbox.retain(|m| m.txnid.as_ref() != Some(&txnid)),
+                Err(_) => { data.outbox_failed.insert(txnid.clone()); }
+            };
+            if let Err(err) = store(&data.outbox) {
+                println!("Error storing the outbox: {}", err);
+            }
+        }
+
+        match r {
+            Ok(js) => {
+                let evid = String::from(js["event_id"].as_str().unwrap_or(""));
+                tx.send(BKResponse::SendMsg(txnid, evid)).unwrap();
+                process(&bk, roomid);
+            }
+            Err(err) => {
+                tx.send(BKResponse::SendMsgFailed(txnid, err)).unwrap();
+            }
+        }
+    });
+}
@@ -0,0 +1,46 @@
+use std::thread;
+use std::panic;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{Sender, Receiver};
+use std::sync::mpsc::channel;
+
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Fixed number of threads running the jobs in order, so we don't launch a thread for each
+/// avatar or thumbnail.
+pub struct ThreadPool {
+    tx: Arc<Mutex<Sender<Job>>>,
+}
+
+impl ThreadPool {
+    pub fn new(size: usize) -> ThreadPool {
+        let (tx, rx): (Sender<Job>, Receiver<Job>) = channel();
+        let rx = Arc::new(Mutex::new(rx));
+
+        for _ in 0..size {
+            let rx = rx.clone();
+            thread::spawn(move || loop {
+                // the lock is released before running the job
+                let job = rx.lock().unwrap().recv();
+                match job {
+                    // a failing job shouldn't take the worker with it
+                    Ok(job) => { let _ = panic::catch_unwind(panic::AssertUnwindSafe(job)); }
+                    Err(_) => break,
+                };
+            });
+        }
+
+        ThreadPool { tx: Arc::new(Mutex::new(tx)) }
+    }
+
+    pub fn run<F>(&self, f: F) where F: FnOnce() + Send + 'static {
+        self.tx.lock().unwrap().send(Box::new(f)).unwrap();
+    }
+}
+
+impl Clone for ThreadPool {
+    fn clone(&self) -> ThreadPool {
+        ThreadPool { tx: self.tx.clone() }
+    }
+}
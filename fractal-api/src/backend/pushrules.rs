@@ -0,0 +1,114 @@
+extern crate serde_json;
+extern crate url;
+
+use globals;
+use std::thread;
+use error::Error;
+use util::json_q;
+use backend::types::BKResponse;
+use backend::types::Backend;
+
+use types::Ruleset;
+use types::PushRule;
+use types::RoomNotify;
+
+use self::serde_json::Value as JsonValue;
+use self::url::Url;
+
+
+pub fn get_push_rules(bk: &Backend) -> Result<(), Error> {
+    let url = bk.url("pushrules/", vec![])?;
+    let tx = bk.tx.clone();
+    get!(&url,
+        |r: JsonValue| {
+            match parse_rules(&r) {
+                Ok(rules) => tx.send(BKResponse::PushRules(rules)).unwrap(),
+                Err(err) => tx.send(BKResponse::PushRulesError(err)).unwrap(),
+            };
+        },
+        |err| { tx.send(BKResponse::PushRulesError(err)).unwrap() }
+    );
+
+    Ok(())
+}
+
+pub fn set_push_rule(bk: &Backend, kind: String, rule: PushRule) -> Result<(), Error> {
+    let url = rule_url(bk, &kind, &rule.rule_id, "")?;
+    edit_rules(bk, vec![("put", url, rule.to_request())]);
+
+    Ok(())
+}
+
+pub fn delete_push_rule(bk: &Backend, kind: String, ruleid: String) -> Result<(), Error> {
+    let url = rule_url(bk, &kind, &ruleid, "")?;
+    edit_rules(bk, vec![("delete", url, json!(null))]);
+
+    Ok(())
+}
+
+pub fn set_push_rule_enabled(bk: &Backend, kind: String, ruleid: String, enabled: bool) -> Result<(), Error> {
+    let url = rule_url(bk, &kind, &ruleid, "/enabled")?;
+    edit_rules(bk, vec![("put", url, json!({ "enabled": enabled }))]);
+
+    Ok(())
+}
+
+/// Replaces the rules of @roomid with the ones for the new @level
+pub fn set_room_notify(bk: &Backend, roomid: String, level: RoomNotify) -> Result<(), Error> {
+    let room = rule_url(bk, "room", &roomid, "")?;
+    let over = rule_url(bk, "override", &roomid, "")?;
+
+    let mut reqs = vec![];
+    match level {
+        RoomNotify::All => {
+            reqs.push(("delete", room, json!(null)));
+            reqs.push(("delete", over, json!(null)));
+        }
+        RoomNotify::Mentions => {
+            reqs.push(("delete", over, json!(null)));
+            reqs.push(("put", room, PushRule::mentions_only_room(&roomid).to_request()));
+        }
+        RoomNotify::Mute => {
+            reqs.push(("delete", room, json!(null)));
+            reqs.push(("put", over, PushRule::mute_room(&roomid).to_request()));
+        }
+    };
+    edit_rules(bk, reqs);
+
+    Ok(())
+}
+
+fn rule_url(bk: &Backend, kind: &str, ruleid: &str, attr: &str) -> Result<Url, Error> {
+    bk.url(&format!("pushrules/global/{}/{}{}", kind, ruleid, attr), vec![])
+}
+
+fn parse_rules(r: &JsonValue) -> Result<Ruleset, Error> {
+    serde_json::from_value(r["global"].clone()).or(Err(Error::BackendError))
+}
+
+// Runs the requests in order and sends the rules again when they finish. Deleting a rule
+// that doesn't exist isn't an error here.
+fn edit_rules(bk: &Backend, reqs: Vec<(&'static str, Url, JsonValue)>) {
+    let tx = bk.tx.clone();
+    let rules = bk.url("pushrules/", vec![]);
+
+    thread::spawn(move || {
+        for (method, url, attrs) in reqs {
+            match json_q(method, &url, &attrs, globals::TIMEOUT) {
+                Err(Error::MatrixError(ref e)) if method == "delete" && e.errcode == "M_NOT_FOUND" => {}
+                Err(err) => {
+                    tx.send(BKResponse::PushRulesError(err)).unwrap();
+                    return;
+                }
+                Ok(_) => {}
+            };
+        }
+
+        let r = rules.and_then(|url| json_q("get", &url, &json!(null), globals::TIMEOUT))
+            .and_then(|r| parse_rules(&r));
+        match r {
+            Ok(rules) => tx.send(BKResponse::PushRules(rules)).unwrap(),
+            Err(err) => tx.send(BKResponse::PushRulesError(err)).unwrap(),
+        };
+    });
+}
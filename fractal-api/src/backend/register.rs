@@ -6,14 +6,19 @@ use self::serde_json::Value as JsonValue;
 use self::regex::Regex;
 
 use std::thread;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
 use self::url::Url;
 
 use util::json_q;
+use util::clear_cache;
 use globals;
 use error::Error;
+use cache::CacheMap;
 
 use backend::types::BKResponse;
 use backend::types::Backend;
+use backend::types::BackendData;
 
 
 pub fn guest(bk: &Backend, server: String) -> Result<(), Error> {
@@ -98,24 +103,124 @@ pub fn login(bk: &Backend, user: String, password: String, server: String) -> Re
     Ok(())
 }
 
-pub fn logout(bk: &Backend) -> Result<(), Error> {
+fn clear_session(data: &Arc<Mutex<BackendData>>) {
+    let mut d = data.lock().unwrap();
+    d.user_id = String::new();
+    d.access_token = String::new();
+    d.since = String::new();
+    d.outbox.clear();
+    d.outbox_failed.clear();
+    d.outbox_sending.clear();
+    for (_, cancel) in d.uploads.drain() {
+        cancel.store(true, Ordering::SeqCst);
+    }
+    d.uploads_failed.clear();
+    // the download threads remove their own entry when they exit
+    d.downloads_waiting.clear();
+    for cancel in d.downloads.values() {
+        cancel.store(true, Ordering::SeqCst);
+    }
+    d.upload_limit = None;
+    d.url_previews.clear();
+    d.url_previews_order.clear();
+    d.joined_rooms.clear();
+    // stopping the sync loop
+    d.sync_running = false;
+    d.sync_gen += 1;
+}
+
+pub fn logout(bk: &mut Backend, wipe: bool) -> Result<(), Error> {
     let url = bk.url("logout", vec![])?;
     let attrs = json!({});
 
+    if wipe {
+        // cached user info points to avatars that we're going to remove
+        bk.user_info_cache = CacheMap::new().timeout(60*60);
+    }
+
     let data = bk.data.clone();
     let tx = bk.tx.clone();
     post!(&url, &attrs,
         |_| {
-            data.lock().unwrap().user_id = String::new();
-            data.lock().unwrap().access_token = String::new();
-            data.lock().unwrap().since = String::new();
+            clear_session(&data);
+            if wipe {
+                if let Err(err) = clear_cache() {
+                    tx.send(BKResponse::ClearCacheError(err)).unwrap();
+                }
+            }
             tx.send(BKResponse::Logout).unwrap();
         },
-        |err| { tx.send(BKResponse::LogoutError(err)).unwrap() }
+        |err| {
+            // the password is removed already, so the session ends even if the server
+            // can't be reached, and the local data is removed too if it was asked
+            clear_session(&data);
+            if wipe {
+                if let Err(err) = clear_cache() {
+                    tx.send(BKResponse::ClearCacheError(err)).unwrap();
+                }
+            }
+            tx.send(BKResponse::LogoutError(err)).unwrap()
+        }
     );
     Ok(())
 }
 
+/// The deactivation endpoint uses the user-interactive authentication API, so the first
+/// request only returns the auth session and a second one is needed with the password.
+fn deactivate_request(url: &Url, uid: &str, password: &str, erase: bool) -> Result<JsonValue, Error> {
+    let attrs = json!({ "erase": erase });
+
+    let session = match json_q("post", url, &attrs, globals::TIMEOUT) {
+        Ok(r) => return Ok(r),
+        Err(Error::MatrixError(ref e)) if e.content["session"].is_string() => {
+            strn!(e.content["session"].as_str().unwrap_or(""))
+        }
+        Err(err) => return Err(err),
+    };
+
+    let attrs = json!({
+        "erase": erase,
+        "auth": {
+            "type": "m.login.password",
+            "session": session,
+            "user": uid,
+            "identifier": {
+                "type": "m.id.user",
+                "user": uid
+            },
+            "password": password
+        }
+    });
+
+    json_q("post", url, &attrs, globals::TIMEOUT)
+}
+
+pub fn deactivate(bk: &mut Backend, password: String, erase: bool) -> Result<(), Error> {
+    let url = bk.url("account/deactivate", vec![])?;
+    let uid = bk.data.lock().unwrap().user_id.clone();
+
+    bk.user_info_cache = CacheMap::new().timeout(60*60);
+
+    let data = bk.data.clone();
+    let tx = bk.tx.clone();
+    thread::spawn(move || {
+        match deactivate_request(&url, &uid, &password, erase) {
+            Ok(_) => {
+                clear_session(&data);
+                if let Err(err) = clear_cache() {
+                    tx.send(BKResponse::ClearCacheError(err)).unwrap();
+                }
+                tx.send(BKResponse::AccountDeactivated).unwrap();
+            }
+            Err(err) => {
+                tx.send(BKResponse::AccountDeactivationError(err)).unwrap();
+            }
+        }
+    });
+
+    Ok(())
+}
+
 pub fn register(bk: &Backend, user: String, password: String, server: String) -> Result<(), Error> {
     let s = server.clone();
     bk.data.lock().unwrap().server_url = s;
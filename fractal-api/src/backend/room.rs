@@ -1,11 +1,18 @@
 extern crate serde_json;
 extern crate tree_magic;
 extern crate chrono;
+extern crate url;
 
 use self::chrono::prelude::*;
+use self::url::Url;
+use self::url::percent_encoding::{utf8_percent_encode, PATH_SEGMENT_ENCODE_SET};
+use std::fs;
 use std::fs::File;
 use std::io::prelude::*;
 use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
 use globals;
 use std::thread;
@@ -13,24 +20,39 @@ use error::Error;
 
 use util::json_q;
 use util::dw_media;
-use util::get_initial_room_messages;
+use util::get_room_messages_page;
+use util::get_room_messages_dir;
 use util::parse_room_message;
 use util::build_url;
 use util::put_media;
+use util::upload_file;
+use util::get_upload_limit;
+use util::image_thumbnail;
+use util::clean_image;
+use util::cache_path;
 use util;
 
 use backend::types::Backend;
 use backend::types::BKResponse;
 use backend::types::BKCommand;
 use backend::types::RoomType;
-use backend::room;
+use backend::types::Attachment;
+use backend::types::Upload;
 
 use types::Room;
+use types::Direction;
+use types::TimelineChunk;
 use types::Member;
 use types::Message;
+use types::MediaInfo;
+use types::ThumbnailInfo;
 
 use self::serde_json::Value as JsonValue;
 
+// Size of the thumbnails that we generate for the images that we send
+const THUMB_WIDTH: i32 = 800;
+const THUMB_HEIGHT: i32 = 600;
+
 pub fn set_room(bk: &Backend, room: Room) -> Result<(), Error> {
     get_room_detail(bk, room.id.clone(), String::from("m.room.topic"))?;
     get_room_avatar(bk, room.id.clone())?;
@@ -84,7 +106,7 @@ pub fn get_room_avatar(bk: &Backend, roomid: String) -> Result<(), Error> {
         },
         |err: Error| {
             match err {
-                Error::MatrixError(ref js) if js["errcode"].as_str().unwrap_or("") == "M_NOT_FOUND" => {
+                Error::MatrixError(ref e) if e.errcode == "M_NOT_FOUND" => {
                     let avatar = util::get_room_avatar(&baseu, &tk, &userid, &roomid)
                         .unwrap_or(String::from(""));
                     tx.send(BKResponse::RoomAvatar(roomid, avatar)).unwrap();
@@ -139,10 +161,8 @@ pub fn get_room_messages(bk: &Backend, roomid: String) -> Result<(), Error> {
 
     let tx = bk.tx.clone();
     thread::spawn(move || {
-        match get_initial_room_messages(&baseu, tk, roomid.clone(),
-                                        globals::PAGE_LIMIT as usize,
-                                        globals::PAGE_LIMIT, None) {
-            Ok((ms, _, _)) => {
+        match get_room_messages_page(&baseu, tk, roomid.clone(), globals::PAGE_LIMIT, None) {
+            Ok((ms, _)) => {
                 tx.send(BKResponse::RoomMessagesInit(ms)).unwrap();
             }
             Err(err) => {
@@ -154,26 +174,60 @@ pub fn get_room_messages(bk: &Backend, roomid: String) -> Result<(), Error> {
     Ok(())
 }
 
+/// Gets the messages before the @token, an empty token means from the last message.
+pub fn back_paginate(bk: &Backend, roomid: String, token: String) -> Result<(), Error> {
+    let baseu = bk.get_base_url()?;
+    let tk = bk.data.lock().unwrap().access_token.clone();
+    let from = match token {
+        ref t if t.is_empty() => None,
+        t => Some(t),
+    };
+
+    let tx = bk.tx.clone();
+    thread::spawn(move || {
+        match get_room_messages_page(&baseu, tk, roomid.clone(), globals::PAGE_LIMIT, from) {
+            Ok((ms, end)) => {
+                tx.send(BKResponse::RoomMessagesBack(roomid, ms, end)).unwrap();
+            }
+            Err(err) => {
+                tx.send(BKResponse::RoomMessagesError(err)).unwrap();
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Gets the messages around @msg, for a timeline chunk that starts at the message.
 pub fn get_message_context(bk: &Backend, msg: Message) -> Result<(), Error> {
-    let url = bk.url(&format!("rooms/{}/context/{}", msg.room, msg.id.unwrap_or_default()),
+    let eventid = msg.id.unwrap_or_default();
+    let url = bk.url(&format!("rooms/{}/context/{}", msg.room, eventid),
                        vec![("limit", String::from("40"))])?;
 
     let tx = bk.tx.clone();
-    let baseu = bk.get_base_url()?;
     let roomid = msg.room.clone();
     get!(&url,
         |r: JsonValue| {
-            let mut ms: Vec<Message> = vec![];
-            let array = r["events_before"].as_array();
-            for msg in array.unwrap().iter().rev() {
-                if msg["type"].as_str().unwrap_or("") != "m.room.message" {
-                    continue;
-                }
-
-                let m = parse_room_message(&baseu, roomid.clone(), msg);
-                ms.push(m);
-            }
-            tx.send(BKResponse::RoomMessagesTo(ms)).unwrap();
+            let empty = vec![];
+            let before = r["events_before"].as_array().unwrap_or(&empty);
+            let after = r["events_after"].as_array().unwrap_or(&empty);
+            let events = before.iter().rev()
+                .chain(Some(&r["event"]))
+                .chain(after.iter());
+
+            let ms = events
+                .filter(|ev| ev["type"].as_str().unwrap_or("") == "m.room.message")
+                .map(|ev| parse_room_message(roomid.clone(), ev))
+                .collect();
+
+            let chunk = TimelineChunk {
+                room: roomid.clone(),
+                event: eventid.clone(),
+                messages: ms,
+                prev_batch: r["start"].as_str().map(String::from),
+                next_batch: r["end"].as_str().map(String::from),
+            };
+            tx.send(BKResponse::RoomContext(chunk)).unwrap();
         },
         |err| { tx.send(BKResponse::RoomMessagesError(err)).unwrap() }
     );
@@ -181,31 +235,22 @@ pub fn get_message_context(bk: &Backend, msg: Message) -> Result<(), Error> {
     Ok(())
 }
 
-pub fn send_msg(bk: &Backend, msg: Message) -> Result<(), Error> {
-    let roomid = msg.room.clone();
-    let msgid;
-
-    {
-        let mut data = bk.data.lock().unwrap();
-        data.msgid = data.msgid + 1;
-        msgid = data.msgid;
-    }
-
-    let url = bk.url(&format!("rooms/{}/send/m.room.message/{}", roomid, msgid), vec![])?;
-
-    let attrs = json!({
-        "body": msg.body.clone(),
-        "url": msg.url.clone(),
-        "msgtype": msg.mtype.clone()
-    });
+/// Gets the page of messages of a timeline chunk from the @token in the @dir direction.
+pub fn chunk_paginate(bk: &Backend, roomid: String, token: String, dir: Direction) -> Result<(), Error> {
+    let baseu = bk.get_base_url()?;
+    let tk = bk.data.lock().unwrap().access_token.clone();
 
     let tx = bk.tx.clone();
-    query!("put", &url, &attrs,
-        move |_| {
-            tx.send(BKResponse::SendMsg).unwrap();
-        },
-        |err| { tx.send(BKResponse::SendMsgError(err)).unwrap(); }
-    );
+    thread::spawn(move || {
+        match get_room_messages_dir(&baseu, tk, roomid.clone(), globals::PAGE_LIMIT, Some(token), dir) {
+            Ok((ms, end)) => {
+                tx.send(BKResponse::RoomChunkMessages(roomid, ms, end, dir)).unwrap();
+            }
+            Err(err) => {
+                tx.send(BKResponse::RoomMessagesError(err)).unwrap();
+            }
+        }
+    });
 
     Ok(())
 }
@@ -218,7 +263,7 @@ pub fn join_room(bk: &Backend, roomid: String) -> Result<(), Error> {
     post!(&url,
         move |_: JsonValue| {
             data.lock().unwrap().join_to_room = roomid.clone();
-            tx.send(BKResponse::JoinRoom).unwrap();
+            tx.send(BKResponse::JoinRoom(roomid.clone())).unwrap();
         },
         |err| { tx.send(BKResponse::JoinRoomError(err)).unwrap(); }
     );
@@ -270,6 +315,43 @@ pub fn set_room_name(bk: &Backend, roomid: String, name: String) -> Result<(), E
     Ok(())
 }
 
+/// The path of the @tag of the room, user tags can have any character so it's encoded
+fn tag_path(userid: &str, roomid: &str, tag: &str) -> String {
+    let tag = utf8_percent_encode(tag, PATH_SEGMENT_ENCODE_SET);
+    format!("user/{}/rooms/{}/tags/{}", userid, roomid, tag)
+}
+
+pub fn add_room_tag(bk: &Backend, roomid: String, tag: String, order: Option<f64>) -> Result<(), Error> {
+    let userid = bk.data.lock().unwrap().user_id.clone();
+    let url = bk.url(&tag_path(&userid, &roomid, &tag), vec![])?;
+
+    let attrs = match order {
+        Some(o) => json!({ "order": o }),
+        None => json!({}),
+    };
+
+    let tx = bk.tx.clone();
+    query!("put", &url, &attrs,
+        |_| { tx.send(BKResponse::RoomTag).unwrap(); },
+        |err| { tx.send(BKResponse::RoomTagError(err)).unwrap(); }
+    );
+
+    Ok(())
+}
+
+pub fn remove_room_tag(bk: &Backend, roomid: String, tag: String) -> Result<(), Error> {
+    let userid = bk.data.lock().unwrap().user_id.clone();
+    let url = bk.url(&tag_path(&userid, &roomid, &tag), vec![])?;
+
+    let tx = bk.tx.clone();
+    query!("delete", &url, &json!({}),
+        |_| { tx.send(BKResponse::RoomTag).unwrap(); },
+        |err| { tx.send(BKResponse::RoomTagError(err)).unwrap(); }
+    );
+
+    Ok(())
+}
+
 pub fn set_room_topic(bk: &Backend, roomid: String, topic: String) -> Result<(), Error> {
     let url = bk.url(&format!("rooms/{}/state/m.room.topic", roomid), vec![])?;
 
@@ -323,73 +405,237 @@ pub fn set_room_avatar(bk: &Backend, roomid: String, avatar: String) -> Result<(
     Ok(())
 }
 
-pub fn attach_image(bk: &Backend, roomid: String, image: Vec<u8>) -> Result<(), Error> {
-    attach_send(bk, roomid, strn!("Screenshot"), image, "m.image")
+pub fn attach_file(bk: &Backend, roomid: String, path: String) -> Result<(), Error> {
+    let name = strn!(path.split("/").last().unwrap_or(&path));
+    attach(bk, roomid, path, name.clone(), name, false, None)
 }
 
-pub fn attach_file(bk: &Backend, roomid: String, path: String) -> Result<(), Error> {
-    let mut file = File::open(&path)?;
-    let mut contents: Vec<u8> = vec![];
-    file.read_to_end(&mut contents)?;
+/// Sends the files chosen in the send dialog. The images that are resized or stripped are
+/// written again to the cache before the upload, so this runs in its own thread.
+pub fn attach_files(bk: &Backend, roomid: String, files: Vec<Attachment>) {
+    let bk = bk.clone();
+    thread::spawn(move || {
+        for file in files {
+            if let Err(err) = attach_one(&bk, roomid.clone(), file) {
+                bk.tx.send(BKResponse::AttachFileError(err)).unwrap();
+            }
+        }
+    });
+}
+
+fn attach_one(bk: &Backend, roomid: String, file: Attachment) -> Result<(), Error> {
+    let name = strn!(file.path.split("/").last().unwrap_or(&file.path));
+    let body = file.caption.clone().unwrap_or(name.clone());
+
+    let mime = tree_magic::from_filepath(Path::new(&file.path));
+    // gifs are written again as a single frame, so they're sent as they are
+    let rewrite = mime.starts_with("image/") && mime != "image/gif" &&
+                  (file.strip_metadata || file.max_size.is_some());
+    if !rewrite {
+        return attach(bk, roomid, file.path, name, body, file.temporary, file.duration);
+    }
+
+    let ext = if mime == "image/jpeg" { "jpg" } else { "png" };
+    let dest = cache_path(&format!("upload-{}.{}", util::new_txn_id(), ext))?;
+    clean_image(&file.path, &dest, file.max_size)?;
+    if file.temporary {
+        let _ = fs::remove_file(&file.path);
+    }
+
+    attach(bk, roomid, dest, name, body, true, None)
+}
+
+fn attach(bk: &Backend, roomid: String, path: String, name: String, body: String,
+          temporary: bool, duration: Option<u64>) -> Result<(), Error> {
+    let upload = Upload {
+        roomid: roomid,
+        path: path,
+        name: name,
+        body: body,
+        temporary: temporary,
+        duration: duration,
+    };
+    upload_attachment(bk, util::new_txn_id(), upload)
+}
+
+pub fn retry_upload(bk: &Backend, txnid: String) -> Result<(), Error> {
+    let upload = bk.data.lock().unwrap().uploads_failed.remove(&txnid);
+    match upload {
+        Some(u) => upload_attachment(bk, txnid, u),
+        None => Ok(()),
+    }
+}
 
+/// Uploads the file streaming it from the disk and sends the message when it's done, with
+/// the media info and a thumbnail for images. The upload can be cancelled with the message
+/// @txnid, and if it fails it's kept to try it again with the same @txnid. The file is
+/// removed when it's sent or cancelled if it's temporary.
+fn upload_attachment(bk: &Backend, txnid: String, upload: Upload) -> Result<(), Error> {
+    let path = upload.path.clone();
     let p: &Path = Path::new(&path);
     let mime = tree_magic::from_filepath(p);
+    let size = fs::metadata(p)?.len();
 
-    let mtype = match mime.as_ref() {
-        "image/gif" => "m.image",
-        "image/png" => "m.image",
-        "image/jpeg" => "m.image",
-        "image/jpg" => "m.image",
+    let mtype = match mime.split('/').next().unwrap_or("") {
+        "image" => "m.image",
+        "video" => "m.video",
+        "audio" => "m.audio",
         _ => "m.file"
     };
 
-    let body = strn!(path.split("/").last().unwrap_or(&path));
-    attach_send(bk, roomid, body, contents, mtype)
-}
-
-pub fn attach_send(bk: &Backend, roomid: String, body: String, contents: Vec<u8>, mtype: &str) -> Result<(), Error> {
     let baseu = bk.get_base_url()?;
     let tk = bk.data.lock().unwrap().access_token.clone();
-    let params = vec![("access_token", tk.clone())];
+    let params = vec![("filename", upload.name.clone()), ("access_token", tk.clone())];
     let mediaurl = media_url!(&baseu, "upload", params)?;
 
-    let now = Local::now();
     let userid = bk.data.lock().unwrap().user_id.clone();
-
     let mut m = Message {
         sender: userid,
         mtype: strn!(mtype),
-        body: body,
-        room: roomid.clone(),
-        date: now,
+        body: upload.body.clone(),
+        room: upload.roomid.clone(),
+        date: Local::now(),
         thumb: None,
         url: None,
         id: None,
+        txnid: Some(txnid.clone()),
+        info: Some(MediaInfo {
+            mimetype: Some(mime.clone()),
+            size: Some(size),
+            duration: upload.duration,
+            ..Default::default()
+        }),
     };
 
+    let cancel = Arc::new(AtomicBool::new(false));
+    bk.data.lock().unwrap().uploads.insert(txnid.clone(), cancel.clone());
+    bk.tx.send(BKResponse::Uploading(m.clone(), size)).unwrap();
+
     let tx = bk.tx.clone();
     let itx = bk.internal_tx.clone();
-    thread::spawn(
-        move || {
-            match put_media(mediaurl.as_str(), contents) {
-                Err(err) => {
-                    tx.send(BKResponse::AttachFileError(err)).unwrap();
+    let data = bk.data.clone();
+    thread::spawn(move || {
+        let mut last = Instant::now();
+        let ptx = tx.clone();
+        let id = txnid.clone();
+        let progress = move |bytes: u64, total: u64| {
+            if last.elapsed() >= Duration::from_millis(500) {
+                last = Instant::now();
+                ptx.send(BKResponse::UploadProgress(id.clone(), bytes, total)).unwrap();
+            }
+        };
+
+        let limit = data.lock().unwrap().upload_limit;
+        let limit = match limit {
+            Some(l) => Some(l),
+            None => {
+                // servers without the config endpoint don't have a limit that we can check
+                let l = get_upload_limit(&baseu, &tk).unwrap_or(None);
+                data.lock().unwrap().upload_limit = l;
+                l
+            }
+        };
+
+        let r = match limit {
+            Some(l) if size > l => Err(Error::FileTooLarge(l)),
+            _ => upload_file(&mediaurl, &path, cancel.clone(), progress),
+        };
+
+        // the thumbnail is only uploaded for an image that was sent
+        if r.is_ok() && m.mtype == "m.image" {
+            if let Some(ref mut info) = m.info {
+                add_image_info(info, &path, &baseu, &tk, cancel.clone());
+            }
+        }
+        let r = match cancel.load(Ordering::SeqCst) {
+            true => Err(Error::Cancelled),
+            false => r,
+        };
+
+        data.lock().unwrap().uploads.remove(&txnid);
+
+        match r {
+            // trying again wouldn't change a file that is too large
+            Err(err @ Error::Cancelled) | Err(err @ Error::FileTooLarge(_)) => {
+                if upload.temporary {
+                    let _ = fs::remove_file(&path);
                 }
-                Ok(js) => {
-                    let uri = js["content_uri"].as_str().unwrap_or("");
-                    m.url = Some(strn!(uri));
-                    if let Some(t) = itx {
-                        t.send(BKCommand::SendMsg(m.clone())).unwrap();
-                    }
-                    tx.send(BKResponse::AttachedFile(m)).unwrap();
+                tx.send(BKResponse::UploadFailed(txnid, err)).unwrap();
+            }
+            Err(err) => {
+                data.lock().unwrap().uploads_failed.insert(txnid.clone(), upload);
+                tx.send(BKResponse::UploadFailed(txnid, err)).unwrap();
+            }
+            Ok(uri) => {
+                if upload.temporary {
+                    let _ = fs::remove_file(&path);
                 }
-            };
-        },
-    );
+                m.url = Some(uri);
+                if let Some(t) = itx {
+                    t.send(BKCommand::SendMsg(m.clone())).unwrap();
+                }
+                tx.send(BKResponse::AttachedFile(m)).unwrap();
+            }
+        };
+    });
 
     Ok(())
 }
 
+/// Adds the image size to the @info, and uploads a thumbnail if the image is big, in the
+/// format of the image if it's a jpeg and as png otherwise. Failures aren't fatal, the
+/// image is sent without this info.
+fn add_image_info(info: &mut MediaInfo, path: &str, baseu: &Url, tk: &str, cancel: Arc<AtomicBool>) {
+    let (ext, mime) = match info.mimetype {
+        Some(ref m) if m == "image/jpeg" => ("jpg", "image/jpeg"),
+        _ => ("png", "image/png"),
+    };
+    let dest = match cache_path(&format!("upload-{}.{}", util::new_txn_id(), ext)) {
+        Ok(d) => d,
+        Err(_) => return,
+    };
+
+    let ((w, h), thumb) = match image_thumbnail(path, &dest, THUMB_WIDTH, THUMB_HEIGHT) {
+        Ok(t) => t,
+        Err(_) => return,
+    };
+
+    info.w = Some(w as u64);
+    info.h = Some(h as u64);
+
+    if let Some((tw, th)) = thumb {
+        let filename = format!("thumbnail.{}", ext);
+        let params = vec![("filename", filename), ("access_token", strn!(tk))];
+        let uri = media_url!(baseu, "upload", params)
+            .and_then(|url| upload_file(&url, &dest, cancel, |_, _| {}));
+        let tsize = fs::metadata(&dest).map(|m| m.len()).ok();
+        let _ = fs::remove_file(&dest);
+
+        if let Ok(uri) = uri {
+            info.thumbnail_url = Some(uri);
+            info.thumbnail_info = Some(ThumbnailInfo {
+                mimetype: Some(strn!(mime)),
+                size: tsize,
+                w: Some(tw as u64),
+                h: Some(th as u64),
+            });
+        }
+    }
+}
+
+pub fn cancel_upload(bk: &Backend, txnid: String) {
+    let mut data = bk.data.lock().unwrap();
+    if let Some(cancel) = data.uploads.remove(&txnid) {
+        cancel.store(true, Ordering::SeqCst);
+    }
+    if let Some(upload) = data.uploads_failed.remove(&txnid) {
+        if upload.temporary {
+            let _ = fs::remove_file(&upload.path);
+        }
+        bk.tx.send(BKResponse::UploadFailed(txnid, Error::Cancelled)).unwrap();
+    }
+}
+
 pub fn new_room(bk: &Backend, name: String, privacy: RoomType) -> Result<(), Error> {
     let url = bk.url("createRoom", vec![])?;
     let attrs = json!({
@@ -420,64 +666,3 @@ pub fn new_room(bk: &Backend, name: String, privacy: RoomType) -> Result<(), Err
     );
     Ok(())
 }
-
-pub fn search(bk: &Backend, roomid: String, term: Option<String>) -> Result<(), Error> {
-    let tx = bk.tx.clone();
-
-    match term {
-        Some(ref t) if !t.is_empty() => {
-            make_search(bk, roomid, t.clone())
-        }
-        _ => {
-            tx.send(BKResponse::SearchEnd).unwrap();
-            room::get_room_messages(bk, roomid)
-        }
-    }
-}
-
-pub fn make_search(bk: &Backend, roomid: String, term: String) -> Result<(), Error> {
-    let url = bk.url("search", vec![])?;
-
-    let attrs = json!({
-        "search_categories": {
-            "room_events": {
-                "keys": ["content.body"],
-                "search_term": term,
-                "filter": {
-                    "rooms": [ roomid.clone() ],
-                },
-                "order_by": "recent",
-            },
-        },
-    });
-
-    let tx = bk.tx.clone();
-    let baseu = bk.get_base_url()?;
-
-    thread::spawn(move || {
-        match json_q("post", &url, &attrs, 0) {
-            Ok(js) => {
-                tx.send(BKResponse::SearchEnd).unwrap();
-                let mut ms: Vec<Message> = vec![];
-
-                let res = &js["search_categories"]["room_events"]["results"];
-                for search in res.as_array().unwrap().iter().rev() {
-                    let msg = &search["result"];
-                    if msg["type"].as_str().unwrap_or("") != "m.room.message" {
-                        continue;
-                    }
-
-                    let m = parse_room_message(&baseu, roomid.clone(), msg);
-                    ms.push(m);
-                }
-                tx.send(BKResponse::RoomMessagesInit(ms)).unwrap();
-            }
-            Err(err) => {
-                tx.send(BKResponse::SearchEnd).unwrap();
-                tx.send(BKResponse::SearchError(err)).unwrap()
-            }
-        };
-    });
-
-    Ok(())
-}
@@ -0,0 +1,112 @@
+extern crate serde_json;
+
+use std::thread;
+use error::Error;
+
+use util::json_q;
+use util::parse_room_message;
+
+use backend::types::BKResponse;
+use backend::types::Backend;
+
+use types::Message;
+use types::SearchQuery;
+use types::SearchResult;
+use types::SearchResults;
+
+use self::serde_json::Value as JsonValue;
+
+// messages before and after each result
+const CONTEXT_LIMIT: u64 = 1;
+
+
+/// Server side search of messages, @next_batch is the token of the page to get, if it's not
+/// the first one
+pub fn search(bk: &Backend, query: SearchQuery, next_batch: Option<String>) -> Result<(), Error> {
+    let mut params = vec![];
+    if let Some(nb) = next_batch {
+        params.push(("next_batch", nb));
+    }
+    let url = bk.url("search", params)?;
+
+    let mut filter = json!({});
+    if let Some(ref roomid) = query.room {
+        filter["rooms"] = json!([roomid]);
+    }
+
+    let attrs = json!({
+        "search_categories": {
+            "room_events": {
+                "keys": ["content.body"],
+                "search_term": query.term,
+                "filter": filter,
+                "order_by": "recent",
+                "event_context": {
+                    "before_limit": CONTEXT_LIMIT,
+                    "after_limit": CONTEXT_LIMIT,
+                    "include_profile": false,
+                },
+            },
+        },
+    });
+
+    let tx = bk.tx.clone();
+    thread::spawn(move || {
+        match json_q("post", &url, &attrs, 0) {
+            Ok(js) => {
+                let results = parse_search_results(query, &js);
+                tx.send(BKResponse::SearchResults(results)).unwrap();
+            }
+            Err(err) => tx.send(BKResponse::SearchError(err)).unwrap(),
+        };
+    });
+
+    Ok(())
+}
+
+fn parse_search_results(query: SearchQuery, js: &JsonValue) -> SearchResults {
+    let events = &js["search_categories"]["room_events"];
+
+    let results = match events["results"].as_array() {
+        Some(rs) => rs.iter().filter_map(parse_search_result).collect(),
+        None => vec![],
+    };
+    let highlights = match events["highlights"].as_array() {
+        Some(hs) => hs.iter().filter_map(|h| h.as_str()).map(String::from).collect(),
+        None => vec![],
+    };
+
+    SearchResults {
+        query: query,
+        count: events["count"].as_u64(),
+        highlights: highlights,
+        results: results,
+        next_batch: events["next_batch"].as_str().map(String::from),
+    }
+}
+
+fn parse_search_result(r: &JsonValue) -> Option<SearchResult> {
+    let ev = &r["result"];
+    if ev["type"].as_str().unwrap_or("") != "m.room.message" {
+        return None;
+    }
+    let roomid = ev["room_id"].as_str()?;
+
+    let context = |key: &str| -> Vec<Message> {
+        r["context"][key].as_array().unwrap_or(&vec![]).iter()
+            .filter(|x| x["type"] == "m.room.message")
+            .map(|x| parse_room_message(roomid.to_string(), x))
+            .collect()
+    };
+
+    // the events before come from the closest to the result
+    let mut before = context("events_before");
+    before.reverse();
+
+    Some(SearchResult {
+        message: parse_room_message(roomid.to_string(), ev),
+        rank: r["rank"].as_f64().unwrap_or(0.0),
+        before: before,
+        after: context("events_after"),
+    })
+}
@@ -1,23 +1,151 @@
+extern crate serde_json;
+
 use globals;
 use std::thread;
+use std::time::Duration;
 use error::Error;
 use util::json_q;
 use util::get_rooms_from_json;
 use util::get_rooms_timeline_from_json;
+use util::get_rooms_limited_from_json;
+use util::get_rooms_tags_from_json;
+use util::get_new_rooms_from_json;
+use util::get_left_rooms_from_json;
+use util::get_rooms_unread_from_json;
+use util::get_push_rules_from_json;
 use util::parse_sync_events;
 use backend::types::BKResponse;
 use backend::types::Backend;
+use backend::types::ConnectionState;
 use types::Room;
 
-pub fn sync(bk: &Backend) -> Result<(), Error> {
+use self::serde_json::Value as JsonValue;
+
+// backoff limits, in milliseconds, used to retry after a failed sync
+const MIN_BACKOFF: u64 = 1000;
+const MAX_BACKOFF: u64 = 5 * 60 * 1000;
+
+
+enum SyncErrorKind {
+    // network problems or server errors, we should retry later
+    Transient,
+    // the server asks us to wait the given milliseconds
+    RateLimited(u64),
+    // the access token isn't valid anymore, we need a new login
+    InvalidToken,
+}
+
+fn classify_error(err: &Error) -> SyncErrorKind {
+    match *err {
+        Error::MatrixError(ref e) => {
+            match e.errcode.as_ref() {
+                "M_UNKNOWN_TOKEN" | "M_MISSING_TOKEN" => SyncErrorKind::InvalidToken,
+                "M_LIMIT_EXCEEDED" => {
+                    SyncErrorKind::RateLimited(e.retry_after_ms.unwrap_or(MIN_BACKOFF))
+                }
+                _ => SyncErrorKind::Transient,
+            }
+        }
+        _ => SyncErrorKind::Transient,
+    }
+}
+
+/// Starts the sync loop in a new thread. If there's a loop running it's replaced when
+/// `force` is true, otherwise nothing happens.
+pub fn start(bk: &Backend, force: bool) -> Result<(), Error> {
     let tk = bk.data.lock().unwrap().access_token.clone();
     if tk.is_empty() {
         return Err(Error::BackendError);
     }
 
-    let since = bk.data.lock().unwrap().since.clone();
-    let userid = bk.data.lock().unwrap().user_id.clone();
+    let gen = {
+        let mut data = bk.data.lock().unwrap();
+        if data.sync_running && !force {
+            return Ok(());
+        }
+        data.sync_running = true;
+        data.sync_gen += 1;
+        data.sync_gen
+    };
+
+    let bk = bk.clone();
+    thread::spawn(move || sync_loop(bk, gen));
+
+    Ok(())
+}
+
+pub fn stop(bk: &Backend) -> Result<(), Error> {
+    let mut data = bk.data.lock().unwrap();
+    data.sync_running = false;
+    data.sync_gen += 1;
+
+    Ok(())
+}
+
+pub fn force_sync(bk: &Backend) -> Result<(), Error> {
+    bk.data.lock().unwrap().since = String::from("");
+    start(bk, true)
+}
+
+fn is_current(bk: &Backend, gen: u64) -> bool {
+    let data = bk.data.lock().unwrap();
+    data.sync_running && data.sync_gen == gen
+}
+
+/// Long polling loop, each request waits in the server until there are new events. On
+/// errors the request is retried with an exponential backoff, and the loop finishes when
+/// the token is rejected or when the loop is replaced or stopped.
+fn sync_loop(bk: Backend, gen: u64) {
+    let tx = bk.tx.clone();
+    let mut backoff = MIN_BACKOFF;
+    let mut state = ConnectionState::Connected;
+
+    while is_current(&bk, gen) {
+        let since = bk.data.lock().unwrap().since.clone();
+
+        let r = sync_request(&bk, &since);
+        if !is_current(&bk, gen) {
+            break;
+        }
+
+        let err = match r {
+            Ok(r) => {
+                if state != ConnectionState::Connected {
+                    state = ConnectionState::Connected;
+                    tx.send(BKResponse::ConnectionState(state.clone())).unwrap();
+                }
+                backoff = MIN_BACKOFF;
+                process_sync(&bk, r, since);
+                continue;
+            }
+            Err(err) => err,
+        };
+
+        let wait = match classify_error(&err) {
+            SyncErrorKind::InvalidToken => {
+                bk.data.lock().unwrap().sync_running = false;
+                tx.send(BKResponse::ConnectionState(ConnectionState::InvalidToken)).unwrap();
+                tx.send(BKResponse::SyncError(err)).unwrap();
+                break;
+            }
+            SyncErrorKind::RateLimited(ms) => {
+                state = ConnectionState::RateLimited(ms);
+                ms
+            }
+            SyncErrorKind::Transient => {
+                let ms = backoff;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+                state = ConnectionState::Disconnected(ms);
+                ms
+            }
+        };
+
+        tx.send(BKResponse::ConnectionState(state.clone())).unwrap();
+        thread::sleep(Duration::from_millis(wait));
+    }
+}
 
+fn sync_request(bk: &Backend, since: &str) -> Result<JsonValue, Error> {
     let mut params: Vec<(&str, String)> = vec![];
     let timeout = 120;
 
@@ -34,7 +162,8 @@ pub fn sync(bk: &Backend) -> Result<(), Error> {
                     \"types\": [\"m.room.message\"],
                     \"limit\": {},
                 }},
-                \"ephemeral\": {{ \"types\": [] }}
+                \"ephemeral\": {{ \"types\": [] }},
+                \"account_data\": {{ \"types\": [\"m.tag\"] }}
             }},
             \"presence\": {{ \"types\": [] }},
             \"event_format\": \"client\",
@@ -43,84 +172,137 @@ pub fn sync(bk: &Backend) -> Result<(), Error> {
 
         params.push(("filter", strn!(filter)));
     } else {
-        params.push(("since", since.clone()));
+        params.push(("since", strn!(since)));
     }
 
-    let baseu = bk.get_base_url()?;
     let url = bk.url("sync", params)?;
+    let attrs = json!(null);
+
+    json_q("get", &url, &attrs, timeout)
+}
 
+fn process_sync(bk: &Backend, r: JsonValue, since: String) {
     let tx = bk.tx.clone();
     let data = bk.data.clone();
+    let userid = data.lock().unwrap().user_id.clone();
 
-    let attrs = json!(null);
+    let next_batch = String::from(r["next_batch"].as_str().unwrap_or(""));
 
-    thread::spawn(move || {
-        match json_q("get", &url, &attrs, timeout) {
-            Ok(r) => {
-                let next_batch = String::from(r["next_batch"].as_str().unwrap_or(""));
-                if since.is_empty() {
-                    let rooms = match get_rooms_from_json(r, &userid, &baseu) {
-                        Ok(rs) => rs,
-                        Err(err) => {
-                            tx.send(BKResponse::SyncError(err)).unwrap();
-                            vec![]
-                        }
-                    };
+    // the rules are needed to know which of the new messages are highlights
+    if let Some(rules) = get_push_rules_from_json(&r) {
+        tx.send(BKResponse::PushRules(rules)).unwrap();
+    }
+
+    if since.is_empty() {
+        let rooms = match get_rooms_from_json(r, &userid) {
+            Ok(rs) => rs,
+            Err(err) => {
+                tx.send(BKResponse::SyncError(err)).unwrap();
+                vec![]
+            }
+        };
+
+        data.lock().unwrap().joined_rooms = rooms.iter().map(|r| r.id.clone()).collect();
 
-                    let mut def: Option<Room> = None;
-                    let jtr = data.lock().unwrap().join_to_room.clone();
-                    if !jtr.is_empty() {
-                        if let Some(r) = rooms.iter().find(|x| x.id == jtr) {
-                            def = Some(r.clone());
+        let mut def: Option<Room> = None;
+        let jtr = data.lock().unwrap().join_to_room.clone();
+        if !jtr.is_empty() {
+            if let Some(r) = rooms.iter().find(|x| x.id == jtr) {
+                def = Some(r.clone());
+            }
+        }
+        tx.send(BKResponse::Rooms(rooms, def)).unwrap();
+    } else {
+        // Rooms left and joined, before the events of these rooms. A room left and joined
+        // again in this sync is in both lists and it's joined now, so the leaves go first
+        // and the room is added again with its new state.
+        let left = get_left_rooms_from_json(&r);
+        if !left.is_empty() {
+            let mut d = data.lock().unwrap();
+            for roomid in left.iter() {
+                d.joined_rooms.remove(roomid);
+            }
+            tx.send(BKResponse::LeftRooms(left)).unwrap();
+        }
+
+        let known = data.lock().unwrap().joined_rooms.clone();
+        match get_new_rooms_from_json(&r, &userid, &known) {
+            Ok(ref rooms) if rooms.is_empty() => {}
+            Ok(rooms) => {
+                let mut d = data.lock().unwrap();
+                let def = rooms.iter().find(|x| x.id == d.join_to_room).cloned();
+                if def.is_some() {
+                    d.join_to_room = String::new();
+                }
+                d.joined_rooms.extend(rooms.iter().map(|r| r.id.clone()));
+                tx.send(BKResponse::JoinedRooms(rooms, def)).unwrap();
+            }
+            Err(err) => tx.send(BKResponse::SyncError(err)).unwrap(),
+        };
+
+        // Rooms with a gap in the timeline, this should go before the new messages
+        match get_rooms_limited_from_json(&r) {
+            Ok(limited) => {
+                for (roomid, prev_batch) in limited {
+                    tx.send(BKResponse::RoomTimelineLimited(roomid, prev_batch)).unwrap();
+                }
+            }
+            Err(err) => tx.send(BKResponse::SyncError(err)).unwrap(),
+        };
+        match get_rooms_tags_from_json(&r) {
+            Ok(tags) => {
+                for (roomid, t) in tags {
+                    tx.send(BKResponse::RoomTags(roomid, t)).unwrap();
+                }
+            }
+            Err(err) => tx.send(BKResponse::SyncError(err)).unwrap(),
+        };
+        // Message events
+        match get_rooms_timeline_from_json(&r) {
+            Ok(msgs) => tx.send(BKResponse::RoomMessages(msgs)).unwrap(),
+            Err(err) => tx.send(BKResponse::RoomMessagesError(err)).unwrap(),
+        };
+        // The counts of the server, after the messages that we count locally
+        match get_rooms_unread_from_json(&r) {
+            Ok(unread) => {
+                for (roomid, n, h) in unread {
+                    tx.send(BKResponse::RoomNotifications(roomid, n, h)).unwrap();
+                }
+            }
+            Err(err) => tx.send(BKResponse::SyncError(err)).unwrap(),
+        };
+        // Other events
+        match parse_sync_events(&r) {
+            Err(err) => tx.send(BKResponse::SyncError(err)).unwrap(),
+            Ok(events) => {
+                for ev in events {
+                    match ev.stype.as_ref() {
+                        "m.room.name" => {
+                            let name = strn!(ev.content["name"].as_str().unwrap_or(""));
+                            tx.send(BKResponse::RoomName(ev.room.clone(), name)).unwrap();
                         }
-                    }
-                    tx.send(BKResponse::Rooms(rooms, def)).unwrap();
-                } else {
-                    // Message events
-                    match get_rooms_timeline_from_json(&baseu, &r) {
-                        Ok(msgs) => tx.send(BKResponse::RoomMessages(msgs)).unwrap(),
-                        Err(err) => tx.send(BKResponse::RoomMessagesError(err)).unwrap(),
-                    };
-                    // Other events
-                    match parse_sync_events(&r) {
-                        Err(err) => tx.send(BKResponse::SyncError(err)).unwrap(),
-                        Ok(events) => {
-                            for ev in events {
-                                match ev.stype.as_ref() {
-                                    "m.room.name" => {
-                                        let name = strn!(ev.content["name"].as_str().unwrap_or(""));
-                                        tx.send(BKResponse::RoomName(ev.room.clone(), name)).unwrap();
-                                    }
-                                    "m.room.topic" => {
-                                        let t = strn!(ev.content["topic"].as_str().unwrap_or(""));
-                                        tx.send(BKResponse::RoomTopic(ev.room.clone(), t)).unwrap();
-                                    }
-                                    "m.room.avatar" => {
-                                        tx.send(BKResponse::NewRoomAvatar(ev.room.clone())).unwrap();
-                                    }
-                                    "m.room.member" => {
-                                        tx.send(BKResponse::RoomMemberEvent(ev)).unwrap();
-                                    }
-                                    _ => {
-                                        println!("EVENT NOT MANAGED: {:?}", ev);
-                                    }
-                                }
-                            }
+                        "m.room.topic" => {
+                            let t = strn!(ev.content["topic"].as_str().unwrap_or(""));
+                            tx.send(BKResponse::RoomTopic(ev.room.clone(), t)).unwrap();
+                        }
+                        "m.room.avatar" => {
+                            tx.send(BKResponse::NewRoomAvatar(ev.room.clone())).unwrap();
+                        }
+                        "m.room.member" => {
+                            tx.send(BKResponse::RoomMemberEvent(ev)).unwrap();
+                        }
+                        "m.room.power_levels" => {
+                            tx.send(BKResponse::RoomPowerLevels(ev.room.clone(), ev.content.clone())).unwrap();
                         }
-                    };
+                        _ => {
+                            println!("EVENT NOT MANAGED: {:?}", ev);
+                        }
+                    }
                 }
-
-                tx.send(BKResponse::Sync(next_batch.clone())).unwrap();
-                data.lock().unwrap().since = next_batch;
-            },
-            Err(err) => { tx.send(BKResponse::SyncError(err)).unwrap() }
+            }
         };
-    });
-
-    Ok(())
-}
+    }
 
-pub fn force_sync(bk: &Backend) -> Result<(), Error> {
-    bk.data.lock().unwrap().since = String::from("");
-    sync(bk)
+    tx.send(BKResponse::Sync(next_batch.clone())).unwrap();
+    data.lock().unwrap().since = next_batch;
 }
@@ -1,6 +1,14 @@
-use std::sync::{Arc, Mutex, Condvar};
+extern crate serde_json;
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::AtomicBool;
 use std::sync::mpsc::Sender;
 
+use self::serde_json::Value as JsonValue;
+
 use error::Error;
 
 use types::Message;
@@ -8,14 +16,26 @@ use types::Member;
 use types::Protocol;
 use types::Room;
 use types::Event;
+use types::UrlPreview;
+use types::Ruleset;
+use types::PushRule;
+use types::RoomNotify;
+use types::SearchQuery;
+use types::SearchResults;
+use types::Direction;
+use types::TimelineChunk;
+use types::DirectoryQuery;
+use types::DirectoryPage;
 
 use cache::CacheMap;
+use backend::pool::ThreadPool;
 
 
 #[derive(Debug)]
 pub enum BKCommand {
     Login(String, String, String),
-    Logout,
+    Logout(bool),
+    DeactivateAccount(String, bool),
     #[allow(dead_code)]
     Register(String, String, String),
     #[allow(dead_code)]
@@ -24,27 +44,50 @@ pub enum BKCommand {
     GetAvatar,
     Sync,
     SyncForced,
+    StopSync,
     GetRoomMessages(String),
+    BackPaginate(String, String),
     GetMessageContext(Message),
+    ChunkPaginate(String, String, Direction),
     GetRoomAvatar(String),
     GetThumbAsync(String, Sender<String>),
+    GetMediaThumbAsync(String, i32, i32, Sender<String>),
     GetAvatarAsync(Option<Member>, Sender<String>),
+    GetMediaAsync(String, Sender<String>),
     GetMedia(String),
+    GetUrlPreview(String, i64, Sender<Option<UrlPreview>>),
+    SaveMedia(String, String),
+    CancelMedia(String),
+    SetMediaCacheSize(u64),
+    ClearMediaCache,
     GetUserInfoAsync(String, Sender<(String, String)>),
     SendMsg(Message),
+    RetrySendMsg(String),
+    CancelSendMsg(String),
+    LoadOutbox,
     SetRoom(Room),
     ShutDown,
     DirectoryProtocols,
-    DirectorySearch(String, String, bool),
+    DirectorySearch(DirectoryQuery, Option<String>),
+    RoomPreview(String),
     JoinRoom(String),
     MarkAsRead(String, String),
     LeaveRoom(String),
     SetRoomName(String, String),
     SetRoomTopic(String, String),
     SetRoomAvatar(String, String),
+    AddRoomTag(String, String, Option<f64>),
+    RemoveRoomTag(String, String),
+    GetPushRules,
+    SetPushRule(String, PushRule),
+    DeletePushRule(String, String),
+    SetPushRuleEnabled(String, String, bool),
+    SetRoomNotify(String, RoomNotify),
     AttachFile(String, String),
-    AttachImage(String, Vec<u8>),
-    Search(String, Option<String>),
+    AttachFiles(String, Vec<Attachment>),
+    CancelUpload(String),
+    RetryUpload(String),
+    Search(SearchQuery, Option<String>),
     NewRoom(String, RoomType),
 }
 
@@ -52,22 +95,33 @@ pub enum BKCommand {
 pub enum BKResponse {
     Token(String, String),
     Logout,
+    AccountDeactivated,
     Name(String),
     Avatar(String),
     Sync(String),
+    ConnectionState(ConnectionState),
     Rooms(Vec<Room>, Option<Room>),
+    JoinedRooms(Vec<Room>, Option<Room>),
+    LeftRooms(Vec<String>),
     RoomDetail(String, String, String),
     RoomAvatar(String, String),
     NewRoomAvatar(String),
     RoomMemberEvent(Event),
+    RoomPowerLevels(String, JsonValue),
     RoomMessages(Vec<Message>),
     RoomMessagesInit(Vec<Message>),
-    RoomMessagesTo(Vec<Message>),
+    RoomContext(TimelineChunk),
+    RoomChunkMessages(String, Vec<Message>, Option<String>, Direction),
+    RoomMessagesBack(String, Vec<Message>, Option<String>),
+    RoomTimelineLimited(String, String),
     RoomMembers(Vec<Member>),
-    SendMsg,
+    SendMsg(String, String),
+    SendMsgFailed(String, Error),
+    Outbox(Vec<Message>),
     DirectoryProtocols(Vec<Protocol>),
-    DirectorySearch(Vec<Room>),
-    JoinRoom,
+    DirectorySearch(DirectoryPage),
+    RoomPreview(Room),
+    JoinRoom(String),
     LeaveRoom,
     MarkedAsRead(String, String),
     SetRoomName,
@@ -75,9 +129,20 @@ pub enum BKResponse {
     SetRoomAvatar,
     RoomName(String, String),
     RoomTopic(String, String),
-    Media(String),
+    RoomTags(String, HashMap<String, Option<f64>>),
+    RoomTag,
+    RoomNotifications(String, i32, i32),
+    PushRules(Ruleset),
+    Media(String, String),
+    MediaSaved(String, String),
+    MediaProgress(String, u64, u64),
+    MediaFailed(String, Error),
+    MediaCacheCleared,
     AttachedFile(Message),
-    SearchEnd,
+    Uploading(Message, u64),
+    UploadProgress(String, u64, u64),
+    UploadFailed(String, Error),
+    SearchResults(SearchResults),
     NewRoom(Room),
 
     //errors
@@ -85,6 +150,8 @@ pub enum BKResponse {
     AvatarError(Error),
     LoginError(Error),
     LogoutError(Error),
+    ClearCacheError(Error),
+    AccountDeactivationError(Error),
     GuestLoginError(Error),
     SyncError(Error),
     RoomDetailError(Error),
@@ -95,6 +162,7 @@ pub enum BKResponse {
     SetRoomError(Error),
     CommandError(Error),
     DirectoryError(Error),
+    RoomPreviewError(Error),
     JoinRoomError(Error),
     MarkAsReadError(Error),
     LeaveRoomError(Error),
@@ -102,12 +170,53 @@ pub enum BKResponse {
     SetRoomTopicError(Error),
     SetRoomAvatarError(Error),
     GetRoomAvatarError(Error),
+    RoomTagError(Error),
+    PushRulesError(Error),
     MediaError(Error),
     AttachFileError(Error),
     SearchError(Error),
     NewRoomError(Error),
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnectionState {
+    Connected,
+    // network or server problem, retrying in the given milliseconds
+    Disconnected(u64),
+    // too many requests, retrying in the given milliseconds
+    RateLimited(u64),
+    // the server doesn't accept the access token
+    InvalidToken,
+}
+
+/// File to send from the composer, with the options chosen in the send dialog
+#[derive(Debug, Clone)]
+pub struct Attachment {
+    pub path: String,
+    // message body, the file name is used if there's no caption
+    pub caption: Option<String>,
+    // images are scaled down to fit in a square of this size
+    pub max_size: Option<i32>,
+    // images are written again without metadata, like the EXIF location
+    pub strip_metadata: bool,
+    // the file is removed after the upload, like a pasted image
+    pub temporary: bool,
+    // length in milliseconds of audio and video, like a voice message
+    pub duration: Option<u64>,
+}
+
+/// File upload of a message, kept when it fails to try it again
+#[derive(Debug, Clone)]
+pub struct Upload {
+    pub roomid: String,
+    pub path: String,
+    pub name: String,
+    pub body: String,
+    // the file is removed when it's sent or the upload is cancelled
+    pub temporary: bool,
+    pub duration: Option<u64>,
+}
+
 #[derive(Debug)]
 pub enum RoomType {
     Public,
@@ -119,9 +228,35 @@ pub struct BackendData {
     pub access_token: String,
     pub server_url: String,
     pub since: String,
-    pub msgid: i32,
-    pub rooms_since: String,
+    pub sync_running: bool,
+    pub sync_gen: u64,
+    // messages waiting to be sent, in order
+    pub outbox: Vec<Message>,
+    // txnids of the outbox messages that failed
+    pub outbox_failed: HashSet<String>,
+    // rooms with a message being sent right now
+    pub outbox_sending: HashSet<String>,
+    // media downloads running, with the flag to cancel them
+    pub downloads: HashMap<String, Arc<AtomicBool>>,
+    // requests waiting for each media download, with the path to save it to, if any
+    pub downloads_waiting: HashMap<String, Vec<Option<String>>>,
+    // file uploads running, by the message txnid, with the flag to cancel them
+    pub uploads: HashMap<String, Arc<AtomicBool>>,
+    // file uploads that failed, by the message txnid
+    pub uploads_failed: HashMap<String, Upload>,
+    // server upload size limit, None until we ask for it
+    pub upload_limit: Option<u64>,
+    // thumbnails being downloaded, by cache key, with the channels waiting for them
+    pub thumbs_pending: HashMap<String, Vec<Sender<String>>>,
+    // link previews by url and timestamp, None if the server hasn't got one
+    pub url_previews: HashMap<String, Option<UrlPreview>>,
+    // keys of the link previews in the order they were added, to drop the oldest ones
+    pub url_previews_order: VecDeque<String>,
+    // link previews being asked, with the channels waiting for them
+    pub previews_pending: HashMap<String, Vec<Sender<Option<UrlPreview>>>>,
     pub join_to_room: String,
+    // rooms in the last sync, the rooms that aren't here in the next sync are new
+    pub joined_rooms: HashSet<String>,
 }
 
 pub struct Backend {
@@ -131,8 +266,8 @@ pub struct Backend {
 
     // user info cache, uid -> (name, avatar)
     pub user_info_cache: CacheMap<Arc<Mutex<(String, String)>>>,
-    // semaphore to limit the number of threads downloading images
-    pub limit_threads: Arc<(Mutex<u8>, Condvar)>,
+    // worker threads to download avatars and thumbnails
+    pub pool: ThreadPool,
 }
 
 impl Clone for Backend {
@@ -142,7 +277,7 @@ impl Clone for Backend {
             data: self.data.clone(),
             internal_tx: self.internal_tx.clone(),
             user_info_cache: self.user_info_cache.clone(),
-            limit_threads: self.limit_threads.clone(),
+            pool: self.pool.clone(),
         }
     }
 }
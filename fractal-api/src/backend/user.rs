@@ -16,36 +16,6 @@ use types::Member;
 use self::serde_json::Value as JsonValue;
 
 
-macro_rules! semaphore {
-    ($cv: expr, $blk: block) => {{
-        let thread_count = $cv.clone();
-        thread::spawn(move || {
-            // waiting, less than 20 threads at the same time
-            // this is a semaphore
-            // TODO: use std::sync::Semaphore when it's on stable version
-            // https://doc.rust-lang.org/1.1.0/std/sync/struct.Semaphore.html
-            let &(ref num, ref cvar) = &*thread_count;
-            {
-                let mut start = num.lock().unwrap();
-                while *start >= 20 {
-                    start = cvar.wait(start).unwrap()
-                }
-                *start += 1;
-            }
-
-            $blk
-
-            // freeing the cvar for new threads
-            {
-                let mut counter = num.lock().unwrap();
-                *counter -= 1;
-            }
-            cvar.notify_one();
-        });
-    }}
-}
-
-
 pub fn get_username(bk: &Backend) -> Result<(), Error> {
     let id = bk.data.lock().unwrap().user_id.clone();
     let url = bk.url(&format!("profile/{}/displayname", id.clone()), vec![])?;
@@ -98,17 +68,17 @@ pub fn get_user_info_async(bk: &mut Backend,
     let cache_key = u.clone();
     let cache_value = info.clone();
 
-    semaphore!(bk.limit_threads, {
+    bk.pool.run(move || {
         let i0 = info.lock();
         match get_user_avatar(&baseu, &u) {
             Ok(info) => {
-                tx.send(info.clone()).unwrap();
+                let _ = tx.send(info.clone());
                 let mut i = i0.unwrap();
                 i.0 = info.0;
                 i.1 = info.1;
             }
             Err(_) => {
-                tx.send((String::new(), String::new())).unwrap();
+                let _ = tx.send((String::new(), String::new()));
             }
         };
     });
@@ -132,12 +102,12 @@ pub fn get_avatar_async(bk: &Backend, member: Option<Member>, tx: Sender<String>
     let alias = m.get_alias().clone();
     let avatar = m.avatar.clone();
 
-    semaphore!(bk.limit_threads, {
+    bk.pool.run(move || {
         match get_user_avatar_img(&baseu, uid,
                                   alias.unwrap_or_default(),
                                   avatar.unwrap_or_default()) {
-            Ok(fname) => { tx.send(fname.clone()).unwrap(); }
-            Err(_) => { tx.send(String::new()).unwrap(); }
+            Ok(fname) => { let _ = tx.send(fname.clone()); }
+            Err(_) => { let _ = tx.send(String::new()); }
         }
     });
 
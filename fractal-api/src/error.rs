@@ -6,17 +6,108 @@ extern crate glib;
 extern crate serde_json;
 
 use std::io;
+use std::fmt;
+use std::error;
 use std::time::SystemTimeError;
 use std::ffi::OsString;
 
 use self::serde_json::Value as JsonValue;
 
+/// Error returned by the homeserver, parsed from the standard `errcode`/`error` body.
+#[derive(Debug, Clone)]
+pub struct MatrixError {
+    /// HTTP status code, 0 if the error came in a successful response
+    pub status: u16,
+    pub errcode: String,
+    pub error: String,
+    pub retry_after_ms: Option<u64>,
+    /// The full response, some endpoints add more fields (like the UIAA flows)
+    pub content: JsonValue,
+}
+
+impl MatrixError {
+    pub fn new(status: u16, content: JsonValue) -> MatrixError {
+        MatrixError {
+            status: status,
+            errcode: String::from(content["errcode"].as_str().unwrap_or("")),
+            error: String::from(content["error"].as_str().unwrap_or("")),
+            retry_after_ms: content["retry_after_ms"].as_u64(),
+            content: content,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum Error {
+    /// Unexpected or incomplete response from the server
     BackendError,
-    CacheError,
+    /// The local cache can't be read or written
+    CacheError(String),
+    /// Failures storing or retrieving secrets, or with encrypted data
+    CryptoError(String),
+    IoError(io::Error),
+    UrlError(url::ParseError),
+    JsonError(serde_json::Error),
+    RegexError(regex::Error),
+    ImageError(String),
+    TimeError(SystemTimeError),
     ReqwestError(reqwest::Error),
-    MatrixError(JsonValue),
+    /// HTTP error without a Matrix error body: status code and reason
+    HttpError(u16, String),
+    MatrixError(MatrixError),
+    /// The operation was cancelled by the user
+    Cancelled,
+    /// The file is bigger than the server upload limit, in bytes
+    FileTooLarge(u64),
+}
+
+impl Error {
+    /// The Matrix errcode, like `M_FORBIDDEN`, if the server returned one.
+    pub fn errcode(&self) -> Option<&str> {
+        match *self {
+            Error::MatrixError(ref e) if !e.errcode.is_empty() => Some(&e.errcode),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::BackendError => write!(f, "Unexpected response from the server"),
+            Error::CacheError(ref e) => write!(f, "Cache error: {}", e),
+            Error::CryptoError(ref e) => write!(f, "Encryption error: {}", e),
+            Error::IoError(ref e) => write!(f, "I/O error: {}", e),
+            Error::UrlError(ref e) => write!(f, "Invalid URL: {}", e),
+            Error::JsonError(ref e) => write!(f, "Invalid JSON: {}", e),
+            Error::RegexError(ref e) => write!(f, "Invalid regular expression: {}", e),
+            Error::ImageError(ref e) => write!(f, "Image error: {}", e),
+            Error::TimeError(ref e) => write!(f, "Time error: {}", e),
+            Error::ReqwestError(ref e) => write!(f, "Connection error: {}", e),
+            Error::HttpError(status, ref reason) => write!(f, "HTTP error {}: {}", status, reason),
+            Error::MatrixError(ref e) if e.error.is_empty() => write!(f, "{}", e.errcode),
+            Error::MatrixError(ref e) => write!(f, "{}", e.error),
+            Error::Cancelled => write!(f, "Cancelled"),
+            Error::FileTooLarge(limit) => {
+                write!(f, "The file is bigger than the server limit of {:.1} MB",
+                       limit as f64 / 1_000_000.0)
+            }
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::IoError(ref e) => Some(e),
+            Error::UrlError(ref e) => Some(e),
+            Error::JsonError(ref e) => Some(e),
+            Error::RegexError(ref e) => Some(e),
+            Error::TimeError(ref e) => Some(e),
+            Error::ReqwestError(ref e) => Some(e),
+            _ => None,
+        }
+    }
 }
 
 impl From<reqwest::Error> for Error {
@@ -25,13 +116,56 @@ impl From<reqwest::Error> for Error {
     }
 }
 
-derror!(url::ParseError, Error::BackendError);
-derror!(io::Error, Error::BackendError);
-derror!(regex::Error, Error::BackendError);
-derror!(cairo::Status, Error::BackendError);
-derror!(cairo::IoError, Error::BackendError);
-derror!(glib::Error, Error::BackendError);
-derror!(SystemTimeError, Error::BackendError);
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::IoError(err)
+    }
+}
+
+impl From<url::ParseError> for Error {
+    fn from(err: url::ParseError) -> Error {
+        Error::UrlError(err)
+    }
+}
 
-derror!(OsString, Error::CacheError);
-derror!(serde_json::Error, Error::CacheError);
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Error {
+        Error::JsonError(err)
+    }
+}
+
+impl From<regex::Error> for Error {
+    fn from(err: regex::Error) -> Error {
+        Error::RegexError(err)
+    }
+}
+
+impl From<SystemTimeError> for Error {
+    fn from(err: SystemTimeError) -> Error {
+        Error::TimeError(err)
+    }
+}
+
+impl From<cairo::Status> for Error {
+    fn from(err: cairo::Status) -> Error {
+        Error::ImageError(format!("{:?}", err))
+    }
+}
+
+impl From<cairo::IoError> for Error {
+    fn from(err: cairo::IoError) -> Error {
+        Error::ImageError(format!("{:?}", err))
+    }
+}
+
+impl From<glib::Error> for Error {
+    fn from(err: glib::Error) -> Error {
+        Error::ImageError(format!("{}", err))
+    }
+}
+
+impl From<OsString> for Error {
+    fn from(path: OsString) -> Error {
+        Error::CacheError(format!("invalid path {:?}", path))
+    }
+}
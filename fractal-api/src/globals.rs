@@ -1,2 +1,13 @@
 pub static TIMEOUT: u64 = 10;
 pub static PAGE_LIMIT: i32 = 40;
+/// Size of the avatars downloaded and drawn when the size isn't given, in logical pixels
+pub static AVATAR_SIZE: i32 = 40;
+/// Threads used to download avatars and thumbnails
+pub static POOL_SIZE: usize = 8;
+/// Default size limit for the downloaded media, in bytes
+pub static MEDIA_CACHE_SIZE: u64 = 200 * 1024 * 1024;
+/// Link previews are asked for timestamps rounded to this many milliseconds, so the same link
+/// in messages close in time is only asked once
+pub static PREVIEW_TS_ROUND: i64 = 60 * 60 * 1000;
+/// Link previews kept in memory, the oldest ones are asked again if they're needed
+pub static URL_PREVIEWS_KEPT: usize = 500;
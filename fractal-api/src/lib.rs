@@ -4,6 +4,8 @@
 extern crate serde_json;
 #[macro_use]
 extern crate serde_derive;
+#[macro_use]
+extern crate lazy_static;
 
 #[macro_use]
 pub mod util;
@@ -13,6 +15,7 @@ pub mod globals;
 mod model;
 pub mod types;
 pub mod cache;
+pub mod mediacache;
 pub mod backend;
 
 #[cfg(test)]
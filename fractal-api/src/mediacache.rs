@@ -0,0 +1,312 @@
+extern crate serde_json;
+extern crate regex;
+
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use self::regex::Regex;
+
+use error::Error;
+use globals;
+use util::cache_path;
+use util::new_txn_id;
+
+// Downloaded media is stored in two buckets, thumbnails and full files, under the "media"
+// directory in the fractal cache. Files are named after the server, the media id and the
+// thumbnail size, so the same mxc url is downloaded only once and a new avatar never
+// reuses the old file. The index keeps the size and the last access of each file, and when
+// the total size goes over the limit the least recently used files are removed.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Bucket {
+    Thumb,
+    Full,
+}
+
+impl Bucket {
+    fn dir(&self) -> &str {
+        match *self {
+            Bucket::Thumb => "thumb",
+            Bucket::Full => "full",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Entry {
+    size: u64,
+    last_access: u64,
+}
+
+struct MediaCache {
+    loaded: bool,
+    max_size: u64,
+    index: HashMap<String, Entry>,
+    // increased with each change of the index that is stored
+    version: u64,
+}
+
+lazy_static! {
+    static ref CACHE: Mutex<MediaCache> = Mutex::new(MediaCache {
+        loaded: false,
+        max_size: globals::MEDIA_CACHE_SIZE,
+        index: HashMap::new(),
+        version: 0,
+    });
+
+    // version of the index in the file, it's written without holding the CACHE lock so
+    // the lookups from the main loop don't wait for it
+    static ref STORED: Mutex<u64> = Mutex::new(0);
+
+    static ref MXC_RE: Regex = Regex::new(r"mxc://(?P<server>[^/]+)/(?P<media>.+)").unwrap();
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn media_dir() -> Result<PathBuf, Error> {
+    Ok(PathBuf::from(cache_path("media")?))
+}
+
+fn index_path() -> Result<PathBuf, Error> {
+    let mut path = media_dir()?;
+    path.push("index.json");
+    Ok(path)
+}
+
+/// Percent-encodes anything that isn't safe in a file name, server names can have a port
+/// and media ids are opaque. The '_' is encoded too, it separates the parts of the names.
+fn escape(s: &str) -> String {
+    let mut escaped = String::new();
+    for c in s.chars() {
+        match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '.' => escaped.push(c),
+            _ => {
+                let mut buf = [0; 4];
+                for b in c.encode_utf8(&mut buf).bytes() {
+                    escaped.push_str(&format!("%{:02X}", b));
+                }
+            }
+        }
+    }
+    escaped
+}
+
+/// Returns the cache key for this media. The key is the path relative to the media
+/// directory, like "thumb/matrix.org_abcdef_64x64".
+pub fn media_key(bucket: Bucket, server: &str, media: &str, w: i32, h: i32) -> String {
+    let name = match bucket {
+        Bucket::Thumb => format!("{}_{}_{}x{}", escape(server), escape(media), w, h),
+        Bucket::Full => format!("{}_{}", escape(server), escape(media)),
+    };
+
+    format!("{}/{}", bucket.dir(), name)
+}
+
+/// Returns the cache key for this mxc url, None if it isn't a valid mxc url
+pub fn mxc_key(url: &str, bucket: Bucket, w: i32, h: i32) -> Option<String> {
+    let caps = MXC_RE.captures(url)?;
+
+    Some(media_key(bucket, &caps["server"], &caps["media"], w, h))
+}
+
+/// The full path of the file for this key, the bucket directory is created if needed.
+pub fn path(key: &str) -> Result<String, Error> {
+    let mut path = media_dir()?;
+    path.push(key);
+
+    if let Some(parent) = path.parent() {
+        if !parent.exists() {
+            fs::create_dir_all(parent)?;
+        }
+    }
+
+    Ok(path.into_os_string().into_string()?)
+}
+
+impl MediaCache {
+    fn load(&mut self) {
+        if self.loaded {
+            return;
+        }
+        self.loaded = true;
+
+        let index: Result<HashMap<String, Entry>, Error> = index_path().and_then(|p| {
+            if !p.is_file() {
+                return Ok(HashMap::new());
+            }
+            let mut serialized = String::new();
+            File::open(p)?.read_to_string(&mut serialized)?;
+            Ok(serde_json::from_str(&serialized)?)
+        });
+
+        match index {
+            Ok(index) => { self.index = index; }
+            Err(err) => { println!("Error loading the media cache index: {}", err); }
+        };
+    }
+
+    /// The serialized index to store, with its version
+    fn snapshot(&mut self) -> Result<(u64, String), Error> {
+        self.version += 1;
+        Ok((self.version, serde_json::to_string(&self.index)?))
+    }
+
+    fn total_size(&self) -> u64 {
+        self.index.values().map(|e| e.size).sum()
+    }
+
+    /// Removes from the index the least recently used files until the cache fits in the
+    /// size limit, and returns their keys to remove the files. The @keep file isn't removed,
+    /// it's the one that we're going to return.
+    fn evict(&mut self, keep: &str) -> Vec<String> {
+        let mut evicted = vec![];
+        let mut total = self.total_size();
+        if total <= self.max_size {
+            return evicted;
+        }
+
+        let mut entries: Vec<(String, Entry)> = self.index.iter()
+            .filter(|&(k, _)| k != keep)
+            .map(|(k, e)| (k.clone(), e.clone()))
+            .collect();
+        entries.sort_by_key(|&(_, ref e)| e.last_access);
+
+        for (k, e) in entries {
+            if total <= self.max_size {
+                break;
+            }
+
+            self.index.remove(&k);
+            total -= e.size;
+            evicted.push(k);
+        }
+
+        evicted
+    }
+}
+
+/// Writes the index, unless a newer one was written already
+fn store(snapshot: (u64, String)) -> Result<(), Error> {
+    let (version, serialized) = snapshot;
+
+    let mut stored = STORED.lock().unwrap();
+    if version <= *stored {
+        return Ok(());
+    }
+
+    let dir = media_dir()?;
+    if !dir.exists() {
+        fs::create_dir_all(&dir)?;
+    }
+    File::create(index_path()?)?.write_all(&serialized.into_bytes())?;
+    *stored = version;
+
+    Ok(())
+}
+
+fn remove_files(keys: Vec<String>) {
+    for k in keys {
+        if let Ok(p) = path(&k) {
+            let _ = fs::remove_file(p);
+        }
+    }
+}
+
+/// Returns the path of the cached file for this key, if it's in the cache, and updates
+/// its last access time. It's called from the main loop, so the index isn't written here,
+/// the access time is stored with the next change.
+pub fn lookup(key: &str) -> Option<String> {
+    let p = path(key).ok()?;
+    let exists = PathBuf::from(&p).is_file();
+
+    let mut cache = CACHE.lock().unwrap();
+    cache.load();
+
+    if !exists {
+        cache.index.remove(key);
+        return None;
+    }
+
+    cache.index.get_mut(key)?.last_access = now();
+
+    Some(p)
+}
+
+/// Stores the @data in the cache for this key and returns the file path. Old files are
+/// evicted if the cache is over the size limit.
+pub fn insert(key: &str, data: &[u8]) -> Result<String, Error> {
+    let p = path(key)?;
+
+    // writing to a temporary file first, so a half written file is never returned. Each
+    // write has its own file, other threads can be storing the same media.
+    let tmp = format!("{}.{}.part", p, new_txn_id());
+    File::create(&tmp)?.write_all(data)?;
+    fs::rename(&tmp, &p)?;
+
+    add(key)
+}
+
+/// Adds to the index the file that's already in the path for this key, like a finished
+/// download, and returns the file path.
+pub fn add(key: &str) -> Result<String, Error> {
+    let p = path(key)?;
+    let size = fs::metadata(&p)?.len();
+
+    let (evicted, snapshot) = {
+        let mut cache = CACHE.lock().unwrap();
+        cache.load();
+
+        cache.index.insert(String::from(key), Entry {
+            size: size,
+            last_access: now(),
+        });
+        (cache.evict(key), cache.snapshot()?)
+    };
+    remove_files(evicted);
+    store(snapshot)?;
+
+    Ok(p)
+}
+
+/// Sets the maximum size of the cache, in bytes, removing files if needed.
+pub fn set_max_size(size: u64) -> Result<(), Error> {
+    let (evicted, snapshot) = {
+        let mut cache = CACHE.lock().unwrap();
+        cache.load();
+
+        cache.max_size = size;
+        (cache.evict(""), cache.snapshot()?)
+    };
+    remove_files(evicted);
+    store(snapshot)
+}
+
+/// Removes all the cached media
+pub fn clear() -> Result<(), Error> {
+    let version = {
+        let mut cache = CACHE.lock().unwrap();
+        cache.index.clear();
+        cache.loaded = true;
+        cache.version += 1;
+        cache.version
+    };
+
+    // the indexes that were going to be written are older than this one
+    let mut stored = STORED.lock().unwrap();
+    *stored = version;
+
+    let dir = media_dir()?;
+    if dir.exists() {
+        fs::remove_dir_all(&dir)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,25 @@
+use model::room::Room;
+
+/// Search in a public room directory, the one of our homeserver if @server is None. The
+/// rooms bridged from other networks are only included with @all_networks, or the ones of
+/// the @third_party instance.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DirectoryQuery {
+    pub term: Option<String>,
+    pub server: Option<String>,
+    pub third_party: Option<String>,
+    pub all_networks: bool,
+    pub limit: i32,
+    // to tell the pages of each search apart, the same query can be searched again
+    pub search: u64,
+}
+
+/// A page of rooms of the directory, @next_batch is the token to get the next page
+#[derive(Debug, Clone)]
+pub struct DirectoryPage {
+    pub query: DirectoryQuery,
+    pub rooms: Vec<Room>,
+    // approximate number of rooms in the directory
+    pub total: Option<u64>,
+    pub next_batch: Option<String>,
+}
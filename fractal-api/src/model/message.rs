@@ -2,6 +2,42 @@ extern crate chrono;
 
 use self::chrono::prelude::*;
 
+/// The `info` of media messages, serialized with the names that the spec uses
+#[derive(Debug, Clone, Default)]
+#[derive(PartialEq, PartialOrd)]
+#[derive(Serialize, Deserialize)]
+pub struct MediaInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mimetype: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub w: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub h: Option<u64>,
+    /// Length of audio and video in milliseconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thumbnail_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thumbnail_info: Option<ThumbnailInfo>,
+}
+
+#[derive(Debug, Clone, Default)]
+#[derive(PartialEq, PartialOrd)]
+#[derive(Serialize, Deserialize)]
+pub struct ThumbnailInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mimetype: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub w: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub h: Option<u64>,
+}
+
 #[derive(Debug)]
 #[derive(PartialEq, PartialOrd)]
 #[derive(Serialize, Deserialize)]
@@ -14,6 +50,12 @@ pub struct Message {
     pub thumb: Option<String>,
     pub url: Option<String>,
     pub id: Option<String>,
+    // transaction id used to send the message, only known for our own messages
+    #[serde(default)]
+    pub txnid: Option<String>,
+    // media info for images, files, video and audio
+    #[serde(default)]
+    pub info: Option<MediaInfo>,
 }
 
 impl Clone for Message {
@@ -27,6 +69,8 @@ impl Clone for Message {
             thumb: self.thumb.clone(),
             url: self.url.clone(),
             id: self.id.clone(),
+            txnid: self.txnid.clone(),
+            info: self.info.clone(),
         }
     }
 }
@@ -42,6 +86,8 @@ impl Default for Message {
             thumb: None,
             url: None,
             id: None,
+            txnid: None,
+            info: None,
         }
     }
 }
@@ -3,3 +3,8 @@ pub mod room;
 pub mod protocol;
 pub mod event;
 pub mod message;
+pub mod urlpreview;
+pub mod pushrules;
+pub mod search;
+pub mod timeline;
+pub mod directory;
@@ -0,0 +1,540 @@
+extern crate regex;
+extern crate serde_json;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use self::regex::Regex;
+use self::serde_json::Value as JsonValue;
+
+use model::message::Message;
+
+/// The push rules of the user, the `global` object of the m.push_rules account data
+#[derive(Debug, Clone, Default)]
+#[derive(Serialize, Deserialize)]
+pub struct Ruleset {
+    #[serde(rename = "override", default)]
+    pub override_: Vec<PushRule>,
+    #[serde(default)]
+    pub content: Vec<PushRule>,
+    #[serde(default)]
+    pub room: Vec<PushRule>,
+    #[serde(default)]
+    pub sender: Vec<PushRule>,
+    #[serde(default)]
+    pub underride: Vec<PushRule>,
+    #[serde(skip)]
+    patterns: Patterns,
+}
+
+/// The regexes of the rules, by their source, so they're compiled once and not for each
+/// message that is evaluated
+#[derive(Debug, Clone, Default)]
+struct Patterns(RefCell<HashMap<String, Option<Regex>>>);
+
+impl Patterns {
+    fn is_match(&self, re: &str, value: &str) -> bool {
+        let mut cache = self.0.borrow_mut();
+        if !cache.contains_key(re) {
+            cache.insert(re.to_string(), Regex::new(re).ok());
+        }
+        cache[re].as_ref().map(|r| r.is_match(value)).unwrap_or(false)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+#[derive(Serialize, Deserialize)]
+pub struct PushRule {
+    pub rule_id: String,
+    #[serde(default)]
+    pub default: bool,
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub actions: Vec<JsonValue>,
+    // only for override and underride rules
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub conditions: Vec<PushCondition>,
+    // only for content rules
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<String>,
+}
+
+/// A condition of override and underride rules. The fields used depend on the kind, and
+/// conditions of an unknown kind never match.
+#[derive(Debug, Clone, Default)]
+#[derive(Serialize, Deserialize)]
+pub struct PushCondition {
+    pub kind: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub is: Option<String>,
+}
+
+/// What to do with an event, from the actions of the first rule that matches it
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PushActions {
+    pub notify: bool,
+    pub highlight: bool,
+    pub sound: Option<String>,
+}
+
+/// Notification level of a room, stored in the push rules like other clients do: a room
+/// rule that doesn't notify for mentions only, and an override rule for muted rooms
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RoomNotify {
+    All,
+    Mentions,
+    Mute,
+}
+
+impl RoomNotify {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            RoomNotify::All => "all",
+            RoomNotify::Mentions => "mentions",
+            RoomNotify::Mute => "mute",
+        }
+    }
+
+    pub fn from_str(s: &str) -> Option<RoomNotify> {
+        match s {
+            "all" => Some(RoomNotify::All),
+            "mentions" => Some(RoomNotify::Mentions),
+            "mute" => Some(RoomNotify::Mute),
+            _ => None,
+        }
+    }
+}
+
+/// What we know about the room and the user when an event is evaluated
+pub struct PushContext<'a> {
+    pub user_id: &'a str,
+    // the display name of the user in the room
+    pub display_name: Option<&'a str>,
+    pub member_count: usize,
+    // content of the m.room.power_levels event of the room, if we have it
+    pub power_levels: Option<&'a JsonValue>,
+}
+
+impl Ruleset {
+    /// Returns the actions of the first enabled rule that matches @ev, in the order of the
+    /// spec: override, content, room, sender and underride rules
+    pub fn evaluate(&self, ev: &JsonValue, ctx: &PushContext) -> Option<PushActions> {
+        let body = ev["content"]["body"].as_str().unwrap_or("");
+        let roomid = ev["room_id"].as_str().unwrap_or("");
+        let sender = ev["sender"].as_str().unwrap_or("");
+
+        let pt = &self.patterns;
+        let rule = self.override_.iter().filter(|r| r.enabled)
+            .find(|r| r.conditions.iter().all(|c| c.matches(ev, ctx, pt)))
+            .or_else(|| self.content.iter().filter(|r| r.enabled)
+                .find(|r| r.pattern.as_ref().map(|p| glob_match(pt, p, body, true)).unwrap_or(false)))
+            .or_else(|| self.room.iter().find(|r| r.enabled && r.rule_id == roomid))
+            .or_else(|| self.sender.iter().find(|r| r.enabled && r.rule_id == sender))
+            .or_else(|| self.underride.iter().filter(|r| r.enabled)
+                .find(|r| r.conditions.iter().all(|c| c.matches(ev, ctx, pt))))?;
+
+        Some(PushRule::parse_actions(&rule.actions))
+    }
+
+    pub fn evaluate_message(&self, msg: &Message, ctx: &PushContext) -> Option<PushActions> {
+        self.evaluate(&message_event(msg), ctx)
+    }
+
+    pub fn room_notify(&self, roomid: &str) -> RoomNotify {
+        let silent = |r: &PushRule| {
+            r.rule_id == roomid && r.enabled && !PushRule::parse_actions(&r.actions).notify
+        };
+
+        if self.override_.iter().any(|r| silent(r)) {
+            RoomNotify::Mute
+        } else if self.room.iter().any(|r| silent(r)) {
+            RoomNotify::Mentions
+        } else {
+            RoomNotify::All
+        }
+    }
+}
+
+impl PushRule {
+    /// The rule that mutes @roomid, for the override kind
+    pub fn mute_room(roomid: &str) -> PushRule {
+        PushRule {
+            rule_id: roomid.to_string(),
+            enabled: true,
+            actions: vec![json!("dont_notify")],
+            conditions: vec![PushCondition {
+                kind: "event_match".to_string(),
+                key: Some("room_id".to_string()),
+                pattern: Some(roomid.to_string()),
+                is: None,
+            }],
+            ..PushRule::default()
+        }
+    }
+
+    /// The rule that only lets the mentions in @roomid notify, for the room kind
+    pub fn mentions_only_room(roomid: &str) -> PushRule {
+        PushRule {
+            rule_id: roomid.to_string(),
+            enabled: true,
+            actions: vec![json!("dont_notify")],
+            ..PushRule::default()
+        }
+    }
+
+    /// The body to PUT this rule in the /pushrules API
+    pub fn to_request(&self) -> JsonValue {
+        let mut body = json!({ "actions": self.actions });
+        if !self.conditions.is_empty() {
+            body["conditions"] = json!(self.conditions);
+        }
+        if let Some(ref pattern) = self.pattern {
+            body["pattern"] = json!(pattern);
+        }
+
+        body
+    }
+
+    pub fn parse_actions(actions: &[JsonValue]) -> PushActions {
+        let mut pa = PushActions::default();
+
+        for action in actions {
+            match action.as_str() {
+                Some("notify") => pa.notify = true,
+                Some(_) => {}
+                None => match action["set_tweak"].as_str() {
+                    Some("highlight") => pa.highlight = action["value"].as_bool().unwrap_or(true),
+                    Some("sound") => pa.sound = action["value"].as_str().map(String::from),
+                    _ => {}
+                },
+            }
+        }
+
+        pa
+    }
+}
+
+impl PushCondition {
+    fn matches(&self, ev: &JsonValue, ctx: &PushContext, pt: &Patterns) -> bool {
+        match self.kind.as_ref() {
+            "event_match" => {
+                let (key, pattern) = match (self.key.as_ref(), self.pattern.as_ref()) {
+                    (Some(k), Some(p)) => (k, p),
+                    _ => return false,
+                };
+                match event_field(ev, key) {
+                    Some(value) => glob_match(pt, pattern, value, key == "content.body"),
+                    None => false,
+                }
+            }
+            "contains_display_name" => {
+                let body = ev["content"]["body"].as_str().unwrap_or("");
+                match ctx.display_name {
+                    Some(name) if !name.is_empty() => word_match(pt, &regex::escape(name), body),
+                    _ => false,
+                }
+            }
+            "room_member_count" => {
+                match self.is.as_ref() {
+                    Some(is) => member_count_match(is, ctx.member_count),
+                    None => false,
+                }
+            }
+            "sender_notification_permission" => {
+                let (key, levels) = match (self.key.as_ref(), ctx.power_levels) {
+                    (Some(k), Some(l)) => (k, l),
+                    _ => return false,
+                };
+                let sender = ev["sender"].as_str().unwrap_or("");
+                let needed = levels["notifications"][key].as_i64().unwrap_or(50);
+                let level = levels["users"][sender].as_i64()
+                    .or(levels["users_default"].as_i64())
+                    .unwrap_or(0);
+                level >= needed
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The event that push rules see for a message
+fn message_event(msg: &Message) -> JsonValue {
+    json!({
+        "type": "m.room.message",
+        "room_id": msg.room,
+        "sender": msg.sender,
+        "content": {
+            "msgtype": msg.mtype,
+            "body": msg.body,
+        }
+    })
+}
+
+// dotted keys like content.body
+fn event_field<'a>(ev: &'a JsonValue, key: &str) -> Option<&'a str> {
+    key.split('.').fold(Some(ev), |v, k| v.and_then(|v| v.get(k)))?.as_str()
+}
+
+// glob patterns, with * and ?, are case insensitive. The body matches if the pattern is
+// found as whole words, the other keys must match the whole value.
+fn glob_match(pt: &Patterns, pattern: &str, value: &str, words: bool) -> bool {
+    let re = regex::escape(pattern).replace("\\*", ".*").replace("\\?", ".");
+    match words {
+        true => word_match(pt, &re, value),
+        false => pt.is_match(&format!("(?i)^{}$", re), value),
+    }
+}
+
+fn word_match(pt: &Patterns, re: &str, value: &str) -> bool {
+    pt.is_match(&format!(r"(?i)(^|\W){}(\W|$)", re), value)
+}
+
+// "2", "==2", "<10", ">=3"...
+fn member_count_match(is: &str, count: usize) -> bool {
+    let split = is.find(|c: char| c.is_digit(10)).unwrap_or(is.len());
+    let (op, n) = is.split_at(split);
+    let n = match n.parse::<usize>() {
+        Ok(n) => n,
+        Err(_) => return false,
+    };
+
+    match op {
+        "" | "==" => count == n,
+        "<" => count < n,
+        ">" => count > n,
+        "<=" => count <= n,
+        ">=" => count >= n,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROOM: &str = "!room:example.org";
+
+    // the server default rules of the spec that we evaluate, for @alice:example.org
+    fn default_rules() -> Ruleset {
+        let notify_highlight = json!(["notify",
+                                      {"set_tweak": "sound", "value": "default"},
+                                      {"set_tweak": "highlight"}]);
+        let rules = json!({
+            "override": [
+                {"rule_id": ".m.rule.master", "default": true, "enabled": false,
+                 "actions": ["dont_notify"], "conditions": []},
+                {"rule_id": ".m.rule.suppress_notices", "default": true, "enabled": true,
+                 "actions": ["dont_notify"],
+                 "conditions": [{"kind": "event_match", "key": "content.msgtype",
+                                 "pattern": "m.notice"}]},
+                {"rule_id": ".m.rule.contains_display_name", "default": true, "enabled": true,
+                 "actions": notify_highlight, "conditions": [{"kind": "contains_display_name"}]},
+                {"rule_id": ".m.rule.roomnotif", "default": true, "enabled": true,
+                 "actions": ["notify", {"set_tweak": "highlight", "value": true}],
+                 "conditions": [{"kind": "event_match", "key": "content.body",
+                                 "pattern": "@room"},
+                                {"kind": "sender_notification_permission", "key": "room"}]}
+            ],
+            "content": [
+                {"rule_id": ".m.rule.contains_user_name", "default": true, "enabled": true,
+                 "pattern": "alice", "actions": notify_highlight}
+            ],
+            "underride": [
+                {"rule_id": ".m.rule.room_one_to_one", "default": true, "enabled": true,
+                 "actions": ["notify", {"set_tweak": "sound", "value": "default"},
+                             {"set_tweak": "highlight", "value": false}],
+                 "conditions": [{"kind": "room_member_count", "is": "2"},
+                                {"kind": "event_match", "key": "type",
+                                 "pattern": "m.room.message"}]},
+                {"rule_id": ".m.rule.message", "default": true, "enabled": true,
+                 "actions": ["notify", {"set_tweak": "highlight", "value": false}],
+                 "conditions": [{"kind": "event_match", "key": "type",
+                                 "pattern": "m.room.message"}]}
+            ]
+        });
+
+        serde_json::from_value(rules).unwrap()
+    }
+
+    fn event(sender: &str, msgtype: &str, body: &str) -> JsonValue {
+        json!({
+            "type": "m.room.message",
+            "room_id": ROOM,
+            "sender": sender,
+            "content": {"msgtype": msgtype, "body": body}
+        })
+    }
+
+    fn context<'a>(members: usize, power_levels: Option<&'a JsonValue>) -> PushContext<'a> {
+        PushContext {
+            user_id: "@alice:example.org",
+            display_name: Some("Alice Liddell"),
+            member_count: members,
+            power_levels: power_levels,
+        }
+    }
+
+    fn actions(notify: bool, highlight: bool, sound: Option<&str>) -> Option<PushActions> {
+        Some(PushActions { notify: notify, highlight: highlight, sound: sound.map(String::from) })
+    }
+
+    #[test]
+    fn display_name_highlights() {
+        let rules = default_rules();
+        let ctx = context(5, None);
+
+        let ev = event("@bob:example.org", "m.text", "Hi alice liddell!");
+        assert_eq!(rules.evaluate(&ev, &ctx), actions(true, true, Some("default")));
+
+        // only whole words
+        let ev = event("@bob:example.org", "m.text", "Hi Alicia Liddells");
+        assert_eq!(rules.evaluate(&ev, &ctx), actions(true, false, None));
+
+        // the user name of the content rule
+        let ev = event("@bob:example.org", "m.text", "ping ALICE");
+        assert_eq!(rules.evaluate(&ev, &ctx), actions(true, true, Some("default")));
+    }
+
+    #[test]
+    fn room_notification_needs_the_power_level() {
+        let rules = default_rules();
+        let levels = json!({
+            "users": {"@mod:example.org": 50},
+            "users_default": 0,
+            "notifications": {"room": 50}
+        });
+        let ctx = context(5, Some(&levels));
+
+        let ev = event("@mod:example.org", "m.text", "@room meeting now");
+        assert_eq!(rules.evaluate(&ev, &ctx), actions(true, true, None));
+
+        let ev = event("@bob:example.org", "m.text", "@room meeting now");
+        assert_eq!(rules.evaluate(&ev, &ctx), actions(true, false, None));
+
+        // without the power levels we can't tell, so it isn't a room notification
+        let ev = event("@mod:example.org", "m.text", "@room meeting now");
+        assert_eq!(rules.evaluate(&ev, &context(5, None)), actions(true, false, None));
+    }
+
+    #[test]
+    fn notices_are_suppressed() {
+        let rules = default_rules();
+        let ev = event("@bot:example.org", "m.notice", "alice: build failed");
+        assert_eq!(rules.evaluate(&ev, &context(5, None)), actions(false, false, None));
+    }
+
+    #[test]
+    fn one_to_one_rooms_ring() {
+        let rules = default_rules();
+        let ev = event("@bob:example.org", "m.text", "hello");
+        assert_eq!(rules.evaluate(&ev, &context(2, None)), actions(true, false, Some("default")));
+        assert_eq!(rules.evaluate(&ev, &context(3, None)), actions(true, false, None));
+    }
+
+    #[test]
+    fn muted_room() {
+        let mut rules = default_rules();
+        rules.override_.insert(0, PushRule::mute_room(ROOM));
+        assert_eq!(rules.room_notify(ROOM), RoomNotify::Mute);
+        assert_eq!(rules.room_notify("!other:example.org"), RoomNotify::All);
+
+        let ev = event("@bob:example.org", "m.text", "Alice Liddell, look");
+        assert_eq!(rules.evaluate(&ev, &context(5, None)), actions(false, false, None));
+    }
+
+    #[test]
+    fn mentions_only_room() {
+        let mut rules = default_rules();
+        rules.room.push(PushRule::mentions_only_room(ROOM));
+        assert_eq!(rules.room_notify(ROOM), RoomNotify::Mentions);
+
+        let ev = event("@bob:example.org", "m.text", "hello");
+        assert_eq!(rules.evaluate(&ev, &context(5, None)), actions(false, false, None));
+
+        let ev = event("@bob:example.org", "m.text", "hello Alice Liddell");
+        assert_eq!(rules.evaluate(&ev, &context(5, None)), actions(true, true, Some("default")));
+    }
+
+    #[test]
+    fn disabled_rules_are_skipped() {
+        let mut rules = default_rules();
+        for r in rules.underride.iter_mut() {
+            r.enabled = false;
+        }
+        let ev = event("@bob:example.org", "m.text", "hello");
+        assert_eq!(rules.evaluate(&ev, &context(5, None)), None);
+    }
+
+    #[test]
+    fn glob_patterns() {
+        let pt = Patterns::default();
+        assert!(glob_match(&pt, "m.room.*", "m.room.message", false));
+        assert!(!glob_match(&pt, "m.room.*", "xm.room.message", false));
+        assert!(glob_match(&pt, "m.notic?", "M.NOTICE", false));
+        assert!(!glob_match(&pt, "m.notice", "m.notices", false));
+
+        assert!(glob_match(&pt, "cake*lie", "The cake is a lie!", true));
+        assert!(!glob_match(&pt, "cake", "cakes", true));
+        assert!(word_match(&pt, &regex::escape("a.b (c)"), "so a.b (c) said"));
+        assert!(!word_match(&pt, &regex::escape("a.b"), "axb"));
+
+        // each pattern is compiled once
+        assert_eq!(pt.0.borrow().len(), 7);
+        assert!(glob_match(&pt, "m.room.*", "m.room.member", false));
+        assert_eq!(pt.0.borrow().len(), 7);
+    }
+
+    #[test]
+    fn member_count() {
+        assert!(member_count_match("2", 2));
+        assert!(member_count_match("==2", 2));
+        assert!(!member_count_match("==2", 3));
+        assert!(member_count_match("<10", 9));
+        assert!(!member_count_match("<10", 10));
+        assert!(member_count_match(">=3", 3));
+        assert!(member_count_match(">3", 4));
+        assert!(member_count_match("<=3", 3));
+        assert!(!member_count_match("~3", 3));
+        assert!(!member_count_match(">=", 3));
+    }
+
+    #[test]
+    fn sender_permission() {
+        let cond = PushCondition {
+            kind: "sender_notification_permission".to_string(),
+            key: Some("room".to_string()),
+            ..PushCondition::default()
+        };
+        let pt = Patterns::default();
+
+        // 50 is needed when the power levels don't say it
+        let levels = json!({"users": {"@mod:example.org": 50}, "users_default": 10});
+        let ctx = context(5, Some(&levels));
+        assert!(cond.matches(&event("@mod:example.org", "m.text", ""), &ctx, &pt));
+        assert!(!cond.matches(&event("@bob:example.org", "m.text", ""), &ctx, &pt));
+
+        let levels = json!({"users_default": 10, "notifications": {"room": 10}});
+        let ctx = context(5, Some(&levels));
+        assert!(cond.matches(&event("@bob:example.org", "m.text", ""), &ctx, &pt));
+    }
+
+    #[test]
+    fn actions_and_tweaks() {
+        let pa = PushRule::parse_actions(&[json!("notify"),
+                                           json!({"set_tweak": "sound", "value": "default"}),
+                                           json!({"set_tweak": "highlight"})]);
+        assert_eq!(pa, PushActions { notify: true, highlight: true, sound: Some("default".to_string()) });
+
+        let pa = PushRule::parse_actions(&[json!("notify"),
+                                           json!({"set_tweak": "highlight", "value": false})]);
+        assert_eq!(pa, PushActions { notify: true, highlight: false, sound: None });
+
+        assert_eq!(PushRule::parse_actions(&[json!("dont_notify")]), PushActions::default());
+        assert_eq!(PushRule::parse_actions(&[]), PushActions::default());
+    }
+}
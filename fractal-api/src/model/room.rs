@@ -1,4 +1,7 @@
+extern crate serde_json;
+
 use std::collections::HashMap;
+use self::serde_json::Value as JsonValue;
 use model::message::Message;
 use model::member::MemberList;
 
@@ -14,7 +17,19 @@ pub struct Room {
     pub n_members: i32,
     pub members: MemberList,
     pub notifications: i32,
+    // unread messages that mention the user, or highlighted by other push rules
+    #[serde(default)]
+    pub highlight: i32,
     pub messages: Vec<Message>,
+    // token to paginate backwards from the first message in messages
+    #[serde(default)]
+    pub prev_batch: Option<String>,
+    // m.tag tags of the room, like m.favourite, with the order of the room in the tag
+    #[serde(default)]
+    pub tags: HashMap<String, Option<f64>>,
+    // content of the m.room.power_levels event, for the push rules that check the sender
+    #[serde(default)]
+    pub power_levels: Option<JsonValue>,
 }
 
 impl Room {
@@ -29,8 +44,12 @@ impl Room {
             world_readable: true,
             n_members: 0,
             notifications: 0,
+            highlight: 0,
             messages: vec![],
             members: HashMap::new(),
+            prev_batch: None,
+            tags: HashMap::new(),
+            power_levels: None,
         }
     }
 }
@@ -47,8 +66,12 @@ impl Clone for Room {
             world_readable: self.world_readable,
             n_members: self.n_members,
             notifications: self.notifications,
+            highlight: self.highlight,
             messages: self.messages.iter().cloned().collect(),
             members: self.members.clone(),
+            prev_batch: self.prev_batch.clone(),
+            tags: self.tags.clone(),
+            power_levels: self.power_levels.clone(),
         }
     }
 }
@@ -0,0 +1,29 @@
+use model::message::Message;
+
+/// What the user is looking for, in one room or in all the rooms when @room is None
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchQuery {
+    pub term: String,
+    pub room: Option<String>,
+}
+
+/// A message that matches the search, with the messages around it in chronological order
+#[derive(Debug, Clone)]
+pub struct SearchResult {
+    pub message: Message,
+    pub rank: f64,
+    pub before: Vec<Message>,
+    pub after: Vec<Message>,
+}
+
+/// A page of search results, @next_batch is the token to get the next page
+#[derive(Debug, Clone)]
+pub struct SearchResults {
+    pub query: SearchQuery,
+    // approximate number of results, for all the pages
+    pub count: Option<u64>,
+    // words to highlight in the results, the server knows about stemming
+    pub highlights: Vec<String>,
+    pub results: Vec<SearchResult>,
+    pub next_batch: Option<String>,
+}
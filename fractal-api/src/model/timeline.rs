@@ -0,0 +1,151 @@
+use std::collections::HashSet;
+
+use model::message::Message;
+
+/// Direction of the pagination in a room timeline
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Backward,
+    Forward,
+}
+
+impl Direction {
+    pub fn as_param(&self) -> &'static str {
+        match *self {
+            Direction::Backward => "b",
+            Direction::Forward => "f",
+        }
+    }
+}
+
+/// A piece of the timeline of a room around @event, that isn't connected to the live
+/// timeline yet. @prev_batch and @next_batch are the tokens to paginate backwards and
+/// forwards, None when there's nothing more in that direction.
+#[derive(Debug, Clone)]
+pub struct TimelineChunk {
+    pub room: String,
+    pub event: String,
+    // in chronological order
+    pub messages: Vec<Message>,
+    pub prev_batch: Option<String>,
+    pub next_batch: Option<String>,
+}
+
+impl TimelineChunk {
+    /// Adds the older @msgs of a backwards page
+    pub fn prepend(&mut self, msgs: Vec<Message>, token: Option<String>) {
+        let mut msgs = msgs;
+        msgs.extend(self.messages.drain(..));
+        self.messages = msgs;
+        self.prev_batch = token;
+    }
+
+    /// Adds the newer @msgs of a forwards page
+    pub fn append(&mut self, msgs: Vec<Message>, token: Option<String>) {
+        self.messages.extend(msgs);
+        self.next_batch = token;
+    }
+
+    /// The @live timeline with the messages of the chunk that are older and newer than it,
+    /// if the chunk reaches any of the live messages. None while there's still a gap.
+    pub fn join(&self, live: &[Message]) -> Option<Vec<Message>> {
+        let ids: HashSet<&String> = live.iter().filter_map(|m| m.id.as_ref()).collect();
+        let pos = self.messages.iter()
+            .position(|m| m.id.as_ref().map(|id| ids.contains(id)).unwrap_or(false))?;
+        let first = self.messages[pos].id.clone();
+
+        // the chunk only has older messages if it overlaps the start of the live timeline
+        let mut joined = match live.iter().position(|m| m.id == first) {
+            Some(0) => self.messages[..pos].to_vec(),
+            _ => vec![],
+        };
+        joined.extend(live.iter().cloned());
+
+        // and newer ones if it has the end of the live timeline
+        let last = live.last().and_then(|m| m.id.as_ref())
+            .and_then(|id| self.messages.iter().position(|m| m.id.as_ref() == Some(id)));
+        if let Some(last) = last {
+            joined.extend(self.messages[last + 1..].iter().cloned());
+        }
+
+        Some(joined)
+    }
+
+    /// There's nothing newer than this chunk, so it's the live timeline itself
+    pub fn is_live(&self) -> bool {
+        self.next_batch.is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(id: Option<&str>) -> Message {
+        Message { id: id.map(String::from), ..Message::default() }
+    }
+
+    fn msgs(ids: &[&str]) -> Vec<Message> {
+        ids.iter().map(|id| msg(Some(id))).collect()
+    }
+
+    fn chunk(messages: Vec<Message>) -> TimelineChunk {
+        TimelineChunk {
+            room: String::from("!room:example.org"),
+            event: String::from("$c1"),
+            messages: messages,
+            prev_batch: None,
+            next_batch: Some(String::from("next")),
+        }
+    }
+
+    fn ids(joined: Option<Vec<Message>>) -> Option<Vec<String>> {
+        joined.map(|j| j.into_iter().map(|m| m.id.unwrap_or_default()).collect())
+    }
+
+    #[test]
+    fn join_without_overlap() {
+        let c = chunk(msgs(&["$c1", "$c2"]));
+        assert_eq!(ids(c.join(&msgs(&["$l1", "$l2"]))), None);
+        assert_eq!(ids(c.join(&[])), None);
+    }
+
+    #[test]
+    fn join_at_the_start_of_live() {
+        let c = chunk(msgs(&["$c1", "$c2", "$l1"]));
+        assert_eq!(ids(c.join(&msgs(&["$l1", "$l2"]))),
+                   Some(vec!["$c1".to_string(), "$c2".to_string(), "$l1".to_string(), "$l2".to_string()]));
+    }
+
+    #[test]
+    fn join_in_the_middle_of_live() {
+        // the live timeline has the older messages already
+        let c = chunk(msgs(&["$l2", "$l3"]));
+        assert_eq!(ids(c.join(&msgs(&["$l1", "$l2", "$l3", "$l4"]))),
+                   Some(vec!["$l1".to_string(), "$l2".to_string(), "$l3".to_string(), "$l4".to_string()]));
+    }
+
+    #[test]
+    fn join_past_the_end_of_live() {
+        let c = chunk(msgs(&["$c1", "$l1", "$l2", "$n1"]));
+        assert_eq!(ids(c.join(&msgs(&["$l1", "$l2"]))),
+                   Some(vec!["$c1".to_string(), "$l1".to_string(), "$l2".to_string(), "$n1".to_string()]));
+
+        // the chunk covers the whole live timeline
+        let c = chunk(msgs(&["$l1", "$l2", "$n1"]));
+        assert_eq!(ids(c.join(&msgs(&["$l1", "$l2"]))),
+                   Some(vec!["$l1".to_string(), "$l2".to_string(), "$n1".to_string()]));
+    }
+
+    #[test]
+    fn join_messages_without_ids() {
+        // they never overlap
+        let c = chunk(vec![msg(None), msg(Some("$c1"))]);
+        assert_eq!(ids(c.join(&[msg(None)])), None);
+
+        let c = chunk(vec![msg(None), msg(Some("$c1")), msg(Some("$l1"))]);
+        let live = vec![msg(Some("$l1")), msg(None)];
+        assert_eq!(ids(c.join(&live)),
+                   Some(vec!["".to_string(), "$c1".to_string(), "$l1".to_string(), "".to_string()]));
+    }
+}
@@ -0,0 +1,18 @@
+/// OpenGraph data of a link, as returned by the homeserver preview_url endpoint
+#[derive(Debug, Clone, Default)]
+#[derive(Serialize, Deserialize)]
+pub struct UrlPreview {
+    pub url: String,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub site_name: Option<String>,
+    // mxc url of the image
+    pub image: Option<String>,
+}
+
+impl UrlPreview {
+    /// A preview without title or description doesn't have anything to show
+    pub fn is_empty(&self) -> bool {
+        self.title.is_none() && self.description.is_none()
+    }
+}
@@ -3,5 +3,21 @@ pub use model::room::Room;
 pub use model::room::RoomList;
 pub use model::protocol::Protocol;
 pub use model::message::Message;
+pub use model::message::MediaInfo;
+pub use model::message::ThumbnailInfo;
 pub use model::member::Member;
+pub use model::urlpreview::UrlPreview;
 pub use model::member::MemberList;
+pub use model::pushrules::Ruleset;
+pub use model::pushrules::PushRule;
+pub use model::pushrules::PushCondition;
+pub use model::pushrules::PushActions;
+pub use model::pushrules::PushContext;
+pub use model::pushrules::RoomNotify;
+pub use model::search::SearchQuery;
+pub use model::search::SearchResult;
+pub use model::search::SearchResults;
+pub use model::timeline::Direction;
+pub use model::timeline::TimelineChunk;
+pub use model::directory::DirectoryQuery;
+pub use model::directory::DirectoryPage;
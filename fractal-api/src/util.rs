@@ -22,34 +22,51 @@ use self::regex::Regex;
 use self::serde_json::Value as JsonValue;
 
 use self::url::Url;
+use std::io;
 use std::io::Read;
 use std::path::Path;
 use std::path::PathBuf;
 
+use std::fs;
 use std::fs::File;
+use std::fs::OpenOptions;
 use std::fs::create_dir_all;
 use std::io::prelude::*;
 
+use std::collections::HashMap;
+use std::collections::HashSet;
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 use self::chrono::prelude::*;
 use self::time::Duration;
 use std::time::Duration as StdDuration;
 
 use error::Error;
+use error::MatrixError;
 use types::Message;
 use types::Room;
 use types::Event;
 use types::Member;
+use types::UrlPreview;
+use types::Ruleset;
+use types::Direction;
 
 use self::reqwest::header::ContentType;
+use self::reqwest::header::ContentLength;
+use self::reqwest::header::{Range, ByteRangeSpec};
+use self::reqwest::StatusCode;
 use self::mime::Mime;
 
 use globals;
+use mediacache;
+use mediacache::Bucket;
 
 
 #[allow(dead_code)]
+#[derive(Clone, Copy)]
 pub enum AvatarMode {
     Rect,
     Circle,
@@ -58,7 +75,12 @@ pub enum AvatarMode {
 
 #[macro_export]
 macro_rules! identicon {
-    ($userid: expr, $name: expr) => { draw_identicon($userid, $name, AvatarMode::Circle) }
+    ($userid: expr, $name: expr) => {
+        draw_identicon($userid, $name, AvatarMode::Circle, $crate::util::avatar_pixels($crate::globals::AVATAR_SIZE))
+    };
+    ($userid: expr, $name: expr, $size: expr) => {
+        draw_identicon($userid, $name, AvatarMode::Circle, $size)
+    };
 }
 
 
@@ -178,7 +200,10 @@ macro_rules! media {
 #[macro_export]
 macro_rules! thumb {
     ($base: expr, $url: expr) => {
-        dw_media($base, $url, true, None, 64, 64)
+        {
+            let size = $crate::util::avatar_pixels($crate::globals::AVATAR_SIZE);
+            dw_media($base, $url, true, None, size, size)
+        }
     };
     ($base: expr, $url: expr, $size: expr) => {
         dw_media($base, $url, true, None, $size, $size)
@@ -199,7 +224,7 @@ pub fn evc(events: &JsonValue, t: &str, field: &str) -> String {
     String::new()
 }
 
-pub fn get_rooms_from_json(r: JsonValue, userid: &str, baseu: &Url) -> Result<Vec<Room>, Error> {
+pub fn get_rooms_from_json(r: JsonValue, userid: &str) -> Result<Vec<Room>, Error> {
     let rooms = &r["rooms"];
     // TODO: do something with invite and leave
     //let invite = rooms["invite"].as_object().ok_or(Error::BackendError)?;
@@ -208,43 +233,103 @@ pub fn get_rooms_from_json(r: JsonValue, userid: &str, baseu: &Url) -> Result<Ve
     let join = rooms["join"].as_object().ok_or(Error::BackendError)?;
 
     let mut rooms: Vec<Room> = vec![];
-    for k in join.keys() {
-        let room = join.get(k).ok_or(Error::BackendError)?;
-        let stevents = &room["state"]["events"];
-        let timeline = &room["timeline"];
-        let name = calculate_room_name(stevents, userid)?;
-        let mut r = Room::new(k.clone(), Some(name));
-
-        r.avatar = Some(evc(stevents, "m.room.avatar", "url"));
-        r.alias = Some(evc(stevents, "m.room.canonical_alias", "alias"));
-        r.topic = Some(evc(stevents, "m.room.topic", "topic"));
-        r.notifications = room["unread_notifications"]["notification_count"]
-            .as_i64()
-            .unwrap_or(0) as i32;
-
-        for ev in timeline["events"].as_array().unwrap_or(&vec![]) {
-            let msg = parse_room_message(baseu, k.clone(), ev);
-            r.messages.push(msg);
-        }
+    for (k, room) in join.iter() {
+        rooms.push(parse_joined_room(k, room, userid)?);
+    }
 
-        let mevents = stevents.as_array().unwrap()
-            .iter()
-            .filter(|x| x["type"] == "m.room.member");
+    Ok(rooms)
+}
 
-        for ev in mevents {
-            let member = parse_room_member(ev);
-            if let Some(m) = member {
-                r.members.insert(m.uid.clone(), m.clone());
-            }
-        }
+/// Returns the rooms joined since the last sync, the ones in @r that aren't in @known. The
+/// messages of these rooms aren't here, they come with the rest of the timeline.
+pub fn get_new_rooms_from_json(r: &JsonValue, userid: &str, known: &HashSet<String>) -> Result<Vec<Room>, Error> {
+    let join = r["rooms"]["join"].as_object().ok_or(Error::BackendError)?;
 
+    let mut rooms: Vec<Room> = vec![];
+    for (k, room) in join.iter().filter(|&(k, _)| !known.contains(k)) {
+        let mut r = parse_joined_room(k, room, userid)?;
+        r.messages.clear();
         rooms.push(r);
     }
 
     Ok(rooms)
 }
 
-pub fn get_rooms_timeline_from_json(baseu: &Url, r: &JsonValue) -> Result<Vec<Message>, Error> {
+/// Returns the ids of the rooms left since the last sync
+pub fn get_left_rooms_from_json(r: &JsonValue) -> Vec<String> {
+    match r["rooms"]["leave"].as_object() {
+        Some(leave) => leave.keys().cloned().collect(),
+        None => vec![],
+    }
+}
+
+fn parse_joined_room(k: &str, room: &JsonValue, userid: &str) -> Result<Room, Error> {
+    let stevents = &room["state"]["events"];
+    let timeline = &room["timeline"];
+    let name = calculate_room_name(stevents, userid)?;
+    let mut r = Room::new(String::from(k), Some(name));
+
+    r.avatar = Some(evc(stevents, "m.room.avatar", "url"));
+    r.alias = Some(evc(stevents, "m.room.canonical_alias", "alias"));
+    r.topic = Some(evc(stevents, "m.room.topic", "topic"));
+    r.power_levels = stevents.as_array()
+        .and_then(|evs| evs.iter().find(|x| x["type"] == "m.room.power_levels"))
+        .map(|ev| ev["content"].clone());
+    let (n, h) = parse_unread(room);
+    r.notifications = n;
+    r.highlight = h;
+    r.prev_batch = timeline["prev_batch"].as_str().map(String::from);
+    if let Some(tags) = parse_room_tags(&room["account_data"]) {
+        r.tags = tags;
+    }
+
+    for ev in timeline["events"].as_array().unwrap_or(&vec![]) {
+        let msg = parse_room_message(String::from(k), ev);
+        r.messages.push(msg);
+    }
+
+    let mevents = stevents.as_array().ok_or(Error::BackendError)?
+        .iter()
+        .filter(|x| x["type"] == "m.room.member");
+
+    for ev in mevents {
+        let member = parse_room_member(ev);
+        if let Some(m) = member {
+            r.members.insert(m.uid.clone(), m.clone());
+        }
+    }
+
+    Ok(r)
+}
+
+/// The room of an initialSync response, for the rooms that we can see without joining
+pub fn get_room_preview_from_json(roomid: &str, r: &JsonValue) -> Room {
+    let stevents = &r["state"];
+    let name = evc(stevents, "m.room.name", "name");
+    let mut room = Room::new(String::from(roomid), Some(name));
+
+    room.avatar = Some(evc(stevents, "m.room.avatar", "url"));
+    room.alias = Some(evc(stevents, "m.room.canonical_alias", "alias"));
+    room.topic = Some(evc(stevents, "m.room.topic", "topic"));
+    room.prev_batch = r["messages"]["start"].as_str().map(String::from);
+
+    for ev in stevents.as_array().unwrap_or(&vec![]).iter().filter(|x| x["type"] == "m.room.member") {
+        if let Some(m) = parse_room_member(ev) {
+            room.members.insert(m.uid.clone(), m);
+        }
+    }
+    room.n_members = room.members.len() as i32;
+
+    for ev in r["messages"]["chunk"].as_array().unwrap_or(&vec![]) {
+        if ev["type"].as_str().unwrap_or("") == "m.room.message" {
+            room.messages.push(parse_room_message(String::from(roomid), ev));
+        }
+    }
+
+    room
+}
+
+pub fn get_rooms_timeline_from_json(r: &JsonValue) -> Result<Vec<Message>, Error> {
     let rooms = &r["rooms"];
     let join = rooms["join"].as_object().ok_or(Error::BackendError)?;
 
@@ -261,7 +346,7 @@ pub fn get_rooms_timeline_from_json(baseu: &Url, r: &JsonValue) -> Result<Vec<Me
             .filter(|x| x["type"] == "m.room.message");
 
         for ev in events {
-            let msg = parse_room_message(baseu, k.clone(), ev);
+            let msg = parse_room_message(k.clone(), ev);
             msgs.push(msg);
         }
     }
@@ -269,6 +354,79 @@ pub fn get_rooms_timeline_from_json(baseu: &Url, r: &JsonValue) -> Result<Vec<Me
     Ok(msgs)
 }
 
+/// Returns the rooms with a limited timeline in this sync, with the prev_batch token. There
+/// can be a gap between the messages that we've and the ones in this sync for these rooms.
+pub fn get_rooms_limited_from_json(r: &JsonValue) -> Result<Vec<(String, String)>, Error> {
+    let join = r["rooms"]["join"].as_object().ok_or(Error::BackendError)?;
+
+    let mut limited = vec![];
+    for (k, room) in join.iter() {
+        let timeline = &room["timeline"];
+        if timeline["limited"].as_bool().unwrap_or(false) {
+            let prev_batch = String::from(timeline["prev_batch"].as_str().unwrap_or(""));
+            limited.push((k.clone(), prev_batch));
+        }
+    }
+
+    Ok(limited)
+}
+
+/// Returns the tags in the m.tag event of the room @account_data, with the order of the room
+/// in each tag, or None if the tags didn't change
+pub fn parse_room_tags(account_data: &JsonValue) -> Option<HashMap<String, Option<f64>>> {
+    let events = account_data["events"].as_array()?;
+    let ev = events.iter().filter(|x| x["type"] == "m.tag").last()?;
+    let tags = ev["content"]["tags"].as_object()?;
+
+    Some(tags.iter()
+         .map(|(k, v)| (k.clone(), v["order"].as_f64()))
+         .collect())
+}
+
+/// Returns the rooms with new tags in this sync
+pub fn get_rooms_tags_from_json(r: &JsonValue) -> Result<Vec<(String, HashMap<String, Option<f64>>)>, Error> {
+    let join = r["rooms"]["join"].as_object().ok_or(Error::BackendError)?;
+
+    let mut tags = vec![];
+    for (k, room) in join.iter() {
+        if let Some(t) = parse_room_tags(&room["account_data"]) {
+            tags.push((k.clone(), t));
+        }
+    }
+
+    Ok(tags)
+}
+
+fn parse_unread(room: &JsonValue) -> (i32, i32) {
+    let unread = &room["unread_notifications"];
+    let n = unread["notification_count"].as_i64().unwrap_or(0) as i32;
+    let h = unread["highlight_count"].as_i64().unwrap_or(0) as i32;
+
+    (n, h)
+}
+
+/// Returns the unread notifications and highlights of the rooms that have the counts in
+/// this sync, the server only sends them when they change
+pub fn get_rooms_unread_from_json(r: &JsonValue) -> Result<Vec<(String, i32, i32)>, Error> {
+    let join = r["rooms"]["join"].as_object().ok_or(Error::BackendError)?;
+
+    let mut unread = vec![];
+    for (k, room) in join.iter().filter(|&(_, room)| room["unread_notifications"].is_object()) {
+        let (n, h) = parse_unread(room);
+        unread.push((k.clone(), n, h));
+    }
+
+    Ok(unread)
+}
+
+/// Returns the global push rules of the user, if they're in the account data of the sync
+pub fn get_push_rules_from_json(r: &JsonValue) -> Option<Ruleset> {
+    let events = r["account_data"]["events"].as_array()?;
+    let ev = events.iter().rev().find(|x| x["type"] == "m.push_rules")?;
+
+    serde_json::from_value(ev["content"]["global"].clone()).ok()
+}
+
 pub fn parse_sync_events(r: &JsonValue) -> Result<Vec<Event>, Error> {
     let rooms = &r["rooms"];
     let join = rooms["join"].as_object().ok_or(Error::BackendError)?;
@@ -305,12 +463,131 @@ pub fn get_media(url: &str) -> Result<Vec<u8>, Error> {
     let mut conn = client.get(url);
     let mut res = conn.send()?;
 
+    if !res.status().is_success() {
+        return Err(response_error(&mut res));
+    }
+
     let mut buffer = Vec::new();
     res.read_to_end(&mut buffer)?;
 
     Ok(buffer)
 }
 
+/// Downloads the @url to the @fname file, reading the response in chunks and calling
+/// @progress with the downloaded bytes and the total size, 0 if it's unknown.
+///
+/// The data is written to "@fname.part" and renamed when it's complete. If the partial file
+/// is there the download continues from the end of it, so a failed or cancelled download
+/// can be resumed. Setting @cancel stops the download with `Error::Cancelled`.
+pub fn download_file<F>(url: &str, fname: &str, cancel: &AtomicBool, mut progress: F) -> Result<(), Error>
+    where F: FnMut(u64, u64) {
+
+    let tmp = format!("{}.part", fname);
+    let mut offset = fs::metadata(&tmp).map(|m| m.len()).unwrap_or(0);
+
+    let client = reqwest::Client::new();
+    let mut conn = client.get(url);
+    if offset > 0 {
+        conn.header(Range::Bytes(vec![ByteRangeSpec::AllFrom(offset)]));
+    }
+    let mut res = conn.send()?;
+
+    match res.status() {
+        StatusCode::PartialContent => {}
+        StatusCode::RangeNotSatisfiable => {
+            // the partial file isn't valid for this media, starting again
+            fs::remove_file(&tmp)?;
+            return download_file(url, fname, cancel, progress);
+        }
+        s if s.is_success() => {
+            // the server doesn't support ranges, the whole file is coming
+            offset = 0;
+        }
+        _ => {
+            return Err(response_error(&mut res));
+        }
+    };
+
+    let total = match res.headers().get::<ContentLength>() {
+        Some(&ContentLength(len)) => len + offset,
+        None => 0,
+    };
+
+    let mut file = match offset {
+        0 => File::create(&tmp)?,
+        _ => OpenOptions::new().append(true).open(&tmp)?,
+    };
+
+    let mut bytes = offset;
+    let mut buffer = [0; 64 * 1024];
+    progress(bytes, total);
+    loop {
+        if cancel.load(Ordering::SeqCst) {
+            return Err(Error::Cancelled);
+        }
+
+        let n = res.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+
+        file.write_all(&buffer[..n])?;
+        bytes += n as u64;
+        progress(bytes, total);
+    }
+
+    fs::rename(&tmp, fname)?;
+
+    Ok(())
+}
+
+/// Returns the path of the full media file for the mxc @url, from the media cache or
+/// downloading it with `download_file`.
+pub fn get_media_file<F>(base: &Url, url: &str, cancel: &AtomicBool, progress: F) -> Result<String, Error>
+    where F: FnMut(u64, u64) {
+
+    let re = Regex::new(r"mxc://(?P<server>[^/]+)/(?P<media>.+)")?;
+    let caps = re.captures(url).ok_or(Error::BackendError)?;
+    let server = String::from(&caps["server"]);
+    let media = String::from(&caps["media"]);
+
+    let key = mediacache::media_key(Bucket::Full, &server, &media, 0, 0);
+    if let Some(fname) = mediacache::lookup(&key) {
+        return Ok(fname);
+    }
+
+    let path = format!("download/{}/{}", server, media);
+    let url = media_url!(base, &path, vec![])?;
+
+    download_file(url.as_str(), &mediacache::path(&key)?, cancel, progress)?;
+    mediacache::add(&key)
+}
+
+/// Asks the homeserver for the OpenGraph data of the link @url, as it was at the time @ts in
+/// milliseconds
+pub fn get_url_preview(base: &Url, tk: &str, url: &str, ts: i64) -> Result<UrlPreview, Error> {
+    let params = vec![
+        ("url", strn!(url)),
+        ("ts", format!("{}", ts)),
+        ("access_token", strn!(tk)),
+    ];
+    let u = media_url!(base, "preview_url", params)?;
+    let js = json_q("get", &u, &json!(null), globals::TIMEOUT)?;
+
+    let field = |k: &str| match js[k].as_str().map(|s| s.trim()) {
+        Some(s) if !s.is_empty() => Some(strn!(s)),
+        _ => None,
+    };
+
+    Ok(UrlPreview {
+        url: strn!(url),
+        title: field("og:title"),
+        description: field("og:description"),
+        site_name: field("og:site_name"),
+        image: field("og:image").and_then(|i| if i.starts_with("mxc://") { Some(i) } else { None }),
+    })
+}
+
 pub fn put_media(url: &str, file: Vec<u8>) -> Result<JsonValue, Error> {
     let client = reqwest::Client::new();
     let mut conn = client.post(url);
@@ -322,9 +599,93 @@ pub fn put_media(url: &str, file: Vec<u8>) -> Result<JsonValue, Error> {
 
     let mut res = conn.send()?;
 
-    match res.json() {
-        Ok(js) => Ok(js),
-        Err(_) => Err(Error::BackendError),
+    if !res.status().is_success() {
+        return Err(response_error(&mut res));
+    }
+
+    Ok(res.json()?)
+}
+
+/// Reader that reports the bytes read so far, and fails if the @cancel flag is set, so a
+/// file can be streamed in a request body.
+struct ProgressReader<R, F> {
+    inner: R,
+    bytes: u64,
+    cancel: Arc<AtomicBool>,
+    progress: F,
+}
+
+impl<R: Read, F: FnMut(u64)> Read for ProgressReader<R, F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.cancel.load(Ordering::SeqCst) {
+            return Err(io::Error::new(io::ErrorKind::Other, "cancelled"));
+        }
+
+        let n = self.inner.read(buf)?;
+        self.bytes += n as u64;
+        let bytes = self.bytes;
+        (self.progress)(bytes);
+
+        Ok(n)
+    }
+}
+
+/// Uploads the file in @path to the media repository @url, streaming it from the disk,
+/// and returns the content uri. @progress is called with the sent bytes and the file size.
+pub fn upload_file<F>(url: &Url, path: &str, cancel: Arc<AtomicBool>, mut progress: F) -> Result<String, Error>
+    where F: FnMut(u64, u64) + Send + 'static {
+
+    let file = File::open(path)?;
+    let total = file.metadata()?.len();
+    let mime: Mime = tree_magic::from_filepath(Path::new(path)).parse()
+        .unwrap_or(mime::APPLICATION_OCTET_STREAM);
+
+    let reader = ProgressReader {
+        inner: file,
+        bytes: 0,
+        cancel: cancel.clone(),
+        progress: move |bytes| progress(bytes, total),
+    };
+
+    let client = reqwest::Client::new();
+    let mut conn = client.post(url.as_str());
+    conn.body(reqwest::Body::sized(reader, total));
+    conn.header(ContentType(mime));
+
+    let res = conn.send();
+    if cancel.load(Ordering::SeqCst) {
+        return Err(Error::Cancelled);
+    }
+
+    let mut res = res?;
+    if !res.status().is_success() {
+        return Err(response_error(&mut res));
+    }
+
+    let js: JsonValue = res.json()?;
+    match js["content_uri"].as_str() {
+        Some(uri) => Ok(String::from(uri)),
+        None => Err(Error::BackendError),
+    }
+}
+
+/// Returns the maximum upload size allowed by the server, None if there's no limit.
+pub fn get_upload_limit(base: &Url, tk: &str) -> Result<Option<u64>, Error> {
+    let url = media_url!(base, "config", vec![("access_token", strn!(tk))])?;
+    let js = json_q("get", &url, &json!(null), globals::TIMEOUT)?;
+
+    Ok(js["m.upload.size"].as_u64())
+}
+
+/// Builds the error for a failed request, using the Matrix error in the body if there's one.
+fn response_error(res: &mut reqwest::Response) -> Error {
+    let status = res.status();
+    match res.json::<JsonValue>() {
+        Ok(js) => Error::MatrixError(MatrixError::new(status.as_u16(), js)),
+        Err(_) => {
+            let reason = status.canonical_reason().unwrap_or("Unknown error");
+            Error::HttpError(status.as_u16(), String::from(reason))
+        }
     }
 }
 
@@ -355,17 +716,25 @@ pub fn dw_media(base: &Url,
     let url = media_url!(base, &path, params)?;
 
     let fname = match dest {
-        None => { cache_path(&media)?  }
+        None => {
+            let bucket = match thumb {
+                true => Bucket::Thumb,
+                false => Bucket::Full,
+            };
+            let key = mediacache::media_key(bucket, &server, &media, w, h);
+            if let Some(fname) = mediacache::lookup(&key) {
+                return Ok(fname);
+            }
+
+            let buffer = get_media(url.as_str())?;
+            return mediacache::insert(&key, &buffer);
+        }
         Some(d) => String::from(d),
     };
 
     let pathname = fname.clone();
     let p = Path::new(&pathname);
     if p.is_file() {
-        if dest.is_none() {
-            return Ok(fname);
-        }
-
         let moddate = p.metadata()?.modified()?;
         // one minute cached
         if moddate.elapsed()?.as_secs() < 60 {
@@ -373,13 +742,23 @@ pub fn dw_media(base: &Url,
         }
     }
 
-    let mut file = File::create(&fname)?;
     let buffer = get_media(url.as_str())?;
+    let mut file = File::create(&fname)?;
     file.write_all(&buffer)?;
 
     Ok(fname)
 }
 
+/// Returns a new transaction id to send an event. The timestamp keeps it unique between
+/// restarts and the counter between events created at the same time.
+pub fn new_txn_id() -> String {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let n = COUNTER.fetch_add(1, Ordering::SeqCst);
+    let now = Local::now();
+    format!("{}{:09}.{}", now.timestamp(), now.timestamp_subsec_nanos(), n)
+}
+
 pub fn age_to_datetime(age: i64) -> DateTime<Local> {
     let now = Local::now();
     let diff = Duration::seconds(age / 1000);
@@ -408,26 +787,15 @@ pub fn json_q(method: &str, url: &Url, attrs: &JsonValue, timeout: u64) -> Resul
     //cb(content);
 
     if !res.status().is_success() {
-        return match res.json() {
-            Ok(js) => Err(Error::MatrixError(js)),
-            Err(err) => Err(Error::ReqwestError(err))
-        }
+        return Err(response_error(&mut res));
     }
 
-    let json: Result<JsonValue, reqwest::Error> = res.json();
-    match json {
-        Ok(js) => {
-            let js2 = js.clone();
-            if let Some(error) = js.as_object() {
-                if error.contains_key("errcode") {
-                    println!("ERROR: {:#?}", js2);
-                    return Err(Error::MatrixError(js2));
-                }
-            }
-            Ok(js)
-        }
-        Err(_) => Err(Error::BackendError),
+    let js: JsonValue = res.json()?;
+    if js["errcode"].is_string() {
+        return Err(Error::MatrixError(MatrixError::new(0, js)));
     }
+
+    Ok(js)
 }
 
 pub fn get_user_avatar(baseu: &Url, userid: &str) -> Result<(String, String), Error> {
@@ -439,8 +807,7 @@ pub fn get_user_avatar(baseu: &Url, userid: &str) -> Result<(String, String), Er
             let name = String::from(js["displayname"].as_str().unwrap_or("@"));
             match js["avatar_url"].as_str() {
                 Some(url) => {
-                    let dest = cache_path(userid)?;
-                    let img = dw_media(baseu, &url, true, Some(&dest), 64, 64)?;
+                    let img = thumb!(baseu, &url)?;
                     Ok((name.clone(), img))
                 },
                 None => Ok((name.clone(), identicon!(userid, name)?)),
@@ -488,63 +855,111 @@ pub fn get_room_avatar(base: &Url, tk: &str, userid: &str, roomid: &str) -> Resu
     Ok(fname)
 }
 
-struct Color {
-    r: i32,
-    g: i32,
-    b: i32,
-}
-
 pub fn calculate_hash<T: Hash>(t: &T) -> u64 {
     let mut s = DefaultHasher::new();
     t.hash(&mut s);
     s.finish()
 }
 
-pub fn draw_identicon(fname: &str, name: String, mode: AvatarMode) -> Result<String, Error> {
-    let colors = vec![
-        Color { r: 69,  g: 189, b: 243, },
-        Color { r: 224, g: 143, b: 112, },
-        Color { r: 77,  g: 182, b: 172, },
-        Color { r: 149, g: 117, b: 205, },
-        Color { r: 176, g: 133, b: 94,  },
-        Color { r: 240, g: 98,  b: 146, },
-        Color { r: 163, g: 211, b: 108, },
-        Color { r: 121, g: 134, b: 203, },
-        Color { r: 241, g: 185, b: 29,  },
-    ];
+/// Sets the scale factor of the window, used to download and draw the avatars with the
+/// real pixel size of the screen
+pub fn set_scale_factor(factor: i32) {
+    SCALE_FACTOR.store(factor.max(1) as usize, Ordering::SeqCst);
+}
 
-    let fname = cache_path(fname)?;
+pub fn scale_factor() -> i32 {
+    SCALE_FACTOR.load(Ordering::SeqCst) as i32
+}
 
-    let image = cairo::ImageSurface::create(cairo::Format::ARgb32, 40, 40)?;
-    let g = cairo::Context::new(&image);
+/// Returns the size in pixels of an avatar of @size logical pixels
+pub fn avatar_pixels(size: i32) -> i32 {
+    size * scale_factor()
+}
 
-    let c = &colors[calculate_hash(&fname) as usize % colors.len() as usize];
-    g.set_source_rgba(c.r as f64 / 256., c.g as f64 / 256., c.b as f64 / 256., 1.);
+static SCALE_FACTOR: AtomicUsize = AtomicUsize::new(1);
 
-    match mode {
-        AvatarMode::Rect => g.rectangle(0., 0., 40., 40.),
-        AvatarMode::Circle => {
-            g.arc(20.0, 20.0, 20.0, 0.0, 2.0 * 3.14159);
-            g.fill();
-        }
-    };
+/// Returns the identicon colour for the user or room @id as rgb values between 0 and 1.
+///
+/// This is the hash and the colours used by Riot for the user names and the avatars, so the
+/// same id gets the same colour in every client.
+pub fn identicon_color(id: &str) -> (f64, f64, f64) {
+    let colors: [u32; 8] = [
+        0x368bd6,
+        0xac3ba8,
+        0x03b381,
+        0xe64f7a,
+        0xff812d,
+        0x2dc2c5,
+        0x5c56f5,
+        0x74d12c,
+    ];
 
-    g.set_font_size(24.);
-    g.set_source_rgb(1.0, 1.0, 1.0);
+    let mut hash: i32 = 0;
+    for c in id.encode_utf16() {
+        hash = (hash << 5).wrapping_sub(hash).wrapping_add(c as i32);
+    }
 
+    let c = colors[((hash as i64).abs() % colors.len() as i64) as usize];
+    (((c >> 16) & 0xff) as f64 / 255.,
+     ((c >> 8) & 0xff) as f64 / 255.,
+     (c & 0xff) as f64 / 255.)
+}
+
+/// Returns the letter shown in the identicon for @name, skipping the room or user sigil
+fn identicon_letter(name: &str) -> String {
     let name = name.to_uppercase();
     let graphs = UnicodeSegmentation::graphemes(name.as_str(), true).collect::<Vec<&str>>();
 
-    let first = match graphs.get(0) {
+    match graphs.get(0) {
         Some(f) if *f == "#" && graphs.len() > 1 => graphs.get(1).unwrap().to_string(),
         Some(f) if *f == "@" && graphs.len() > 1 => graphs.get(1).unwrap().to_string(),
         Some(n) => n.to_string(),
         None => String::from("X"),
+    }
+}
+
+/// Returns the cache path of the @size pixels identicon for @id with the letter of @name,
+/// drawn with the @mode shape. There's one file per size and shape, so each widget can use
+/// the one that it needs.
+pub fn identicon_path(id: &str, name: &str, mode: AvatarMode, size: i32) -> Result<String, Error> {
+    let letter = identicon_letter(name);
+    let shape = match mode {
+        AvatarMode::Rect => "rect",
+        AvatarMode::Circle => "circle",
+    };
+    cache_path(&format!("identicon-{:x}-{}-{}.png", calculate_hash(&(id, letter)), shape, size))
+}
+
+/// Draws the identicon of @size pixels for the user or room @id, with the first letter of
+/// @name, and returns its path. The identicon is drawn only once for each size.
+pub fn draw_identicon(id: &str, name: String, mode: AvatarMode, size: i32) -> Result<String, Error> {
+    use std::f64::consts::PI;
+
+    let fname = identicon_path(id, &name, mode, size)?;
+    if Path::new(&fname).is_file() {
+        return Ok(fname);
+    }
+
+    let image = cairo::ImageSurface::create(cairo::Format::ARgb32, size, size)?;
+    let g = cairo::Context::new(&image);
+    let s = size as f64;
+
+    let (r, gr, b) = identicon_color(id);
+    g.set_source_rgba(r, gr, b, 1.);
+
+    match mode {
+        AvatarMode::Rect => g.rectangle(0., 0., s, s),
+        AvatarMode::Circle => g.arc(s / 2.0, s / 2.0, s / 2.0, 0.0, 2.0 * PI),
     };
+    g.fill();
 
+    g.set_font_size(s * 0.6);
+    g.set_source_rgb(1.0, 1.0, 1.0);
+
+    let first = identicon_letter(&name);
     let te = g.text_extents(&first);
-    g.move_to(20.0 - te.x_bearing - te.width / 2.0,
-              20.0 + te.height / 2.0);
+    g.move_to(s / 2.0 - te.x_bearing - te.width / 2.0,
+              s / 2.0 - te.y_bearing - te.height / 2.0);
     g.show_text(&first);
 
     let mut buffer = File::create(&fname)?;
@@ -598,7 +1013,7 @@ pub fn calculate_room_name(roomst: &JsonValue, userid: &str) -> Result<String, E
     Ok(name)
 }
 
-pub fn parse_room_message(baseu: &Url, roomid: String, msg: &JsonValue) -> Message {
+pub fn parse_room_message(roomid: String, msg: &JsonValue) -> Message {
     let sender = msg["sender"].as_str().unwrap_or("");
     let mut age = msg["age"].as_i64().unwrap_or(0);
     if age == 0 {
@@ -606,22 +1021,23 @@ pub fn parse_room_message(baseu: &Url, roomid: String, msg: &JsonValue) -> Messa
     }
 
     let id = msg["event_id"].as_str().unwrap_or("");
+    let txnid = msg["unsigned"]["transaction_id"].as_str().map(String::from);
 
     let c = &msg["content"];
     let mtype = c["msgtype"].as_str().unwrap_or("");
     let body = c["body"].as_str().unwrap_or("");
 
+    // media isn't downloaded here, the url and thumb are the mxc urls and the UI asks for
+    // the thumbnails when it shows the message
     let mut url = String::new();
     let mut thumb = String::new();
+    let mut info = None;
 
     match mtype {
         "m.image" | "m.file" | "m.video" | "m.audio" => {
+            info = serde_json::from_value(c["info"].clone()).ok();
             url = String::from(c["url"].as_str().unwrap_or(""));
-            let mut t = String::from(c["info"]["thumbnail_url"].as_str().unwrap_or(""));
-            if t.is_empty() && !url.is_empty() {
-                t = url.clone();
-            }
-            thumb = media!(baseu, &t).unwrap_or(String::from(""));
+            thumb = String::from(c["info"]["thumbnail_url"].as_str().unwrap_or(""));
         }
         _ => {}
     };
@@ -635,69 +1051,77 @@ pub fn parse_room_message(baseu: &Url, roomid: String, msg: &JsonValue) -> Messa
         url: Some(url),
         thumb: Some(thumb),
         id: Some(String::from(id)),
+        txnid: txnid,
+        info: info,
     }
 }
 
-/// Recursive function that tries to get at least @get Messages for the room.
+/// Gets a page of messages for the room, going backwards from the @from token, or from the
+/// last message if there's no token.
 ///
-/// The @limit is the first "limit" param in the GET request.
-/// The @end param is used as "from" param in the GET request, so we'll get
-/// messages before that.
-pub fn get_initial_room_messages(baseu: &Url,
-                                 tk: String,
-                                 roomid: String,
-                                 get: usize,
-                                 limit: i32,
-                                 end: Option<String>)
-                                 -> Result<(Vec<Message>, String, String), Error> {
+/// Returns the messages in chronological order and the token to get the previous page.
+pub fn get_room_messages_page(baseu: &Url,
+                              tk: String,
+                              roomid: String,
+                              limit: i32,
+                              from: Option<String>)
+                              -> Result<(Vec<Message>, Option<String>), Error> {
+    get_room_messages_dir(baseu, tk, roomid, limit, from, Direction::Backward)
+}
 
+/// Gets a page of messages for the room from the @from token in the @dir direction.
+///
+/// Returns the messages in chronological order and the token to get the next page in the
+/// same direction, None when we've reached the start of the room or the last message. The
+/// page can be empty and have a token anyway, when the filter skips all its events.
+pub fn get_room_messages_dir(baseu: &Url,
+                             tk: String,
+                             roomid: String,
+                             limit: i32,
+                             from: Option<String>,
+                             dir: Direction)
+                             -> Result<(Vec<Message>, Option<String>), Error> {
     let mut ms: Vec<Message> = vec![];
-    let mut nstart;
-    let mut nend;
 
+    let filter = json!({ "types": ["m.room.message"] });
     let mut params = vec![
-        ("dir", strn!("b")),
+        ("dir", strn!(dir.as_param())),
         ("limit", format!("{}", limit)),
+        ("filter", filter.to_string()),
         ("access_token", tk.clone()),
     ];
 
-    match end {
-        Some(ref e) => { params.push(("from", e.clone())) }
-        None => {}
-    };
+    if let Some(ref f) = from {
+        params.push(("from", f.clone()));
+    }
 
     let path = format!("rooms/{}/messages", roomid);
     let url = client_url!(baseu, &path, params)?;
 
     let r = json_q("get", &url, &json!(null), globals::TIMEOUT)?;
-    nend = String::from(r["end"].as_str().unwrap_or(""));
-    nstart = String::from(r["start"].as_str().unwrap_or(""));
+    let array = r["chunk"].as_array().cloned().unwrap_or_default();
 
-    let array = r["chunk"].as_array();
-    if array.is_none() || array.unwrap().len() == 0 {
-        return Ok((ms, nstart, nend));
-    }
+    let events: Vec<&JsonValue> = match dir {
+        Direction::Backward => array.iter().rev().collect(),
+        Direction::Forward => array.iter().collect(),
+    };
 
-    for msg in array.unwrap().iter().rev() {
+    for msg in events {
         if msg["type"].as_str().unwrap_or("") != "m.room.message" {
             continue;
         }
 
-        let m = parse_room_message(&baseu, roomid.clone(), msg);
+        let m = parse_room_message(roomid.clone(), msg);
         ms.push(m);
     }
 
-    if ms.len() < get {
-        let (more, s, e) =
-            get_initial_room_messages(baseu, tk, roomid, get, limit * 2, Some(nend))?;
-        nstart = s;
-        nend = e;
-        for m in more.iter().rev() {
-            ms.insert(0, m.clone());
-        }
-    }
+    // we've reached the start of the room, or the last message, when there's no new token
+    let end = match r["end"].as_str() {
+        Some(e) if from.as_ref().map(|f| f != e).unwrap_or(true) => Some(String::from(e)),
+        _ => None,
+    };
 
-    Ok((ms, nstart, nend))
+    Ok((ms, end))
 }
 
 pub fn build_url(base: &Url, path: &str, params: Vec<(&str, String)>) -> Result<Url, Error> {
@@ -714,33 +1138,43 @@ pub fn build_url(base: &Url, path: &str, params: Vec<(&str, String)>) -> Result<
     Ok(url)
 }
 
-pub fn circle_image(fname: String) -> Result<String, Error> {
-    use std::f64::consts::PI;
-
-    let pb = Pixbuf::new_from_file_at_scale(&fname, 40, -1, true)?;
-    let image = cairo::ImageSurface::create(cairo::Format::ARgb32, 40, 40)?;
-    let g = cairo::Context::new(&image);
-    g.set_antialias(cairo::Antialias::Best);
-    let hpos: f64 = (40.0 - (pb.get_height()) as f64) / 2.0;
-    g.set_source_pixbuf(&pb, 0.0, hpos);
-
-    g.arc(20.0, 20.0, 20.0, 0.0, 2.0 * PI);
-    g.clip();
-
-    g.paint();
+/// Returns the size of the image in @path, and writes to @dest a thumbnail that fits in
+/// @w x @h, returning its size too. The thumbnail is written as jpeg if @dest ends with
+/// ".jpg", and as png otherwise. There's no thumbnail if the image is already smaller than
+/// that.
+pub fn image_thumbnail(path: &str, dest: &str, w: i32, h: i32) -> Result<((i32, i32), Option<(i32, i32)>), Error> {
+    let pb = Pixbuf::new_from_file(path)?;
+    let size = (pb.get_width(), pb.get_height());
+    if size.0 <= w && size.1 <= h {
+        return Ok((size, None));
+    }
 
-    let mut buffer = File::create(&fname)?;
-    image.write_to_png(&mut buffer)?;
+    let thumb = Pixbuf::new_from_file_at_scale(path, w, h, true)?;
+    if dest.ends_with(".jpg") {
+        thumb.savev(dest, "jpeg", &[("quality", "90")])?;
+    } else {
+        thumb.savev(dest, "png", &[])?;
+    }
 
-    Ok(fname)
+    Ok((size, Some((thumb.get_width(), thumb.get_height()))))
 }
 
-pub fn cache_path(name: &str) -> Result<String, Error> {
+fn cache_dir() -> PathBuf {
     let mut path = match glib::get_user_cache_dir() {
         Some(path) => path,
         None => PathBuf::from("/tmp"),
     };
 
+    path.push("fractal");
+    path
+}
+
+/// Path of @name in the config directory, for the files that aren't removed with the cache
+pub fn config_path(name: &str) -> Result<String, Error> {
+    let mut path = match glib::get_user_config_dir() {
+        Some(path) => path,
+        None => PathBuf::from("/tmp"),
+    };
     path.push("fractal");
 
     if !path.exists() {
@@ -752,14 +1186,89 @@ pub fn cache_path(name: &str) -> Result<String, Error> {
     Ok(path.into_os_string().into_string()?)
 }
 
+/// Writes the image in @path to @dest without its metadata, rotated as the metadata says and
+/// scaled down to fit in a @max_size square if it's set. The image is written as jpeg if
+/// @dest ends with ".jpg", and as png otherwise.
+pub fn clean_image(path: &str, dest: &str, max_size: Option<i32>) -> Result<(), Error> {
+    let mut pb = Pixbuf::new_from_file(path)?;
+    if let Some(m) = max_size {
+        if pb.get_width() > m || pb.get_height() > m {
+            pb = Pixbuf::new_from_file_at_scale(path, m, m, true)?;
+        }
+    }
+
+    // the orientation is in the metadata that we're removing
+    if let Some(rotated) = pb.apply_embedded_orientation() {
+        pb = rotated;
+    }
+
+    if dest.ends_with(".jpg") {
+        pb.savev(dest, "jpeg", &[("quality", "90")])?;
+    } else {
+        pb.savev(dest, "png", &[])?;
+    }
+
+    Ok(())
+}
+
+pub fn cache_path(name: &str) -> Result<String, Error> {
+    let mut path = cache_dir();
+
+    if !path.exists() {
+        create_dir_all(&path)?;
+    }
+
+    path.push(name);
+
+    Ok(path.into_os_string().into_string()?)
+}
+
+/// Files in the cache directory that aren't data of the account, older versions stored the
+/// settings there
+const KEPT_FILES: &[&str] = &["settings.json"];
+
+/// Removes the local data of the account from the fractal cache directory: the rooms
+/// cache, the outbox, the search index, the generated files and the downloaded media, with
+/// the media and avatars that older versions stored with the media and user ids as names.
+/// Everything but the settings is removed. Every file is tried, and the first error is
+/// returned.
+pub fn clear_cache() -> Result<(), Error> {
+    let mut result = mediacache::clear();
+
+    let entries = match fs::read_dir(cache_dir()) {
+        Ok(entries) => entries,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return result,
+        Err(err) => return Err(err.into()),
+    };
+
+    for entry in entries {
+        let r = entry.and_then(|e| {
+            let name = e.file_name().into_string().unwrap_or_default();
+            if KEPT_FILES.contains(&&name[..]) {
+                return Ok(());
+            }
+            match e.file_type()?.is_dir() {
+                true => fs::remove_dir_all(e.path()),
+                false => fs::remove_file(e.path()),
+            }
+        });
+
+        if let Err(err) = r {
+            if result.is_ok() {
+                result = Err(err.into());
+            }
+        }
+    }
+
+    result
+}
+
 pub fn get_user_avatar_img(baseu: &Url, userid: String, alias: String, avatar: String) -> Result<String, Error> {
     if avatar.is_empty() {
         return identicon!(&userid, alias);
     }
 
-    let dest = cache_path(&userid)?;
-    let img = dw_media(baseu, &avatar, true, Some(&dest), 64, 64)?;
-    Ok(img)
+    thumb!(baseu, &avatar)
 }
 
 pub fn parse_room_member(msg: &JsonValue) -> Option<Member> {
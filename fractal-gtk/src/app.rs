@@ -4,32 +4,46 @@ extern crate gdk_pixbuf_sys;
 extern crate secret_service;
 extern crate chrono;
 extern crate gdk;
-extern crate notify_rust;
-
-use self::notify_rust::Notification;
+extern crate pango;
+extern crate serde_json;
 
 use util::get_pixbuf_data;
 use util::markup;
+use util::format_size;
+use util::uris_to_paths;
 
 use self::chrono::prelude::*;
 
 use self::secret_service::SecretService;
 use self::secret_service::EncryptionType;
+use self::secret_service::SsError;
+use self::serde_json::Value as JsonValue;
 
 use std::sync::{Arc, Mutex};
 use std::sync::mpsc::channel;
 use std::sync::mpsc::{Sender, Receiver};
 use std::sync::mpsc::TryRecvError;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::process::Command;
+use std::rc::Rc;
+use std::cell::Cell;
+use std::fs;
+use std::fs::File;
+use std::io::prelude::*;
 use std::thread;
+use std::time::Instant;
 
 use gio::ApplicationExt;
 use gio::SimpleActionExt;
 use gio::ActionMapExt;
 use glib;
+use glib::ToVariant;
 use gio;
+use gst;
+use gst::prelude::*;
 use self::gdk_pixbuf::Pixbuf;
+use self::gdk_pixbuf::PixbufAnimation;
 use self::gio::prelude::*;
 use self::gtk::prelude::*;
 
@@ -38,6 +52,8 @@ use globals;
 use backend::Backend;
 use backend::BKCommand;
 use backend::BKResponse;
+use backend::Attachment;
+use backend::ConnectionState;
 use backend;
 
 use types::Member;
@@ -46,26 +62,94 @@ use types::Protocol;
 use types::Room;
 use types::RoomList;
 use types::Event;
+use types::Ruleset;
+use types::PushActions;
+use types::PushContext;
+use types::RoomNotify;
+use types::SearchQuery;
+use types::SearchResults;
+use types::TimelineChunk;
+use types::Direction;
+use types::DirectoryQuery;
+use types::DirectoryPage;
 
 use widgets;
 use widgets::AvatarExt;
 use cache;
+use searchindex;
+use searchindex::SearchIndex;
+use searchindex::LocalQuery;
+use settings;
+use settings::Settings;
 
+use fractal_api as api;
+use error::Error;
 
-#[derive(Debug)]
-pub enum Error {
-    SecretServiceError,
-}
 
-derror!(secret_service::SsError, Error::SecretServiceError);
+// the secret service errors keep their message, they can't be converted with From because
+// both types are from other crates
+fn ss_error(err: SsError) -> Error {
+    Error::CryptoError(err.to_string())
+}
 
 
 const APP_ID: &'static str = "org.gnome.Fractal";
+/// Characters of each message and number of messages shown in the desktop notifications
+const NOTIFICATION_BODY_LEN: usize = 80;
+const NOTIFICATION_LINES: usize = 3;
+
+
+// Search shown in the results view, the results are loaded by pages
+struct Search {
+    query: SearchQuery,
+    next_batch: Option<String>,
+    results: Vec<Message>,
+}
+
+// Search in the public room directory, the rooms are loaded by pages
+struct Directory {
+    query: DirectoryQuery,
+    next_batch: Option<String>,
+    loading: bool,
+}
+
+// Media download started by the user, the row is created when the first progress arrives
+struct Download {
+    pub name: String,
+    pub row: Option<(gtk::Box, gtk::ProgressBar)>,
+    // opened with the default application when it's done
+    pub open: bool,
+}
+
+
+// Image gallery of the active room shown in the media viewer
+struct MediaViewer {
+    pub media: Vec<Message>,
+    pub current: usize,
+    // None means fit to window
+    pub zoom: Option<f64>,
+    pub loading: Option<String>,
+    pub pixbuf: Option<Pixbuf>,
+    pub animation: Option<PixbufAnimation>,
+    pub size: (i32, i32),
+}
+
+
+// Voice message being recorded from the composer, it's sent to the room where it started
+struct Recording {
+    pub pipeline: gst::Element,
+    pub path: String,
+    pub room: String,
+    pub started: Instant,
+}
 
 
+// Message waiting for the server echo. The widget is only there for messages in the active
+// room.
 struct TmpMsg {
     pub msg: Message,
-    pub widget: gtk::Widget,
+    pub widget: Option<gtk::Widget>,
+    pub failed: bool,
 }
 
 
@@ -75,12 +159,30 @@ pub struct AppOp {
     pub backend: Sender<backend::BKCommand>,
     pub internal: Sender<InternalCommand>,
 
-    pub syncing: bool,
     tmp_msgs: Vec<TmpMsg>,
+    downloads: HashMap<String, Download>,
+    uploads: HashMap<String, (gtk::Box, gtk::ProgressBar)>,
+    viewer: Option<MediaViewer>,
+    recording: Option<Recording>,
     shown_messages: usize,
+    loading_more: bool,
+    // part of the timeline around a message that we're showing instead of the live one,
+    // until it reaches the last messages
+    chunk: Option<TimelineChunk>,
 
     pub username: Option<String>,
     pub uid: Option<String>,
+    pub push_rules: Option<Ruleset>,
+    // current search, with the messages of the results shown
+    search: Option<Search>,
+    index: SearchIndex,
+    online: bool,
+    // current search in the room directory
+    directory: Option<Directory>,
+    // number of directory searches made, to ignore the pages of the previous ones
+    directory_searches: u64,
+    // messages of the desktop notification of each room
+    notifications: HashMap<String, Vec<String>>,
     pub server_url: String,
 
     pub autoscroll: bool,
@@ -91,6 +193,9 @@ pub struct AppOp {
     pub more_members_btn: gtk::Button,
 
     pub state: AppState,
+    pub settings: Settings,
+    // GStreamer is available, to play the media messages and record voice messages
+    pub gst: bool,
     pub since: Option<String>,
     pub member_limit: usize,
 
@@ -117,13 +222,15 @@ pub enum AppState {
     Chat,
     Directory,
     Loading,
+    MediaViewer,
 }
 
 impl AppOp {
     pub fn new(app: gtk::Application,
                builder: gtk::Builder,
                tx: Sender<BKCommand>,
-               itx: Sender<InternalCommand>) -> AppOp {
+               itx: Sender<InternalCommand>,
+               gst: bool) -> AppOp {
         AppOp {
             gtk_builder: builder,
             gtk_app: app,
@@ -136,12 +243,26 @@ impl AppOp {
             rooms: HashMap::new(),
             username: None,
             uid: None,
+            push_rules: None,
+            search: None,
+            index: SearchIndex::default(),
+            online: true,
+            directory: None,
+            directory_searches: 0,
+            notifications: HashMap::new(),
             server_url: String::from("https://matrix.org"),
-            syncing: false,
             tmp_msgs: vec![],
+            downloads: HashMap::new(),
+            uploads: HashMap::new(),
+            viewer: None,
+            recording: None,
             shown_messages: 0,
+            loading_more: false,
+            chunk: None,
             state: AppState::Login,
-            roomlist: widgets::RoomList::new(None),
+            settings: settings::load(),
+            gst: gst,
+            roomlist: widgets::RoomList::new(None, None),
             since: None,
             member_limit: 50,
 
@@ -152,11 +273,18 @@ impl AppOp {
     pub fn set_state(&mut self, state: AppState) {
         self.state = state;
 
+        // the viewer only keeps the image while it's shown
+        match self.state {
+            AppState::MediaViewer => {}
+            _ => { self.viewer = None; }
+        };
+
         let widget_name = match self.state {
             AppState::Login => "login",
             AppState::Chat => "chat",
             AppState::Directory => "directory",
             AppState::Loading => "loading",
+            AppState::MediaViewer => "media_viewer",
         };
 
         self.gtk_builder
@@ -168,6 +296,7 @@ impl AppOp {
         let bar_name = match self.state {
             AppState::Login => "login",
             AppState::Directory => "back",
+            AppState::MediaViewer => "back",
             AppState::Loading => "login",
             _ => "normal",
         };
@@ -264,9 +393,9 @@ impl AppOp {
         };
 
         self.store_pass(username.clone()?, password.clone()?, self.server_url.clone())
-            .unwrap_or_else(|_| {
+            .unwrap_or_else(|err| {
                 // TODO: show an error
-                println!("Error: Can't store the password using libsecret");
+                println!("Error: Can't store the password using libsecret: {}", err);
             });
 
         let uname = username?;
@@ -289,6 +418,8 @@ impl AppOp {
     pub fn get_username(&self) {
         self.backend.send(BKCommand::GetUsername).unwrap();
         self.backend.send(BKCommand::GetAvatar).unwrap();
+        // the sync only has the push rules when they change
+        self.backend.send(BKCommand::GetPushRules).unwrap();
     }
 
     pub fn set_username(&mut self, username: Option<String>) {
@@ -315,21 +446,116 @@ impl AppOp {
         self.backend.send(BKCommand::ShutDown).unwrap();
     }
 
-    pub fn logout(&self) {
+    pub fn logout(&mut self, wipe: bool) {
         let _ = self.delete_pass();
-        self.backend.send(BKCommand::Logout).unwrap();
+        if wipe {
+            // the local data is removed even if the server can't be reached
+            self.clear_session();
+        }
+        self.backend.send(BKCommand::Logout(wipe)).unwrap();
+    }
+
+    pub fn clear_session(&mut self) {
+        self.logged_in = false;
+        self.set_connection_state(ConnectionState::Connected);
+        self.set_uid(None);
+        self.set_username(None);
+        self.push_rules = None;
+        self.withdraw_notifications();
+        self.index = SearchIndex::default();
+        self.directory = None;
+        self.since = None;
+
+        self.active_room = None;
+        self.chunk = None;
+        self.show_chunk_bar(false);
+        self.clear_tmp_msgs();
+        self.tmp_msgs.clear();
+        self.remove_messages();
+        self.rooms.clear();
+
+        let container: gtk::Box = self.gtk_builder
+            .get_object("room_container")
+            .expect("Couldn't find room_container in ui file.");
+        for ch in container.get_children().iter() {
+            container.remove(ch);
+        }
+        self.roomlist = widgets::RoomList::new(None, None);
+
+        self.room_panel(RoomPanel::NoRoom);
+        self.set_state(AppState::Login);
+    }
+
+    pub fn deactivate_account_dialog(&self) {
+        let window: gtk::ApplicationWindow = self.gtk_builder
+            .get_object("main_window")
+            .expect("Can't find main_window in ui file.");
+        let dialog = gtk::Dialog::new_with_buttons(
+            Some("Deactivate Account"),
+            Some(&window),
+            gtk::DialogFlags::MODAL|
+            gtk::DialogFlags::USE_HEADER_BAR|
+            gtk::DialogFlags::DESTROY_WITH_PARENT,
+            &[]);
+
+        let content = gtk::Box::new(gtk::Orientation::Vertical, 6);
+        content.set_border_width(18);
+
+        let label = gtk::Label::new("Deactivating your account can't be undone. \
+                                     Enter your password to confirm.");
+        label.set_line_wrap(true);
+        label.set_max_width_chars(40);
+        label.set_halign(gtk::Align::Start);
+
+        let pass = gtk::Entry::new();
+        pass.set_visibility(false);
+        pass.set_placeholder_text("Password");
+
+        let erase = gtk::CheckButton::new_with_label("Also erase all my messages");
+
+        content.pack_start(&label, false, false, 0);
+        content.pack_start(&pass, false, false, 0);
+        content.pack_start(&erase, false, false, 0);
+        content.show_all();
+        dialog.get_content_area().add(&content);
+
+        if let Some(hbar) = dialog.get_header_bar() {
+            let bar = hbar.downcast::<gtk::HeaderBar>().unwrap();
+            let closebtn = gtk::Button::new_with_label("Cancel");
+            let okbtn = gtk::Button::new_with_label("Deactivate");
+            okbtn.get_style_context().unwrap().add_class("destructive-action");
+
+            bar.set_show_close_button(false);
+            bar.pack_start(&closebtn);
+            bar.pack_end(&okbtn);
+            bar.show_all();
+
+            closebtn.connect_clicked(clone!(dialog => move |_| {
+                dialog.destroy();
+            }));
+            let bk = self.backend.clone();
+            okbtn.connect_clicked(clone!(dialog, pass, erase => move |_| {
+                let p = pass.get_text().unwrap_or_default();
+                if !p.is_empty() {
+                    bk.send(BKCommand::DeactivateAccount(p, erase.get_active())).unwrap();
+                    dialog.destroy();
+                }
+            }));
+        }
+
+        dialog.present();
     }
 
     pub fn delete_pass(&self) -> Result<(), Error> {
-        let ss = SecretService::new(EncryptionType::Dh)?;
-        let collection = ss.get_default_collection()?;
+        let ss = SecretService::new(EncryptionType::Dh).map_err(ss_error)?;
+        let collection = ss.get_default_collection().map_err(ss_error)?;
 
         // deleting previous items
-        let allpass = collection.get_all_items()?;
+        let allpass = collection.get_all_items().map_err(ss_error)?;
         let passwds = allpass.iter()
             .filter(|x| x.get_label().unwrap_or(strn!("")) == "fractal");
         for p in passwds {
-            p.delete()?;
+            p.delete().map_err(ss_error)?;
         }
 
         Ok(())
@@ -340,8 +566,8 @@ impl AppOp {
                       password: String,
                       server: String)
                       -> Result<(), Error> {
-        let ss = SecretService::new(EncryptionType::Dh)?;
-        let collection = ss.get_default_collection()?;
+        let ss = SecretService::new(EncryptionType::Dh).map_err(ss_error)?;
+        let collection = ss.get_default_collection().map_err(ss_error)?;
 
         // deleting previous items
         self.delete_pass()?;
@@ -356,15 +582,15 @@ impl AppOp {
             password.as_bytes(), //secret
             true, // replace item with same attributes
             "text/plain" // secret content type
-        )?;
+        ).map_err(ss_error)?;
 
         Ok(())
     }
 
     pub fn migrate_old_passwd(&self) -> Result<(), Error> {
-        let ss = SecretService::new(EncryptionType::Dh)?;
-        let collection = ss.get_default_collection()?;
-        let allpass = collection.get_all_items()?;
+        let ss = SecretService::new(EncryptionType::Dh).map_err(ss_error)?;
+        let collection = ss.get_default_collection().map_err(ss_error)?;
+        let allpass = collection.get_all_items().map_err(ss_error)?;
 
         // old name password
         let passwd = allpass.iter()
@@ -375,22 +601,22 @@ impl AppOp {
         }
 
         let p = passwd.unwrap();
-        let attrs = p.get_attributes()?;
-        let secret = p.get_secret()?;
+        let attrs = p.get_attributes().map_err(ss_error)?;
+        let secret = p.get_secret().map_err(ss_error)?;
 
         let mut attr = attrs.iter()
             .find(|&ref x| x.0 == "username")
-            .ok_or(Error::SecretServiceError)?;
+            .ok_or(Error::CryptoError(strn!("The stored password hasn't got the username")))?;
         let username = attr.1.clone();
         attr = attrs.iter()
             .find(|&ref x| x.0 == "server")
-            .ok_or(Error::SecretServiceError)?;
+            .ok_or(Error::CryptoError(strn!("The stored password hasn't got the server")))?;
         let server = attr.1.clone();
         let pwd = String::from_utf8(secret).unwrap();
 
         // removing old
         for p in passwd {
-            p.delete()?;
+            p.delete().map_err(ss_error)?;
         }
 
         self.store_pass(username, pwd, server)?;
@@ -401,28 +627,28 @@ impl AppOp {
     pub fn get_pass(&self) -> Result<(String, String, String), Error> {
         self.migrate_old_passwd()?;
 
-        let ss = SecretService::new(EncryptionType::Dh)?;
-        let collection = ss.get_default_collection()?;
-        let allpass = collection.get_all_items()?;
+        let ss = SecretService::new(EncryptionType::Dh).map_err(ss_error)?;
+        let collection = ss.get_default_collection().map_err(ss_error)?;
+        let allpass = collection.get_all_items().map_err(ss_error)?;
 
         let passwd = allpass.iter()
             .find(|x| x.get_label().unwrap_or(strn!("")) == "fractal");
 
         if passwd.is_none() {
-            return Err(Error::SecretServiceError);
+            return Err(Error::CryptoError(strn!("There's no stored password")));
         }
 
         let p = passwd.unwrap();
-        let attrs = p.get_attributes()?;
-        let secret = p.get_secret()?;
+        let attrs = p.get_attributes().map_err(ss_error)?;
+        let secret = p.get_secret().map_err(ss_error)?;
 
         let mut attr = attrs.iter()
             .find(|&ref x| x.0 == "username")
-            .ok_or(Error::SecretServiceError)?;
+            .ok_or(Error::CryptoError(strn!("The stored password hasn't got the username")))?;
         let username = attr.1.clone();
         attr = attrs.iter()
             .find(|&ref x| x.0 == "server")
-            .ok_or(Error::SecretServiceError)?;
+            .ok_or(Error::CryptoError(strn!("The stored password hasn't got the server")))?;
         let server = attr.1.clone();
 
         let tup = (username, String::from_utf8(secret).unwrap(), server);
@@ -433,12 +659,20 @@ impl AppOp {
     pub fn init(&mut self) {
         self.set_state(AppState::Loading);
 
+        let size = self.settings.media_cache_size * 1024 * 1024;
+        self.backend.send(BKCommand::SetMediaCacheSize(size)).unwrap();
+
+        if let Ok(index) = searchindex::load() {
+            self.index = index;
+        }
+
         if let Ok(data) = cache::load() {
             let r: Vec<Room> = data.rooms.values().cloned().collect();
+            // the room list needs to know who we are
+            self.uid = Some(data.uid);
             self.set_rooms(r, None);
             self.since = Some(data.since);
             self.username = Some(data.username);
-            self.uid = Some(data.uid);
         } else {
             self.set_state(AppState::Login);
         }
@@ -478,42 +712,100 @@ impl AppOp {
     }
 
     pub fn sync(&mut self) {
-        if !self.syncing && self.logged_in {
-            self.syncing = true;
+        if self.logged_in {
             self.backend.send(BKCommand::Sync).unwrap();
         }
     }
 
     pub fn synced(&mut self, since: Option<String>) {
-        self.syncing = false;
         self.since = since;
     }
 
+    pub fn set_connection_state(&mut self, state: ConnectionState) {
+        self.online = state == ConnectionState::Connected;
+
+        let revealer: gtk::Revealer = self.gtk_builder
+            .get_object("connection_revealer")
+            .expect("Couldn't find connection_revealer in ui file.");
+        let label: gtk::Label = self.gtk_builder
+            .get_object("connection_label")
+            .expect("Couldn't find connection_label in ui file.");
+
+        match state {
+            ConnectionState::Connected => {
+                revealer.set_reveal_child(false);
+            }
+            ConnectionState::Disconnected(ms) => {
+                let secs = (ms + 999) / 1000;
+                label.set_text(&format!("Offline, trying to reconnect in {} seconds…", secs));
+                revealer.set_reveal_child(true);
+            }
+            ConnectionState::RateLimited(ms) => {
+                let secs = (ms + 999) / 1000;
+                label.set_text(&format!("The server is busy, trying again in {} seconds…", secs));
+                revealer.set_reveal_child(true);
+            }
+            ConnectionState::InvalidToken => {
+                revealer.set_reveal_child(false);
+                // the session isn't valid anymore, so we try to login again with the stored
+                // credentials or we ask the user to do it
+                if let Ok(pass) = self.get_pass() {
+                    self.connect(Some(pass.0), Some(pass.1), Some(pass.2));
+                } else {
+                    self.clear_session();
+                    self.show_error("Your session has expired, please log in again");
+                }
+            }
+        }
+    }
+
     pub fn set_rooms(&mut self, rooms: Vec<Room>, def: Option<Room>) {
         let container: gtk::Box = self.gtk_builder
             .get_object("room_container")
             .expect("Couldn't find room_container in ui file.");
 
-        let selected_room = self.roomlist.get_selected();
+        // the room list is created once for the session and updated in place after that, so
+        // the selection and the scroll are kept
+        if container.get_children().is_empty() {
+            self.roomlist = widgets::RoomList::new(Some(self.server_url.clone()), self.uid.clone());
+            container.add(&self.roomlist.widget());
 
-        self.rooms.clear();
-        for ch in container.get_children().iter() {
-            container.remove(ch);
-        }
+            let filter: gtk::SearchEntry = self.gtk_builder
+                .get_object("room_filter_entry")
+                .expect("Can't find room_filter_entry in ui file.");
+            self.roomlist.filter(&filter.get_text().unwrap_or_default());
 
-        for r in rooms.iter() {
-            self.rooms.insert(r.id.clone(), r.clone());
+            let bk = self.internal.clone();
+            self.roomlist.connect(move |room| {
+                bk.send(InternalCommand::SelectRoom(room)).unwrap();
+            });
+            let bk = self.internal.clone();
+            self.roomlist.connect_drop(move |room, from, to, order| {
+                bk.send(InternalCommand::MoveRoom(room, from, to, order)).unwrap();
+            });
         }
 
-        self.roomlist = widgets::RoomList::new(Some(self.server_url.clone()));
-        self.roomlist.add_rooms(rooms.iter().cloned().collect());
-        container.add(&self.roomlist.widget());
-        self.roomlist.set_selected(selected_room);
+        let ids = rooms.iter().map(|r| r.id.clone()).collect::<HashSet<String>>();
+        let left = self.rooms.keys()
+            .filter(|id| !ids.contains(*id))
+            .cloned()
+            .collect::<Vec<String>>();
+        for roomid in left {
+            self.rooms.remove(&roomid);
+            self.roomlist.remove_room(roomid);
+        }
 
-        let bk = self.internal.clone();
-        self.roomlist.connect(move |room| {
-            bk.send(InternalCommand::SelectRoom(room)).unwrap();
-        });
+        let mut new_rooms = vec![];
+        for r in rooms.into_iter() {
+            self.index.add(&r.messages);
+            if self.rooms.contains_key(&r.id) {
+                self.roomlist.update_room(r.clone());
+            } else {
+                new_rooms.push(r.clone());
+            }
+            self.rooms.insert(r.id.clone(), r);
+        }
+        self.roomlist.add_rooms(new_rooms);
 
         let mut godef = def;
         if let Some(aroom) = self.active_room.clone() {
@@ -523,7 +815,7 @@ impl AppOp {
         }
 
         if let Some(d) = godef {
-            self.set_active_room_by_id(d.id.clone());
+            self.select_room(d.id.clone());
         } else {
             self.set_state(AppState::Chat);
             self.room_panel(RoomPanel::NoRoom);
@@ -534,16 +826,107 @@ impl AppOp {
         self.cache_rooms();
     }
 
-    pub fn cache_rooms(&self) {
+    /// Adds the rooms joined after the first sync, and goes to @def, the room that we've
+    /// asked to join
+    pub fn add_rooms(&mut self, rooms: Vec<Room>, def: Option<Room>) {
+        for r in rooms.into_iter() {
+            self.rooms.insert(r.id.clone(), r.clone());
+            self.roomlist.prepend_room(r);
+        }
+
+        if let Some(d) = def {
+            self.set_state(AppState::Chat);
+            self.select_room(d.id);
+        }
+
+        self.cache_rooms();
+    }
+
+    /// Removes the rooms left from other client or where we've been kicked
+    pub fn remove_rooms(&mut self, rooms: Vec<String>) {
+        for roomid in rooms.into_iter() {
+            self.rooms.remove(&roomid);
+            self.roomlist.remove_room(roomid.clone());
+
+            if self.active_room == Some(roomid) {
+                self.active_room = None;
+                self.clear_tmp_msgs();
+                self.room_panel(RoomPanel::NoRoom);
+            }
+        }
+
+        self.cache_rooms();
+    }
+
+    /// The join is done, but the room is added with the next sync if we weren't there yet
+    pub fn room_joined(&mut self, roomid: String) {
+        if self.rooms.contains_key(&roomid) {
+            self.set_state(AppState::Chat);
+            self.select_room(roomid);
+        }
+    }
+
+    pub fn cache_rooms(&mut self) {
+        if !self.logged_in {
+            // nothing new to store, and after a logout the cache may have been removed
+            return;
+        }
+
         // serializing rooms
-        if let Err(_) = cache::store(&self.rooms, self.since.clone().unwrap_or_default(), self.username.clone().unwrap_or_default(), self.uid.clone().unwrap_or_default()) {
-            println!("Error caching rooms");
+        if let Err(err) = cache::store(&self.rooms, self.since.clone().unwrap_or_default(), self.username.clone().unwrap_or_default(), self.uid.clone().unwrap_or_default()) {
+            println!("Error caching rooms: {}", err);
+        };
+
+        if let Err(err) = searchindex::store(&mut self.index) {
+            println!("Error storing the search index: {}", err);
         };
     }
 
-    pub fn reload_rooms(&mut self) {
-        self.set_state(AppState::Loading);
-        self.backend.send(BKCommand::SyncForced).unwrap();
+    fn store_settings(&self) {
+        if let Err(err) = settings::store(&self.settings) {
+            println!("Error storing the settings: {}", err);
+        }
+    }
+
+    /// Enables or disables the link previews in all the rooms
+    pub fn set_url_previews(&mut self, enabled: bool) {
+        self.settings.url_previews = enabled;
+        self.store_settings();
+        self.reload_active_room();
+    }
+
+    /// Sets the size limit of the downloaded media, in MB
+    pub fn set_media_cache_size(&mut self, size: u64) {
+        self.settings.media_cache_size = size;
+        self.store_settings();
+        self.backend.send(BKCommand::SetMediaCacheSize(size * 1024 * 1024)).unwrap();
+    }
+
+    pub fn clear_media_cache(&self) {
+        self.backend.send(BKCommand::ClearMediaCache).unwrap();
+    }
+
+    /// Enables or disables the link previews in the active room
+    pub fn set_room_url_previews(&mut self, enabled: bool) {
+        let roomid = match self.active_room {
+            Some(ref r) => r.clone(),
+            None => return,
+        };
+
+        if enabled {
+            self.settings.rooms_without_previews.remove(&roomid);
+        } else {
+            self.settings.rooms_without_previews.insert(roomid);
+        }
+        self.store_settings();
+        self.reload_active_room();
+    }
+
+    // builds the messages again, after a change in how they're shown
+    fn reload_active_room(&mut self) {
+        if let Some(roomid) = self.active_room.clone() {
+            self.set_active_room_by_id(roomid);
+        }
     }
 
     pub fn remove_messages(&mut self) {
@@ -566,6 +949,53 @@ impl AppOp {
         }
     }
 
+    /// Shows the room and selects it in the room list, for the rooms chosen with the keyboard
+    pub fn select_room(&mut self, roomid: String) {
+        self.roomlist.set_selected(Some(roomid.clone()));
+        self.set_active_room_by_id(roomid);
+    }
+
+    pub fn filter_rooms(&self, term: &str) {
+        self.roomlist.filter(term);
+    }
+
+    /// Goes to the first room shown with the room list filter
+    pub fn select_first_room(&mut self) {
+        if let Some(roomid) = self.roomlist.first_match() {
+            self.select_room(roomid);
+        }
+    }
+
+    /// Goes to the next room with unread messages in the room list, or to the previous one if
+    /// @delta is negative
+    pub fn go_to_unread_room(&mut self, delta: i32) {
+        let ids = self.roomlist.room_ids();
+        let n = ids.len() as i32;
+        let active = self.active_room.clone().unwrap_or_default();
+        let current = match ids.iter().position(|id| *id == active) {
+            Some(p) => p as i32,
+            None if delta > 0 => -1,
+            None => n,
+        };
+
+        for i in 1..n + 1 {
+            let id = &ids[((current + delta * i) % n + n) as usize % n as usize];
+            let unread = self.rooms.get(id).map(|r| r.notifications > 0).unwrap_or(false);
+            if unread && *id != active {
+                self.select_room(id.clone());
+                return;
+            }
+        }
+    }
+
+    pub fn quick_switcher(&self) {
+        let window: gtk::Window = self.gtk_builder
+            .get_object("main_window")
+            .expect("Can't find main_window in ui file.");
+
+        widgets::QuickSwitcher::new(self).dialog(&window);
+    }
+
     pub fn set_active_room(&mut self, room: &Room) {
         self.member_limit = 50;
         self.room_panel(RoomPanel::Loading);
@@ -573,11 +1003,17 @@ impl AppOp {
         self.active_room = Some(room.id.clone());
         self.clear_tmp_msgs();
         self.autoscroll = true;
+        self.withdraw_notification(&room.id);
+        self.close_search_results();
+        self.chunk = None;
+        self.show_chunk_bar(false);
 
         self.remove_messages();
+        self.show_tmp_msgs();
 
         let mut getmessages = true;
         self.shown_messages = 0;
+        self.loading_more = false;
         let msgs = room.messages.iter().rev()
                                 .take(globals::INITIAL_MESSAGES)
                                 .collect::<Vec<&Message>>();
@@ -601,6 +1037,12 @@ impl AppOp {
         // getting room details
         self.backend.send(BKCommand::SetRoom(room.clone())).unwrap();
 
+        let previews = !self.settings.rooms_without_previews.contains(&room.id);
+        if let Some(action) = self.gtk_app.lookup_action("room_url_previews") {
+            action.change_state(&previews.to_variant());
+        }
+        self.update_room_notify_action();
+
         let members = self.gtk_builder
             .get_object::<gtk::ListStore>("members_store")
             .expect("Can't find members_store in ui file.");
@@ -635,7 +1077,9 @@ impl AppOp {
         self.set_current_room_detail(String::from("m.room.topic"), room.topic.clone());
 
         if getmessages {
-            self.backend.send(BKCommand::GetRoomMessages(self.active_room.clone().unwrap_or_default())).unwrap();
+            self.loading_more = true;
+            let token = room.prev_batch.clone().unwrap_or_default();
+            self.backend.send(BKCommand::BackPaginate(room.id.clone(), token)).unwrap();
         }
     }
 
@@ -738,6 +1182,23 @@ impl AppOp {
                             msgpos: MsgPos,
                             prev: Option<Message>,
                             force_full: bool) {
+        if msg.room == self.active_room.clone().unwrap_or_default() {
+            // the live messages aren't shown while we're looking at an older chunk
+            if self.chunk.is_none() {
+                self.render_message(msg, msgpos, prev, force_full);
+            }
+        } else {
+            self.update_room_notifications(&msg.room, |n| n + 1);
+        }
+
+        self.remove_tmp_room_message(msg);
+    }
+
+    fn render_message(&mut self,
+                      msg: &Message,
+                      msgpos: MsgPos,
+                      prev: Option<Message>,
+                      force_full: bool) {
         let msg_entry: gtk::Entry = self.gtk_builder
             .get_object("msg_entry")
             .expect("Couldn't find msg_entry in ui file.");
@@ -755,74 +1216,116 @@ impl AppOp {
             }
         }
 
-        if msg.room == self.active_room.clone().unwrap_or_default() {
-            if let Some(r) = self.rooms.get(&self.active_room.clone().unwrap_or_default()) {
-                let m;
-                {
-                    let mb = widgets::MessageBox::new(r, msg, &self);
-                    let entry = msg_entry.clone();
-                    mb.username_event_box.connect_button_press_event(move |eb, _| {
-                        if let Some(label) = eb.get_children().iter().nth(0) {
-                            if let Ok(l) = label.clone().downcast::<gtk::Label>() {
-                                if let Some(t) = l.get_text() {
-                                    let mut pos = entry.get_position();
-                                    entry.insert_text(&t[..], &mut pos);
-                                }
+        if let Some(r) = self.rooms.get(&self.active_room.clone().unwrap_or_default()) {
+            let m;
+            {
+                let mb = widgets::MessageBox::new(r, msg, &self);
+                let entry = msg_entry.clone();
+                mb.username_event_box.connect_button_press_event(move |eb, _| {
+                    if let Some(label) = eb.get_children().iter().nth(0) {
+                        if let Ok(l) = label.clone().downcast::<gtk::Label>() {
+                            if let Some(t) = l.get_text() {
+                                let mut pos = entry.get_position();
+                                entry.insert_text(&t[..], &mut pos);
                             }
                         }
-                        glib::signal::Inhibit(false)
-                    });
-                    m = match calc_prev {
-                        Some(ref p) if p.sender == msg.sender => mb.small_widget(),
-                        _ => mb.widget(),
                     }
+                    glib::signal::Inhibit(false)
+                });
+                m = match calc_prev {
+                    Some(ref p) if p.sender == msg.sender => mb.small_widget(),
+                    _ => mb.widget(),
                 }
-
-                match msgpos {
-                    MsgPos::Bottom => messages.add(&m),
-                    MsgPos::Top => messages.insert(&m, 1),
-                };
-                self.shown_messages += 1;
             }
-            self.remove_tmp_room_message(msg);
-        } else {
-            self.update_room_notifications(&msg.room, |n| n + 1);
+
+            // the messages not sent yet are always at the bottom
+            match (msgpos, self.first_tmp_msg_position()) {
+                (MsgPos::Bottom, Some(p)) => messages.insert(&m, p),
+                (MsgPos::Bottom, None) => messages.add(&m),
+                (MsgPos::Top, _) => messages.insert(&m, 1),
+            };
+            self.shown_messages += 1;
+        }
+    }
+
+    fn first_tmp_msg_position(&self) -> Option<i32> {
+        self.tmp_msgs.iter()
+            .filter_map(|t| t.widget.clone())
+            .filter_map(|w| w.downcast::<gtk::ListBoxRow>().ok())
+            .map(|row| row.get_index())
+            .min()
+    }
+
+    fn build_tmp_msg_widget(&self, msg: &Message, failed: bool) -> Option<gtk::Box> {
+        let r = self.rooms.get(&msg.room)?;
+        let mb = widgets::MessageBox::new(r, msg, &self);
+        match failed {
+            true => Some(mb.failed_widget()),
+            false => Some(mb.widget()),
         }
     }
 
     pub fn add_tmp_room_message(&mut self, msg: &Message) {
+        if self.tmp_msgs.iter().any(|t| t.msg.txnid.is_some() && t.msg.txnid == msg.txnid) {
+            return;
+        }
+
+        // our new messages go after the last ones
+        if msg.room == self.active_room.clone().unwrap_or_default() {
+            self.jump_to_present();
+        }
+
         let messages = self.gtk_builder
             .get_object::<gtk::ListBox>("message_list")
             .expect("Can't find message_list in ui file.");
 
-        if let Some(r) = self.rooms.get(&self.active_room.clone().unwrap_or_default()) {
-            let m;
-            {
-                let mb = widgets::MessageBox::new(r, msg, &self);
-                m = mb.widget();
+        let mut widget = None;
+        if msg.room == self.active_room.clone().unwrap_or_default() {
+            if let Some(m) = self.build_tmp_msg_widget(msg, false) {
+                messages.add(&m);
+                widget = messages.get_children().iter().last().cloned();
+                self.scroll_down();
             }
-
-            messages.add(&m);
         }
 
-        if let Some(w) = messages.get_children().iter().last() {
-            self.tmp_msgs.push(TmpMsg {
-                    msg: msg.clone(),
-                    widget: w.clone(),
-            });
-
-            self.scroll_down();
-        };
+        self.tmp_msgs.push(TmpMsg {
+                msg: msg.clone(),
+                widget: widget,
+                failed: false,
+        });
     }
 
+    /// Removes the temporary message widgets from the message list, the messages are kept
+    /// to show them again when the room is selected.
     pub fn clear_tmp_msgs(&mut self) {
         let messages = self.gtk_builder
             .get_object::<gtk::ListBox>("message_list")
             .expect("Can't find message_list in ui file.");
-        for t in self.tmp_msgs.iter() {
-            messages.remove(&t.widget);
+        for t in self.tmp_msgs.iter_mut() {
+            if let Some(w) = t.widget.take() {
+                messages.remove(&w);
+            }
+        }
+    }
+
+    pub fn show_tmp_msgs(&mut self) {
+        let messages = self.gtk_builder
+            .get_object::<gtk::ListBox>("message_list")
+            .expect("Can't find message_list in ui file.");
+        let active = self.active_room.clone().unwrap_or_default();
+
+        for i in 0..self.tmp_msgs.len() {
+            if self.tmp_msgs[i].msg.room != active || self.tmp_msgs[i].widget.is_some() {
+                continue;
+            }
+
+            let msg = self.tmp_msgs[i].msg.clone();
+            let failed = self.tmp_msgs[i].failed;
+            if let Some(m) = self.build_tmp_msg_widget(&msg, failed) {
+                messages.add(&m);
+                self.tmp_msgs[i].widget = messages.get_children().iter().last().cloned();
+            }
         }
-        self.tmp_msgs.clear();
     }
 
     pub fn remove_tmp_room_message(&mut self, msg: &Message) {
@@ -830,29 +1333,176 @@ impl AppOp {
             .get_object::<gtk::ListBox>("message_list")
             .expect("Can't find message_list in ui file.");
 
-        let mut rmidx = None;
+        let rmidx = match msg.txnid {
+            Some(_) => self.tmp_msgs.iter().position(|t| t.msg.txnid == msg.txnid),
+            // messages sent from other clients don't have txnid, but it can be our own
+            // message sent before the txnid was stored
+            None => self.tmp_msgs.iter().position(|t| {
+                t.msg.sender == msg.sender &&
+                t.msg.mtype == msg.mtype &&
+                t.msg.room == msg.room &&
+                t.msg.body == msg.body
+            }),
+        };
+
+        if let Some(idx) = rmidx {
+            let t = self.tmp_msgs.remove(idx);
+            if let Some(w) = t.widget {
+                messages.remove(&w);
+            }
+        }
+    }
+
+    fn set_tmp_msg_failed(&mut self, txnid: &str, failed: bool) {
+        let messages = self.gtk_builder
+            .get_object::<gtk::ListBox>("message_list")
+            .expect("Can't find message_list in ui file.");
+
+        let idx = match self.tmp_msgs.iter().position(|t| t.msg.txnid.as_ref().map(|s| &s[..]) == Some(txnid)) {
+            Some(i) => i,
+            None => return,
+        };
+
+        self.tmp_msgs[idx].failed = failed;
+
+        // replacing the widget in the same position
+        let old = self.tmp_msgs[idx].widget.take()
+            .and_then(|w| w.downcast::<gtk::ListBoxRow>().ok());
+        if let Some(row) = old {
+            let pos = row.get_index();
+            messages.remove(&row);
+
+            let msg = self.tmp_msgs[idx].msg.clone();
+            if let Some(m) = self.build_tmp_msg_widget(&msg, failed) {
+                messages.insert(&m, pos);
+                self.tmp_msgs[idx].widget = messages.get_row_at_index(pos)
+                    .map(|r| r.upcast::<gtk::Widget>());
+            }
+        }
+    }
+
+    pub fn tmp_msg_sent(&mut self, txnid: String, evid: String) {
+        // the message is removed when the event comes in the sync, until then we show it
+        // as not sent but without the failed state
+        if let Some(t) = self.tmp_msgs.iter_mut().find(|t| t.msg.txnid == Some(txnid.clone())) {
+            t.msg.id = Some(evid);
+        }
+        self.set_tmp_msg_failed(&txnid, false);
+    }
+
+    pub fn tmp_msg_failed(&mut self, txnid: String) {
+        self.set_tmp_msg_failed(&txnid, true);
+    }
+
+    pub fn retry_tmp_msg(&mut self, txnid: String) {
+        self.set_tmp_msg_failed(&txnid, false);
+        self.backend.send(BKCommand::RetrySendMsg(txnid)).unwrap();
+    }
 
-        for (i, t) in self.tmp_msgs.iter().enumerate() {
-            if t.msg.sender == msg.sender &&
-               t.msg.mtype == msg.mtype &&
-               t.msg.room == msg.room &&
-               t.msg.body == msg.body {
+    pub fn cancel_tmp_msg(&mut self, txnid: String) {
+        let messages = self.gtk_builder
+            .get_object::<gtk::ListBox>("message_list")
+            .expect("Can't find message_list in ui file.");
 
-                messages.remove(&t.widget);
-                rmidx = Some(i);
-                break;
+        if let Some(idx) = self.tmp_msgs.iter().position(|t| t.msg.txnid == Some(txnid.clone())) {
+            let t = self.tmp_msgs.remove(idx);
+            if let Some(w) = t.widget {
+                messages.remove(&w);
             }
         }
+        self.backend.send(BKCommand::CancelSendMsg(txnid)).unwrap();
+    }
 
-        if rmidx.is_some() {
-            self.tmp_msgs.remove(rmidx.unwrap());
+    pub fn set_outbox(&mut self, msgs: Vec<Message>) {
+        for msg in msgs.iter() {
+            self.add_tmp_room_message(msg);
         }
     }
 
     pub fn update_room_notifications(&mut self, roomid: &str, f: fn(i32) -> i32) {
         if let Some(r) = self.rooms.get_mut(roomid) {
             r.notifications = f(r.notifications);
-            self.roomlist.set_room_notifications(roomid.to_string(), r.notifications);
+            self.roomlist.set_room_notifications(roomid.to_string(), r.notifications, r.highlight);
+        }
+    }
+
+    pub fn update_room_highlight(&mut self, roomid: &str, f: fn(i32) -> i32) {
+        if let Some(r) = self.rooms.get_mut(roomid) {
+            r.highlight = f(r.highlight);
+            self.roomlist.set_room_notifications(roomid.to_string(), r.notifications, r.highlight);
+        }
+    }
+
+    /// Unread counts of the server, these replace the ones that we count locally
+    pub fn set_room_unread(&mut self, roomid: String, n: i32, h: i32) {
+        // the active room is marked as read when the messages are shown
+        if self.active_room.as_ref() == Some(&roomid) {
+            return;
+        }
+
+        if let Some(r) = self.rooms.get_mut(&roomid) {
+            r.notifications = n;
+            r.highlight = h;
+            self.roomlist.set_room_notifications(roomid.clone(), n, h);
+        }
+
+        // read in other client
+        if n == 0 {
+            self.withdraw_notification(&roomid);
+        }
+    }
+
+    pub fn set_push_rules(&mut self, rules: Ruleset) {
+        self.push_rules = Some(rules);
+        self.update_room_notify_action();
+    }
+
+    /// Evaluates the push rules of the user for @msg, None if there are no rules yet or
+    /// if no rule matches
+    pub fn push_actions(&self, msg: &Message) -> Option<PushActions> {
+        let rules = self.push_rules.as_ref()?;
+        let uid = self.uid.clone()?;
+        let room = self.rooms.get(&msg.room)?;
+
+        // the display name in this room, or the global one
+        let name = room.members.get(&uid)
+            .and_then(|m| m.alias.clone())
+            .or(self.username.clone());
+        let ctx = PushContext {
+            user_id: &uid,
+            display_name: name.as_ref().map(|n| &n[..]),
+            member_count: room.members.len().max(room.n_members as usize),
+            power_levels: room.power_levels.as_ref(),
+        };
+
+        rules.evaluate_message(msg, &ctx)
+    }
+
+    /// The user is looking at the messages of @roomid
+    fn is_room_visible(&self, roomid: &str) -> bool {
+        let window: gtk::Window = self.gtk_builder
+            .get_object("main_window")
+            .expect("Can't find main_window in ui file.");
+
+        window.is_active() && self.active_room.as_ref().map(|r| r == roomid).unwrap_or(false)
+    }
+
+    /// Changes the notification level of the active room, it's stored in the push rules
+    pub fn set_room_notify(&mut self, level: RoomNotify) {
+        if let Some(roomid) = self.active_room.clone() {
+            self.backend.send(BKCommand::SetRoomNotify(roomid, level)).unwrap();
+        }
+    }
+
+    // shows the notification level of the active room in the room menu
+    fn update_room_notify_action(&self) {
+        let level = match (self.push_rules.as_ref(), self.active_room.as_ref()) {
+            (Some(rules), Some(roomid)) => rules.room_notify(roomid),
+            _ => RoomNotify::All,
+        };
+
+        if let Some(action) = self.gtk_app.lookup_action("room_notify") {
+            action.change_state(&level.as_str().to_variant());
         }
     }
 
@@ -881,18 +1531,24 @@ impl AppOp {
             return;
         }
 
-        let room = self.active_room.clone();
-        let now = Local::now();
-
+        let room = self.active_room.clone().unwrap_or_default();
+        self.send_text(room, msg);
+    }
+
+    fn send_text(&mut self, roomid: String, msg: String) {
+        let now = Local::now();
+
         let m = Message {
             sender: self.uid.clone().unwrap_or_default(),
             mtype: strn!("m.text"),
             body: msg.clone(),
-            room: room.clone().unwrap_or_default(),
+            room: roomid,
             date: now,
             thumb: None,
             url: None,
             id: None,
+            txnid: Some(api::util::new_txn_id()),
+            info: None,
         };
 
         self.add_tmp_room_message(&m);
@@ -909,33 +1565,546 @@ impl AppOp {
 
         let btn = dialog.add_button("Select", 1);
         btn.get_style_context().unwrap().add_class("suggested-action");
+        dialog.set_select_multiple(true);
+
+        let internal = self.internal.clone();
+        dialog.connect_response(move |dialog, resp| {
+            if resp == 1 {
+                let files = dialog.get_filenames().iter()
+                    .filter_map(|f| f.to_str().map(String::from))
+                    .collect();
+                internal.send(InternalCommand::SendFiles(files, false)).unwrap();
+            }
+            dialog.destroy();
+        });
+
+        let internal = self.internal.clone();
+        dialog.connect_file_activated(move |dialog| {
+            let files = dialog.get_filenames().iter()
+                .filter_map(|f| f.to_str().map(String::from))
+                .collect();
+            internal.send(InternalCommand::SendFiles(files, false)).unwrap();
+            dialog.destroy();
+        });
+
+        dialog.show();
+    }
+
+    /// Starts recording a voice message for the active room, returns false if it can't be
+    /// recorded
+    pub fn start_recording(&mut self) -> bool {
+        let room = match self.active_room {
+            Some(ref r) => r.clone(),
+            None => return false,
+        };
+
+        let name = Local::now().format("Voice message %Y-%m-%d %H-%M-%S.ogg").to_string();
+        let r = api::util::cache_path(&name).map_err(|e| format!("{}", e)).and_then(|path| {
+            let launch = "autoaudiosrc ! audioconvert ! opusenc ! oggmux ! filesink name=sink";
+            let pipeline = gst::parse_launch(launch).map_err(|e| format!("{}", e))?;
+            let sink = pipeline.clone().dynamic_cast::<gst::Bin>().ok()
+                .and_then(|bin| bin.get_by_name("sink"))
+                .ok_or(String::from("no file sink"))?;
+            sink.set_property("location", &path).map_err(|e| format!("{}", e))?;
+
+            if pipeline.set_state(gst::State::Playing) == gst::StateChangeReturn::Failure {
+                return Err(String::from("the microphone isn't available"));
+            }
+
+            Ok(Recording {
+                pipeline: pipeline,
+                path: path,
+                room: room,
+                started: Instant::now(),
+            })
+        });
+
+        match r {
+            Ok(recording) => {
+                self.recording = Some(recording);
+                true
+            }
+            Err(err) => {
+                self.show_error(&format!("Can't record a voice message: {}", err));
+                false
+            }
+        }
+    }
+
+    /// Stops the recording and sends it as an audio file, that is removed after the upload
+    pub fn stop_recording(&mut self) {
+        let rec = match self.recording.take() {
+            Some(r) => r,
+            None => return,
+        };
+
+        let elapsed = rec.started.elapsed();
+        let duration = elapsed.as_secs() * 1000 + elapsed.subsec_nanos() as u64 / 1_000_000;
+
+        // the ogg file is only complete when the end of stream reaches the muxer, and we
+        // wait for it out of the main loop
+        rec.pipeline.send_event(gst::Event::new_eos().build());
+        let backend = self.backend.clone();
+        thread::spawn(move || {
+            if let Some(bus) = rec.pipeline.get_bus() {
+                let timeout = gst::ClockTime::from_seconds(globals::RECORDING_EOS_TIMEOUT);
+                bus.timed_pop_filtered(timeout, &[gst::MessageType::Eos, gst::MessageType::Error]);
+            }
+            rec.pipeline.set_state(gst::State::Null);
+
+            let file = Attachment {
+                path: rec.path,
+                caption: None,
+                max_size: None,
+                strip_metadata: false,
+                temporary: true,
+                duration: Some(duration),
+            };
+            backend.send(BKCommand::AttachFiles(rec.room, vec![file])).unwrap();
+        });
+    }
+
+    /// Downloads the media and opens it with the default application
+    pub fn open_media(&mut self, url: String, name: String) {
+        self.downloads.entry(url.clone())
+            .or_insert(Download { name: name, row: None, open: false })
+            .open = true;
+        self.backend.send(BKCommand::GetMedia(url)).unwrap();
+    }
+
+    pub fn save_media_dialog(&mut self, url: String, name: String) {
+        let window: gtk::ApplicationWindow = self.gtk_builder
+            .get_object("main_window")
+            .expect("Can't find main_window in ui file.");
+        let dialog = gtk::FileChooserDialog::new(Some("Save as"),
+                                                 Some(&window),
+                                                 gtk::FileChooserAction::Save);
+
+        dialog.add_button("Cancel", 0);
+        let btn = dialog.add_button("Save", 1);
+        btn.get_style_context().unwrap().add_class("suggested-action");
+        dialog.set_current_name(&name);
+        dialog.set_do_overwrite_confirmation(true);
+
+        self.downloads.entry(url.clone()).or_insert(Download { name: name, row: None, open: false });
 
         let backend = self.backend.clone();
-        let room = self.active_room.clone().unwrap_or_default();
         dialog.connect_response(move |dialog, resp| {
             if resp == 1 {
                 if let Some(fname) = dialog.get_filename() {
                     let f = strn!(fname.to_str().unwrap_or(""));
-                    backend.send(BKCommand::AttachFile(room.clone(), f)).unwrap();
+                    backend.send(BKCommand::SaveMedia(url.clone(), f)).unwrap();
                 }
             }
             dialog.destroy();
         });
 
+        dialog.show();
+    }
+
+    pub fn media_progress(&mut self, url: String, bytes: u64, total: u64) {
+        let downloads_box = self.gtk_builder
+            .get_object::<gtk::Box>("downloads_box")
+            .expect("Can't find downloads_box in ui file.");
+        let revealer = self.gtk_builder
+            .get_object::<gtk::Revealer>("downloads_revealer")
+            .expect("Can't find downloads_revealer in ui file.");
+
         let backend = self.backend.clone();
-        let room = self.active_room.clone().unwrap_or_default();
-        dialog.connect_file_activated(move |dialog| {
-            if let Some(fname) = dialog.get_filename() {
-                let f = strn!(fname.to_str().unwrap_or(""));
-                backend.send(BKCommand::AttachFile(room.clone(), f)).unwrap();
+        let download = self.downloads.entry(url.clone())
+            .or_insert(Download { name: url.clone(), row: None, open: false });
+
+        if download.row.is_none() {
+            let (row, progress) = transfer_row(&download.name, "Cancel download", move || {
+                backend.send(BKCommand::CancelMedia(url.clone())).unwrap();
+            });
+            downloads_box.add(&row);
+            revealer.set_reveal_child(true);
+
+            download.row = Some((row, progress));
+        }
+
+        if let Some((_, ref progress)) = download.row {
+            set_transfer_progress(progress, bytes, total);
+        }
+    }
+
+    pub fn media_downloaded(&mut self, url: String) {
+        if let Some(Download { row: Some((row, _)), .. }) = self.downloads.remove(&url) {
+            row.destroy();
+        }
+
+        if !self.downloads.values().any(|d| d.row.is_some()) {
+            let revealer = self.gtk_builder
+                .get_object::<gtk::Revealer>("downloads_revealer")
+                .expect("Can't find downloads_revealer in ui file.");
+            revealer.set_reveal_child(false);
+        }
+    }
+
+    /// The downloaded media is shown in the viewer if it's the image that it's waiting for,
+    /// and opened with the default application if the user asked for it. The images that
+    /// the viewer isn't showing anymore are dropped.
+    pub fn media_ready(&mut self, url: String, fname: String) {
+        let open = self.downloads.get(&url).map(|d| d.open).unwrap_or(false);
+        self.media_downloaded(url.clone());
+
+        let waiting = match self.viewer {
+            Some(MediaViewer { loading: Some(ref u), .. }) => *u == url,
+            _ => false,
+        };
+
+        if waiting {
+            self.media_viewer_loaded(fname.clone());
+        }
+
+        if open {
+            if let Err(err) = Command::new("xdg-open").arg(&fname).spawn() {
+                self.show_error(&format!("Can't open the file: {}", err));
             }
-            dialog.destroy();
+        }
+    }
+
+    pub fn media_failed(&mut self, url: String, err: api::error::Error) {
+        self.media_downloaded(url.clone());
+
+        if let Some(ref mut viewer) = self.viewer {
+            if viewer.loading == Some(url) {
+                viewer.loading = None;
+                let image = self.gtk_builder
+                    .get_object::<gtk::Image>("media_viewer_image")
+                    .expect("Can't find media_viewer_image in ui file.");
+                image.set_from_icon_name("image-missing-symbolic", 6);
+            }
+        }
+
+        match err {
+            api::error::Error::Cancelled => {}
+            err => self.show_error(&format!("Can't download the file: {}", err)),
+        };
+    }
+
+    /// Opens the image in the media viewer, with the other images of the room as the gallery
+    pub fn show_image(&mut self, msg: Message) {
+        let mut media: Vec<Message> = match self.rooms.get(&msg.room) {
+            Some(r) => r.messages.iter()
+                .filter(|m| m.mtype == "m.image" && m.url.is_some())
+                .cloned()
+                .collect(),
+            None => vec![],
+        };
+
+        let pos = media.iter().position(|m| m.id == msg.id && m.url == msg.url);
+        let current = match pos {
+            Some(i) => i,
+            None => {
+                media.push(msg);
+                media.len() - 1
+            }
+        };
+
+        self.set_state(AppState::MediaViewer);
+        self.viewer = Some(MediaViewer {
+            media: media,
+            current: current,
+            zoom: None,
+            loading: None,
+            pixbuf: None,
+            animation: None,
+            size: (0, 0),
         });
 
-        dialog.show();
+        self.media_viewer_load();
+    }
+
+    pub fn close_media_viewer(&mut self) {
+        self.set_state(AppState::Chat);
+    }
+
+    /// Moves @delta images forward or backward in the gallery
+    pub fn media_viewer_go(&mut self, delta: i32) {
+        let moved = match self.viewer {
+            Some(ref mut viewer) => {
+                let next = viewer.current as i32 + delta;
+                if next >= 0 && (next as usize) < viewer.media.len() {
+                    viewer.current = next as usize;
+                    viewer.zoom = None;
+                    true
+                } else {
+                    false
+                }
+            }
+            None => false,
+        };
+
+        if moved {
+            self.media_viewer_load();
+        }
+    }
+
+    fn media_viewer_load(&mut self) {
+        let msg = match self.viewer {
+            Some(ref mut viewer) => {
+                let msg = viewer.media[viewer.current].clone();
+                viewer.pixbuf = None;
+                viewer.animation = None;
+                viewer.loading = msg.url.clone();
+
+                let prev = self.gtk_builder
+                    .get_object::<gtk::Button>("media_viewer_prev")
+                    .expect("Can't find media_viewer_prev in ui file.");
+                let next = self.gtk_builder
+                    .get_object::<gtk::Button>("media_viewer_next")
+                    .expect("Can't find media_viewer_next in ui file.");
+                prev.set_sensitive(viewer.current > 0);
+                next.set_sensitive(viewer.current + 1 < viewer.media.len());
+
+                msg
+            }
+            None => return,
+        };
+
+        let name = self.gtk_builder
+            .get_object::<gtk::Label>("media_viewer_name")
+            .expect("Can't find media_viewer_name in ui file.");
+        let image = self.gtk_builder
+            .get_object::<gtk::Image>("media_viewer_image")
+            .expect("Can't find media_viewer_image in ui file.");
+        name.set_text(&msg.body);
+        image.set_from_icon_name("image-loading-symbolic", 6);
+        self.media_viewer_buttons();
+
+        let url = msg.url.clone().unwrap_or_default();
+        self.downloads.entry(url.clone()).or_insert(Download { name: msg.body.clone(), row: None, open: false });
+        self.backend.send(BKCommand::GetMedia(url)).unwrap();
+    }
+
+    fn media_viewer_loaded(&mut self, fname: String) {
+        if let Some(ref mut viewer) = self.viewer {
+            viewer.loading = None;
+            viewer.pixbuf = Pixbuf::new_from_file(&fname).ok();
+            viewer.animation = match PixbufAnimation::new_from_file(&fname) {
+                Ok(ref a) if a.is_static_image() => None,
+                Ok(a) => Some(a),
+                Err(_) => None,
+            };
+        }
+
+        self.media_viewer_draw();
+    }
+
+    /// The scale that makes the image fit in the viewer, images smaller than the viewer aren't
+    /// scaled up
+    fn media_viewer_fit_scale(&self, pixbuf: &Pixbuf) -> f64 {
+        let scroll = self.gtk_builder
+            .get_object::<gtk::ScrolledWindow>("media_viewer_scroll")
+            .expect("Can't find media_viewer_scroll in ui file.");
+        let w = scroll.get_allocated_width() as f64 / pixbuf.get_width() as f64;
+        let h = scroll.get_allocated_height() as f64 / pixbuf.get_height() as f64;
+
+        w.min(h).min(1.0)
+    }
+
+    fn media_viewer_scale(&self) -> Option<f64> {
+        let viewer = self.viewer.as_ref()?;
+        let pixbuf = viewer.pixbuf.as_ref()?;
+
+        Some(viewer.zoom.unwrap_or_else(|| self.media_viewer_fit_scale(pixbuf)))
+    }
+
+    fn media_viewer_draw(&self) {
+        let image = self.gtk_builder
+            .get_object::<gtk::Image>("media_viewer_image")
+            .expect("Can't find media_viewer_image in ui file.");
+        self.media_viewer_buttons();
+
+        let viewer = match self.viewer {
+            Some(ref v) => v,
+            None => return,
+        };
+
+        // GtkImage can't scale animations, so they're always shown at their size
+        if let Some(ref animation) = viewer.animation {
+            image.set_from_animation(animation);
+            return;
+        }
+
+        let pixbuf = match viewer.pixbuf {
+            Some(ref p) => p,
+            None => {
+                image.set_from_icon_name("image-missing-symbolic", 6);
+                return;
+            }
+        };
+
+        let scale = self.media_viewer_scale().unwrap_or(1.0);
+        if scale == 1.0 {
+            image.set_from_pixbuf(pixbuf);
+            return;
+        }
+
+        let w = ((pixbuf.get_width() as f64 * scale) as i32).max(1);
+        let h = ((pixbuf.get_height() as f64 * scale) as i32).max(1);
+        if let Ok(scaled) = pixbuf.scale_simple(w, h, gdk_pixbuf_sys::GDK_INTERP_BILINEAR) {
+            image.set_from_pixbuf(&scaled);
+        }
+    }
+
+    fn media_viewer_buttons(&self) {
+        let (zoomable, loaded) = match self.viewer {
+            Some(ref v) => (v.pixbuf.is_some() && v.animation.is_none(), v.pixbuf.is_some()),
+            None => (false, false),
+        };
+
+        for id in ["media_viewer_zoom_out", "media_viewer_zoom_fit", "media_viewer_zoom_in"].iter() {
+            self.gtk_builder
+                .get_object::<gtk::Button>(id)
+                .expect("Can't find media viewer zoom buttons in ui file.")
+                .set_sensitive(zoomable);
+        }
+
+        self.gtk_builder
+            .get_object::<gtk::Button>("media_viewer_copy")
+            .expect("Can't find media_viewer_copy in ui file.")
+            .set_sensitive(loaded);
     }
 
-    pub fn load_more_messages(&self) {
+    /// Zooms in for positive @steps and out for negative ones, starting from the current scale
+    pub fn media_viewer_zoom(&mut self, steps: i32) {
+        let scale = match self.media_viewer_scale() {
+            Some(s) => s,
+            None => return,
+        };
+
+        let zoom = scale * globals::ZOOM_STEP.powi(steps);
+        if let Some(ref mut viewer) = self.viewer {
+            viewer.zoom = Some(zoom.max(globals::MIN_ZOOM).min(globals::MAX_ZOOM));
+        }
+
+        self.media_viewer_draw();
+    }
+
+    pub fn media_viewer_zoom_fit(&mut self) {
+        if let Some(ref mut viewer) = self.viewer {
+            viewer.zoom = None;
+        }
+
+        self.media_viewer_draw();
+    }
+
+    /// Redraws the image when the window changes and the image is fitted to it
+    pub fn media_viewer_resized(&mut self, w: i32, h: i32) {
+        let redraw = match self.viewer {
+            Some(ref mut viewer) if viewer.size != (w, h) => {
+                viewer.size = (w, h);
+                viewer.zoom.is_none()
+            }
+            _ => false,
+        };
+
+        if redraw {
+            self.media_viewer_draw();
+        }
+    }
+
+    pub fn media_viewer_save(&mut self) {
+        let msg = match self.viewer {
+            Some(ref viewer) => viewer.media[viewer.current].clone(),
+            None => return,
+        };
+
+        self.save_media_dialog(msg.url.unwrap_or_default(), msg.body);
+    }
+
+    pub fn media_viewer_copy(&self) {
+        let pixbuf = match self.viewer {
+            Some(MediaViewer { pixbuf: Some(ref p), .. }) => p,
+            _ => return,
+        };
+
+        if let Some(display) = gdk::Display::get_default() {
+            if let Some(clipboard) = gtk::Clipboard::get_default(&display) {
+                clipboard.set_image(pixbuf);
+            }
+        }
+    }
+
+    /// Shows the file upload in the composer, so it can be cancelled
+    pub fn upload_started(&mut self, msg: Message, size: u64) {
+        let uploads_box = self.gtk_builder
+            .get_object::<gtk::Box>("uploads_box")
+            .expect("Can't find uploads_box in ui file.");
+        let revealer = self.gtk_builder
+            .get_object::<gtk::Revealer>("uploads_revealer")
+            .expect("Can't find uploads_revealer in ui file.");
+
+        let backend = self.backend.clone();
+        let txnid = msg.txnid.clone().unwrap_or_default();
+        // the row of a failed upload that we're trying again
+        if let Some((row, _)) = self.uploads.remove(&txnid) {
+            row.destroy();
+        }
+        let id = txnid.clone();
+        let (row, progress) = transfer_row(&msg.body, "Cancel upload", move || {
+            backend.send(BKCommand::CancelUpload(id.clone())).unwrap();
+        });
+        set_transfer_progress(&progress, 0, size);
+
+        uploads_box.add(&row);
+        revealer.set_reveal_child(true);
+
+        self.uploads.insert(txnid, (row, progress));
+    }
+
+    pub fn upload_progress(&mut self, txnid: String, bytes: u64, total: u64) {
+        if let Some(&(_, ref progress)) = self.uploads.get(&txnid) {
+            set_transfer_progress(progress, bytes, total);
+        }
+    }
+
+    /// Keeps the failed upload in the composer, so it can be tried again or cancelled
+    pub fn upload_failed(&mut self, txnid: String) {
+        if let Some(&(ref row, ref progress)) = self.uploads.get(&txnid) {
+            progress.set_text(Some("Failed"));
+
+            let retry = gtk::Button::new_from_icon_name("view-refresh-symbolic", 1);
+            retry.set_relief(gtk::ReliefStyle::None);
+            retry.set_tooltip_text(Some("Try again"));
+            let backend = self.backend.clone();
+            retry.connect_clicked(move |b| {
+                b.set_sensitive(false);
+                backend.send(BKCommand::RetryUpload(txnid.clone())).unwrap();
+            });
+
+            row.pack_start(&retry, false, false, 0);
+            row.reorder_child(&retry, 2);
+            retry.show();
+        }
+    }
+
+    pub fn upload_finished(&mut self, txnid: String) {
+        if let Some((row, _)) = self.uploads.remove(&txnid) {
+            row.destroy();
+        }
+
+        if self.uploads.is_empty() {
+            let revealer = self.gtk_builder
+                .get_object::<gtk::Revealer>("uploads_revealer")
+                .expect("Can't find uploads_revealer in ui file.");
+            revealer.set_reveal_child(false);
+        }
+    }
+
+    pub fn load_more_messages(&mut self) {
+        if self.loading_more {
+            return;
+        }
+
+        if self.chunk.is_some() {
+            self.load_chunk_messages(Direction::Backward);
+            return;
+        }
+
         if let Some(r) = self.rooms.get(&self.active_room.clone().unwrap_or_default()) {
             if self.shown_messages < r.messages.len() {
                 let msgs = r.messages.iter().rev()
@@ -949,7 +2118,13 @@ impl AppOp {
                                                                   i == msgs.len() - 1);
                     self.internal.send(command).unwrap();
                 }
+            } else if let Some(ref token) = r.prev_batch {
+                self.loading_more = true;
+                self.load_more_btn.set_label("loading...");
+                self.backend.send(BKCommand::BackPaginate(r.id.clone(), token.clone())).unwrap();
             } else if let Some(m) = r.messages.get(0) {
+                // without a pagination token we can only ask for the context of the oldest message
+                self.loading_more = true;
                 self.load_more_btn.set_label("loading...");
                 self.backend.send(BKCommand::GetMessageContext(m.clone())).unwrap();
             }
@@ -958,6 +2133,42 @@ impl AppOp {
 
     pub fn load_more_normal(&self) {
         self.load_more_btn.set_label("load more messages");
+        self.gtk_builder
+            .get_object::<gtk::Button>("load_newer_button")
+            .expect("Can't find load_newer_button in ui file.")
+            .set_label("Load newer messages");
+    }
+
+    /// Gets the next page of the chunk that we're showing, in the @dir direction
+    pub fn load_chunk_messages(&mut self, dir: Direction) {
+        if self.loading_more {
+            return;
+        }
+
+        let page = self.chunk.as_ref().and_then(|c| {
+            let token = match dir {
+                Direction::Backward => c.prev_batch.clone(),
+                Direction::Forward => c.next_batch.clone(),
+            };
+            token.map(|t| (c.room.clone(), t))
+        });
+
+        if let Some((room, token)) = page {
+            self.loading_more = true;
+            match dir {
+                Direction::Backward => self.load_more_btn.set_label("loading..."),
+                Direction::Forward => self.gtk_builder
+                    .get_object::<gtk::Button>("load_newer_button")
+                    .expect("Can't find load_newer_button in ui file.")
+                    .set_label("loading..."),
+            };
+            self.backend.send(BKCommand::ChunkPaginate(room, token, dir)).unwrap();
+        }
+    }
+
+    pub fn load_more_error(&mut self) {
+        self.loading_more = false;
+        self.load_more_normal();
     }
 
     pub fn init_protocols(&self) {
@@ -970,8 +2181,13 @@ impl AppOp {
             .expect("Can't find protocol_model in ui file.");
         combo.clear();
 
-        for p in protocols {
-            combo.insert_with_values(None, &[0, 1], &[&p.desc, &p.id]);
+        for (i, p) in protocols.iter().enumerate() {
+            combo.insert_with_values(None, &[0, 1, 2], &[&p.desc, &p.id, &false]);
+
+            // after our server, its rooms with the ones of all the networks that it bridges
+            if i == 0 {
+                combo.insert_with_values(None, &[0, 1, 2], &[&"All networks", &"", &true]);
+            }
         }
 
         self.gtk_builder
@@ -980,7 +2196,12 @@ impl AppOp {
             .set_active(0);
     }
 
-    pub fn search_rooms(&self, more: bool) {
+    pub fn search_rooms(&mut self, more: bool) {
+        if more {
+            self.load_more_rooms();
+            return;
+        }
+
         let combo_store = self.gtk_builder
             .get_object::<gtk::ListStore>("protocol_model")
             .expect("Can't find protocol_model in ui file.");
@@ -989,40 +2210,86 @@ impl AppOp {
             .expect("Can't find directory_combo in ui file.");
 
         let active = combo.get_active();
-        let protocol: String = match combo_store.iter_nth_child(None, active) {
+        let (protocol, all): (String, bool) = match combo_store.iter_nth_child(None, active) {
             Some(it) => {
-                let v = combo_store.get_value(&it, 1);
-                v.get().unwrap()
+                let p = combo_store.get_value(&it, 1);
+                let a = combo_store.get_value(&it, 2);
+                (p.get().unwrap_or_default(), a.get().unwrap_or(false))
             }
-            None => String::from(""),
+            None => (String::from(""), false),
         };
 
         let q = self.gtk_builder
             .get_object::<gtk::Entry>("directory_search_entry")
             .expect("Can't find directory_search_entry in ui file.");
+        let server = self.gtk_builder
+            .get_object::<gtk::Entry>("directory_server_entry")
+            .expect("Can't find directory_server_entry in ui file.");
 
-        let btn = self.gtk_builder
-            .get_object::<gtk::Button>("directory_search_button")
-            .expect("Can't find directory_search_button in ui file.");
-        btn.set_label("Searching...");
-        btn.set_sensitive(false);
+        let not_empty = |s: Option<String>| match s {
+            Some(ref s) if s.trim().is_empty() => None,
+            s => s.map(|s| String::from(s.trim())),
+        };
 
-        if !more {
-            let directory = self.gtk_builder
-                .get_object::<gtk::ListBox>("directory_room_list")
-                .expect("Can't find directory_room_list in ui file.");
-            for ch in directory.get_children() {
-                directory.remove(&ch);
-            }
+        // the networks bridged by our server aren't the ones of the other server
+        let server = not_empty(server.get_text());
+        let (protocol, all) = match server {
+            Some(_) => (String::from(""), false),
+            None => (protocol, all),
+        };
+
+        self.directory_searches += 1;
+        let query = DirectoryQuery {
+            term: not_empty(q.get_text()),
+            server: server,
+            third_party: not_empty(Some(protocol)),
+            all_networks: all,
+            limit: globals::DIRECTORY_PAGE_LIMIT,
+            search: self.directory_searches,
+        };
+        self.directory = Some(Directory { query: query.clone(), next_batch: None, loading: true });
+
+        let directory = self.gtk_builder
+            .get_object::<gtk::ListBox>("directory_room_list")
+            .expect("Can't find directory_room_list in ui file.");
+        for ch in directory.get_children() {
+            directory.remove(&ch);
         }
 
-        self.backend
-            .send(BKCommand::DirectorySearch(q.get_text().unwrap(), protocol, more))
-            .unwrap();
+        self.directory_searching(true);
+        self.backend.send(BKCommand::DirectorySearch(query, None)).unwrap();
+    }
+
+    /// Gets the next page of the current directory search, if there's one
+    pub fn load_more_rooms(&mut self) {
+        let page = match self.directory {
+            Some(ref mut d) if !d.loading => d.next_batch.clone().map(|nb| {
+                d.loading = true;
+                (d.query.clone(), nb)
+            }),
+            _ => None,
+        };
+
+        if let Some((query, next_batch)) = page {
+            self.directory_searching(true);
+            self.backend.send(BKCommand::DirectorySearch(query, Some(next_batch))).unwrap();
+        }
     }
 
-    pub fn load_more_rooms(&self) {
-        self.search_rooms(true);
+    pub fn set_directory_rooms(&mut self, page: DirectoryPage) {
+        // rooms of a search that isn't the current one
+        match self.directory {
+            Some(ref mut d) if d.query == page.query => {
+                d.next_batch = page.next_batch.clone();
+                d.loading = false;
+            }
+            _ => return,
+        }
+
+        self.directory_searching(false);
+        for room in page.rooms {
+            self.set_directory_room(room);
+        }
     }
 
     pub fn set_directory_room(&self, room: Room) {
@@ -1030,66 +2297,191 @@ impl AppOp {
             .get_object::<gtk::ListBox>("directory_room_list")
             .expect("Can't find directory_room_list in ui file.");
 
-        let rb = widgets::RoomBox::new(&room, &self);
+        let remote = self.directory.as_ref().map(|d| d.query.server.is_some()).unwrap_or(false);
+        let rb = widgets::RoomBox::new(&room, &self, remote);
         let room_widget = rb.widget();
         directory.add(&room_widget);
+    }
 
+    pub fn directory_error(&mut self, err: String) {
+        if let Some(ref mut d) = self.directory {
+            d.loading = false;
+        }
+        self.directory_searching(false);
+        self.show_error(&format!("Can't search the room directory: {}", err));
+    }
+
+    fn directory_searching(&self, searching: bool) {
         let btn = self.gtk_builder
             .get_object::<gtk::Button>("directory_search_button")
             .expect("Can't find directory_search_button in ui file.");
-        btn.set_label("Search");
-        btn.set_sensitive(true);
+        btn.set_label(match searching {
+            true => "Searching...",
+            false => "Search",
+        });
+        btn.set_sensitive(!searching);
+    }
+
+    pub fn show_room_preview(&self, room: Room) {
+        let window: gtk::Window = self.gtk_builder
+            .get_object("main_window")
+            .expect("Can't find main_window in ui file.");
+
+        widgets::RoomPreview::new(&room, self).dialog(&window);
+    }
+
+    /// Shows a desktop notification for @msg, when the sender info is ready
+    pub fn notify(&self, msg: &Message) {
+        let (tx, rx): (Sender<(String, String)>, Receiver<(String, String)>) = channel();
+        self.backend.send(BKCommand::GetUserInfoAsync(msg.sender.clone(), tx)).unwrap();
+        let internal = self.internal.clone();
+        let m = msg.clone();
+        gtk::timeout_add(50, move || match rx.try_recv() {
+            Err(TryRecvError::Empty) => gtk::Continue(true),
+            Err(TryRecvError::Disconnected) => gtk::Continue(false),
+            Ok((name, avatar)) => {
+                internal.send(InternalCommand::ShowNotification(m.clone(), name, avatar)).unwrap();
+                gtk::Continue(false)
+            }
+        });
+    }
+
+    /// There's one notification for each room, with the last messages that aren't read yet
+    pub fn show_notification(&mut self, msg: Message, name: String, avatar: String) {
+        let roomname = match self.rooms.get(&msg.room) {
+            Some(r) => r.name.clone().unwrap_or_default(),
+            None => msg.room.clone(),
+        };
+
+        let body = msg.body.chars().take(NOTIFICATION_BODY_LEN).collect::<String>();
+        let lines = self.notifications.entry(msg.room.clone()).or_insert(vec![]);
+        lines.push(format!("{}: {}", name, body));
+
+        let (title, text) = match lines.len() {
+            1 => (format!("{} / {}", name, roomname), body),
+            n => {
+                let last = &lines[n.saturating_sub(NOTIFICATION_LINES)..];
+                (format!("{} ({} new messages)", roomname, n), last.join("\n"))
+            }
+        };
+
+        let notification = gio::Notification::new(&title);
+        notification.set_body(Some(&text[..]));
+        if !avatar.is_empty() {
+            notification.set_icon(&gio::FileIcon::new(&gio::File::new_for_path(&avatar)));
+        }
+
+        let target = msg.room.to_variant();
+        notification.set_default_action_and_target_value("app.open_room", Some(&target));
+        notification.add_button_with_target_value("Reply", "app.reply_room", Some(&target));
+        notification.add_button_with_target_value("Mark as read", "app.mark_room_read", Some(&target));
+
+        self.gtk_app.send_notification(Some(&notification_id(&msg.room)[..]), &notification);
+    }
+
+    /// Removes the notification of @roomid, after the room is read here or in other client
+    pub fn withdraw_notification(&mut self, roomid: &str) {
+        if self.notifications.remove(roomid).is_some() {
+            self.gtk_app.withdraw_notification(&notification_id(roomid));
+        }
+    }
+
+    pub fn withdraw_notifications(&mut self) {
+        let rooms = self.notifications.keys().cloned().collect::<Vec<String>>();
+        for roomid in rooms {
+            self.withdraw_notification(&roomid);
+        }
+    }
+
+    /// Brings the window up and opens the room of the clicked notification
+    pub fn notification_opened(&mut self, roomid: String) {
+        self.activate();
+        self.select_room(roomid);
     }
 
-    pub fn notify(&self, msg: &Message) {
-        let roomname = match self.rooms.get(&msg.room) {
+    /// Asks for a reply to the notification of @roomid, that is sent without opening the room
+    pub fn reply_dialog(&self, roomid: String) {
+        let window: gtk::Window = self.gtk_builder
+            .get_object("main_window")
+            .expect("Couldn't find main_window in ui file.");
+        let roomname = match self.rooms.get(&roomid) {
             Some(r) => r.name.clone().unwrap_or_default(),
-            None => msg.room.clone(),
+            None => roomid.clone(),
         };
 
-        let mut body = msg.body.clone();
-        body.truncate(80);
+        let dialog = gtk::Dialog::new_with_buttons(
+            Some(&roomname[..]),
+            Some(&window),
+            gtk::DialogFlags::USE_HEADER_BAR|
+            gtk::DialogFlags::DESTROY_WITH_PARENT,
+            &[]);
+        let btn = dialog.add_button("Send", 1);
+        btn.get_style_context().unwrap().add_class("suggested-action");
+        dialog.set_default_response(1);
+
+        // the messages of the notification, to see what we're replying to
+        let lines = self.notifications.get(&roomid).cloned().unwrap_or_default();
+        let last = &lines[lines.len().saturating_sub(NOTIFICATION_LINES)..];
+        let label = gtk::Label::new(Some(&last.join("\n")[..]));
+        label.set_line_wrap(true);
+        label.set_halign(gtk::Align::Start);
+        label.get_style_context().unwrap().add_class("dim-label");
+
+        let entry = gtk::Entry::new();
+        entry.set_activates_default(true);
+        entry.set_placeholder_text(Some("Reply"));
+
+        let content = dialog.get_content_area();
+        content.set_spacing(6);
+        content.set_border_width(6);
+        content.pack_start(&label, false, false, 0);
+        content.pack_start(&entry, false, false, 0);
+
+        let internal = self.internal.clone();
+        dialog.connect_response(move |d, resp| {
+            match entry.get_text() {
+                Some(ref text) if resp == 1 && !text.is_empty() => {
+                    let command = InternalCommand::SendReply(roomid.clone(), text.clone());
+                    internal.send(command).unwrap();
+                }
+                _ => {}
+            };
+            d.destroy();
+        });
 
-        let (tx, rx): (Sender<(String, String)>, Receiver<(String, String)>) = channel();
-        self.backend.send(BKCommand::GetUserInfoAsync(msg.sender.clone(), tx)).unwrap();
-        let bk = self.internal.clone();
-        let m = msg.clone();
-        gtk::timeout_add(50, move || match rx.try_recv() {
-            Err(TryRecvError::Empty) => gtk::Continue(true),
-            Err(TryRecvError::Disconnected) => gtk::Continue(false),
-            Ok((name, avatar)) => {
-                let summary = format!("@{} / {}", name, roomname);
-
-                let bk = bk.clone();
-                let m = m.clone();
-                let body = body.clone();
-                let summary = summary.clone();
-                let avatar = avatar.clone();
-                thread::spawn(move || {
-                    let mut notification = Notification::new();
-                    notification.summary(&summary);
-                    notification.body(&body);
-                    notification.icon(&avatar);
-                    notification.action("default", "default");
-
-                    if let Ok(n) = notification.show() {
-                        n.wait_for_action({|action|
-                            match action {
-                                "default" => {
-                                    bk.send(InternalCommand::NotifyClicked(m)).unwrap();
-                                },
-                                _ => ()
-                            }
-                        });
-                    }
-                });
+        dialog.show_all();
+        dialog.present();
+    }
 
-                gtk::Continue(false)
-            }
-        });
+    /// Sends the reply written in the notification dialog, the room is read after it
+    pub fn send_reply(&mut self, roomid: String, text: String) {
+        self.send_text(roomid.clone(), text);
+        self.mark_room_as_read(roomid);
+    }
+
+    /// Marks the last message of @roomid as read, without opening the room
+    pub fn mark_room_as_read(&mut self, roomid: String) {
+        let last = self.rooms.get(&roomid).and_then(|r| r.messages.last().cloned());
+        if let Some(msg) = last {
+            self.mark_as_read(&msg);
+        }
+        self.withdraw_notification(&roomid);
     }
 
     pub fn show_room_messages(&mut self, msgs: Vec<Message>, init: bool) -> Option<()> {
+        // the newest messages of a chunk that reached the live timeline are there already
+        let msgs: Vec<Message> = match init {
+            true => msgs,
+            false => {
+                let rooms = &self.rooms;
+                msgs.into_iter().filter(|m| {
+                    m.id.is_none() ||
+                    rooms.get(&m.room).map(|r| r.messages.iter().all(|x| x.id != m.id)).unwrap_or(true)
+                }).collect()
+            }
+        };
+
+        self.index.add(&msgs);
         for msg in msgs.iter() {
             if let Some(r) = self.rooms.get_mut(&msg.room) {
                 r.messages.push(msg.clone());
@@ -1098,17 +2490,21 @@ impl AppOp {
 
         let mut prev = None;
         for msg in msgs.iter() {
-            let mut should_notify = msg.body.contains(&self.username.clone()?);
-            // not notifying the initial messages
-            should_notify = should_notify && !init;
-            // not notifying my own messages
-            should_notify = should_notify && (msg.sender != self.uid.clone()?);
+            // not notifying the initial messages, nor my own messages
+            let actions = match !init && msg.sender != self.uid.clone()? {
+                true => self.push_actions(msg).unwrap_or_default(),
+                false => PushActions::default(),
+            };
+            let highlight = actions.highlight;
 
-            if should_notify {
+            if actions.notify && !self.is_room_visible(&msg.room) {
                 self.notify(msg);
             }
 
             self.add_room_message(msg, MsgPos::Bottom, prev, false);
+            if highlight && msg.room != self.active_room.clone().unwrap_or_default() {
+                self.update_room_highlight(&msg.room, |h| h + 1);
+            }
             prev = Some(msg.clone());
 
             if !init {
@@ -1119,10 +2515,13 @@ impl AppOp {
         if !msgs.is_empty() {
             let fs = msgs.iter().filter(|x| x.room == self.active_room.clone().unwrap_or_default());
             if let Some(msg) = fs.last() {
-                if self.autoscroll {
-                    self.scroll_down();
+                // the live messages aren't seen while a chunk is shown instead
+                if self.chunk.is_none() {
+                    if self.autoscroll {
+                        self.scroll_down();
+                    }
+                    self.mark_as_read(msg);
                 }
-                self.mark_as_read(msg);
             }
         }
 
@@ -1134,6 +2533,8 @@ impl AppOp {
     }
 
     pub fn show_room_messages_top(&mut self, msgs: Vec<Message>) {
+        self.loading_more = false;
+        self.index.add(&msgs);
         if msgs.is_empty() {
             self.load_more_normal();
             return;
@@ -1160,6 +2561,46 @@ impl AppOp {
         self.load_more_normal();
     }
 
+    /// Back pagination result, @token is the one to use to get the previous page, and there's
+    /// nothing more to load without it. The page can be empty when the server skips the
+    /// events that aren't messages, then we go on with the next one.
+    pub fn show_room_messages_back(&mut self, roomid: String, msgs: Vec<Message>, token: Option<String>) {
+        self.index.add(&msgs);
+        let more = msgs.is_empty() && token.is_some();
+        if let Some(r) = self.rooms.get_mut(&roomid) {
+            r.prev_batch = token;
+        }
+
+        if self.active_room.clone().unwrap_or_default() == roomid {
+            self.show_room_messages_top(msgs);
+            if more {
+                self.load_more_messages();
+            }
+        } else if let Some(r) = self.rooms.get_mut(&roomid) {
+            for msg in msgs.iter().rev() {
+                r.messages.insert(0, msg.clone());
+            }
+        }
+    }
+
+    /// The sync skipped some messages in this room, so the stored ones aren't contiguous
+    /// with the new ones anymore. We drop them and paginate from @prev_batch.
+    pub fn room_timeline_limited(&mut self, roomid: String, prev_batch: String) {
+        if let Some(r) = self.rooms.get_mut(&roomid) {
+            r.messages.clear();
+            r.prev_batch = Some(prev_batch);
+        }
+
+        if self.active_room.clone().unwrap_or_default() == roomid {
+            self.clear_tmp_msgs();
+            self.remove_messages();
+            self.show_tmp_msgs();
+            self.shown_messages = 0;
+            self.loading_more = false;
+            self.load_more_normal();
+        }
+    }
+
     pub fn show_room_dialog(&self) {
         let dialog = self.gtk_builder
             .get_object::<gtk::Dialog>("room_config_dialog")
@@ -1288,6 +2729,48 @@ impl AppOp {
         self.roomlist.rename_room(roomid.clone(), name);
     }
 
+    pub fn set_room_tags(&mut self, roomid: String, tags: HashMap<String, Option<f64>>) {
+        match self.rooms.get_mut(&roomid) {
+            Some(r) => r.tags = tags.clone(),
+            None => return,
+        };
+
+        self.roomlist.set_room_tags(roomid, tags);
+    }
+
+    /// Moves the room from the section of the tag @from to the section of the tag @to, with
+    /// the @order in the new tag. The sections without tag are the rooms without tags.
+    pub fn move_room(&mut self, roomid: String, from: Option<String>, to: Option<String>, order: Option<f64>) {
+        let mut tags = match self.rooms.get(&roomid) {
+            Some(r) => r.tags.clone(),
+            None => return,
+        };
+
+        let mut old: Vec<String> = from.into_iter().collect();
+        // the favourites and low priority sections exclude each other and "Rooms", so the
+        // room leaves all of them, or it would still be shown in one of them
+        let priority = [widgets::FAVOURITE_TAG, widgets::LOW_PRIORITY_TAG];
+        if to.as_ref().map(|t| priority.contains(&&t[..])).unwrap_or(true) {
+            old.extend(priority.iter().filter(|t| tags.contains_key(**t)).map(|t| t.to_string()));
+        }
+        old.sort();
+        old.dedup();
+
+        for t in old {
+            if Some(&t) != to.as_ref() {
+                tags.remove(&t);
+                self.backend.send(BKCommand::RemoveRoomTag(roomid.clone(), t)).unwrap();
+            }
+        }
+
+        if let Some(t) = to {
+            tags.insert(t.clone(), order);
+            self.backend.send(BKCommand::AddRoomTag(roomid.clone(), t, order)).unwrap();
+        }
+
+        self.set_room_tags(roomid, tags);
+    }
+
     pub fn room_topic_change(&mut self, roomid: String, topic: Option<String>) {
         if !self.rooms.contains_key(&roomid) {
             return;
@@ -1331,6 +2814,12 @@ impl AppOp {
         self.backend.send(BKCommand::GetRoomAvatar(roomid)).unwrap();
     }
 
+    pub fn set_room_power_levels(&mut self, roomid: String, levels: JsonValue) {
+        if let Some(r) = self.rooms.get_mut(&roomid) {
+            r.power_levels = Some(levels);
+        }
+    }
+
     pub fn room_member_event(&mut self, ev: Event) {
         // NOTE: maybe we should show this events in the message list to notify enters and leaves
         // to the user
@@ -1383,18 +2872,398 @@ impl AppOp {
         }
     }
 
-    pub fn toggle_search(&self) {
+    pub fn toggle_search(&mut self) {
         let r: gtk::Revealer = self.gtk_builder
             .get_object("search_revealer")
             .expect("Couldn't find search_revealer in ui file.");
-        r.set_reveal_child(!r.get_child_revealed());
+        let reveal = !r.get_child_revealed();
+        r.set_reveal_child(reveal);
+
+        if !reveal {
+            self.close_search_results();
+        }
     }
 
+    /// Searches @term in the active room or in all the rooms, depending on the scope chosen
+    /// in the search bar. The results are shown in their own view, over the messages.
     pub fn search(&mut self, term: Option<String>) {
-        let r = self.active_room.clone().unwrap_or_default();
+        let term = term.unwrap_or_default();
+        if term.is_empty() {
+            self.close_search_results();
+            return;
+        }
+
+        let scope = self.gtk_builder
+            .get_object::<gtk::ComboBoxText>("search_scope")
+            .expect("Can't find search_scope in ui file.");
+        let room = match scope.get_active_id() {
+            Some(ref s) if s == "all" => None,
+            _ => self.active_room.clone(),
+        };
+
+        // the server can't search offline or with the filters
+        let local = LocalQuery::parse(&term);
+        let use_index = !self.online || local.has_filters();
+
+        let query = SearchQuery { term: term, room: room };
+        self.search = Some(Search { query: query.clone(), next_batch: None, results: vec![] });
+        self.clear_search_results();
+        self.show_search_view(true);
+
+        if use_index {
+            self.local_search(query, local);
+        } else {
+            self.backend.send(BKCommand::Search(query, None)).unwrap();
+            self.search_start();
+        }
+    }
+
+    // results from the index of the messages that we've seen, in one page
+    fn local_search(&mut self, query: SearchQuery, local: LocalQuery) {
+        let results = self.index.search(&local, query.room.as_ref().map(|r| &r[..]));
+        let mut highlights = local.words.clone();
+        highlights.extend(local.phrases.iter().cloned());
+
+        let page = SearchResults {
+            query: query,
+            count: Some(results.len() as u64),
+            highlights: highlights,
+            results: results,
+            next_batch: None,
+        };
+        self.show_search_results(page);
+    }
+
+    pub fn load_more_search_results(&mut self) {
+        let page = self.search.as_ref()
+            .and_then(|s| s.next_batch.clone().map(|nb| (s.query.clone(), nb)));
+
+        if let Some((query, next_batch)) = page {
+            self.backend.send(BKCommand::Search(query, Some(next_batch))).unwrap();
+            self.search_start();
+        }
+    }
+
+    pub fn show_search_results(&mut self, page: SearchResults) {
+        self.search_end();
+
+        // results of a search that isn't the current one
+        if self.search.as_ref().map(|s| s.query != page.query).unwrap_or(true) {
+            return;
+        }
+
+        let list = self.gtk_builder
+            .get_object::<gtk::ListBox>("search_results_list")
+            .expect("Can't find search_results_list in ui file.");
+        for r in page.results.iter() {
+            let row = widgets::SearchResultBox::new(r, &page.highlights, &self);
+            list.add(&row.widget());
+        }
+
+        let more = self.gtk_builder
+            .get_object::<gtk::Button>("search_more_button")
+            .expect("Can't find search_more_button in ui file.");
+        more.set_visible(page.next_batch.is_some());
+
+        let shown = match self.search {
+            Some(ref mut s) => {
+                s.results.extend(page.results.into_iter().map(|r| r.message));
+                s.next_batch = page.next_batch;
+                s.results.len()
+            }
+            None => 0,
+        };
+
+        let count = self.gtk_builder
+            .get_object::<gtk::Label>("search_results_count")
+            .expect("Can't find search_results_count in ui file.");
+        count.set_text(&match (shown, page.count) {
+            (0, _) => String::from("No results"),
+            (_, Some(n)) => format!("{} results", n),
+            (n, None) => format!("{} results", n),
+        });
+    }
+
+    /// Goes to the message of the result in @index, the timeline is loaded around it
+    pub fn jump_to_search_result(&mut self, index: usize) {
+        let msg = self.search.as_ref().and_then(|s| s.results.get(index).cloned());
+        if let Some(msg) = msg {
+            self.close_search_results();
+            self.jump_to_message(msg);
+        }
+    }
+
+    pub fn jump_to_message(&mut self, msg: Message) {
+        if self.active_room.as_ref() != Some(&msg.room) {
+            self.select_room(msg.room.clone());
+        }
+
+        // the message is in the chunk that we're showing, the first row is the load more button
+        let pos = self.chunk.as_ref().and_then(|c| c.messages.iter().position(|m| m.id == msg.id));
+        if let Some(p) = pos {
+            self.scroll_to_row(p as i32 + 1);
+            return;
+        }
+
+        self.jump_to_present();
+        self.backend.send(BKCommand::GetMessageContext(msg)).unwrap();
+    }
+
+    /// Shows the messages of @chunk, around the message that we're looking for. If they
+    /// reach the live timeline they're added to it, otherwise they're shown instead of the
+    /// live timeline until the forward pagination closes the gap.
+    pub fn show_chunk(&mut self, chunk: TimelineChunk) {
+        self.loading_more = false;
+        self.load_more_normal();
+        self.index.add(&chunk.messages);
+
+        if self.active_room.as_ref() != Some(&chunk.room) {
+            return;
+        }
+
+        let live = self.rooms.get(&chunk.room).map(|r| r.messages.clone()).unwrap_or_default();
+        if let Some(joined) = chunk.join(&live) {
+            // the cached live messages that aren't shown yet go before the older ones
+            let first = live.len().saturating_sub(self.shown_messages);
+            for msg in live[..first].iter().rev() {
+                self.render_message(msg, MsgPos::Top, None, false);
+            }
+
+            let start = joined.iter().position(|m| m.id == live[0].id).unwrap_or(0);
+            let older = joined[..start].to_vec();
+            let newer = joined[start + live.len()..].to_vec();
+            if !older.is_empty() {
+                if let Some(r) = self.rooms.get_mut(&chunk.room) {
+                    r.prev_batch = chunk.prev_batch.clone();
+                }
+            }
+            self.show_room_messages_top(older);
+
+            // the sync didn't bring them yet, it's skipped when it does
+            for msg in newer.iter() {
+                if let Some(r) = self.rooms.get_mut(&msg.room) {
+                    r.messages.push(msg.clone());
+                }
+                self.render_message(msg, MsgPos::Bottom, None, false);
+            }
+
+            self.scroll_to_message(&chunk.event);
+            return;
+        }
+
+        self.clear_tmp_msgs();
         self.remove_messages();
-        self.backend.send(BKCommand::Search(r, term)).unwrap();
+        self.shown_messages = 0;
+        self.autoscroll = false;
+
+        let mut prev = None;
+        for msg in chunk.messages.iter() {
+            self.render_message(msg, MsgPos::Bottom, prev, false);
+            prev = Some(msg.clone());
+        }
+
+        let target = chunk.messages.iter().position(|m| m.id.as_ref() == Some(&chunk.event));
+        self.chunk = Some(chunk);
+        self.show_chunk_bar(true);
+        if let Some(p) = target {
+            self.scroll_to_row(p as i32 + 1);
+        }
+    }
+
+    /// A page of the chunk that we're showing, @token is the one to get the next page in the
+    /// same direction
+    pub fn show_chunk_messages(&mut self,
+                               roomid: String,
+                               msgs: Vec<Message>,
+                               token: Option<String>,
+                               dir: Direction) {
+        self.loading_more = false;
+        self.load_more_normal();
+        self.index.add(&msgs);
+
+        let mut chunk = match self.chunk.take() {
+            Some(c) => c,
+            None => return,
+        };
+        if chunk.room != roomid {
+            self.chunk = Some(chunk);
+            return;
+        }
+
+        match dir {
+            Direction::Backward => {
+                for (i, msg) in msgs.iter().enumerate().rev() {
+                    let prev = match i {
+                        0 => None,
+                        i => msgs.get(i - 1).cloned(),
+                    };
+                    self.render_message(msg, MsgPos::Top, prev, false);
+                }
+                chunk.prepend(msgs, token);
+            }
+            Direction::Forward => {
+                let mut prev = chunk.messages.last().cloned();
+                for msg in msgs.iter() {
+                    self.render_message(msg, MsgPos::Bottom, prev, false);
+                    prev = Some(msg.clone());
+                }
+                chunk.append(msgs, token);
+            }
+        }
+
+        let live = self.rooms.get(&roomid).map(|r| r.messages.clone()).unwrap_or_default();
+        match chunk.join(&live) {
+            Some(joined) => self.join_chunk(chunk, joined),
+            None if chunk.is_live() => {
+                // there's no overlap, so only the live messages newer than the chunk are
+                // missing in it
+                let last = chunk.messages.last().map(|m| m.date.clone());
+                let mut joined = chunk.messages.clone();
+                joined.extend(live.into_iter().filter(|m| last.as_ref().map(|d| &m.date > d).unwrap_or(true)));
+                self.join_chunk(chunk, joined);
+            }
+            None => self.chunk = Some(chunk),
+        }
+    }
+
+    // the chunk that we're showing reached the last messages, so @joined is the live
+    // timeline again and it's shown from the start of the chunk
+    fn join_chunk(&mut self, chunk: TimelineChunk, joined: Vec<Message>) {
+        let first = chunk.messages.first().and_then(|m| m.id.clone());
+        let last = chunk.messages.last().and_then(|m| m.id.clone());
+
+        // the live messages after the chunk aren't shown yet
+        let start = joined.iter().position(|m| m.id == last).map(|p| p + 1).unwrap_or(0);
+        let mut prev = match start {
+            0 => None,
+            n => joined.get(n - 1).cloned(),
+        };
+        for msg in joined[start..].iter() {
+            self.render_message(msg, MsgPos::Bottom, prev, false);
+            prev = Some(msg.clone());
+        }
+
+        self.shown_messages = joined.len() - joined.iter().position(|m| m.id == first).unwrap_or(0);
+        if let Some(r) = self.rooms.get_mut(&chunk.room) {
+            // the chunk has older messages than the live timeline
+            if joined.first().map(|m| &m.id) != r.messages.first().map(|m| &m.id) {
+                r.prev_batch = chunk.prev_batch.clone();
+            }
+            r.messages = joined;
+        }
+
+        self.show_chunk_bar(false);
+        self.show_tmp_msgs();
+    }
+
+    /// Leaves the chunk that we're showing and goes back to the last messages of the room
+    pub fn jump_to_present(&mut self) {
+        if self.chunk.take().is_some() {
+            self.reload_active_room();
+        }
+    }
+
+    fn show_chunk_bar(&self, show: bool) {
+        self.gtk_builder
+            .get_object::<gtk::Revealer>("timeline_chunk_revealer")
+            .expect("Can't find timeline_chunk_revealer in ui file.")
+            .set_reveal_child(show);
+    }
+
+    // shows the live messages of the active room until @eventid, and scrolls to it
+    fn scroll_to_message(&mut self, eventid: &str) {
+        let msgs = match self.rooms.get(&self.active_room.clone().unwrap_or_default()) {
+            Some(r) => r.messages.clone(),
+            None => return,
+        };
+        let pos = match msgs.iter().position(|m| m.id.as_ref().map(|id| id == eventid).unwrap_or(false)) {
+            Some(p) => p,
+            None => return,
+        };
+
+        let first = msgs.len().saturating_sub(self.shown_messages);
+        if pos < first {
+            for msg in msgs[pos..first].iter().rev() {
+                self.render_message(msg, MsgPos::Top, None, false);
+            }
+        }
+
+        let first = msgs.len().saturating_sub(self.shown_messages);
+        self.scroll_to_row((pos - first) as i32 + 1);
+    }
+
+    // highlights the message in the row @index of the message list and scrolls to it,
+    // once the rows have their size
+    fn scroll_to_row(&self, index: i32) {
+        let messages = self.gtk_builder
+            .get_object::<gtk::ListBox>("message_list")
+            .expect("Can't find message_list in ui file.");
+        let scroll = self.gtk_builder
+            .get_object::<gtk::ScrolledWindow>("messages_scroll")
+            .expect("Can't find message_scroll in ui file.");
+
+        let row = match messages.get_row_at_index(index) {
+            Some(row) => row,
+            None => return,
+        };
+        if let Some(ctx) = row.get_style_context() {
+            ctx.add_class("jumped-message");
+        }
 
+        // only the first allocation, so it doesn't jump back later when the row changes
+        let done = Rc::new(Cell::new(false));
+        row.connect_size_allocate(move |_, rect| {
+            if done.get() {
+                return;
+            }
+            done.set(true);
+            if let Some(adj) = scroll.get_vadjustment() {
+                adj.set_value(rect.y as f64 - adj.get_page_size() / 3.0);
+            }
+        });
+        row.queue_resize();
+    }
+
+    pub fn close_search_results(&mut self) {
+        if self.search.take().is_some() {
+            self.clear_search_results();
+            self.show_search_view(false);
+            self.search_end();
+        }
+    }
+
+    fn clear_search_results(&self) {
+        let list = self.gtk_builder
+            .get_object::<gtk::ListBox>("search_results_list")
+            .expect("Can't find search_results_list in ui file.");
+        for ch in list.get_children().iter() {
+            list.remove(ch);
+        }
+
+        self.gtk_builder
+            .get_object::<gtk::Label>("search_results_count")
+            .expect("Can't find search_results_count in ui file.")
+            .set_text("");
+        self.gtk_builder
+            .get_object::<gtk::Button>("search_more_button")
+            .expect("Can't find search_more_button in ui file.")
+            .hide();
+    }
+
+    // the search results replace the messages of the room while they're shown
+    fn show_search_view(&self, visible: bool) {
+        let messages = self.gtk_builder
+            .get_object::<gtk::Widget>("messages_scroll")
+            .expect("Can't find messages_scroll in ui file.");
+        let results = self.gtk_builder
+            .get_object::<gtk::Widget>("search_results_scroll")
+            .expect("Can't find search_results_scroll in ui file.");
+
+        messages.set_visible(!visible);
+        results.set_visible(visible);
+    }
+
+    fn search_start(&self) {
         self.gtk_builder
             .get_object::<gtk::Stack>("search_button_stack")
             .expect("Can't find search_button_stack in ui file.")
@@ -1421,89 +3290,207 @@ impl AppOp {
         dialog.connect_response(move |d, _| { d.destroy(); });
     }
 
-    pub fn paste(&self) {
-        if let Some(display) = gdk::Display::get_default() {
-            if let Some(clipboard) = gtk::Clipboard::get_default(&display) {
-                if clipboard.wait_is_image_available() {
-                    if let Some(pixb) = clipboard.wait_for_image() {
-                        self.draw_image_paste_dialog(&pixb);
+    /// Handles the paste in the composer. Returns true if the clipboard @content isn't pasted
+    /// as text: files and images are sent with the send dialog, and a long text can be sent as
+    /// a file.
+    pub fn paste(&self, content: Paste) -> bool {
+        match content {
+            Paste::Files(files) => {
+                self.send_files_dialog(files, false);
+                true
+            }
+            Paste::Image(pixb) => {
+                let name = Local::now().format("Screenshot %Y-%m-%d %H-%M-%S.png").to_string();
+                let r = get_pixbuf_data(&pixb).map_err(|e| e.to_string()).and_then(|data| {
+                    let path = api::util::cache_path(&name).map_err(|e| e.to_string())?;
+                    File::create(&path).and_then(|mut f| f.write_all(&data)).map_err(|e| e.to_string())?;
+                    Ok(path)
+                });
 
-                        // removing text from clipboard
-                        clipboard.set_text("");
-                        clipboard.set_image(&pixb);
-                    }
-                } else {
-                    // TODO: manage code pasting
-                }
+                match r {
+                    Ok(path) => self.send_files_dialog(vec![path], true),
+                    Err(err) => self.show_error(&format!("Can't paste the image: {}", err)),
+                };
+                true
+            }
+            Paste::Text(ref text) if text.chars().count() > globals::LONG_PASTE ||
+                                     text.lines().count() > globals::LONG_PASTE_LINES => {
+                self.long_paste_dialog(text.clone());
+                true
             }
+            _ => false,
         }
     }
 
-    fn draw_image_paste_dialog(&self, pixb: &Pixbuf) {
-        let w = pixb.get_width();
-        let h = pixb.get_height();
-        let scaled;
-        if w > 600 {
-            scaled = pixb.scale_simple(600, h*600/w, gdk_pixbuf_sys::GDK_INTERP_BILINEAR);
-        } else {
-            scaled = Ok(pixb.clone());
+    fn long_paste_dialog(&self, text: String) {
+        let window: gtk::Window = self.gtk_builder
+            .get_object("main_window")
+            .expect("Couldn't find main_window in ui file.");
+        let msg_entry: gtk::Entry = self.gtk_builder
+            .get_object("msg_entry")
+            .expect("Couldn't find msg_entry in ui file.");
+
+        let dialog = gtk::MessageDialog::new(Some(&window),
+                                             gtk::DialogFlags::MODAL,
+                                             gtk::MessageType::Question,
+                                             gtk::ButtonsType::None,
+                                             "This text is long, do you want to send it as a file?");
+        dialog.add_button("Paste", 0);
+        let btn = dialog.add_button("Send as file", 1);
+        btn.get_style_context().unwrap().add_class("suggested-action");
+
+        let internal = self.internal.clone();
+        dialog.connect_response(move |d, resp| {
+            if resp == 1 {
+                let name = Local::now().format("Pasted text %Y-%m-%d %H-%M-%S.txt").to_string();
+                let r = api::util::cache_path(&name).map_err(|e| e.to_string()).and_then(|path| {
+                    File::create(&path).and_then(|mut f| f.write_all(text.as_bytes())).map_err(|e| e.to_string())?;
+                    Ok(path)
+                });
+                match r {
+                    Ok(path) => { internal.send(InternalCommand::SendFiles(vec![path], true)).unwrap(); }
+                    Err(err) => { println!("Error storing the pasted text: {}", err); }
+                };
+            } else if resp == 0 {
+                let mut pos = msg_entry.get_position();
+                msg_entry.insert_text(&text, &mut pos);
+                msg_entry.set_position(pos);
+            }
+            d.destroy();
+        });
+
+        dialog.show();
+    }
+
+    /// Asks for the captions and the image options before sending the @files to the active
+    /// room. The @temporary files are removed if they aren't sent.
+    pub fn send_files_dialog(&self, files: Vec<String>, temporary: bool) {
+        let room = match self.active_room {
+            Some(ref r) if !files.is_empty() => r.clone(),
+            _ => return,
+        };
+
+        let window: gtk::ApplicationWindow = self.gtk_builder
+            .get_object("main_window")
+            .expect("Can't find main_window in ui file.");
+        let title = match files.len() {
+            1 => String::from("Send file"),
+            n => format!("Send {} files", n),
+        };
+        let dialog = gtk::Dialog::new_with_buttons(
+            Some(&title[..]),
+            Some(&window),
+            gtk::DialogFlags::MODAL|
+            gtk::DialogFlags::USE_HEADER_BAR|
+            gtk::DialogFlags::DESTROY_WITH_PARENT,
+            &[]);
+
+        let list = gtk::Box::new(gtk::Orientation::Vertical, 12);
+        let mut rows: Vec<(String, gtk::Entry)> = vec![];
+        let mut images = false;
+        for path in files.iter() {
+            let image = match Pixbuf::new_from_file_at_scale(path, 96, 96, true) {
+                Ok(pixbuf) => {
+                    images = true;
+                    gtk::Image::new_from_pixbuf(&pixbuf)
+                }
+                Err(_) => gtk::Image::new_from_icon_name("text-x-generic-symbolic", 6),
+            };
+            image.set_size_request(96, 96);
+
+            let name = path.split('/').last().unwrap_or(path);
+            let size = fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+            let label = gtk::Label::new(Some(&format!("{} ({})", name, format_size(size))[..]));
+            label.set_halign(gtk::Align::Start);
+            label.set_ellipsize(pango::EllipsizeMode::Middle);
+
+            let caption = gtk::Entry::new();
+            caption.set_placeholder_text("Add a caption");
+            caption.set_activates_default(true);
+
+            let info = gtk::Box::new(gtk::Orientation::Vertical, 6);
+            info.set_valign(gtk::Align::Center);
+            info.pack_start(&label, false, false, 0);
+            info.pack_start(&caption, false, false, 0);
+
+            let row = gtk::Box::new(gtk::Orientation::Horizontal, 12);
+            row.pack_start(&image, false, false, 0);
+            row.pack_start(&info, true, true, 0);
+            list.pack_start(&row, false, false, 0);
+
+            rows.push((path.clone(), caption));
         }
 
-        if let Ok(pb) = scaled {
-            let window: gtk::ApplicationWindow = self.gtk_builder
-                .get_object("main_window")
-                .expect("Can't find main_window in ui file.");
-            let img = gtk::Image::new();
-            let dialog = gtk::Dialog::new_with_buttons(
-                Some("Image from Clipboard"),
-                Some(&window),
-                gtk::DialogFlags::MODAL|
-                gtk::DialogFlags::USE_HEADER_BAR|
-                gtk::DialogFlags::DESTROY_WITH_PARENT,
-                &[]);
-
-            img.set_from_pixbuf(&pb);
-            img.show();
-            dialog.get_content_area().add(&img);
-            dialog.present();
+        let scroll = gtk::ScrolledWindow::new(None, None);
+        scroll.set_policy(gtk::PolicyType::Never, gtk::PolicyType::Automatic);
+        scroll.set_propagate_natural_height(true);
+        scroll.set_max_content_height(400);
+        scroll.add(&list);
+
+        let content = dialog.get_content_area();
+        content.set_spacing(12);
+        content.set_border_width(12);
+        content.pack_start(&scroll, true, true, 0);
+
+        let resize = gtk::CheckButton::new_with_label(
+            &format!("Resize images to {} pixels", globals::SEND_IMAGE_SIZE));
+        let strip = gtk::CheckButton::new_with_label("Remove the image metadata, like the location");
+        strip.set_active(true);
+        if images {
+            content.pack_start(&resize, false, false, 0);
+            content.pack_start(&strip, false, false, 0);
+        }
 
-            if let Some(hbar) = dialog.get_header_bar() {
-                let bar = hbar.downcast::<gtk::HeaderBar>().unwrap();
-                let closebtn = gtk::Button::new_with_label("Cancel");
-                let okbtn = gtk::Button::new_with_label("Send");
-                okbtn.get_style_context().unwrap().add_class("suggested-action");
+        content.show_all();
+        dialog.present();
 
-                bar.set_show_close_button(false);
-                bar.pack_start(&closebtn);
-                bar.pack_end(&okbtn);
-                bar.show_all();
+        let sent = Rc::new(Cell::new(false));
+        let s = sent.clone();
+        dialog.connect_destroy(move |_| {
+            if temporary && !s.get() {
+                for f in files.iter() {
+                    let _ = fs::remove_file(f);
+                }
+            }
+        });
 
-                closebtn.connect_clicked(clone!(dialog => move |_| {
-                    dialog.destroy();
-                }));
-                let room = self.active_room.clone().unwrap_or_default();
-                let bk = self.backend.clone();
-                okbtn.connect_clicked(clone!(pixb, dialog => move |_| {
-                    if let Ok(data) = get_pixbuf_data(&pixb) {
-                        bk.send(BKCommand::AttachImage(room.clone(), data)).unwrap();
+        if let Some(hbar) = dialog.get_header_bar() {
+            let bar = hbar.downcast::<gtk::HeaderBar>().unwrap();
+            let closebtn = gtk::Button::new_with_label("Cancel");
+            let okbtn = gtk::Button::new_with_label("Send");
+            okbtn.get_style_context().unwrap().add_class("suggested-action");
+            okbtn.set_can_default(true);
+
+            bar.set_show_close_button(false);
+            bar.pack_start(&closebtn);
+            bar.pack_end(&okbtn);
+            bar.show_all();
+
+            closebtn.connect_clicked(clone!(dialog => move |_| {
+                dialog.destroy();
+            }));
+            let bk = self.backend.clone();
+            okbtn.connect_clicked(clone!(dialog => move |_| {
+                let max_size = if resize.get_active() { Some(globals::SEND_IMAGE_SIZE) } else { None };
+                let attachments = rows.iter().map(|&(ref path, ref caption)| {
+                    let text = caption.get_text().unwrap_or_default();
+                    Attachment {
+                        path: path.clone(),
+                        caption: if text.trim().is_empty() { None } else { Some(String::from(text.trim())) },
+                        max_size: max_size,
+                        strip_metadata: strip.get_active(),
+                        temporary: temporary,
+                        duration: None,
                     }
-                    dialog.destroy();
-                }));
-
-                okbtn.grab_focus();
-            }
-        }
-    }
+                }).collect();
 
-    pub fn notification_cliked(&mut self, msg: Message) {
-        self.activate();
-        let mut room = None;
-        if let Some(r) = self.rooms.get(&msg.room) {
-            room = Some(r.clone());
-        }
+                bk.send(BKCommand::AttachFiles(room.clone(), attachments)).unwrap();
+                sent.set(true);
+                dialog.destroy();
+            }));
 
-        if let Some(r) = room {
-            self.set_active_room_by_id(r.id.clone());
+            okbtn.grab_default();
+            okbtn.grab_focus();
         }
     }
 
@@ -1515,7 +3502,7 @@ impl AppOp {
         window.present();
     }
 
-    pub fn quit(&self) {
+    pub fn quit(&mut self) {
         self.cache_rooms();
         self.disconnect();
         self.gtk_app.quit();
@@ -1613,7 +3600,7 @@ pub struct App {
 
 impl App {
     /// Create an App instance
-    pub fn new() {
+    pub fn new(gst: bool) {
         let gtk_app = gtk::Application::new(Some(APP_ID), gio::ApplicationFlags::empty())
             .expect("Failed to initialize GtkApplication");
 
@@ -1631,10 +3618,9 @@ impl App {
             window.set_application(gtk_app);
 
             let op = Arc::new(Mutex::new(
-                AppOp::new(gtk_app.clone(), gtk_builder.clone(), apptx, itx)
+                AppOp::new(gtk_app.clone(), gtk_builder.clone(), apptx, itx, gst)
             ));
 
-            sync_loop(op.clone());
             backend_loop(op.clone(), rx);
             appop_loop(op.clone(), irx);
 
@@ -1661,6 +3647,12 @@ impl App {
         window.set_title("Fractal");
         window.show_all();
 
+        // avatars are downloaded and drawn with the size in pixels of the screen
+        api::util::set_scale_factor(window.get_scale_factor());
+        window.connect_property_scale_factor_notify(|w| {
+            api::util::set_scale_factor(w.get_scale_factor());
+        });
+
         let op = self.op.clone();
         window.connect_delete_event(move |_, _| {
             op.lock().unwrap().quit();
@@ -1682,11 +3674,14 @@ impl App {
         });
 
         self.create_load_more_btn();
+        self.connect_timeline_chunk_buttons();
         self.connect_more_members_btn();
         self.create_actions();
 
         self.connect_headerbars();
         self.connect_login_view();
+        self.connect_media_viewer();
+        self.connect_drop();
 
         self.connect_msg_scroll();
 
@@ -1701,6 +3696,7 @@ impl App {
         self.connect_search();
 
         self.connect_member_search();
+        self.connect_room_filter();
     }
 
     fn create_actions(&self) {
@@ -1709,20 +3705,60 @@ impl App {
         let chat = gio::SimpleAction::new("start_chat", None);
         let newr = gio::SimpleAction::new("new_room", None);
         let logout = gio::SimpleAction::new("logout", None);
+        let logout_wipe = gio::SimpleAction::new("logout_wipe", None);
+        let deactivate = gio::SimpleAction::new("deactivate_account", None);
+
+        let switcher = gio::SimpleAction::new("quick_switcher", None);
+        let next_unread = gio::SimpleAction::new("next_unread_room", None);
+        let prev_unread = gio::SimpleAction::new("prev_unread_room", None);
 
         let room = gio::SimpleAction::new("room_details", None);
         let search = gio::SimpleAction::new("search", None);
         let leave = gio::SimpleAction::new("leave_room", None);
+        let room_previews = gio::SimpleAction::new_stateful("room_url_previews", None,
+                                                            &true.to_variant());
+
+        // actions of the desktop notifications, with the room id as target
+        let open_room = gio::SimpleAction::new("open_room", glib::VariantTy::new("s").ok());
+        let reply_room = gio::SimpleAction::new("reply_room", glib::VariantTy::new("s").ok());
+        let mark_read = gio::SimpleAction::new("mark_room_read", glib::VariantTy::new("s").ok());
+
+        let room_notify = gio::SimpleAction::new_stateful("room_notify",
+                                                          glib::VariantTy::new("s").ok(),
+                                                          &RoomNotify::All.as_str().to_variant());
+
+        let enabled = self.op.lock().unwrap().settings.url_previews;
+        let previews = gio::SimpleAction::new_stateful("url_previews", None, &enabled.to_variant());
+
+        let size = self.op.lock().unwrap().settings.media_cache_size;
+        let cache_size = gio::SimpleAction::new_stateful("media_cache_size",
+                                                         glib::VariantTy::new("t").ok(),
+                                                         &size.to_variant());
+        let clear_cache = gio::SimpleAction::new("clear_media_cache", None);
 
         self.op.lock().unwrap().gtk_app.add_action(&settings);
         self.op.lock().unwrap().gtk_app.add_action(&dir);
         self.op.lock().unwrap().gtk_app.add_action(&chat);
         self.op.lock().unwrap().gtk_app.add_action(&newr);
         self.op.lock().unwrap().gtk_app.add_action(&logout);
+        self.op.lock().unwrap().gtk_app.add_action(&logout_wipe);
+        self.op.lock().unwrap().gtk_app.add_action(&deactivate);
+
+        self.op.lock().unwrap().gtk_app.add_action(&switcher);
+        self.op.lock().unwrap().gtk_app.add_action(&next_unread);
+        self.op.lock().unwrap().gtk_app.add_action(&prev_unread);
 
         self.op.lock().unwrap().gtk_app.add_action(&room);
         self.op.lock().unwrap().gtk_app.add_action(&search);
         self.op.lock().unwrap().gtk_app.add_action(&leave);
+        self.op.lock().unwrap().gtk_app.add_action(&room_previews);
+        self.op.lock().unwrap().gtk_app.add_action(&room_notify);
+        self.op.lock().unwrap().gtk_app.add_action(&open_room);
+        self.op.lock().unwrap().gtk_app.add_action(&reply_room);
+        self.op.lock().unwrap().gtk_app.add_action(&mark_read);
+        self.op.lock().unwrap().gtk_app.add_action(&previews);
+        self.op.lock().unwrap().gtk_app.add_action(&cache_size);
+        self.op.lock().unwrap().gtk_app.add_action(&clear_cache);
 
         settings.connect_activate(move |_, _| { println!("SETTINGS"); });
         chat.connect_activate(move |_, _| { println!("START CHAT"); });
@@ -1732,7 +3768,25 @@ impl App {
         let op = self.op.clone();
         dir.connect_activate(move |_, _| { op.lock().unwrap().set_state(AppState::Directory); });
         let op = self.op.clone();
-        logout.connect_activate(move |_, _| { op.lock().unwrap().logout(); });
+        logout.connect_activate(move |_, _| { op.lock().unwrap().logout(false); });
+        let op = self.op.clone();
+        logout_wipe.connect_activate(move |_, _| { op.lock().unwrap().logout(true); });
+        let op = self.op.clone();
+        deactivate.connect_activate(move |_, _| { op.lock().unwrap().deactivate_account_dialog(); });
+
+        let op = self.op.clone();
+        switcher.connect_activate(move |_, _| { op.lock().unwrap().quick_switcher(); });
+        let op = self.op.clone();
+        next_unread.connect_activate(move |_, _| { op.lock().unwrap().go_to_unread_room(1); });
+        let op = self.op.clone();
+        prev_unread.connect_activate(move |_, _| { op.lock().unwrap().go_to_unread_room(-1); });
+
+        {
+            let app = self.op.lock().unwrap().gtk_app.clone();
+            app.set_accels_for_action("app.quick_switcher", &["<Ctrl>k"]);
+            app.set_accels_for_action("app.next_unread_room", &["<Alt><Shift>Down"]);
+            app.set_accels_for_action("app.prev_unread_room", &["<Alt><Shift>Up"]);
+        }
 
         let op = self.op.clone();
         room.connect_activate(move |_, _| { op.lock().unwrap().show_room_dialog(); });
@@ -1742,6 +3796,59 @@ impl App {
         leave.connect_activate(move |_, _| { op.lock().unwrap().leave_active_room(); });
         let op = self.op.clone();
         newr.connect_activate(move |_, _| { op.lock().unwrap().new_room_dialog(); });
+
+        // boolean actions without parameter are shown as a check and toggled here
+        let op = self.op.clone();
+        previews.connect_activate(move |action, _| {
+            let enabled = !action.get_state().and_then(|s| s.get::<bool>()).unwrap_or(false);
+            action.set_state(&enabled.to_variant());
+            op.lock().unwrap().set_url_previews(enabled);
+        });
+
+        let op = self.op.clone();
+        cache_size.connect_activate(move |action, param| {
+            if let Some(size) = param.as_ref().and_then(|p| p.get::<u64>()) {
+                action.set_state(&size.to_variant());
+                op.lock().unwrap().set_media_cache_size(size);
+            }
+        });
+        let op = self.op.clone();
+        clear_cache.connect_activate(move |_, _| { op.lock().unwrap().clear_media_cache(); });
+        let op = self.op.clone();
+        room_previews.connect_activate(move |action, _| {
+            let enabled = !action.get_state().and_then(|s| s.get::<bool>()).unwrap_or(false);
+            action.set_state(&enabled.to_variant());
+            op.lock().unwrap().set_room_url_previews(enabled);
+        });
+
+        let op = self.op.clone();
+        open_room.connect_activate(move |_, param| {
+            if let Some(roomid) = param.as_ref().and_then(|p| p.get::<String>()) {
+                op.lock().unwrap().notification_opened(roomid);
+            }
+        });
+        let op = self.op.clone();
+        reply_room.connect_activate(move |_, param| {
+            if let Some(roomid) = param.as_ref().and_then(|p| p.get::<String>()) {
+                op.lock().unwrap().reply_dialog(roomid);
+            }
+        });
+        let op = self.op.clone();
+        mark_read.connect_activate(move |_, param| {
+            if let Some(roomid) = param.as_ref().and_then(|p| p.get::<String>()) {
+                op.lock().unwrap().mark_room_as_read(roomid);
+            }
+        });
+
+        // string actions are shown as radio buttons, one for each target
+        let op = self.op.clone();
+        room_notify.connect_activate(move |action, param| {
+            let level = param.as_ref().and_then(|p| p.get::<String>());
+            if let Some(level) = level.and_then(|l| RoomNotify::from_str(&l)) {
+                action.set_state(&level.as_str().to_variant());
+                op.lock().unwrap().set_room_notify(level);
+            }
+        });
     }
 
     fn connect_headerbars(&self) {
@@ -1754,6 +3861,107 @@ impl App {
         });
     }
 
+    /// Files dropped on the room view are sent with the send dialog
+    fn connect_drop(&self) {
+        let view: gtk::Box = self.gtk_builder
+            .get_object("focused_room")
+            .expect("Can't find focused_room in ui file.");
+
+        let targets = vec![gtk::TargetEntry::new("text/uri-list", gtk::TargetFlags::OTHER_APP, 0)];
+        view.drag_dest_set(gtk::DestDefaults::ALL, &targets, gdk::DragAction::COPY);
+
+        let internal = self.op.lock().unwrap().internal.clone();
+        view.connect_drag_data_received(move |_, _, _, _, data, _, _| {
+            let files = uris_to_paths(&data.get_uris());
+            internal.send(InternalCommand::SendFiles(files, false)).unwrap();
+        });
+    }
+
+    fn connect_media_viewer(&self) {
+        let buttons: [(&str, fn(&mut AppOp)); 8] = [
+            ("media_viewer_prev", |op| op.media_viewer_go(-1)),
+            ("media_viewer_next", |op| op.media_viewer_go(1)),
+            ("media_viewer_zoom_out", |op| op.media_viewer_zoom(-1)),
+            ("media_viewer_zoom_fit", |op| op.media_viewer_zoom_fit()),
+            ("media_viewer_zoom_in", |op| op.media_viewer_zoom(1)),
+            ("media_viewer_copy", |op| op.media_viewer_copy()),
+            ("media_viewer_save", |op| op.media_viewer_save()),
+            ("media_viewer_close", |op| op.close_media_viewer()),
+        ];
+
+        for &(id, action) in buttons.iter() {
+            let op = self.op.clone();
+            self.gtk_builder
+                .get_object::<gtk::Button>(id)
+                .expect("Can't find media viewer buttons in ui file.")
+                .connect_clicked(move |_| { action(&mut op.lock().unwrap()); });
+        }
+
+        let op = self.op.clone();
+        let viewer: gtk::Widget = self.gtk_builder
+            .get_object("media_viewer_state")
+            .expect("Can't find media_viewer_state in ui file.");
+        viewer.connect_key_release_event(move |_, k| {
+            let mut op = op.lock().unwrap();
+            match k.get_keyval() {
+                gdk::enums::key::Escape => op.close_media_viewer(),
+                gdk::enums::key::Left => op.media_viewer_go(-1),
+                gdk::enums::key::Right => op.media_viewer_go(1),
+                gdk::enums::key::plus | gdk::enums::key::KP_Add => op.media_viewer_zoom(1),
+                gdk::enums::key::minus | gdk::enums::key::KP_Subtract => op.media_viewer_zoom(-1),
+                _ => return Inhibit(false),
+            };
+            Inhibit(true)
+        });
+
+        let op = self.op.clone();
+        let scroll: gtk::ScrolledWindow = self.gtk_builder
+            .get_object("media_viewer_scroll")
+            .expect("Can't find media_viewer_scroll in ui file.");
+        scroll.connect_size_allocate(move |_, rect| {
+            op.lock().unwrap().media_viewer_resized(rect.width, rect.height);
+        });
+
+        // dragging the image moves it inside the scrolled window
+        let evbox: gtk::EventBox = self.gtk_builder
+            .get_object("media_viewer_event_box")
+            .expect("Can't find media_viewer_event_box in ui file.");
+        let drag = Rc::new(Cell::new((0.0, 0.0)));
+
+        let d = drag.clone();
+        evbox.connect_button_press_event(move |_, ev| {
+            d.set(ev.get_root());
+            Inhibit(false)
+        });
+
+        let d = drag.clone();
+        let s = scroll.clone();
+        evbox.connect_motion_notify_event(move |_, ev| {
+            let (x0, y0) = d.get();
+            let (x, y) = ev.get_root();
+            if let (Some(h), Some(v)) = (s.get_hadjustment(), s.get_vadjustment()) {
+                h.set_value(h.get_value() - (x - x0));
+                v.set_value(v.get_value() - (y - y0));
+            }
+            d.set((x, y));
+            Inhibit(true)
+        });
+
+        let op = self.op.clone();
+        evbox.connect_scroll_event(move |_, ev| {
+            if !ev.get_state().contains(gdk::ModifierType::CONTROL_MASK) {
+                return Inhibit(false);
+            }
+
+            match ev.get_direction() {
+                gdk::ScrollDirection::Up => op.lock().unwrap().media_viewer_zoom(1),
+                gdk::ScrollDirection::Down => op.lock().unwrap().media_viewer_zoom(-1),
+                _ => {}
+            };
+            Inhibit(true)
+        });
+    }
+
     fn connect_leave_room_dialog(&self) {
         let dialog = self.gtk_builder
             .get_object::<gtk::Dialog>("leave_room_dialog")
@@ -1901,6 +4109,21 @@ impl App {
 
         op = self.op.clone();
         q.connect_activate(move |_| { op.lock().unwrap().search_rooms(false); });
+
+        // the networks of the combo are the ones bridged by our server
+        let server = self.gtk_builder
+            .get_object::<gtk::Entry>("directory_server_entry")
+            .expect("Can't find directory_server_entry in ui file.");
+        let combo = self.gtk_builder
+            .get_object::<gtk::ComboBox>("directory_combo")
+            .expect("Can't find directory_combo in ui file.");
+        server.connect_changed(move |e| {
+            let remote = e.get_text().map(|t| !t.trim().is_empty()).unwrap_or(false);
+            if remote {
+                combo.set_active(0);
+            }
+            combo.set_sensitive(!remote);
+        });
     }
 
     fn create_load_more_btn(&self) {
@@ -1916,6 +4139,23 @@ impl App {
         btn.connect_clicked(move |_| { op.lock().unwrap().load_more_messages(); });
     }
 
+    fn connect_timeline_chunk_buttons(&self) {
+        let newer = self.gtk_builder
+            .get_object::<gtk::Button>("load_newer_button")
+            .expect("Can't find load_newer_button in ui file.");
+        let present = self.gtk_builder
+            .get_object::<gtk::Button>("jump_to_present_button")
+            .expect("Can't find jump_to_present_button in ui file.");
+
+        let mut op = self.op.clone();
+        newer.connect_clicked(move |_| {
+            op.lock().unwrap().load_chunk_messages(Direction::Forward);
+        });
+
+        op = self.op.clone();
+        present.connect_clicked(move |_| { op.lock().unwrap().jump_to_present(); });
+    }
+
     fn connect_more_members_btn(&self) {
         let mlist: gtk::ListBox = self.gtk_builder
             .get_object("member_list")
@@ -1937,8 +4177,10 @@ impl App {
             .expect("Can't find message_scroll in ui file.");
 
         let op = self.op.clone();
-        s.connect_edge_overshot(move |_, dir| if dir == gtk::PositionType::Top {
-            op.lock().unwrap().load_more_messages();
+        s.connect_edge_overshot(move |_, dir| match dir {
+            gtk::PositionType::Top => op.lock().unwrap().load_more_messages(),
+            gtk::PositionType::Bottom => op.lock().unwrap().load_chunk_messages(Direction::Forward),
+            _ => {}
         });
 
         let op = self.op.clone();
@@ -1966,8 +4208,13 @@ impl App {
         });
 
         op = self.op.clone();
-        msg_entry.connect_paste_clipboard(move |_| {
-            op.lock().unwrap().paste();
+        msg_entry.connect_paste_clipboard(move |entry| {
+            // waiting for the clipboard runs the main loop, so it's read before taking the
+            // lock. The default handler would paste the text too.
+            let content = read_clipboard();
+            if op.lock().unwrap().paste(content) {
+                entry.stop_signal_emission("paste-clipboard");
+            }
         });
     }
 
@@ -1980,6 +4227,30 @@ impl App {
         attach_button.connect_clicked(move |_| {
             op.lock().unwrap().attach_file();
         });
+
+        let record_button: gtk::ToggleButton = self.gtk_builder
+            .get_object("record_button")
+            .expect("Couldn't find record_button in ui file.");
+        record_button.set_no_show_all(true);
+        record_button.set_visible(self.op.lock().unwrap().gst);
+
+        let op = self.op.clone();
+        record_button.connect_toggled(move |btn| {
+            let style = btn.get_style_context().unwrap();
+            if !btn.get_active() {
+                style.remove_class("recording");
+                op.lock().unwrap().stop_recording();
+                return;
+            }
+
+            // the lock is released before untoggling, that calls this again
+            let started = op.lock().unwrap().start_recording();
+            if started {
+                style.add_class("recording");
+            } else {
+                btn.set_active(false);
+            }
+        });
     }
 
     fn connect_login_view(&self) {
@@ -2030,7 +4301,50 @@ impl App {
         let op = self.op.clone();
         input.connect_activate(move |inp| op.lock().unwrap().search(inp.get_text()));
         let op = self.op.clone();
-        btn.connect_clicked(move |_| op.lock().unwrap().search(input.get_text()));
+        let inp = input.clone();
+        btn.connect_clicked(move |_| op.lock().unwrap().search(inp.get_text()));
+
+        // searching again with the new scope, if there's a search
+        let scope: gtk::ComboBoxText = self.gtk_builder
+            .get_object("search_scope")
+            .expect("Couldn't find search_scope in ui file.");
+        let op = self.op.clone();
+        scope.connect_changed(move |_| {
+            let mut op = op.lock().unwrap();
+            if op.search.is_some() {
+                op.search(input.get_text());
+            }
+        });
+
+        let results: gtk::ListBox = self.gtk_builder
+            .get_object("search_results_list")
+            .expect("Couldn't find search_results_list in ui file.");
+        let op = self.op.clone();
+        results.connect_row_activated(move |_, row| {
+            op.lock().unwrap().jump_to_search_result(row.get_index() as usize);
+        });
+
+        let more: gtk::Button = self.gtk_builder
+            .get_object("search_more_button")
+            .expect("Couldn't find search_more_button in ui file.");
+        let op = self.op.clone();
+        more.connect_clicked(move |_| op.lock().unwrap().load_more_search_results());
+    }
+
+    fn connect_room_filter(&self) {
+        let input: gtk::SearchEntry = self.gtk_builder
+            .get_object("room_filter_entry")
+            .expect("Couldn't find room_filter_entry in ui file.");
+
+        let op = self.op.clone();
+        input.connect_search_changed(move |entry| {
+            op.lock().unwrap().filter_rooms(&entry.get_text().unwrap_or_default());
+        });
+
+        let op = self.op.clone();
+        input.connect_activate(move |_| {
+            op.lock().unwrap().select_first_room();
+        });
     }
 
     fn connect_member_search(&self) {
@@ -2066,154 +4380,359 @@ impl App {
     }
 }
 
-fn sync_loop(op: Arc<Mutex<AppOp>>) {
-    // Sync loop every 3 seconds
-    gtk::timeout_add(1000, move || {
-        op.lock().unwrap().sync();
-        gtk::Continue(true)
-    });
-}
-
 fn backend_loop(op: Arc<Mutex<AppOp>>, rx: Receiver<BKResponse>) {
     gtk::timeout_add(500, move || {
-        let recv = rx.try_recv();
-        match recv {
-            Ok(BKResponse::Token(uid, _)) => {
-                op.lock().unwrap().logged_in = true;
-
-                op.lock().unwrap().set_state(AppState::Chat);
-                op.lock().unwrap().set_uid(Some(uid.clone()));
-                op.lock().unwrap().set_username(Some(uid));
-                op.lock().unwrap().get_username();
-                op.lock().unwrap().sync();
-
-                op.lock().unwrap().init_protocols();
-            }
-            Ok(BKResponse::Logout) => {
-                op.lock().unwrap().logged_in = false;
+        // the backend can send a lot of responses between two ticks, like the
+        // download progress, so all the pending ones are handled at once
+        while let Ok(resp) = rx.try_recv() {
+            match resp {
+                BKResponse::Token(uid, _) => {
+                    op.lock().unwrap().logged_in = true;
+
+                    op.lock().unwrap().set_state(AppState::Chat);
+                    op.lock().unwrap().set_uid(Some(uid.clone()));
+                    op.lock().unwrap().set_username(Some(uid));
+                    op.lock().unwrap().get_username();
+                    op.lock().unwrap().sync();
+                    op.lock().unwrap().backend.send(BKCommand::LoadOutbox).unwrap();
+
+                    op.lock().unwrap().init_protocols();
+                }
+                BKResponse::Logout => {
+                    op.lock().unwrap().clear_session();
+                }
+                BKResponse::LogoutError(err) => {
+                    // the stored password is removed already, so we're logged out here
+                    op.lock().unwrap().clear_session();
+                    let error = format!("Can't log out from the server: {}", err);
+                    op.lock().unwrap().show_error(&error);
+                }
+                BKResponse::ClearCacheError(err) => {
+                    let error = format!("Can't remove the local data: {}", err);
+                    op.lock().unwrap().show_error(&error);
+                }
+                BKResponse::MediaCacheCleared => {
+                    // the media that is shown is downloaded again when it's needed
+                }
+                BKResponse::MediaError(err) => {
+                    let error = format!("Can't change the media cache: {}", err);
+                    op.lock().unwrap().show_error(&error);
+                }
+                BKResponse::AccountDeactivated => {
+                    let _ = op.lock().unwrap().delete_pass();
+                    op.lock().unwrap().clear_session();
+                }
+                BKResponse::Name(username) => {
+                    op.lock().unwrap().set_username(Some(username));
+                }
+                BKResponse::Avatar(path) => {
+                    op.lock().unwrap().set_avatar(&path);
+                }
+                BKResponse::Sync(since) => {
+                    println!("SYNC");
+                    op.lock().unwrap().synced(Some(since));
+                }
+                BKResponse::ConnectionState(state) => {
+                    op.lock().unwrap().set_connection_state(state);
+                }
+                BKResponse::Rooms(rooms, default) => {
+                    // uploading each room avatar
+                    for r in rooms.iter() {
+                        let bk = op.lock().unwrap().backend.clone();
+                        bk.send(BKCommand::GetRoomAvatar(r.id.clone())).unwrap();
+                    }
 
-                op.lock().unwrap().set_state(AppState::Login);
-                op.lock().unwrap().set_uid(None);
-                op.lock().unwrap().set_username(None);
-            }
-            Ok(BKResponse::Name(username)) => {
-                op.lock().unwrap().set_username(Some(username));
-            }
-            Ok(BKResponse::Avatar(path)) => {
-                op.lock().unwrap().set_avatar(&path);
-            }
-            Ok(BKResponse::Sync(since)) => {
-                println!("SYNC");
-                op.lock().unwrap().synced(Some(since));
-            }
-            Ok(BKResponse::Rooms(rooms, default)) => {
-                // uploading each room avatar
-                for r in rooms.iter() {
-                    let bk = op.lock().unwrap().backend.clone();
-                    bk.send(BKCommand::GetRoomAvatar(r.id.clone())).unwrap();
+                    op.lock().unwrap().set_rooms(rooms, default);
                 }
+                BKResponse::JoinedRooms(rooms, default) => {
+                    for r in rooms.iter() {
+                        let bk = op.lock().unwrap().backend.clone();
+                        bk.send(BKCommand::GetRoomAvatar(r.id.clone())).unwrap();
+                    }
 
-                op.lock().unwrap().set_rooms(rooms, default);
-            }
-            Ok(BKResponse::RoomDetail(room, key, value)) => {
-                op.lock().unwrap().set_room_detail(room, key, Some(value));
-            }
-            Ok(BKResponse::RoomAvatar(room, avatar)) => {
-                op.lock().unwrap().set_room_avatar(room, Some(avatar));
-            }
-            Ok(BKResponse::RoomMessages(msgs)) => {
-                op.lock().unwrap().show_room_messages(msgs, false);
-            }
-            Ok(BKResponse::RoomMessagesInit(msgs)) => {
-                op.lock().unwrap().show_room_messages(msgs, true);
-            }
-            Ok(BKResponse::RoomMessagesTo(msgs)) => {
-                op.lock().unwrap().show_room_messages_top(msgs);
-            }
-            Ok(BKResponse::SendMsg) => {
-                op.lock().unwrap().sync();
-            }
-            Ok(BKResponse::DirectoryProtocols(protocols)) => {
-                op.lock().unwrap().set_protocols(protocols);
-            }
-            Ok(BKResponse::DirectorySearch(rooms)) => {
-                for room in rooms {
-                    op.lock().unwrap().set_directory_room(room);
+                    op.lock().unwrap().add_rooms(rooms, default);
+                }
+                BKResponse::LeftRooms(rooms) => {
+                    op.lock().unwrap().remove_rooms(rooms);
+                }
+                BKResponse::RoomDetail(room, key, value) => {
+                    op.lock().unwrap().set_room_detail(room, key, Some(value));
+                }
+                BKResponse::RoomAvatar(room, avatar) => {
+                    op.lock().unwrap().set_room_avatar(room, Some(avatar));
+                }
+                BKResponse::RoomMessages(msgs) => {
+                    op.lock().unwrap().show_room_messages(msgs, false);
+                }
+                BKResponse::RoomMessagesInit(msgs) => {
+                    op.lock().unwrap().show_room_messages(msgs, true);
+                }
+                BKResponse::RoomContext(chunk) => {
+                    op.lock().unwrap().show_chunk(chunk);
+                }
+                BKResponse::RoomChunkMessages(room, msgs, token, dir) => {
+                    op.lock().unwrap().show_chunk_messages(room, msgs, token, dir);
+                }
+                BKResponse::RoomMessagesBack(room, msgs, token) => {
+                    op.lock().unwrap().show_room_messages_back(room, msgs, token);
+                }
+                BKResponse::RoomTimelineLimited(room, prev_batch) => {
+                    op.lock().unwrap().room_timeline_limited(room, prev_batch);
+                }
+                BKResponse::SendMsg(txnid, evid) => {
+                    op.lock().unwrap().tmp_msg_sent(txnid, evid);
+                }
+                BKResponse::SendMsgFailed(txnid, err) => {
+                    println!("Error sending message: {}", err);
+                    op.lock().unwrap().tmp_msg_failed(txnid);
+                }
+                BKResponse::Outbox(msgs) => {
+                    op.lock().unwrap().set_outbox(msgs);
+                }
+                BKResponse::DirectoryProtocols(protocols) => {
+                    op.lock().unwrap().set_protocols(protocols);
+                }
+                BKResponse::DirectorySearch(page) => {
+                    op.lock().unwrap().set_directory_rooms(page);
+                }
+                BKResponse::DirectoryError(err) => {
+                    op.lock().unwrap().directory_error(err.to_string());
+                }
+                BKResponse::RoomPreview(room) => {
+                    op.lock().unwrap().show_room_preview(room);
+                }
+                BKResponse::RoomPreviewError(err) => {
+                    let error = format!("Can't preview the room: {}", err);
+                    op.lock().unwrap().show_error(&error);
+                }
+                BKResponse::JoinRoom(roomid) => {
+                    op.lock().unwrap().room_joined(roomid);
+                }
+                BKResponse::LeaveRoom => { }
+                BKResponse::SetRoomName => { }
+                BKResponse::SetRoomTopic => { }
+                BKResponse::SetRoomAvatar => { }
+                BKResponse::MarkedAsRead(r, _) => {
+                    let mut op = op.lock().unwrap();
+                    op.update_room_notifications(&r, |_| 0);
+                    op.update_room_highlight(&r, |_| 0);
+                    op.withdraw_notification(&r);
                 }
-            }
-            Ok(BKResponse::JoinRoom) => {
-                op.lock().unwrap().reload_rooms();
-            }
-            Ok(BKResponse::LeaveRoom) => { }
-            Ok(BKResponse::SetRoomName) => { }
-            Ok(BKResponse::SetRoomTopic) => { }
-            Ok(BKResponse::SetRoomAvatar) => { }
-            Ok(BKResponse::MarkedAsRead(r, _)) => {
-                op.lock().unwrap().update_room_notifications(&r, |_| 0);
-            }
 
-            Ok(BKResponse::RoomName(roomid, name)) => {
-                op.lock().unwrap().room_name_change(roomid, Some(name));
-            }
-            Ok(BKResponse::RoomTopic(roomid, topic)) => {
-                op.lock().unwrap().room_topic_change(roomid, Some(topic));
-            }
-            Ok(BKResponse::NewRoomAvatar(roomid)) => {
-                op.lock().unwrap().new_room_avatar(roomid);
-            }
-            Ok(BKResponse::RoomMemberEvent(ev)) => {
-                op.lock().unwrap().room_member_event(ev);
-            }
-            Ok(BKResponse::Media(fname)) => {
-                Command::new("xdg-open")
-                            .arg(&fname)
-                            .spawn()
-                            .expect("failed to execute process");
-            }
-            Ok(BKResponse::AttachedFile(msg)) => {
-                op.lock().unwrap().add_tmp_room_message(&msg);
-            }
-            Ok(BKResponse::SearchEnd) => {
-                op.lock().unwrap().search_end();
-            }
-            Ok(BKResponse::NewRoom(r)) => {
-                op.lock().unwrap().new_room(r);
-            }
+                BKResponse::RoomName(roomid, name) => {
+                    op.lock().unwrap().room_name_change(roomid, Some(name));
+                }
+                BKResponse::RoomTopic(roomid, topic) => {
+                    op.lock().unwrap().room_topic_change(roomid, Some(topic));
+                }
+                BKResponse::RoomTags(roomid, tags) => {
+                    op.lock().unwrap().set_room_tags(roomid, tags);
+                }
+                BKResponse::RoomTag => { }
+                BKResponse::RoomNotifications(roomid, n, h) => {
+                    op.lock().unwrap().set_room_unread(roomid, n, h);
+                }
+                BKResponse::PushRules(rules) => {
+                    op.lock().unwrap().set_push_rules(rules);
+                }
+                BKResponse::NewRoomAvatar(roomid) => {
+                    op.lock().unwrap().new_room_avatar(roomid);
+                }
+                BKResponse::RoomMemberEvent(ev) => {
+                    op.lock().unwrap().room_member_event(ev);
+                }
+                BKResponse::RoomPowerLevels(roomid, levels) => {
+                    op.lock().unwrap().set_room_power_levels(roomid, levels);
+                }
+                BKResponse::Media(url, fname) => {
+                    op.lock().unwrap().media_ready(url, fname);
+                }
+                BKResponse::MediaSaved(url, _) => {
+                    op.lock().unwrap().media_downloaded(url);
+                }
+                BKResponse::MediaProgress(url, bytes, total) => {
+                    op.lock().unwrap().media_progress(url, bytes, total);
+                }
+                BKResponse::MediaFailed(url, err) => {
+                    op.lock().unwrap().media_failed(url, err);
+                }
+                BKResponse::AttachedFile(msg) => {
+                    op.lock().unwrap().upload_finished(msg.txnid.clone().unwrap_or_default());
+                    op.lock().unwrap().add_tmp_room_message(&msg);
+                }
+                BKResponse::Uploading(msg, size) => {
+                    op.lock().unwrap().upload_started(msg, size);
+                }
+                BKResponse::UploadProgress(txnid, bytes, total) => {
+                    op.lock().unwrap().upload_progress(txnid, bytes, total);
+                }
+                BKResponse::UploadFailed(txnid, err) => {
+                    match err {
+                        api::error::Error::Cancelled => op.lock().unwrap().upload_finished(txnid),
+                        // it can't be sent, so there's nothing to retry
+                        err @ api::error::Error::FileTooLarge(_) => {
+                            op.lock().unwrap().upload_finished(txnid);
+                            op.lock().unwrap().show_error(&format!("Can't send the file: {}", err));
+                        }
+                        err => {
+                            op.lock().unwrap().upload_failed(txnid);
+                            op.lock().unwrap().show_error(&format!("Can't send the file: {}", err));
+                        }
+                    };
+                }
+                BKResponse::SearchResults(results) => {
+                    op.lock().unwrap().show_search_results(results);
+                }
+                BKResponse::NewRoom(r) => {
+                    op.lock().unwrap().new_room(r);
+                }
 
-            // errors
-            Ok(BKResponse::NewRoomError(err)) => {
-                println!("ERROR: {:?}", err);
-                op.lock().unwrap().show_error("Can't create the room, try again");
-                op.lock().unwrap().room_panel(RoomPanel::NoRoom);
-            },
-            Ok(BKResponse::LoginError(_)) => {
-                op.lock().unwrap().show_error("Can't login, try again");
-                op.lock().unwrap().set_state(AppState::Login);
-            },
-            Ok(BKResponse::SendMsgError(_)) => {
-                op.lock().unwrap().show_error("Error sending message");
-            }
-            Ok(BKResponse::SyncError(_)) => {
-                println!("SYNC Error");
-                op.lock().unwrap().syncing = false;
-            }
-            Ok(err) => {
-                println!("Query error: {:?}", err);
+                // errors
+                BKResponse::NewRoomError(err) => {
+                    println!("ERROR: {:?}", err);
+                    op.lock().unwrap().show_error(&format!("Can't create the room: {}", err));
+                    op.lock().unwrap().room_panel(RoomPanel::NoRoom);
+                },
+                BKResponse::LoginError(err) => {
+                    let msg = match err.errcode() {
+                        Some("M_FORBIDDEN") => String::from("Wrong username or password, try again"),
+                        _ => format!("Can't login: {}", err),
+                    };
+                    op.lock().unwrap().show_error(&msg);
+                    op.lock().unwrap().set_state(AppState::Login);
+                },
+                BKResponse::AccountDeactivationError(err) => {
+                    let msg = match err.errcode() {
+                        Some("M_FORBIDDEN") => String::from("Can't deactivate the account, check your password"),
+                        _ => format!("Can't deactivate the account: {}", err),
+                    };
+                    op.lock().unwrap().show_error(&msg);
+                }
+                BKResponse::SendMsgError(err) => {
+                    op.lock().unwrap().show_error(&format!("Error sending message: {}", err));
+                }
+                BKResponse::JoinRoomError(err) => {
+                    op.lock().unwrap().show_error(&format!("Can't join the room: {}", err));
+                }
+                BKResponse::LeaveRoomError(err) => {
+                    op.lock().unwrap().show_error(&format!("Can't leave the room: {}", err));
+                }
+                BKResponse::AttachFileError(err) => {
+                    op.lock().unwrap().show_error(&format!("Can't send the file: {}", err));
+                }
+                BKResponse::SearchError(err) => {
+                    op.lock().unwrap().search_end();
+                    op.lock().unwrap().show_error(&format!("Search failed: {}", err));
+                }
+                BKResponse::SyncError(err) => {
+                    println!("SYNC Error: {}", err);
+                }
+                BKResponse::RoomMessagesError(err) => {
+                    println!("Error loading messages: {}", err);
+                    op.lock().unwrap().load_more_error();
+                }
+                err => {
+                    println!("Query error: {:?}", err);
+                }
             }
-            Err(_) => {}
-        };
+        }
 
         gtk::Continue(true)
     });
 }
 
 
+// Content of the clipboard pasted in the composer
+pub enum Paste {
+    Files(Vec<String>),
+    Image(Pixbuf),
+    Text(String),
+    Nothing,
+}
+
+/// Reads the clipboard waiting for its content, so it can't be called holding the AppOp lock
+fn read_clipboard() -> Paste {
+    let clipboard = match gdk::Display::get_default() {
+        Some(display) => match gtk::Clipboard::get_default(&display) {
+            Some(c) => c,
+            None => return Paste::Nothing,
+        },
+        None => return Paste::Nothing,
+    };
+
+    if clipboard.wait_is_uris_available() {
+        let files = uris_to_paths(&clipboard.wait_for_uris());
+        if !files.is_empty() {
+            return Paste::Files(files);
+        }
+    }
+
+    if clipboard.wait_is_image_available() {
+        if let Some(pixb) = clipboard.wait_for_image() {
+            return Paste::Image(pixb);
+        }
+    }
+
+    match clipboard.wait_for_text() {
+        Some(text) => Paste::Text(text),
+        None => Paste::Nothing,
+    }
+}
+
+fn notification_id(roomid: &str) -> String {
+    format!("room-{}", roomid)
+}
+
+/// Row with a progress bar and a cancel button for a file download or upload
+fn transfer_row<F: Fn() + 'static>(name: &str, tooltip: &str, cancel_cb: F) -> (gtk::Box, gtk::ProgressBar) {
+    let row = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+    let label = gtk::Label::new(Some(name));
+    label.set_ellipsize(pango::EllipsizeMode::Middle);
+    label.set_max_width_chars(30);
+    let progress = gtk::ProgressBar::new();
+    progress.set_show_text(true);
+    progress.set_valign(gtk::Align::Center);
+    let cancel = gtk::Button::new_from_icon_name("window-close-symbolic", 1);
+    cancel.set_relief(gtk::ReliefStyle::None);
+    cancel.set_tooltip_text(Some(tooltip));
+    cancel.connect_clicked(move |_| cancel_cb());
+
+    row.pack_start(&label, false, false, 0);
+    row.pack_start(&progress, true, true, 0);
+    row.pack_start(&cancel, false, false, 0);
+    row.show_all();
+
+    (row, progress)
+}
+
+fn set_transfer_progress(progress: &gtk::ProgressBar, bytes: u64, total: u64) {
+    match total {
+        0 => {
+            progress.pulse();
+            progress.set_text(Some(&format_size(bytes)[..]));
+        }
+        t => {
+            progress.set_fraction(bytes as f64 / t as f64);
+            let text = format!("{} of {}", format_size(bytes), format_size(t));
+            progress.set_text(Some(&text[..]));
+        }
+    };
+}
+
 #[derive(Debug)]
 pub enum InternalCommand {
     AddRoomMessage(Message, MsgPos, Option<Message>, bool),
     SetPanel(RoomPanel),
-    NotifyClicked(Message),
+    ShowNotification(Message, String, String),
+    SendReply(String, String),
     SelectRoom(Room),
+    RetryTmpMsg(String),
+    CancelTmpMsg(String),
+    OpenMedia(String, String),
+    SaveMedia(String, String),
+    ShowImage(Message),
+    SendFiles(Vec<String>, bool),
+    MoveRoom(String, Option<String>, Option<String>, Option<f64>),
 }
 
 
@@ -2227,11 +4746,35 @@ fn appop_loop(op: Arc<Mutex<AppOp>>, rx: Receiver<InternalCommand>) {
             Ok(InternalCommand::SetPanel(st)) => {
                 op.lock().unwrap().room_panel(st);
             }
-            Ok(InternalCommand::NotifyClicked(msg)) => {
-                op.lock().unwrap().notification_cliked(msg);
+            Ok(InternalCommand::ShowNotification(msg, name, avatar)) => {
+                op.lock().unwrap().show_notification(msg, name, avatar);
+            }
+            Ok(InternalCommand::SendReply(roomid, text)) => {
+                op.lock().unwrap().send_reply(roomid, text);
             }
             Ok(InternalCommand::SelectRoom(r)) => {
-                op.lock().unwrap().set_active_room_by_id(r.id);
+                op.lock().unwrap().select_room(r.id);
+            }
+            Ok(InternalCommand::RetryTmpMsg(txnid)) => {
+                op.lock().unwrap().retry_tmp_msg(txnid);
+            }
+            Ok(InternalCommand::CancelTmpMsg(txnid)) => {
+                op.lock().unwrap().cancel_tmp_msg(txnid);
+            }
+            Ok(InternalCommand::OpenMedia(url, name)) => {
+                op.lock().unwrap().open_media(url, name);
+            }
+            Ok(InternalCommand::SaveMedia(url, name)) => {
+                op.lock().unwrap().save_media_dialog(url, name);
+            }
+            Ok(InternalCommand::ShowImage(msg)) => {
+                op.lock().unwrap().show_image(msg);
+            }
+            Ok(InternalCommand::MoveRoom(roomid, from, to, order)) => {
+                op.lock().unwrap().move_room(roomid, from, to, order);
+            }
+            Ok(InternalCommand::SendFiles(files, temporary)) => {
+                op.lock().unwrap().send_files_dialog(files, temporary);
             }
             Err(_) => {
             }
@@ -28,6 +28,10 @@ pub fn store(rooms: &RoomList, since: String, username: String, uid: String) ->
             _ => 0,
         };
         r.messages = r.messages.iter().skip(skip).cloned().collect();
+        // the token points before the first message we had, so it's not valid anymore
+        if skip > 0 {
+            r.prev_batch = None;
+        }
     }
 
     let data = CacheData {
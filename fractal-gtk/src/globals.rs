@@ -2,3 +2,24 @@ pub static INITIAL_MESSAGES: usize = 40;
 pub static CACHE_SIZE: usize = 40;
 pub static MSG_ICON_SIZE: i32 = 40;
 pub static USERLIST_ICON_SIZE: i32 = 30;
+/// Size of the thumbnails that we ask to the server for the images in the messages
+pub static IMAGE_THUMB_SIZE: (i32, i32) = (400, 400);
+pub static URL_PREVIEW_IMAGE_SIZE: i32 = 80;
+/// Size of the images sent with the resize option of the send dialog
+pub static SEND_IMAGE_SIZE: i32 = 1920;
+/// Pasted text longer than this, in characters or lines, can be sent as a file
+pub static LONG_PASTE: usize = 2000;
+pub static LONG_PASTE_LINES: usize = 30;
+/// Zoom factor applied by each zoom in / zoom out step in the image viewer
+pub static ZOOM_STEP: f64 = 1.25;
+pub static MIN_ZOOM: f64 = 0.1;
+pub static MAX_ZOOM: f64 = 8.0;
+/// Seconds to wait for the voice message file to be finished when the recording stops
+pub static RECORDING_EOS_TIMEOUT: u64 = 2;
+/// Messages kept in the local search index, and results shown for each local search
+pub static SEARCH_INDEX_SIZE: usize = 20000;
+pub static LOCAL_SEARCH_RESULTS: usize = 100;
+/// Seconds between the stores of the search index while new messages are added
+pub static SEARCH_INDEX_STORE_INTERVAL: u64 = 60;
+/// Rooms asked to the server for each page of the public room directory
+pub static DIRECTORY_PAGE_LIMIT: i32 = 20;
@@ -1,10 +1,14 @@
 #![deny(unused_extern_crates)]
 extern crate glib;
 extern crate gio;
+extern crate gstreamer as gst;
 
 #[macro_use]
 extern crate failure;
 
+#[macro_use]
+extern crate lazy_static;
+
 #[macro_use]
 extern crate serde_derive;
 
@@ -19,6 +23,8 @@ mod globals;
 mod util;
 mod widgets;
 mod cache;
+mod searchindex;
+mod settings;
 mod app;
 mod static_resources;
 
@@ -27,5 +33,14 @@ use app::App;
 
 fn main() {
     static_resources::init().expect("GResource initialization failed.");
-    App::new();
+    // without GStreamer everything works but playing and recording media, the media
+    // messages are shown as files
+    let gst = match gst::init() {
+        Ok(_) => true,
+        Err(err) => {
+            println!("GStreamer initialization failed, media playback is disabled: {}", err);
+            false
+        }
+    };
+    App::new(gst);
 }
@@ -0,0 +1,362 @@
+extern crate serde_json;
+extern crate chrono;
+
+use self::chrono::prelude::*;
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::prelude::*;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use types::Message;
+use types::SearchResult;
+use error::Error;
+
+use fractal_api::util::cache_path;
+use globals;
+
+/// Inverted index of the messages that we've seen, to search without the server: offline
+/// and with the filters that the server doesn't have. It's stored next to the rooms cache,
+/// and every SEARCH_INDEX_STORE_INTERVAL seconds while messages are added, in a thread, so
+/// a crash doesn't lose it.
+#[derive(Serialize, Deserialize, Default)]
+pub struct SearchIndex {
+    // indexed messages by event id
+    messages: HashMap<String, Message>,
+    // word -> event ids of the messages with the word
+    words: HashMap<String, HashSet<String>>,
+    // there are messages that aren't stored yet
+    #[serde(skip)]
+    changed: bool,
+    #[serde(skip)]
+    last_store: Option<Instant>,
+    // thread writing a copy of the index
+    #[serde(skip)]
+    writer: Option<thread::JoinHandle<()>>,
+}
+
+/// Search in the local index, the term can have words, "quoted phrases" and the filters
+/// from:user, before:2018-03-01 and after:2018-03-01
+#[derive(Debug, Default, PartialEq)]
+pub struct LocalQuery {
+    pub words: Vec<String>,
+    pub phrases: Vec<String>,
+    pub sender: Option<String>,
+    pub before: Option<NaiveDate>,
+    pub after: Option<NaiveDate>,
+}
+
+impl LocalQuery {
+    pub fn parse(term: &str) -> LocalQuery {
+        let mut q = LocalQuery::default();
+
+        // the odd pieces between quotes are the phrases
+        for (i, piece) in term.split('"').enumerate() {
+            if i % 2 == 1 {
+                let phrase = tokenize(piece);
+                if !phrase.is_empty() {
+                    q.words.extend(phrase.iter().cloned());
+                    q.phrases.push(phrase.join(" "));
+                }
+                continue;
+            }
+
+            for token in piece.split_whitespace() {
+                let date = |d: &str| NaiveDate::parse_from_str(d, "%Y-%m-%d").ok();
+                if token.starts_with("from:") {
+                    q.sender = Some(token["from:".len()..].trim_left_matches('@').to_lowercase());
+                } else if token.starts_with("before:") {
+                    q.before = date(&token["before:".len()..]);
+                } else if token.starts_with("after:") {
+                    q.after = date(&token["after:".len()..]);
+                } else {
+                    q.words.extend(tokenize(token));
+                }
+            }
+        }
+
+        q
+    }
+
+    /// The query has filters that the server search doesn't support
+    pub fn has_filters(&self) -> bool {
+        self.sender.is_some() || self.before.is_some() || self.after.is_some()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty() && !self.has_filters()
+    }
+
+    fn matches(&self, msg: &Message) -> bool {
+        let date = msg.date.naive_local().date();
+        if self.before.map(|d| date >= d).unwrap_or(false) ||
+           self.after.map(|d| date <= d).unwrap_or(false) {
+            return false;
+        }
+
+        if let Some(ref sender) = self.sender {
+            if !msg.sender.to_lowercase().contains(sender) {
+                return false;
+            }
+        }
+
+        // the words of each phrase one after another, not only the text
+        let body = tokenize(&msg.body);
+        self.phrases.iter().all(|p| {
+            let phrase: Vec<&str> = p.split(' ').collect();
+            body.windows(phrase.len()).any(|w| w.iter().zip(phrase.iter()).all(|(a, b)| a == b))
+        })
+    }
+}
+
+impl SearchIndex {
+    /// Adds the @msgs that aren't in the index yet, the ones from the sync, the back
+    /// pagination and the cache
+    pub fn add(&mut self, msgs: &[Message]) {
+        for msg in msgs.iter() {
+            let id = match msg.id {
+                Some(ref id) if !self.messages.contains_key(id) => id.clone(),
+                _ => continue,
+            };
+
+            for w in tokenize(&msg.body) {
+                self.words.entry(w).or_insert_with(HashSet::new).insert(id.clone());
+            }
+            self.messages.insert(id, msg.clone());
+            self.changed = true;
+        }
+
+        if self.messages.len() > globals::SEARCH_INDEX_SIZE {
+            self.forget_oldest();
+        }
+
+        let interval = Duration::from_secs(globals::SEARCH_INDEX_STORE_INTERVAL);
+        let due = self.last_store.map(|t| t.elapsed() >= interval).unwrap_or(true);
+        if self.changed && due {
+            self.store_in_thread();
+        }
+    }
+
+    // the main loop adds the messages, so the copy is serialized and written in a thread
+    fn store_in_thread(&mut self) {
+        self.wait_writer();
+
+        let snapshot = SearchIndex {
+            messages: self.messages.clone(),
+            words: self.words.clone(),
+            ..SearchIndex::default()
+        };
+        self.writer = Some(thread::spawn(move || {
+            if let Err(err) = write(&snapshot) {
+                println!("Error storing the search index: {}", err);
+            }
+        }));
+
+        self.changed = false;
+        self.last_store = Some(Instant::now());
+    }
+
+    // the index written before is older, so it has to finish first
+    fn wait_writer(&mut self) {
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+
+    /// The messages that match @query, in @room or in all the rooms, the newest first. The
+    /// context of each result is the indexed messages around it in the same room.
+    pub fn search(&self, query: &LocalQuery, room: Option<&str>) -> Vec<SearchResult> {
+        if query.is_empty() {
+            return vec![];
+        }
+
+        let mut found = match query.words.split_first() {
+            Some((first, rest)) => {
+                let mut ids = self.words.get(first).cloned().unwrap_or_default();
+                for w in rest {
+                    let other = self.words.get(w).cloned().unwrap_or_default();
+                    ids = ids.intersection(&other).cloned().collect();
+                }
+                ids.iter().filter_map(|id| self.messages.get(id)).collect::<Vec<&Message>>()
+            }
+            None => self.messages.values().collect(),
+        };
+
+        found.retain(|m| room.map(|r| m.room == r).unwrap_or(true) && query.matches(m));
+        found.sort_by(|a, b| b.date.cmp(&a.date));
+        found.truncate(globals::LOCAL_SEARCH_RESULTS);
+
+        let mut timelines: HashMap<&str, Vec<&Message>> = HashMap::new();
+        found.iter().map(|m| {
+            let timeline = timelines.entry(&m.room).or_insert_with(|| self.timeline(&m.room));
+            let pos = timeline.iter().position(|x| x.id == m.id).unwrap_or(0);
+            SearchResult {
+                message: (*m).clone(),
+                rank: 0.0,
+                before: timeline[..pos].iter().rev().take(1).map(|x| (*x).clone()).collect(),
+                after: timeline[pos + 1..].iter().take(1).map(|x| (*x).clone()).collect(),
+            }
+        }).collect()
+    }
+
+    // the indexed messages of @roomid by date
+    fn timeline(&self, roomid: &str) -> Vec<&Message> {
+        let mut msgs = self.messages.values().filter(|m| m.room == roomid).collect::<Vec<&Message>>();
+        msgs.sort_by_key(|m| m.date);
+        msgs
+    }
+
+    // removes the oldest tenth of the index, so this isn't done after each new message
+    fn forget_oldest(&mut self) {
+        let mut dates = self.messages.iter()
+            .map(|(id, m)| (m.date, id.clone()))
+            .collect::<Vec<(DateTime<Local>, String)>>();
+        dates.sort();
+
+        let n = self.messages.len() - globals::SEARCH_INDEX_SIZE * 9 / 10;
+        for (_, id) in dates.into_iter().take(n) {
+            if let Some(msg) = self.messages.remove(&id) {
+                for w in tokenize(&msg.body) {
+                    let empty = match self.words.get_mut(&w) {
+                        Some(ids) => {
+                            ids.remove(&id);
+                            ids.is_empty()
+                        }
+                        None => false,
+                    };
+                    if empty {
+                        self.words.remove(&w);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// lowercase words, without the punctuation
+fn tokenize(s: &str) -> Vec<String> {
+    s.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn write(index: &SearchIndex) -> Result<(), Error> {
+    let fname = cache_path("search-index.json")?;
+
+    let serialized = serde_json::to_string(index)?;
+    File::create(fname)?.write_all(&serialized.into_bytes())?;
+
+    Ok(())
+}
+
+pub fn store(index: &mut SearchIndex) -> Result<(), Error> {
+    index.wait_writer();
+    write(index)?;
+
+    index.changed = false;
+    index.last_store = Some(Instant::now());
+
+    Ok(())
+}
+
+pub fn load() -> Result<SearchIndex, Error> {
+    let fname = cache_path("search-index.json")?;
+
+    let mut file = File::open(fname)?;
+    let mut serialized = String::new();
+    file.read_to_string(&mut serialized)?;
+
+    let deserialized: SearchIndex = serde_json::from_str(&serialized)?;
+
+    Ok(deserialized)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(id: &str, room: &str, sender: &str, body: &str, day: u32) -> Message {
+        Message {
+            id: Some(id.to_string()),
+            room: room.to_string(),
+            sender: sender.to_string(),
+            body: body.to_string(),
+            date: Local.ymd(2018, 3, day).and_hms(12, 0, 0),
+            ..Message::default()
+        }
+    }
+
+    // not stored, the last store was just now
+    fn index() -> SearchIndex {
+        let mut index = SearchIndex { last_store: Some(Instant::now()), ..SearchIndex::default() };
+        index.add(&[
+            msg("$1", "!a", "@bob:example.org", "The foo barn is red", 10),
+            msg("$4", "!a", "@bob:example.org", "nothing here", 11),
+            msg("$2", "!a", "@alice:example.org", "foo bar baz", 12),
+            msg("$3", "!b", "@bob:example.org", "Foo, bar!", 15),
+        ]);
+        index
+    }
+
+    fn ids(results: Vec<SearchResult>) -> Vec<String> {
+        results.into_iter().filter_map(|r| r.message.id).collect()
+    }
+
+    #[test]
+    fn parse_words_phrases_and_filters() {
+        let q = LocalQuery::parse("hello \"Foo, bar!\" from:@Bob after:2018-03-01 before:2018-04-01 world");
+        assert_eq!(q.words, vec!["hello", "foo", "bar", "world"]);
+        assert_eq!(q.phrases, vec!["foo bar"]);
+        assert_eq!(q.sender, Some(String::from("bob")));
+        assert_eq!(q.after, Some(NaiveDate::from_ymd(2018, 3, 1)));
+        assert_eq!(q.before, Some(NaiveDate::from_ymd(2018, 4, 1)));
+        assert!(q.has_filters());
+
+        // an unclosed quote goes to the end, and empty quotes are nothing
+        let q = LocalQuery::parse("\"\" \"foo bar");
+        assert_eq!(q.phrases, vec!["foo bar"]);
+
+        let q = LocalQuery::parse("before:yesterday");
+        assert_eq!(q.before, None);
+        assert!(q.is_empty());
+
+        assert!(!LocalQuery::parse("from:bob").is_empty());
+        assert!(LocalQuery::parse(" ?! ").is_empty());
+    }
+
+    #[test]
+    fn phrases_match_whole_words() {
+        let index = index();
+        let q = LocalQuery::parse("\"foo bar\"");
+        assert_eq!(ids(index.search(&q, None)), vec!["$3", "$2"]);
+        assert_eq!(ids(index.search(&q, Some("!a"))), vec!["$2"]);
+
+        let q = LocalQuery::parse("\"bar foo\"");
+        assert!(index.search(&q, None).is_empty());
+    }
+
+    #[test]
+    fn search_with_filters() {
+        let index = index();
+        assert_eq!(ids(index.search(&LocalQuery::parse("foo from:bob"), None)), vec!["$3", "$1"]);
+        assert_eq!(ids(index.search(&LocalQuery::parse("foo before:2018-03-12"), None)), vec!["$1"]);
+        assert_eq!(ids(index.search(&LocalQuery::parse("foo after:2018-03-11"), None)), vec!["$3", "$2"]);
+        assert_eq!(ids(index.search(&LocalQuery::parse("from:alice"), None)), vec!["$2"]);
+        assert!(index.search(&LocalQuery::parse("foo missing"), None).is_empty());
+    }
+
+    #[test]
+    fn search_context() {
+        let index = index();
+        let results = index.search(&LocalQuery::parse("baz"), None);
+        assert_eq!(results.len(), 1);
+        assert_eq!(ids(results.clone()), vec!["$2"]);
+        assert_eq!(results[0].before.iter().filter_map(|m| m.id.clone()).collect::<Vec<String>>(),
+                   vec!["$4"]);
+        assert!(results[0].after.is_empty());
+    }
+}
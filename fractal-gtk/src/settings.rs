@@ -0,0 +1,71 @@
+extern crate serde_json;
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::prelude::*;
+
+use error::Error;
+
+use fractal_api::globals;
+use fractal_api::util::cache_path;
+use fractal_api::util::config_path;
+
+fn enabled() -> bool {
+    true
+}
+
+fn media_cache_size() -> u64 {
+    globals::MEDIA_CACHE_SIZE / (1024 * 1024)
+}
+
+// User preferences, stored in the config directory so clearing the cache keeps them
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Settings {
+    // global switch, some users don't want the server to fetch the links they receive
+    #[serde(default = "enabled")]
+    pub url_previews: bool,
+    #[serde(default)]
+    pub rooms_without_previews: HashSet<String>,
+    // size limit of the downloaded media, in MB
+    #[serde(default = "media_cache_size")]
+    pub media_cache_size: u64,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            url_previews: enabled(),
+            rooms_without_previews: HashSet::new(),
+            media_cache_size: media_cache_size(),
+        }
+    }
+}
+
+impl Settings {
+    pub fn url_previews_in(&self, roomid: &str) -> bool {
+        self.url_previews && !self.rooms_without_previews.contains(roomid)
+    }
+}
+
+pub fn store(settings: &Settings) -> Result<(), Error> {
+    let fname = config_path("settings.json")?;
+
+    let serialized = serde_json::to_string(settings)?;
+    File::create(fname)?.write_all(&serialized.into_bytes())?;
+
+    Ok(())
+}
+
+fn load_from(fname: &str) -> Result<Settings, Error> {
+    let mut serialized = String::new();
+    File::open(fname)?.read_to_string(&mut serialized)?;
+    Ok(serde_json::from_str(&serialized)?)
+}
+
+/// The stored settings, or the default ones if there aren't any. Older versions stored them
+/// in the cache directory, so they're read from there if they aren't in the config one.
+pub fn load() -> Settings {
+    config_path("settings.json").and_then(|fname| load_from(&fname))
+        .or_else(|_| cache_path("settings.json").and_then(|fname| load_from(&fname)))
+        .unwrap_or_default()
+}
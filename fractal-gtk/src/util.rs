@@ -2,12 +2,17 @@ extern crate regex;
 extern crate cairo;
 extern crate gdk;
 extern crate gdk_pixbuf;
+extern crate url;
 
 use self::regex::Regex;
+use self::url::Url;
+use std::path::Path;
 use self::gdk_pixbuf::Pixbuf;
 use failure::Error;
 use self::gdk::ContextExt;
 
+use types::Room;
+
 pub mod glib_thread_prelude {
     pub use std::thread;
     pub use std::sync::mpsc::channel;
@@ -40,16 +45,153 @@ macro_rules! glib_thread {
     }}
 }
 
+/// Formats a size in bytes to show it to the user, like "1.5 MB"
+pub fn format_size(bytes: u64) -> String {
+    let units = ["bytes", "kB", "MB", "GB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1000.0 && unit < units.len() - 1 {
+        size /= 1000.0;
+        unit += 1;
+    }
+
+    match unit {
+        0 => format!("{} {}", bytes, units[0]),
+        _ => format!("{:.1} {}", size, units[unit]),
+    }
+}
+
+/// The local files in a list of uris, like the dropped or copied files
+pub fn uris_to_paths(uris: &[String]) -> Vec<String> {
+    uris.iter()
+        .filter_map(|u| Url::parse(u.trim()).ok())
+        .filter_map(|u| u.to_file_path().ok())
+        .filter(|p| Path::new(p).is_file())
+        .filter_map(|p| p.to_str().map(String::from))
+        .collect()
+}
+
+/// Formats a duration in seconds like "1:05"
+pub fn format_time(secs: u64) -> String {
+    match secs {
+        s if s >= 3600 => format!("{}:{:02}:{:02}", s / 3600, s / 60 % 60, s % 60),
+        s => format!("{}:{:02}", s / 60, s % 60),
+    }
+}
+
+/// Returns the score of the fuzzy match of @pattern in @text, or None if the characters of
+/// @pattern aren't in @text in the same order. Matches at the start of a word and consecutive
+/// characters score more.
+pub fn fuzzy_match(pattern: &str, text: &str) -> Option<i32> {
+    let pattern = pattern.to_lowercase();
+    let text = text.to_lowercase();
+    let mut chars = pattern.chars().filter(|c| !c.is_whitespace()).peekable();
+
+    let mut score = 0;
+    let mut consecutive = false;
+    let mut prev: Option<char> = None;
+    for c in text.chars() {
+        match chars.peek() {
+            None => break,
+            Some(&p) if p == c => {
+                score += 1;
+                if consecutive {
+                    score += 5;
+                }
+                if prev.map(|x| !x.is_alphanumeric()).unwrap_or(true) {
+                    score += 10;
+                }
+                consecutive = true;
+                chars.next();
+            }
+            _ => consecutive = false,
+        };
+        prev = Some(c);
+    }
+
+    match chars.peek() {
+        None => Some(score),
+        Some(_) => None,
+    }
+}
+
+/// The names to look for a room: the room name, the alias and, for direct chats, the names
+/// of the members but the logged in user @uid, who is in all of them
+pub fn room_search_names(room: &Room, uid: &str) -> Vec<String> {
+    let mut names = vec![];
+    names.extend(room.name.clone());
+    names.extend(room.alias.clone());
+
+    if room.members.len() <= 2 {
+        for m in room.members.values().filter(|m| m.uid != uid) {
+            names.extend(m.alias.clone());
+            names.push(m.uid.clone());
+        }
+    }
+
+    names
+}
+
+/// Returns the best fuzzy match score of @term in the names of the @room, for the user @uid
+pub fn room_match(room: &Room, term: &str, uid: &str) -> Option<i32> {
+    room_search_names(room, uid).iter()
+        .filter_map(|n| fuzzy_match(term, n))
+        .max()
+}
+
+const URL_REGEX: &'static str = "(?P<url>https?://[^\\s&,)(\"]+(&\\w=[\\w._-]?)*(#[\\w._-]+)?)";
+
+lazy_static! {
+    // every shown message is checked for a link to preview
+    static ref URL_RE: Regex = Regex::new(URL_REGEX).unwrap();
+}
+
+/// The first link in the message text, the one that we show a preview for
+pub fn first_url(s: &str) -> Option<String> {
+    URL_RE.captures(s).map(|caps| String::from(&caps["url"]))
+}
+
+fn escape_markup(s: &str) -> String {
+    s.replace('&', "&amp;")
+     .replace('<', "&lt;")
+     .replace('>', "&gt;")
+}
+
 pub fn markup(s: &str) -> String {
-    let mut out = String::from(s);
+    let mut out = escape_markup(s.trim());
 
-    out = String::from(out.trim());
-    out = out.replace('&', "&amp;");
-    out = out.replace('<', "&lt;");
-    out = out.replace('>', "&gt;");
+    out = String::from(URL_RE.replace_all(&out, "<a href=\"$url\">$url</a>"));
 
-    let re = Regex::new("(?P<url>https?://[^\\s&,)(\"]+(&\\w=[\\w._-]?)*(#[\\w._-]+)?)").unwrap();
-    out = String::from(re.replace_all(&out, "<a href=\"$url\">$url</a>"));
+    out
+}
+
+/// The markup of @s with the @words in bold, for the search results
+pub fn highlight_markup(s: &str, words: &[String]) -> String {
+    let s = s.trim();
+
+    let words = words.iter()
+        .filter(|w| !w.is_empty())
+        .map(|w| regex::escape(w))
+        .collect::<Vec<String>>();
+    if words.is_empty() {
+        return escape_markup(s);
+    }
+
+    let re = match Regex::new(&format!("(?i)({})", words.join("|"))) {
+        Ok(re) => re,
+        Err(_) => return escape_markup(s),
+    };
+
+    // the words are found in the text before escaping it, or they'd match the entities
+    let mut out = String::new();
+    let mut last = 0;
+    for m in re.find_iter(s) {
+        out.push_str(&escape_markup(&s[last..m.start()]));
+        out.push_str(&format!("<b>{}</b>", escape_markup(m.as_str())));
+        last = m.end();
+    }
+    out.push_str(&escape_markup(&s[last..]));
 
     out
 }
@@ -68,3 +210,73 @@ pub fn get_pixbuf_data(pb: &Pixbuf) -> Result<Vec<u8>, Error> {
     image.write_to_png(&mut buf)?;
     Ok(buf)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::highlight_markup;
+    use super::fuzzy_match;
+    use super::room_match;
+    use types::Member;
+    use types::Room;
+
+    fn room(id: &str, name: Option<&str>, members: &[(&str, &str)]) -> Room {
+        let mut r = Room::new(String::from(id), name.map(String::from));
+        for &(uid, alias) in members {
+            r.members.insert(String::from(uid), Member {
+                alias: Some(String::from(alias)),
+                uid: String::from(uid),
+                avatar: None,
+            });
+        }
+        r
+    }
+
+    #[test]
+    fn highlight_markup_escapes_around_the_matches() {
+        let words = vec![String::from("amp"), String::from("lt")];
+        assert_eq!(highlight_markup("a & b < c, example", &words),
+                   "a &amp; b &lt; c, ex<b>amp</b>le");
+
+        let words = vec![String::from("fractal"), String::from("<3")];
+        assert_eq!(highlight_markup(" Fractal <3 ", &words),
+                   "<b>Fractal</b> <b>&lt;3</b>");
+
+        assert_eq!(highlight_markup("a > b", &[]), "a &gt; b");
+    }
+
+    #[test]
+    fn fuzzy_match_scores() {
+        // consecutive letters and word starts are better
+        assert_eq!(fuzzy_match("fr", "Fractal"), Some(17));
+        assert_eq!(fuzzy_match("fl", "Fractal"), Some(12));
+        assert_eq!(fuzzy_match("fr ac", "Fractal"), Some(29));
+        assert_eq!(fuzzy_match("t", "the room"), Some(11));
+        assert_eq!(fuzzy_match("t", "at"), Some(1));
+
+        assert_eq!(fuzzy_match("", "Fractal"), Some(0));
+        assert_eq!(fuzzy_match("xyz", "Fractal"), None);
+        assert_eq!(fuzzy_match("lf", "Fractal"), None);
+    }
+
+    #[test]
+    fn room_match_names() {
+        let me = "@alice:example.org";
+
+        let r = room("!a:example.org", Some("Fractal"), &[]);
+        assert!(room_match(&r, "frac", me).is_some());
+        assert!(room_match(&r, "gnome", me).is_none());
+
+        // direct chats are found by the other member, but not by us
+        let dm = room("!b:example.org", None, &[(me, "Alice"), ("@bob:example.org", "Bob Builder")]);
+        assert!(room_match(&dm, "builder", me).is_some());
+        assert!(room_match(&dm, "@bob", me).is_some());
+        assert!(room_match(&dm, "alice", me).is_none());
+
+        // the members of bigger rooms aren't looked for
+        let group = room("!c:example.org", Some("Team"), &[(me, "Alice"),
+                                                            ("@bob:example.org", "Bob"),
+                                                            ("@carol:example.org", "Carol")]);
+        assert!(room_match(&group, "carol", me).is_none());
+        assert!(room_match(&group, "team", me).is_some());
+    }
+}
@@ -1,12 +1,16 @@
 extern crate gtk;
 extern crate gdk;
 extern crate gdk_pixbuf;
+extern crate cairo;
 
 use self::gtk::prelude::*;
 pub use self::gtk::DrawingArea;
 use self::gdk_pixbuf::Pixbuf;
 use self::gdk::ContextExt;
 
+use std::cell::RefCell;
+use std::rc::Rc;
+
 
 pub type Avatar = gtk::Box;
 
@@ -66,33 +70,21 @@ impl AvatarExt for gtk::Box {
         let da = self.create_da(size);
         let s = size.unwrap_or(40);
 
-        let pixbuf = match gtk::IconTheme::get_default() {
-            None => None,
-            Some(i1) => match i1.load_icon(&icon[..], s, gtk::IconLookupFlags::empty()) {
-                Err(_) => None,
-                Ok(i2) => i2,
-            }
-        };
+        let loaded: Rc<RefCell<Option<(i32, Pixbuf)>>> = Rc::new(RefCell::new(None));
 
         da.connect_draw(move |da, g| {
-            use std::f64::consts::PI;
-
-            let width = s as f64;
-            let height = s as f64;
+            let sf = da.get_scale_factor();
+            let pixbuf = scaled_pixbuf(&loaded, s, sf, |size| {
+                gtk::IconTheme::get_default()
+                    .and_then(|theme| theme.load_icon(&icon[..], size, gtk::IconLookupFlags::empty()).ok())
+                    .and_then(|pb| pb)
+            });
 
             let context = da.get_style_context().unwrap();
-
-            gtk::render_background(&context, g, 0.0, 0.0, width, height);
+            gtk::render_background(&context, g, 0.0, 0.0, s as f64, s as f64);
 
             if let Some(ref pb) = pixbuf {
-                let hpos: f64 = (width - (pb.get_height()) as f64) / 2.0;
-
-                g.arc(width / 2.0, height / 2.0, width.min(height) / 2.5, 0.0, 2.0 * PI);
-                g.clip();
-
-                g.set_source_pixbuf(&pb, 0.0, hpos);
-                g.rectangle(0.0, 0.0, width, height);
-                g.fill();
+                draw_circle(g, pb, s, sf, 2.5);
             }
 
             Inhibit(false)
@@ -109,30 +101,60 @@ impl AvatarExt for gtk::Box {
         let da = self.create_da(size);
         let s = size.unwrap_or(40);
 
-        let pixbuf = Pixbuf::new_from_file_at_scale(&path, s, -1, true);
+        let loaded: Rc<RefCell<Option<(i32, Pixbuf)>>> = Rc::new(RefCell::new(None));
 
         da.connect_draw(move |da, g| {
-            use std::f64::consts::PI;
-
-            let width = s as f64;
-            let height = s as f64;
+            let sf = da.get_scale_factor();
+            let pixbuf = scaled_pixbuf(&loaded, s, sf, |size| {
+                Pixbuf::new_from_file_at_scale(&path, size, -1, true).ok()
+            });
 
             let context = da.get_style_context().unwrap();
+            gtk::render_background(&context, g, 0.0, 0.0, s as f64, s as f64);
 
-            gtk::render_background(&context, g, 0.0, 0.0, width, height);
-
-            if let Ok(ref pb) = pixbuf {
-                let hpos: f64 = (width - (pb.get_height()) as f64) / 2.0;
-
-                g.arc(width / 2.0, height / 2.0, width.min(height) / 2.0, 0.0, 2.0 * PI);
-                g.clip();
-
-                g.set_source_pixbuf(&pb, 0.0, hpos);
-                g.rectangle(0.0, 0.0, width, height);
-                g.fill();
+            if let Some(ref pb) = pixbuf {
+                draw_circle(g, pb, s, sf, 2.0);
             }
 
             Inhibit(false)
         });
     }
 }
+
+/// Returns the pixbuf for the scale factor @sf. It's loaded with @load, that gets the size in
+/// pixels, the first time and again if the widget is moved to a screen with other scale factor.
+fn scaled_pixbuf<F>(loaded: &Rc<RefCell<Option<(i32, Pixbuf)>>>, size: i32, sf: i32, load: F) -> Option<Pixbuf>
+    where F: Fn(i32) -> Option<Pixbuf> {
+    let mut l = loaded.borrow_mut();
+    if let Some((f, ref pb)) = *l {
+        if f == sf {
+            return Some(pb.clone());
+        }
+    }
+
+    let pb = load(size * sf);
+    *l = pb.clone().map(|pb| (sf, pb));
+    pb
+}
+
+/// Draws @pb, loaded for the scale factor @sf, clipped to a circle with the @size diameter
+/// divided by @ratio
+fn draw_circle(g: &cairo::Context, pb: &Pixbuf, size: i32, sf: i32, ratio: f64) {
+    use std::f64::consts::PI;
+
+    // the pixbuf is drawn in device pixels so it isn't scaled up in hidpi screens
+    let s = (size * sf) as f64;
+    g.save();
+    g.scale(1.0 / sf as f64, 1.0 / sf as f64);
+
+    let hpos: f64 = (s - (pb.get_height()) as f64) / 2.0;
+
+    g.arc(s / 2.0, s / 2.0, s / ratio, 0.0, 2.0 * PI);
+    g.clip();
+
+    g.set_source_pixbuf(pb, 0.0, hpos);
+    g.rectangle(0.0, 0.0, s, s);
+    g.fill();
+
+    g.restore();
+}
@@ -0,0 +1,286 @@
+extern crate gtk;
+extern crate url;
+
+use self::gtk::prelude::*;
+use self::url::Url;
+
+use gst;
+use gst::prelude::*;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::mpsc::channel;
+use std::sync::mpsc::{Sender, Receiver};
+use std::sync::mpsc::TryRecvError;
+
+use backend::BKCommand;
+use types::Message;
+use util::format_time;
+
+use app::AppOp;
+
+/// Milliseconds between position updates while the media is loaded
+const TICK_INTERVAL: u32 = 250;
+
+// Inline player for audio and video messages. The file is downloaded to the media cache the
+// first time that it's played.
+pub struct MediaPlayer<'a> {
+    msg: &'a Message,
+    op: &'a AppOp,
+}
+
+// The playbin is shared by the widget callbacks and it's stopped when the widget is destroyed
+struct Playback {
+    pipeline: Option<gst::Element>,
+    playing: bool,
+    loading: bool,
+    destroyed: bool,
+}
+
+struct Controls {
+    button: gtk::Button,
+    scale: gtk::Scale,
+    time: gtk::Label,
+    screen: gtk::Box,
+}
+
+impl<'a> MediaPlayer<'a> {
+    pub fn new(msg: &'a Message, op: &'a AppOp) -> MediaPlayer<'a> {
+        MediaPlayer {
+            msg: msg,
+            op: op,
+        }
+    }
+
+    pub fn widget(&self) -> gtk::Box {
+        let msg = self.msg;
+        let video = msg.mtype == "m.video";
+        let duration = msg.info.as_ref().and_then(|i| i.duration).unwrap_or(0) / 1000;
+
+        let bx = gtk::Box::new(gtk::Orientation::Vertical, 6);
+
+        let name = gtk::Label::new(Some(&msg.body[..]));
+        name.set_halign(gtk::Align::Start);
+        name.set_selectable(true);
+        bx.pack_start(&name, false, false, 0);
+
+        // the video widget is added here when the video is loaded
+        let screen = gtk::Box::new(gtk::Orientation::Vertical, 0);
+        bx.pack_start(&screen, false, false, 0);
+
+        let controls = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+        let button = gtk::Button::new_from_icon_name("media-playback-start-symbolic", 1);
+        button.set_relief(gtk::ReliefStyle::None);
+        button.set_tooltip_text(Some("Play"));
+
+        let scale = gtk::Scale::new_with_range(gtk::Orientation::Horizontal,
+                                               0.0, duration.max(1) as f64, 1.0);
+        scale.set_draw_value(false);
+        scale.set_size_request(200, -1);
+        scale.set_sensitive(false);
+
+        let time = gtk::Label::new(Some(&time_text(0, duration)[..]));
+        time.get_style_context().unwrap().add_class("dim-label");
+
+        controls.pack_start(&button, false, false, 0);
+        controls.pack_start(&scale, true, true, 0);
+        controls.pack_start(&time, false, false, 0);
+        bx.pack_start(&controls, false, false, 0);
+
+        let controls = Rc::new(Controls {
+            button: button.clone(),
+            scale: scale.clone(),
+            time: time,
+            screen: screen,
+        });
+        let playback = Rc::new(RefCell::new(Playback {
+            pipeline: None,
+            playing: false,
+            loading: false,
+            destroyed: false,
+        }));
+
+        let pb = playback.clone();
+        let c = controls.clone();
+        let backend = self.op.backend.clone();
+        let url = msg.url.clone().unwrap_or_default();
+        button.connect_clicked(move |_| {
+            if pb.borrow().loading {
+                return;
+            }
+
+            if pb.borrow().pipeline.is_none() {
+                load(&pb, &c, &backend, &url, video);
+            } else {
+                toggle(&pb, &c);
+            }
+        });
+
+        let pb = playback.clone();
+        scale.connect_change_value(move |_, _, value| {
+            if let Some(ref pipeline) = pb.borrow().pipeline {
+                let pos = gst::ClockTime::from_seconds(value.max(0.0) as u64);
+                let _ = pipeline.seek_simple(gst::SeekFlags::FLUSH | gst::SeekFlags::KEY_UNIT, pos);
+            }
+            Inhibit(false)
+        });
+
+        let pb = playback.clone();
+        bx.connect_destroy(move |_| {
+            let mut p = pb.borrow_mut();
+            p.destroyed = true;
+            if let Some(pipeline) = p.pipeline.take() {
+                pipeline.set_state(gst::State::Null);
+            }
+        });
+
+        bx
+    }
+}
+
+fn time_text(pos: u64, duration: u64) -> String {
+    match duration {
+        0 => format_time(pos),
+        d => format!("{} / {}", format_time(pos), format_time(d)),
+    }
+}
+
+fn set_playing(controls: &Controls, playing: bool) {
+    let (icon, tooltip) = if playing {
+        ("media-playback-pause-symbolic", "Pause")
+    } else {
+        ("media-playback-start-symbolic", "Play")
+    };
+
+    let image = gtk::Image::new_from_icon_name(icon, 1);
+    controls.button.set_image(&image);
+    controls.button.set_tooltip_text(Some(tooltip));
+}
+
+fn set_error(controls: &Controls) {
+    let image = gtk::Image::new_from_icon_name("dialog-error-symbolic", 1);
+    controls.button.set_image(&image);
+    controls.button.set_tooltip_text(Some("Can't play this file"));
+    controls.button.set_sensitive(false);
+    controls.scale.set_sensitive(false);
+}
+
+/// Downloads the media and starts playing it
+fn load(playback: &Rc<RefCell<Playback>>, controls: &Rc<Controls>,
+        backend: &Sender<BKCommand>, url: &str, video: bool) {
+    playback.borrow_mut().loading = true;
+    controls.button.set_sensitive(false);
+
+    let (tx, rx): (Sender<String>, Receiver<String>) = channel();
+    backend.send(BKCommand::GetMediaAsync(String::from(url), tx)).unwrap();
+
+    let pb = playback.clone();
+    let c = controls.clone();
+    gtk::timeout_add(50, move || match rx.try_recv() {
+        Err(TryRecvError::Empty) => gtk::Continue(true),
+        Err(TryRecvError::Disconnected) => gtk::Continue(false),
+        Ok(fname) => {
+            pb.borrow_mut().loading = false;
+            if pb.borrow().destroyed {
+                return gtk::Continue(false);
+            }
+
+            c.button.set_sensitive(true);
+            match start(&pb, &c, &fname, video) {
+                Some(_) => {
+                    toggle(&pb, &c);
+                    tick(&pb, &c);
+                }
+                None => set_error(&c),
+            };
+            gtk::Continue(false)
+        }
+    });
+}
+
+/// Creates the playbin for the downloaded file, with a gtksink for videos
+fn start(playback: &Rc<RefCell<Playback>>, controls: &Controls, fname: &str, video: bool) -> Option<()> {
+    if fname.is_empty() {
+        return None;
+    }
+
+    let uri = Url::from_file_path(fname).ok()?;
+    let playbin = gst::ElementFactory::make("playbin", None)?;
+    playbin.set_property("uri", &uri.as_str()).ok()?;
+
+    if video {
+        let sink = gst::ElementFactory::make("gtksink", None)?;
+        let widget = sink.get_property("widget").ok()?.get::<gtk::Widget>()?;
+        widget.set_size_request(320, 180);
+        playbin.set_property("video-sink", &sink).ok()?;
+
+        controls.screen.pack_start(&widget, false, false, 0);
+        widget.show();
+    }
+
+    controls.scale.set_sensitive(true);
+    playback.borrow_mut().pipeline = Some(playbin);
+
+    Some(())
+}
+
+fn toggle(playback: &Rc<RefCell<Playback>>, controls: &Controls) {
+    let mut p = playback.borrow_mut();
+    let playing = !p.playing;
+
+    if let Some(ref pipeline) = p.pipeline {
+        let state = if playing { gst::State::Playing } else { gst::State::Paused };
+        if pipeline.set_state(state) == gst::StateChangeReturn::Failure {
+            return;
+        }
+    }
+
+    p.playing = playing;
+    set_playing(controls, playing);
+}
+
+/// Updates the position while the media is loaded and handles the end of the stream and the
+/// playback errors
+fn tick(playback: &Rc<RefCell<Playback>>, controls: &Rc<Controls>) {
+    let pb = playback.clone();
+    let c = controls.clone();
+    gtk::timeout_add(TICK_INTERVAL, move || {
+        let mut p = pb.borrow_mut();
+        let pipeline = match p.pipeline {
+            Some(ref pipeline) => pipeline.clone(),
+            None => return gtk::Continue(false),
+        };
+
+        if let Some(bus) = pipeline.get_bus() {
+            while let Some(msg) = bus.pop() {
+                match msg.view() {
+                    gst::MessageView::Eos(..) => {
+                        // back to the start, ready to play again
+                        pipeline.set_state(gst::State::Paused);
+                        let _ = pipeline.seek_simple(gst::SeekFlags::FLUSH, gst::ClockTime::from_seconds(0));
+                        p.playing = false;
+                        set_playing(&c, false);
+                    }
+                    gst::MessageView::Error(err) => {
+                        println!("Error playing the media: {}", err.get_error());
+                        pipeline.set_state(gst::State::Null);
+                        p.pipeline = None;
+                        set_error(&c);
+                        return gtk::Continue(false);
+                    }
+                    _ => {}
+                };
+            }
+        }
+
+        let pos = pipeline.query_position::<gst::ClockTime>().and_then(|t| t.seconds());
+        let duration = pipeline.query_duration::<gst::ClockTime>().and_then(|t| t.seconds());
+        if let (Some(pos), Some(duration)) = (pos, duration) {
+            c.scale.set_range(0.0, duration.max(1) as f64);
+            c.scale.set_value(pos as f64);
+            c.time.set_text(&time_text(pos, duration));
+        }
+
+        gtk::Continue(true)
+    });
+}
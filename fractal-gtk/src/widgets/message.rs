@@ -9,17 +9,23 @@ use self::gtk::prelude::*;
 use types::Message;
 use types::Member;
 use types::Room;
+use types::UrlPreview;
 
 use self::chrono::prelude::*;
 
 use backend::BKCommand;
 
 use fractal_api as api;
+use glib;
 use util;
 
 use std::path::Path;
+use std::sync::mpsc::channel;
+use std::sync::mpsc::{Sender, Receiver};
+use std::sync::mpsc::TryRecvError;
 
 use app::AppOp;
+use app::InternalCommand;
 use globals;
 use widgets;
 use widgets::AvatarExt;
@@ -81,6 +87,50 @@ impl<'a> MessageBox<'a> {
         msg_widget
     }
 
+    pub fn failed_widget(&self) -> gtk::Box {
+        // msg
+        // +--------+---------+
+        // | avatar | content |
+        // +--------+---------+
+        // |        | error   |
+        // +--------+---------+
+        let msg_widget = gtk::Box::new(gtk::Orientation::Vertical, 0);
+        let error = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+
+        let label = gtk::Label::new("Couldn't send the message");
+        if let Some(style) = label.get_style_context() {
+            style.add_class("msg-failed");
+        }
+
+        let txnid = self.msg.txnid.clone().unwrap_or_default();
+
+        let retry = gtk::Button::new_with_label("Retry");
+        let internal = self.op.internal.clone();
+        let id = txnid.clone();
+        retry.connect_clicked(move |_| {
+            internal.send(InternalCommand::RetryTmpMsg(id.clone())).unwrap();
+        });
+
+        let cancel = gtk::Button::new_with_label("Cancel");
+        let internal = self.op.internal.clone();
+        let id = txnid.clone();
+        cancel.connect_clicked(move |_| {
+            internal.send(InternalCommand::CancelTmpMsg(id.clone())).unwrap();
+        });
+
+        error.pack_start(&label, false, false, 0);
+        error.pack_start(&retry, false, false, 0);
+        error.pack_start(&cancel, false, false, 0);
+        error.set_margin_start(55);
+
+        msg_widget.pack_start(&self.widget(), true, true, 0);
+        msg_widget.pack_start(&error, false, false, 0);
+
+        msg_widget.show_all();
+
+        msg_widget
+    }
+
     fn build_room_msg_content(&self, small: bool) -> gtk::Box {
         // content
         // +------+
@@ -102,7 +152,11 @@ impl<'a> MessageBox<'a> {
             "m.image" => {
                 body = self.build_room_msg_image();
             }
-            "m.video" | "m.audio" | "m.file" => {
+            "m.video" | "m.audio" if self.op.gst => {
+                body = self.build_room_msg_media();
+            }
+            // without GStreamer the media can't be played here
+            "m.file" | "m.video" | "m.audio" => {
                 body = self.build_room_msg_file();
             }
             _ => {
@@ -120,7 +174,20 @@ impl<'a> MessageBox<'a> {
         let backend = self.op.backend.clone();
         let avatar = widgets::Avatar::avatar_new(Some(globals::MSG_ICON_SIZE));
 
-        let fname = api::util::cache_path(&sender).unwrap_or(strn!(""));
+        // the avatar is in the media cache if it's downloaded, or it's an identicon
+        let size = api::util::avatar_pixels(api::globals::AVATAR_SIZE);
+        let member = self.room.members.get(&sender);
+        let cached = member
+            .and_then(|m| m.avatar.clone())
+            .and_then(|url| api::mediacache::mxc_key(&url, api::mediacache::Bucket::Thumb, size, size))
+            .and_then(|key| api::mediacache::lookup(&key));
+        let fname = match cached {
+            Some(f) => f,
+            None => {
+                let name = member.and_then(|m| m.get_alias()).unwrap_or(sender.clone());
+                api::util::identicon_path(&sender, &name, api::util::AvatarMode::Circle, size).unwrap_or(strn!(""))
+            }
+        };
 
         let pathname = fname.clone();
         let p = Path::new(&pathname);
@@ -164,14 +231,15 @@ impl<'a> MessageBox<'a> {
     }
 
     fn build_room_msg_body(&self, body: &str) -> gtk::Box {
-        let bx = gtk::Box::new(gtk::Orientation::Horizontal, 0);
+        let bx = gtk::Box::new(gtk::Orientation::Vertical, 6);
         let msg = gtk::Label::new("");
 
-        let uname = &self.op.username.clone().unwrap_or_default();
+        // the same highlight of the push rules that notifies the message
+        let highlight = self.op.push_actions(self.msg).map(|a| a.highlight).unwrap_or(false);
 
         if self.msg.id.is_none() || self.msg.id.clone().unwrap_or_default().is_empty() {
             msg.set_markup(&format!("<span color=\"#aaaaaa\">{}</span>", util::markup(body)));
-        } else if String::from(body).contains(uname) {
+        } else if highlight {
             msg.set_markup(&format!("<span color=\"#ff888e\">{}</span>", util::markup(body)));
         } else {
             msg.set_markup(&util::markup(body));
@@ -185,34 +253,107 @@ impl<'a> MessageBox<'a> {
         msg.set_selectable(true);
 
         bx.add(&msg);
+
+        let sent = self.msg.id.as_ref().map(|id| !id.is_empty()).unwrap_or(false);
+        if sent && self.op.settings.url_previews_in(&self.msg.room) {
+            if let Some(url) = util::first_url(body) {
+                bx.add(&self.build_url_preview(url));
+            }
+        }
+
         bx
     }
 
+    /// The preview card is hidden until the server gives us something to show
+    fn build_url_preview(&self, url: String) -> gtk::Box {
+        let card = gtk::Box::new(gtk::Orientation::Horizontal, 12);
+        card.get_style_context().unwrap().add_class("url-preview");
+        card.set_halign(gtk::Align::Start);
+        card.set_no_show_all(true);
+
+        let (tx, rx): (Sender<Option<UrlPreview>>, Receiver<Option<UrlPreview>>) = channel();
+        let ts = self.msg.date.timestamp() * 1000;
+        self.op.backend.send(BKCommand::GetUrlPreview(url, ts, tx)).unwrap();
+
+        let backend = self.op.backend.clone();
+        let c = card.clone();
+        gtk::timeout_add(50, move || match rx.try_recv() {
+            Err(TryRecvError::Empty) => gtk::Continue(true),
+            Err(TryRecvError::Disconnected) => gtk::Continue(false),
+            Ok(None) => gtk::Continue(false),
+            Ok(Some(preview)) => {
+                fill_url_preview(&c, &backend, &preview);
+                gtk::Continue(false)
+            }
+        });
+
+        card
+    }
+
     fn build_room_msg_image(&self) -> gtk::Box {
         let msg = self.msg;
         let bx = gtk::Box::new(gtk::Orientation::Horizontal, 0);
-        let image = gtk::Image::new();
+        let image = gtk::Image::new_from_icon_name("image-x-generic-symbolic", 6);
+
+        // the sender thumbnail if there's one, or a thumbnail of the image made by the server.
+        // Messages cached by older versions have the path of a downloaded thumbnail instead
+        // of the mxc url, so for those it's asked again.
+        let source = match msg.thumb {
+            Some(ref t) if t.starts_with("mxc://") => t.clone(),
+            _ => msg.url.clone().unwrap_or_default(),
+        };
 
-        if let Ok(pixbuf) = Pixbuf::new_from_file_at_scale(&msg.thumb.clone().unwrap_or_default(), 200, 200, true) {
-            image.set_from_pixbuf(&pixbuf);
-        } else {
-            image.set_from_file(&msg.thumb.clone().unwrap_or_default());
-        }
+        let (tx, rx): (Sender<String>, Receiver<String>) = channel();
+        let (w, h) = globals::IMAGE_THUMB_SIZE;
+        self.op.backend.send(BKCommand::GetMediaThumbAsync(source, w, h, tx)).unwrap();
+        let img = image.clone();
+        gtk::timeout_add(50, move || match rx.try_recv() {
+            Err(TryRecvError::Empty) => gtk::Continue(true),
+            Err(TryRecvError::Disconnected) => gtk::Continue(false),
+            Ok(fname) => {
+                if let Ok(pixbuf) = Pixbuf::new_from_file_at_scale(&fname, 200, 200, true) {
+                    img.set_from_pixbuf(&pixbuf);
+                }
+                gtk::Continue(false)
+            }
+        });
 
         let viewbtn = gtk::Button::new();
-        let url = msg.url.clone().unwrap_or_default();
-        let backend = self.op.backend.clone();
-        //let img = image.clone();
+        let m = msg.clone();
+        let internal = self.op.internal.clone();
         viewbtn.connect_clicked(move |_| {
-            //let spin = gtk::Spinner::new();
-            //spin.start();
-            //btn.add(&spin);
-            backend.send(BKCommand::GetMedia(url.clone())).unwrap();
+            internal.send(InternalCommand::ShowImage(m.clone())).unwrap();
         });
 
         viewbtn.set_image(&image);
 
         bx.add(&viewbtn);
+        bx.pack_start(&self.build_save_button(), false, false, 0);
+        bx
+    }
+
+    fn build_save_button(&self) -> gtk::Button {
+        let btn = gtk::Button::new_from_icon_name("document-save-symbolic", 1);
+        btn.set_tooltip_text(Some("Save as…"));
+        btn.set_valign(gtk::Align::Start);
+        btn.set_relief(gtk::ReliefStyle::None);
+
+        let url = self.msg.url.clone().unwrap_or_default();
+        let name = self.msg.body.clone();
+        let internal = self.op.internal.clone();
+        btn.connect_clicked(move |_| {
+            internal.send(InternalCommand::SaveMedia(url.clone(), name.clone())).unwrap();
+        });
+
+        btn
+    }
+
+    fn build_room_msg_media(&self) -> gtk::Box {
+        let bx = gtk::Box::new(gtk::Orientation::Horizontal, 0);
+        let player = widgets::MediaPlayer::new(self.msg, self.op);
+
+        bx.pack_start(&player.widget(), true, true, 0);
+        bx.pack_start(&self.build_save_button(), false, false, 0);
         bx
     }
 
@@ -222,14 +363,19 @@ impl<'a> MessageBox<'a> {
 
         let viewbtn = gtk::Button::new();
         let url = msg.url.clone().unwrap_or_default();
-        let backend = self.op.backend.clone();
+        let name = msg.body.clone();
+        let internal = self.op.internal.clone();
         viewbtn.connect_clicked(move |_| {
-            backend.send(BKCommand::GetMedia(url.clone())).unwrap();
+            internal.send(InternalCommand::OpenMedia(url.clone(), name.clone())).unwrap();
         });
 
-        viewbtn.set_label(&msg.body);
+        match msg.info.as_ref().and_then(|i| i.size) {
+            Some(size) => viewbtn.set_label(&format!("{} ({})", msg.body, util::format_size(size))),
+            None => viewbtn.set_label(&msg.body),
+        };
 
         bx.add(&viewbtn);
+        bx.pack_start(&self.build_save_button(), false, false, 0);
         bx
     }
 
@@ -265,3 +411,59 @@ impl<'a> MessageBox<'a> {
         info
     }
 }
+
+fn fill_url_preview(card: &gtk::Box, backend: &Sender<BKCommand>, preview: &UrlPreview) {
+    if let Some(ref mxc) = preview.image {
+        let size = globals::URL_PREVIEW_IMAGE_SIZE;
+        let image = gtk::Image::new();
+        image.set_valign(gtk::Align::Start);
+        card.pack_start(&image, false, false, 0);
+
+        let (tx, rx): (Sender<String>, Receiver<String>) = channel();
+        backend.send(BKCommand::GetMediaThumbAsync(mxc.clone(), size, size, tx)).unwrap();
+        gtk::timeout_add(50, move || match rx.try_recv() {
+            Err(TryRecvError::Empty) => gtk::Continue(true),
+            Err(TryRecvError::Disconnected) => gtk::Continue(false),
+            Ok(fname) => {
+                if let Ok(pixbuf) = Pixbuf::new_from_file_at_scale(&fname, size, size, true) {
+                    image.set_from_pixbuf(&pixbuf);
+                }
+                gtk::Continue(false)
+            }
+        });
+    }
+
+    let text = gtk::Box::new(gtk::Orientation::Vertical, 2);
+
+    if let Some(ref site) = preview.site_name {
+        let label = gtk::Label::new(Some(&site[..]));
+        label.set_halign(gtk::Align::Start);
+        label.get_style_context().unwrap().add_class("dim-label");
+        text.pack_start(&label, false, false, 0);
+    }
+
+    let title = preview.title.clone().unwrap_or(preview.url.clone());
+    let label = gtk::Label::new("");
+    label.set_markup(&format!("<a href=\"{}\"><b>{}</b></a>",
+                              glib::markup_escape_text(&preview.url),
+                              glib::markup_escape_text(&title)));
+    label.set_halign(gtk::Align::Start);
+    label.set_line_wrap(true);
+    label.set_xalign(0.0);
+    text.pack_start(&label, false, false, 0);
+
+    if let Some(ref desc) = preview.description {
+        let label = gtk::Label::new(Some(&desc[..]));
+        label.set_halign(gtk::Align::Start);
+        label.set_xalign(0.0);
+        label.set_line_wrap(true);
+        label.set_lines(3);
+        label.set_ellipsize(pango::EllipsizeMode::End);
+        label.set_max_width_chars(60);
+        text.pack_start(&label, false, false, 0);
+    }
+
+    card.pack_start(&text, true, true, 0);
+    card.set_no_show_all(false);
+    card.show_all();
+}
@@ -4,11 +4,21 @@ mod member;
 mod roomrow;
 mod roomlist;
 mod avatar;
+mod mediaplayer;
+mod quickswitcher;
+mod searchresult;
+mod roompreview;
 
 pub use self::message::MessageBox;
 pub use self::room::RoomBox;
 pub use self::member::MemberBox;
 pub use self::roomrow::RoomRow;
 pub use self::roomlist::RoomList;
+pub use self::roomlist::FAVOURITE_TAG;
+pub use self::roomlist::LOW_PRIORITY_TAG;
 pub use self::avatar::Avatar;
 pub use self::avatar::AvatarExt;
+pub use self::mediaplayer::MediaPlayer;
+pub use self::quickswitcher::QuickSwitcher;
+pub use self::searchresult::SearchResultBox;
+pub use self::roompreview::RoomPreview;
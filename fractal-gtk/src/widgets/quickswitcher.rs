@@ -0,0 +1,194 @@
+extern crate gtk;
+extern crate gdk;
+extern crate pango;
+
+use self::gtk::prelude::*;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use types::Room;
+use types::Message;
+use util::room_match;
+
+use app::AppOp;
+use app::InternalCommand;
+
+/// Rooms shown in the quick switcher
+const MAX_RESULTS: usize = 12;
+/// Score added to the rooms with unread messages, and to the most recent rooms
+const UNREAD_SCORE: i32 = 30;
+const RECENT_SCORE: i32 = 20;
+
+// Dialog to go to a room by its name, alias or the name of the person in a direct chat. The
+// results are sorted by the match, if there are unread messages and by the last activity.
+pub struct QuickSwitcher<'a> {
+    op: &'a AppOp,
+}
+
+struct Candidate {
+    room: Room,
+    recency: i32,
+}
+
+impl<'a> QuickSwitcher<'a> {
+    pub fn new(op: &'a AppOp) -> QuickSwitcher<'a> {
+        QuickSwitcher {
+            op: op,
+        }
+    }
+
+    pub fn dialog(&self, parent: &gtk::Window) -> gtk::Dialog {
+        let dialog = gtk::Dialog::new_with_buttons(
+            Some("Switch to room"),
+            Some(parent),
+            gtk::DialogFlags::MODAL|
+            gtk::DialogFlags::USE_HEADER_BAR|
+            gtk::DialogFlags::DESTROY_WITH_PARENT,
+            &[]);
+        dialog.set_default_size(400, 360);
+
+        let entry = gtk::SearchEntry::new();
+        entry.set_placeholder_text("Room name, alias or person");
+
+        let list = gtk::ListBox::new();
+        let scroll = gtk::ScrolledWindow::new(None, None);
+        scroll.set_vexpand(true);
+        scroll.set_hscrollbar_policy(gtk::PolicyType::Never);
+        scroll.add(&list);
+
+        let content = dialog.get_content_area();
+        content.set_spacing(6);
+        content.set_border_width(6);
+        content.pack_start(&entry, false, false, 0);
+        content.pack_start(&scroll, true, true, 0);
+
+        // copies of the rooms without the messages, the callbacks can't borrow the AppOp
+        let mut rooms = self.op.rooms.values().collect::<Vec<&Room>>();
+        rooms.sort_by_key(|r| r.messages.last().map(|m| m.date).unwrap_or(Message::default().date));
+        let candidates = rooms.iter().rev().enumerate().map(|(i, r)| {
+            let mut room = (*r).clone();
+            room.messages = vec![];
+            Candidate { room: room, recency: i as i32 }
+        }).collect::<Vec<Candidate>>();
+
+        let uid = self.op.uid.clone().unwrap_or_default();
+        let results: Rc<RefCell<Vec<Room>>> = Rc::new(RefCell::new(vec![]));
+        fill(&list, &results, &candidates, "", &uid);
+
+        let l = list.clone();
+        let rs = results.clone();
+        entry.connect_search_changed(move |entry| {
+            let term = entry.get_text().unwrap_or_default();
+            fill(&l, &rs, &candidates, &term, &uid);
+        });
+
+        // the focus stays in the entry, the arrows move the selection in the results
+        let l = list.clone();
+        entry.connect_key_press_event(move |_, k| {
+            let delta = match k.get_keyval() {
+                gdk::enums::key::Down => 1,
+                gdk::enums::key::Up => -1,
+                _ => return Inhibit(false),
+            };
+
+            let current = l.get_selected_row().map(|r| r.get_index()).unwrap_or(-1);
+            if let Some(row) = l.get_row_at_index((current + delta).max(0)) {
+                l.select_row(&row);
+            }
+            Inhibit(true)
+        });
+
+        let internal = self.op.internal.clone();
+        let rs = results.clone();
+        let d = dialog.clone();
+        list.connect_row_activated(move |_, row| {
+            if let Some(room) = rs.borrow().get(row.get_index() as usize) {
+                internal.send(InternalCommand::SelectRoom(room.clone())).unwrap();
+            }
+            d.destroy();
+        });
+
+        let l = list.clone();
+        entry.connect_activate(move |_| {
+            let row = l.get_selected_row().or_else(|| l.get_row_at_index(0));
+            if let Some(row) = row {
+                row.activate();
+            }
+        });
+
+        let d = dialog.clone();
+        entry.connect_stop_search(move |_| {
+            d.destroy();
+        });
+
+        dialog.show_all();
+        entry.grab_focus();
+
+        dialog
+    }
+}
+
+fn score(c: &Candidate, term: &str, uid: &str) -> Option<i32> {
+    let m = room_match(&c.room, term, uid)?;
+    let unread = if c.room.notifications > 0 || c.room.highlight > 0 { UNREAD_SCORE } else { 0 };
+    let recent = (RECENT_SCORE - c.recency).max(0);
+
+    Some(m + unread + recent)
+}
+
+fn fill(list: &gtk::ListBox, results: &Rc<RefCell<Vec<Room>>>, candidates: &[Candidate], term: &str, uid: &str) {
+    for ch in list.get_children().iter() {
+        list.remove(ch);
+    }
+
+    let mut ranked = candidates.iter()
+        .filter_map(|c| score(c, term, uid).map(|s| (s, c)))
+        .collect::<Vec<(i32, &Candidate)>>();
+    ranked.sort_by_key(|&(s, c)| (-s, c.recency));
+
+    let mut rs = results.borrow_mut();
+    rs.clear();
+    for &(_, c) in ranked.iter().take(MAX_RESULTS) {
+        list.add(&row(&c.room));
+        rs.push(c.room.clone());
+    }
+
+    if let Some(first) = list.get_row_at_index(0) {
+        list.select_row(&first);
+    }
+}
+
+fn row(room: &Room) -> gtk::Box {
+    let b = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+    b.set_border_width(6);
+
+    let name = room.name.clone().unwrap_or_default();
+    let label = gtk::Label::new(Some(&name[..]));
+    label.set_halign(gtk::Align::Start);
+    label.set_ellipsize(pango::EllipsizeMode::End);
+    b.pack_start(&label, false, false, 0);
+
+    if let Some(ref alias) = room.alias {
+        if !alias.is_empty() && *alias != name {
+            let alias = gtk::Label::new(Some(&alias[..]));
+            alias.set_ellipsize(pango::EllipsizeMode::End);
+            alias.get_style_context().unwrap().add_class("dim-label");
+            b.pack_start(&alias, false, false, 0);
+        }
+    }
+
+    let unread = room.notifications.max(room.highlight);
+    if unread > 0 {
+        let n = gtk::Label::new(Some(&format!("{}", unread)[..]));
+        let style = n.get_style_context().unwrap();
+        style.add_class("notify-badge");
+        if room.highlight > 0 {
+            style.add_class("highlight");
+        }
+        b.pack_end(&n, false, false, 0);
+    }
+
+    b.show_all();
+    b
+}
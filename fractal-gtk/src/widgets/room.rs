@@ -1,8 +1,6 @@
 extern crate gtk;
-extern crate gdk_pixbuf;
 extern crate pango;
 
-use self::gdk_pixbuf::Pixbuf;
 use self::gtk::prelude::*;
 
 use types::Room;
@@ -18,17 +16,25 @@ use std::sync::mpsc::TryRecvError;
 
 use app::AppOp;
 
+use widgets;
+use widgets::AvatarExt;
+
+const AVATAR_SIZE: i32 = 32;
+
 // Room Search item
 pub struct RoomBox<'a> {
     room: &'a Room,
     op: &'a AppOp,
+    // from the directory of another server, so ours may not know the room
+    remote: bool,
 }
 
 impl<'a> RoomBox<'a> {
-    pub fn new(room: &'a Room, op: &'a AppOp) -> RoomBox<'a> {
+    pub fn new(room: &'a Room, op: &'a AppOp, remote: bool) -> RoomBox<'a> {
         RoomBox {
             room: room,
             op: op,
+            remote: remote,
         }
     }
 
@@ -43,7 +49,8 @@ impl<'a> RoomBox<'a> {
             ref n => n.clone(),
         };
 
-        let avatar = gtk::Image::new_from_icon_name("image-missing", 5);
+        let avatar = widgets::Avatar::avatar_new(Some(AVATAR_SIZE));
+        avatar.default(String::from("image-missing"), Some(AVATAR_SIZE));
         let a = avatar.clone();
         let id = r.id.clone();
         let name = mname.clone();
@@ -55,11 +62,11 @@ impl<'a> RoomBox<'a> {
             Ok(fname) => {
                 let mut f = fname.clone();
                 if f.is_empty() {
-                    f = api::util::draw_identicon(&id, name.clone().unwrap_or_default(), api::util::AvatarMode::Circle).unwrap();
-                }
-                if let Ok(pixbuf) = Pixbuf::new_from_file_at_scale(&f, 32, 32, false) {
-                    a.set_from_pixbuf(&pixbuf);
+                    let size = api::util::avatar_pixels(AVATAR_SIZE);
+                    f = api::util::draw_identicon(&id, name.clone().unwrap_or_default(),
+                                                  api::util::AvatarMode::Circle, size).unwrap_or_default();
                 }
+                a.circle(f, Some(AVATAR_SIZE));
                 gtk::Continue(false)
             }
         });
@@ -97,9 +104,21 @@ impl<'a> RoomBox<'a> {
         });
         joinbtn.get_style_context().unwrap().add_class("suggested-action");
 
-        let buttons = gtk::Box::new(gtk::Orientation::Horizontal, 0);
+        let buttons = gtk::Box::new(gtk::Orientation::Horizontal, 6);
         buttons.pack_start(&joinbtn, false, false, 0);
 
+        // anyone can read the messages of world readable rooms without joining, but the
+        // preview comes from our server
+        if r.world_readable && !self.remote {
+            let previewbtn = gtk::Button::new_with_label("Preview");
+            let rid = r.id.clone();
+            let backend = self.op.backend.clone();
+            previewbtn.connect_clicked(move |_| {
+                backend.send(BKCommand::RoomPreview(rid.clone())).unwrap();
+            });
+            buttons.pack_start(&previewbtn, false, false, 0);
+        }
+
         b.add(&msg);
         b.add(&topic);
         b.add(&idw);
@@ -1,16 +1,31 @@
 extern crate url;
 extern crate gtk;
+extern crate gdk;
 
 use self::url::Url;
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::rc::Rc;
 use self::gtk::prelude::*;
 
 use widgets::roomrow::RoomRow;
 use types::Room;
 use types::Message;
+use util::room_match;
 use std::sync::{Arc, Mutex};
 
 
+pub const FAVOURITE_TAG: &'static str = "m.favourite";
+pub const LOW_PRIORITY_TAG: &'static str = "m.lowpriority";
+// the tags that users create have this prefix
+const USER_TAG_PREFIX: &'static str = "u.";
+// drag and drop target for the room rows, the data is the room id and the tag of the section
+const ROOM_TARGET: &'static str = "FRACTAL_ROOM";
+
+type SelectCallback = Rc<RefCell<Option<Box<dyn Fn(Room)>>>>;
+type DropCallback = Rc<RefCell<Option<Box<dyn Fn(String, Option<String>, Option<String>, Option<f64>)>>>>;
+
+
 fn get_url(url: Option<String>) -> Url {
     let defurl = Url::parse("https://matrix.org").unwrap();
 
@@ -25,143 +40,541 @@ fn get_url(url: Option<String>) -> Url {
     }
 }
 
+/// Returns the tag of the section where the @room is shown, None for the rooms without tags.
+/// Favourites go first, then the user tags and the low priority rooms.
+pub fn room_section(room: &Room) -> Option<String> {
+    if room.tags.contains_key(FAVOURITE_TAG) {
+        return Some(String::from(FAVOURITE_TAG));
+    }
 
-pub struct RoomList {
-    pub rooms: HashMap<String, RoomRow>,
-    pub baseu: Url,
-    list: gtk::ListBox,
+    let mut user_tags = room.tags.keys()
+        .filter(|t| t.starts_with(USER_TAG_PREFIX))
+        .collect::<Vec<&String>>();
+    user_tags.sort();
+    if let Some(t) = user_tags.first() {
+        return Some(t.to_string());
+    }
+
+    if room.tags.contains_key(LOW_PRIORITY_TAG) {
+        return Some(String::from(LOW_PRIORITY_TAG));
+    }
+
+    None
+}
+
+fn section_rank(tag: &Option<String>) -> (u8, String) {
+    match *tag {
+        Some(ref t) if t == FAVOURITE_TAG => (0, String::new()),
+        Some(ref t) if t == LOW_PRIORITY_TAG => (3, String::new()),
+        Some(ref t) => (1, t.clone()),
+        None => (2, String::new()),
+    }
+}
+
+fn section_title(tag: &Option<String>) -> String {
+    match *tag {
+        Some(ref t) if t == FAVOURITE_TAG => String::from("Favourites"),
+        Some(ref t) if t == LOW_PRIORITY_TAG => String::from("Low priority"),
+        Some(ref t) if t.starts_with(USER_TAG_PREFIX) => String::from(&t[USER_TAG_PREFIX.len()..]),
+        Some(ref t) => t.clone(),
+        None => String::from("Rooms"),
+    }
+}
+
+/// Returns the manual order of the @room in the section of the @tag
+fn room_order(room: &Room, tag: &Option<String>) -> Option<f64> {
+    tag.as_ref().and_then(|t| room.tags.get(t).cloned()).and_then(|o| o)
+}
+
+/// Returns the order for the @roomid dropped before the room in the position @idx of the
+/// section of the @tag, or at the end if there's no @idx. Tag orders go from 0 to 1.
+fn drop_order(rooms: &[Room], tag: &Option<String>, roomid: &str, idx: Option<usize>) -> f64 {
+    let orders = rooms.iter()
+        .filter(|r| r.id != roomid)
+        .map(|r| room_order(r, tag))
+        .collect::<Vec<Option<f64>>>();
+
+    let idx = match (idx, rooms.iter().position(|r| r.id == roomid)) {
+        (Some(i), Some(moved)) if moved < i => i - 1,
+        (Some(i), _) => i,
+        (None, _) => orders.len(),
+    };
+
+    let prev = match idx {
+        0 => 0.0,
+        i => orders.get(i - 1).cloned().and_then(|o| o).unwrap_or(0.0),
+    };
+    let next = orders.get(idx).cloned().and_then(|o| o).unwrap_or(1.0);
+
+    (prev + next.max(prev)) / 2.0
+}
 
+
+// Collapsible section of the room list with the rooms of one tag. Rooms with an order in the
+// tag go first, and the rest are sorted by the last activity.
+struct RoomListGroup {
+    tag: Option<String>,
+    // the logged in user, that isn't looked for in the members
+    uid: String,
+    rooms: HashMap<String, RoomRow>,
     roomvec: Arc<Mutex<Vec<Room>>>,
-    // TODO:
-    // * Add a header to the list
-    // * Add a collapse/expand button with a revealer
-    // * Add drag & drop support for favorites
+    list: gtk::ListBox,
+    widget: gtk::Box,
 }
 
-impl RoomList {
-    pub fn new(url: Option<String>) -> RoomList {
+impl RoomListGroup {
+    fn new(tag: Option<String>, uid: String, filter: Rc<RefCell<String>>,
+           on_select: SelectCallback, on_drop: DropCallback) -> RoomListGroup {
+        let widget = gtk::Box::new(gtk::Orientation::Vertical, 0);
         let list = gtk::ListBox::new();
-        let baseu = get_url(url);
-        let rooms = HashMap::new();
-        let roomvec = Arc::new(Mutex::new(vec![]));
+        let roomvec: Arc<Mutex<Vec<Room>>> = Arc::new(Mutex::new(vec![]));
 
-        RoomList {
-            list,
-            baseu,
-            rooms,
+        let header = gtk::Button::new();
+        header.set_relief(gtk::ReliefStyle::None);
+        if let Some(style) = header.get_style_context() {
+            style.add_class("room-list-header");
+        }
+        let hbox = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+        let title = gtk::Label::new(Some(&section_title(&tag)[..]));
+        title.set_halign(gtk::Align::Start);
+        let arrow = gtk::Image::new_from_icon_name("pan-down-symbolic", 1);
+        hbox.pack_start(&title, true, true, 0);
+        hbox.pack_start(&arrow, false, false, 0);
+        header.add(&hbox);
+
+        let revealer = gtk::Revealer::new();
+        revealer.add(&list);
+        revealer.set_reveal_child(true);
+
+        header.connect_clicked(clone!(revealer, arrow => move |_| {
+            let reveal = !revealer.get_reveal_child();
+            revealer.set_reveal_child(reveal);
+            let icon = if reveal { "pan-down-symbolic" } else { "pan-end-symbolic" };
+            arrow.set_from_icon_name(icon, 1);
+        }));
+
+        // favourites and low priority are shown without rooms, to drop rooms there
+        let placeholder = gtk::Label::new(Some("Drop rooms here"));
+        if let Some(style) = placeholder.get_style_context() {
+            style.add_class("dim-label");
+        }
+        placeholder.show();
+        list.set_placeholder(&placeholder);
+
+        let rs = roomvec.clone();
+        let me = uid.clone();
+        list.set_filter_func(Some(Box::new(move |row| {
+            let term = filter.borrow();
+            if term.is_empty() {
+                return true;
+            }
+
+            match rs.lock().unwrap().get(row.get_index() as usize) {
+                Some(r) => room_match(r, &term, &me).is_some(),
+                None => true,
+            }
+        })));
+
+        let rs = roomvec.clone();
+        list.connect_row_activated(move |_, row| {
+            let room = rs.lock().unwrap()[row.get_index() as usize].clone();
+            if let Some(ref cb) = *on_select.borrow() {
+                cb(room);
+            }
+        });
+
+        let targets = vec![gtk::TargetEntry::new(ROOM_TARGET, gtk::TargetFlags::SAME_APP, 0)];
+        list.drag_dest_set(gtk::DestDefaults::ALL, &targets, gdk::DragAction::MOVE);
+
+        let rs = roomvec.clone();
+        let t = tag.clone();
+        list.connect_drag_data_received(move |list, _, _, y, data, _, _| {
+            let text = data.get_text().unwrap_or_default();
+            let mut parts = text.splitn(2, '\n');
+            let roomid = String::from(parts.next().unwrap_or_default());
+            let from = match parts.next() {
+                Some(f) if !f.is_empty() => Some(String::from(f)),
+                _ => None,
+            };
+
+            if roomid.is_empty() {
+                return;
+            }
+
+            // only the sections of a tag have a manual order
+            let idx = list.get_row_at_y(y).map(|row| row.get_index() as usize);
+            let order = match t {
+                Some(_) => Some(drop_order(&rs.lock().unwrap(), &t, &roomid, idx)),
+                None => None,
+            };
+
+            if let Some(ref cb) = *on_drop.borrow() {
+                cb(roomid, from, t.clone(), order);
+            }
+        });
+
+        widget.pack_start(&header, false, false, 0);
+        widget.pack_start(&revealer, false, false, 0);
+        widget.show_all();
+
+        RoomListGroup {
+            tag,
+            uid,
+            rooms: HashMap::new(),
             roomvec,
+            list,
+            widget,
         }
     }
 
-    pub fn add_room(&mut self, r: Room) {
+    /// Adds the room in its position by order, or at the top of the rooms without order if
+    /// @top is true and at the end otherwise
+    fn insert(&mut self, r: Room, top: bool, baseu: &Url) {
         if self.rooms.contains_key(&r.id) {
             // room added, we'll pass
             return;
         }
 
+        let idx = {
+            let mut rv = self.roomvec.lock().unwrap();
+            let pos = match room_order(&r, &self.tag) {
+                Some(o) => rv.iter().position(|x| room_order(x, &self.tag).map(|xo| xo > o).unwrap_or(true)),
+                None if top => rv.iter().position(|x| room_order(x, &self.tag).is_none()),
+                None => None,
+            };
+            let idx = pos.unwrap_or(rv.len());
+            rv.insert(idx, r.clone());
+            idx
+        };
+
         let rid = r.id.clone();
-        self.roomvec.lock().unwrap().push(r.clone());
+        let row = RoomRow::new(r, baseu);
+        self.list.insert(&self.row_widget(&row), idx as i32);
+        self.rooms.insert(rid, row);
+        self.widget.show();
+    }
 
-        let row = RoomRow::new(r, &self.baseu);
-        self.list.add(&row.widget());
+    // the row is the drag source to move the room to other section
+    fn row_widget(&self, row: &RoomRow) -> gtk::EventBox {
+        let eb = gtk::EventBox::new();
+        eb.add(&row.widget());
 
-        self.rooms.insert(rid, row);
+        let targets = vec![gtk::TargetEntry::new(ROOM_TARGET, gtk::TargetFlags::SAME_APP, 0)];
+        eb.drag_source_set(gdk::ModifierType::BUTTON1_MASK, &targets, gdk::DragAction::MOVE);
+
+        let data = format!("{}\n{}", row.room.id, self.tag.clone().unwrap_or_default());
+        eb.connect_drag_data_get(move |_, _, sel, _, _| {
+            sel.set_text(&data, -1);
+        });
+
+        eb.show();
+        eb
     }
 
-    pub fn prepend_room(&mut self, r: Room) {
-        if self.rooms.contains_key(&r.id) {
-            // room added, we'll pass
-            return;
+    fn remove(&mut self, room: &str) -> Option<Room> {
+        self.rooms.remove(room);
+
+        // the filter function locks the roomvec while the list is changed
+        let (idx, r, empty) = {
+            let mut rv = self.roomvec.lock().unwrap();
+            let idx = rv.iter().position(|x| { x.id == room})?;
+            let r = rv.remove(idx);
+            (idx, r, rv.is_empty())
+        };
+
+        if let Some(row) = self.list.get_row_at_index(idx as i32) {
+            self.list.remove(&row);
         }
 
-        let rid = r.id.clone();
-        self.roomvec.lock().unwrap().insert(0, r.clone());
+        // user tags without rooms aren't shown
+        if empty && section_rank(&self.tag).0 == 1 {
+            self.widget.hide();
+        }
 
-        let row = RoomRow::new(r, &self.baseu);
-        self.list.prepend(&row.widget());
+        Some(r)
+    }
 
-        self.rooms.insert(rid, row);
+    /// Shows only the rooms that match the filter, and hides the section if there's none
+    fn refilter(&self, term: &str) {
+        self.list.invalidate_filter();
+
+        let visible = match term {
+            "" => section_rank(&self.tag).0 != 1 || !self.rooms.is_empty(),
+            t => self.roomvec.lock().unwrap().iter().any(|r| room_match(r, t, &self.uid).is_some()),
+        };
+        self.widget.set_visible(visible);
+    }
+
+    /// The first room that matches the filter
+    fn first_match(&self, term: &str) -> Option<String> {
+        self.roomvec.lock().unwrap().iter()
+            .find(|r| term.is_empty() || room_match(r, term, &self.uid).is_some())
+            .map(|r| r.id.clone())
     }
 
-    pub fn set_room_notifications(&mut self, room: String, n: i32) {
-        if let Some(r) = self.rooms.get(&room) {
-            r.set_notifications(n);
+    fn get_selected(&self) -> Option<String> {
+        let rv = self.roomvec.lock().unwrap();
+        match self.list.get_selected_row() {
+            Some(row) => Some(rv[row.get_index() as usize].id.clone()),
+            None => None,
         }
+    }
 
-        self.edit_room(&room, move |rv| { rv.notifications = n; });
+    fn select(&self, room: &str) {
+        let rv = self.roomvec.lock().unwrap();
+        if let Some(idx) = rv.iter().position(|x| { x.id == room}) {
+            if let Some(ref row) = self.list.get_row_at_index(idx as i32) {
+                self.list.select_row(row);
+            }
+        }
     }
 
-    pub fn remove_room(&mut self, room: String) -> Option<Room> {
-        self.rooms.remove(&room);
+    fn edit_room<F: Fn(&mut Room) + 'static>(&mut self, room: &str, cb: F) {
         let mut rv = self.roomvec.lock().unwrap();
         if let Some(idx) = rv.iter().position(|x| { x.id == room}) {
-            if let Some(row) = self.list.get_row_at_index(idx as i32) {
-                self.list.remove(&row);
+            if let Some(ref mut m) = rv.get_mut(idx) {
+                cb(m);
+            }
+        }
+    }
+}
+
+
+pub struct RoomList {
+    pub baseu: Url,
+    uid: String,
+    groups: Vec<RoomListGroup>,
+    widget: gtk::Box,
+    filter: Rc<RefCell<String>>,
+
+    on_select: SelectCallback,
+    on_drop: DropCallback,
+}
+
+impl RoomList {
+    pub fn new(url: Option<String>, uid: Option<String>) -> RoomList {
+        let baseu = get_url(url);
+        let widget = gtk::Box::new(gtk::Orientation::Vertical, 0);
+        if let Some(style) = widget.get_style_context() {
+            style.add_class("room-list");
+        }
+
+        let mut rl = RoomList {
+            baseu,
+            uid: uid.unwrap_or_default(),
+            groups: vec![],
+            widget,
+            filter: Rc::new(RefCell::new(String::new())),
+            on_select: Rc::new(RefCell::new(None)),
+            on_drop: Rc::new(RefCell::new(None)),
+        };
+
+        rl.group(Some(String::from(FAVOURITE_TAG)));
+        rl.group(None);
+        rl.group(Some(String::from(LOW_PRIORITY_TAG)));
+
+        rl
+    }
+
+    /// Returns the index of the section for the @tag, creating it if it doesn't exist
+    fn group(&mut self, tag: Option<String>) -> usize {
+        if let Some(idx) = self.groups.iter().position(|g| g.tag == tag) {
+            return idx;
+        }
+
+        let rank = section_rank(&tag);
+        let idx = self.groups.iter()
+            .position(|g| section_rank(&g.tag) > rank)
+            .unwrap_or(self.groups.len());
+
+        let g = RoomListGroup::new(tag, self.uid.clone(), self.filter.clone(),
+                                   self.on_select.clone(), self.on_drop.clone());
+
+        // only one room is selected in the whole list
+        let lists = self.groups.iter().map(|g| g.list.clone()).collect::<Vec<gtk::ListBox>>();
+        for l in lists.iter() {
+            let other = g.list.clone();
+            l.connect_row_selected(move |_, row| {
+                if row.is_some() {
+                    other.unselect_all();
+                }
+            });
+            let other = l.clone();
+            g.list.connect_row_selected(move |_, row| {
+                if row.is_some() {
+                    other.unselect_all();
+                }
+            });
+        }
+
+        self.widget.pack_start(&g.widget, false, false, 0);
+        self.widget.reorder_child(&g.widget, idx as i32);
+        self.groups.insert(idx, g);
+
+        idx
+    }
+
+    fn room_group(&self, room: &str) -> Option<usize> {
+        self.groups.iter().position(|g| g.rooms.contains_key(room))
+    }
+
+    pub fn add_room(&mut self, r: Room) {
+        if self.room_group(&r.id).is_some() {
+            return;
+        }
+
+        let idx = self.group(room_section(&r));
+        let baseu = self.baseu.clone();
+        self.groups[idx].insert(r, false, &baseu);
+    }
+
+    pub fn prepend_room(&mut self, r: Room) {
+        if self.room_group(&r.id).is_some() {
+            return;
+        }
+
+        let idx = self.group(room_section(&r));
+        let baseu = self.baseu.clone();
+        self.groups[idx].insert(r, true, &baseu);
+    }
+
+    pub fn set_room_notifications(&mut self, room: String, n: i32, h: i32) {
+        if let Some(idx) = self.room_group(&room) {
+            let g = &mut self.groups[idx];
+            if let Some(r) = g.rooms.get_mut(&room) {
+                r.set_notifications(n, h);
             }
-            return Some(rv.remove(idx));
+            g.edit_room(&room, move |rv| {
+                rv.notifications = n;
+                rv.highlight = h;
+            });
         }
+    }
 
-        None
+    pub fn remove_room(&mut self, room: String) -> Option<Room> {
+        match self.room_group(&room) {
+            Some(idx) => self.groups[idx].remove(&room),
+            None => None,
+        }
     }
 
     pub fn rename_room(&mut self, room: String, newname: Option<String>) {
-        if let (Some(r), Some(n)) = (self.rooms.get_mut(&room), newname.clone()) {
-            r.set_name(n);
+        if let Some(idx) = self.room_group(&room) {
+            let g = &mut self.groups[idx];
+            if let (Some(r), Some(n)) = (g.rooms.get_mut(&room), newname.clone()) {
+                r.set_name(n);
+            }
+            g.edit_room(&room, move |rv| { rv.name = newname.clone(); });
         }
-
-        self.edit_room(&room, move |rv| { rv.name = newname.clone(); });
     }
 
     pub fn set_room_avatar(&mut self, room: String, av: Option<String>) {
-        if let Some(r) = self.rooms.get_mut(&room) {
-            r.set_avatar(av.clone());
+        if let Some(idx) = self.room_group(&room) {
+            let g = &mut self.groups[idx];
+            if let Some(r) = g.rooms.get_mut(&room) {
+                r.set_avatar(av.clone());
+            }
+            g.edit_room(&room, move |rv| { rv.avatar = av.clone(); });
+        }
+    }
+
+    /// Updates the row of a room that's already in the list with the room from a new sync
+    pub fn update_room(&mut self, r: Room) {
+        let idx = match self.room_group(&r.id) {
+            Some(idx) => idx,
+            None => return self.add_room(r),
+        };
+
+        if self.groups[idx].tag != room_section(&r) {
+            self.set_room_tags(r.id.clone(), r.tags.clone());
         }
 
-        self.edit_room(&room, move |rv| { rv.avatar = av.clone(); });
+        let (id, name, n, h) = (r.id.clone(), r.name.clone(), r.notifications, r.highlight);
+        self.rename_room(id.clone(), name);
+        self.set_room_notifications(id.clone(), n, h);
+
+        // the avatar is updated when it's downloaded again
+        if let Some(idx) = self.room_group(&id) {
+            self.groups[idx].edit_room(&id, move |rv| {
+                let avatar = rv.avatar.clone();
+                *rv = r.clone();
+                rv.avatar = avatar;
+            });
+        }
     }
 
-    pub fn widget(&self) -> gtk::Box {
-        let b = gtk::Box::new(gtk::Orientation::Vertical, 0);
-        if let Some(style) = b.get_style_context() {
-            style.add_class("room-list");
+    /// Moves the room to the section of its new @tags
+    pub fn set_room_tags(&mut self, room: String, tags: HashMap<String, Option<f64>>) {
+        let s = self.get_selected();
+
+        if let Some(mut r) = self.remove_room(room) {
+            r.tags = tags;
+            self.prepend_room(r);
         }
 
-        b.pack_start(&self.list, true, true, 0);
-        b.show_all();
+        self.set_selected(s);
+    }
+
+    pub fn widget(&self) -> gtk::Box {
+        self.widget.show();
         self.render_notifies();
 
-        b
+        // user tags without rooms aren't shown
+        for g in self.groups.iter() {
+            if section_rank(&g.tag).0 == 1 && g.rooms.is_empty() {
+                g.widget.hide();
+            }
+        }
+
+        self.widget.clone()
     }
 
     pub fn connect<F: Fn(Room) + 'static>(&self, cb: F) {
-        let rs = self.roomvec.clone();
-        self.list.connect_row_activated(move |_, row| {
-            let idx = row.get_index();
-            cb(rs.lock().unwrap()[idx as usize].clone());
-        });
+        *self.on_select.borrow_mut() = Some(Box::new(cb));
     }
 
-    pub fn get_selected(&self) -> Option<String> {
-        let rv = self.roomvec.lock().unwrap();
-        match self.list.get_selected_row() {
-            Some(row) => Some(rv[row.get_index() as usize].id.clone()),
-            None => None,
-        }
+    /// Calls @cb with the room id, the tag of the section where the room was, the tag of the
+    /// section where it's dropped and the order in that tag, when a room is dragged to other
+    /// position
+    pub fn connect_drop<F: Fn(String, Option<String>, Option<String>, Option<f64>) + 'static>(&self, cb: F) {
+        *self.on_drop.borrow_mut() = Some(Box::new(cb));
     }
 
-    pub fn set_selected(&self, room: Option<String>) {
-        self.list.unselect_all();
+    /// Shows only the rooms with a name, alias or member name that matches @term
+    pub fn filter(&self, term: &str) {
+        *self.filter.borrow_mut() = String::from(term.trim());
 
-        if room.is_none() {
-            return;
+        let term = self.filter.borrow();
+        for g in self.groups.iter() {
+            g.refilter(&term);
         }
+    }
 
-        let room = room.unwrap();
+    /// The first room shown with the current filter
+    pub fn first_match(&self) -> Option<String> {
+        let term = self.filter.borrow();
+        self.groups.iter().filter_map(|g| g.first_match(&term)).next()
+    }
 
-        let rv = self.roomvec.lock().unwrap();
-        if let Some(idx) = rv.iter().position(|x| { x.id == room}) {
-            if let Some(ref row) = self.list.get_row_at_index(idx as i32) {
-                self.list.select_row(row);
+    /// The ids of the rooms in the order that they're shown
+    pub fn room_ids(&self) -> Vec<String> {
+        self.groups.iter()
+            .flat_map(|g| g.roomvec.lock().unwrap().iter().map(|r| r.id.clone()).collect::<Vec<String>>())
+            .collect()
+    }
+
+    pub fn get_selected(&self) -> Option<String> {
+        self.groups.iter().filter_map(|g| g.get_selected()).next()
+    }
+
+    pub fn set_selected(&self, room: Option<String>) {
+        for g in self.groups.iter() {
+            g.list.unselect_all();
+        }
+
+        if let Some(room) = room {
+            if let Some(idx) = self.room_group(&room) {
+                self.groups[idx].select(&room);
             }
         }
     }
@@ -190,16 +603,9 @@ impl RoomList {
     }
 
     fn render_notifies(&self) {
-        for (_k, r) in self.rooms.iter() {
-            r.render_notifies();
-        }
-    }
-
-    fn edit_room<F: Fn(&mut Room) + 'static>(&mut self, room: &str, cb: F) {
-        let mut rv = self.roomvec.lock().unwrap();
-        if let Some(idx) = rv.iter().position(|x| { x.id == room}) {
-            if let Some(ref mut m) = rv.get_mut(idx) {
-                cb(m);
+        for g in self.groups.iter() {
+            for (_k, r) in g.rooms.iter() {
+                r.render_notifies();
             }
         }
     }
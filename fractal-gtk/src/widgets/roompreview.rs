@@ -0,0 +1,107 @@
+extern crate gtk;
+extern crate pango;
+
+use self::gtk::prelude::*;
+
+use types::Room;
+
+use backend::BKCommand;
+
+use app::AppOp;
+use util;
+use widgets;
+
+// Dialog with the topic and the last messages of a world readable room from the directory,
+// to see what's going on before joining it
+pub struct RoomPreview<'a> {
+    room: &'a Room,
+    op: &'a AppOp,
+}
+
+impl<'a> RoomPreview<'a> {
+    pub fn new(room: &'a Room, op: &'a AppOp) -> RoomPreview<'a> {
+        RoomPreview {
+            room: room,
+            op: op,
+        }
+    }
+
+    pub fn dialog(&self, parent: &gtk::Window) -> gtk::Dialog {
+        let r = self.room;
+
+        let title = match r.name {
+            Some(ref n) if !n.is_empty() => n.clone(),
+            _ => r.alias.clone().unwrap_or(r.id.clone()),
+        };
+
+        let dialog = gtk::Dialog::new_with_buttons(
+            Some(&title[..]),
+            Some(parent),
+            gtk::DialogFlags::MODAL|
+            gtk::DialogFlags::USE_HEADER_BAR|
+            gtk::DialogFlags::DESTROY_WITH_PARENT,
+            &[]);
+        dialog.set_default_size(500, 500);
+
+        let joinbtn = dialog.add_button("Join", 1);
+        joinbtn.get_style_context().unwrap().add_class("suggested-action");
+
+        let topic = gtk::Label::new("");
+        topic.set_line_wrap(true);
+        topic.set_line_wrap_mode(pango::WrapMode::WordChar);
+        topic.set_markup(&util::markup(&r.topic.clone().unwrap_or_default()));
+        topic.set_halign(gtk::Align::Start);
+        topic.set_alignment(0.0, 0.0);
+
+        let list = gtk::ListBox::new();
+        list.set_selection_mode(gtk::SelectionMode::None);
+        let mut prev = None;
+        for msg in r.messages.iter() {
+            let mb = widgets::MessageBox::new(r, msg, self.op);
+            let w = match prev {
+                Some(ref p) if p == &msg.sender => mb.small_widget(),
+                _ => mb.widget(),
+            };
+            list.add(&w);
+            prev = Some(msg.sender.clone());
+        }
+
+        if r.messages.is_empty() {
+            let empty = gtk::Label::new("There are no messages to show");
+            empty.get_style_context().unwrap().add_class("dim-label");
+            list.add(&empty);
+        }
+
+        let scroll = gtk::ScrolledWindow::new(None, None);
+        scroll.set_vexpand(true);
+        scroll.set_hscrollbar_policy(gtk::PolicyType::Never);
+        scroll.add(&list);
+
+        // the last messages are the interesting ones
+        let s = scroll.clone();
+        gtk::timeout_add(500, move || {
+            if let Some(adj) = s.get_vadjustment() {
+                adj.set_value(adj.get_upper() - adj.get_page_size());
+            }
+            gtk::Continue(false)
+        });
+
+        let content = dialog.get_content_area();
+        content.set_spacing(6);
+        content.set_border_width(6);
+        content.pack_start(&topic, false, false, 0);
+        content.pack_start(&scroll, true, true, 0);
+
+        let rid = r.id.clone();
+        let backend = self.op.backend.clone();
+        dialog.connect_response(move |d, resp| {
+            if resp == 1 {
+                backend.send(BKCommand::JoinRoom(rid.clone())).unwrap();
+            }
+            d.destroy();
+        });
+        dialog.show_all();
+
+        dialog
+    }
+}
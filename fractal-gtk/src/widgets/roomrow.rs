@@ -8,6 +8,8 @@ use self::gtk::prelude::*;
 use fractal_api;
 use fractal_api::util::AvatarMode;
 use fractal_api::util::draw_identicon;
+use fractal_api::util::dw_media;
+use fractal_api::util::avatar_pixels;
 
 use types::Room;
 
@@ -21,7 +23,7 @@ const ICON_SIZE: i32 = 20;
 
 
 // Room row for the room sidebar. This widget shows the room avatar, the room name and the unread
-// messages in the room
+// messages in the room, the badge is red when some of them are highlights
 // +-----+--------------------------+------+
 // | IMG | Fractal                  |  32  |
 // +-----+--------------------------+------+
@@ -43,15 +45,10 @@ impl RoomRow {
         text.set_alignment(0.0, 0.0);
         text.set_ellipsize(pango::EllipsizeMode::End);
 
-        let n = room.notifications;
-        let notifications = gtk::Label::new(&format!("{}", n)[..]);
+        let notifications = gtk::Label::new("");
         if let Some(style) = notifications.get_style_context() {
             style.add_class("notify-badge");
         }
-        match n {
-            0 => notifications.hide(),
-            _ => notifications.show(),
-        }
 
         icon.default(String::from("avatar-default-symbolic"), Some(ICON_SIZE));
         if avatar.starts_with("mxc") || avatar.is_empty() {
@@ -60,25 +57,37 @@ impl RoomRow {
             icon.circle(avatar, Some(ICON_SIZE));
         }
 
-        RoomRow {
+        let row = RoomRow {
             room,
             icon,
             text,
             notifications,
             baseu,
-        }
+        };
+        row.render_notifies();
+
+        row
     }
 
-    pub fn set_notifications(&self, n: i32) {
-        self.notifications.set_text(&format!("{}", n));
-        match n {
-            0 => self.notifications.hide(),
-            _ => self.notifications.show(),
-        }
+    pub fn set_notifications(&mut self, n: i32, highlight: i32) {
+        self.room.notifications = n;
+        self.room.highlight = highlight;
+        self.render_notifies();
     }
 
     pub fn render_notifies(&self) {
-        match self.room.notifications {
+        // the highlights are also notifications, but we count them locally too
+        let n = self.room.notifications.max(self.room.highlight);
+        self.notifications.set_text(&format!("{}", n));
+
+        if let Some(style) = self.notifications.get_style_context() {
+            match self.room.highlight {
+                0 => style.remove_class("highlight"),
+                _ => style.add_class("highlight"),
+            }
+        }
+
+        match n {
             0 => self.notifications.hide(),
             _ => self.notifications.show(),
         }
@@ -112,10 +121,7 @@ impl RoomRow {
         b.pack_start(&self.text, true, true, 0);
         b.pack_start(&self.notifications, false, false, 5);
         b.show_all();
-
-        if self.room.notifications == 0 {
-            self.notifications.hide();
-        }
+        self.render_notifies();
 
         b
     }
@@ -132,8 +138,8 @@ fn download_avatar(baseu: &Url,
     glib_thread!(Result<String, Error>,
         || {
             match avatar {
-                ref s if s.is_empty() => identicon!(&rid, name),
-                _ => fractal_api::util::dw_media(&url, &avatar, true, None, 40, 40),
+                ref s if s.is_empty() => identicon!(&rid, name, avatar_pixels(ICON_SIZE)),
+                _ => thumb!(&url, &avatar, avatar_pixels(ICON_SIZE)),
             }
         },
         |rc: Result<String, Error>| {
@@ -0,0 +1,100 @@
+extern crate pango;
+extern crate gtk;
+
+use self::gtk::prelude::*;
+
+use types::Message;
+use types::SearchResult;
+
+use util::highlight_markup;
+use util::markup;
+
+use app::AppOp;
+
+// Search result, the message that matches with the highlights in bold and the messages
+// around it, dimmed
+// +----------------------------------------+
+// | Alice in Fractal        12/Mar/18 10:20 |
+// | bob: before                             |
+// | the **result**                          |
+// | bob: after                              |
+// +----------------------------------------+
+pub struct SearchResultBox<'a> {
+    result: &'a SearchResult,
+    highlights: &'a [String],
+    op: &'a AppOp,
+}
+
+impl<'a> SearchResultBox<'a> {
+    pub fn new(result: &'a SearchResult, highlights: &'a [String], op: &'a AppOp) -> SearchResultBox<'a> {
+        SearchResultBox {
+            result: result,
+            highlights: highlights,
+            op: op,
+        }
+    }
+
+    pub fn widget(&self) -> gtk::Box {
+        let b = gtk::Box::new(gtk::Orientation::Vertical, 3);
+        b.set_border_width(6);
+        if let Some(style) = b.get_style_context() {
+            style.add_class("search-result");
+        }
+
+        let msg = &self.result.message;
+        let roomname = match self.op.rooms.get(&msg.room) {
+            Some(r) => r.name.clone().unwrap_or_default(),
+            None => msg.room.clone(),
+        };
+
+        let header = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+        let title = gtk::Label::new("");
+        title.set_markup(&format!("<b>{}</b> in {}", markup(&self.sender_name(msg)), markup(&roomname)));
+        title.set_ellipsize(pango::EllipsizeMode::End);
+        let date = gtk::Label::new(&msg.date.format("%d/%b/%y %H:%M").to_string()[..]);
+        if let Some(style) = date.get_style_context() {
+            style.add_class("dim-label");
+        }
+        header.pack_start(&title, false, false, 0);
+        header.pack_end(&date, false, false, 0);
+        b.pack_start(&header, false, false, 0);
+
+        for m in self.result.before.iter() {
+            b.pack_start(&self.context_line(m), false, false, 0);
+        }
+
+        let body = gtk::Label::new("");
+        body.set_markup(&highlight_markup(&msg.body, self.highlights));
+        body.set_line_wrap(true);
+        body.set_line_wrap_mode(pango::WrapMode::WordChar);
+        body.set_halign(gtk::Align::Start);
+        body.set_xalign(0.0);
+        b.pack_start(&body, false, false, 0);
+
+        for m in self.result.after.iter() {
+            b.pack_start(&self.context_line(m), false, false, 0);
+        }
+
+        b.show_all();
+        b
+    }
+
+    fn context_line(&self, msg: &Message) -> gtk::Label {
+        let text = format!("{}: {}", self.sender_name(msg), msg.body);
+        let l = gtk::Label::new(&text[..]);
+        l.set_ellipsize(pango::EllipsizeMode::End);
+        l.set_halign(gtk::Align::Start);
+        if let Some(style) = l.get_style_context() {
+            style.add_class("dim-label");
+        }
+
+        l
+    }
+
+    fn sender_name(&self, msg: &Message) -> String {
+        self.op.rooms.get(&msg.room)
+            .and_then(|r| r.members.get(&msg.sender))
+            .and_then(|m| m.get_alias())
+            .unwrap_or(msg.sender.clone())
+    }
+}